@@ -0,0 +1,1462 @@
+use std;
+use std::f64::consts::PI;
+
+use channel;
+use crc;
+use endian;
+use fft;
+use layout;
+use sample;
+use sample_type;
+use stream;
+
+mod tables;
+
+static LFE: uint = 5;
+static CPL: uint = 6;
+
+static SAMPLE_RATES: [f64, ..3] = [48000.0, 44100.0, 32000.0];
+
+/// Speaker positions of the decoded channels, in the order they are output
+/// when the stream is not downmixed.
+#[deriving(PartialEq)]
+enum Position {
+  Left, Right, Center, LowFrequency, LeftSurround, RightSurround, Surround
+}
+
+/// Decodes an AC-3 (Dolby Digital, ATSC A/52) elementary stream into float
+/// audio, one packet per sync frame (1536 samples).
+///
+/// Frames that fail their CRC, or that cannot be parsed, are replaced by
+/// silence so that the output keeps its length.
+pub struct Decoder {
+  source: channel::Source<::Binary>,
+  sink: channel::Sink<::Audio>,
+  channels: uint
+}
+
+impl Decoder {
+  /// Creates a decoder that outputs `channels` channels. 1 and 2 downmix the
+  /// stream (using the mix levels it carries), 0 outputs every channel of the
+  /// stream in WAVE order (L R C LFE Ls Rs).
+  ///
+  /// 3 to 6 output L R C, quadraphonic, L R C Ls Rs or 5.1, whatever the
+  /// stream carries, so that the output keeps its channels when the stream
+  /// changes mode. Channels without a speaker of their own are mixed into
+  /// their neighbours, and speakers the stream lacks are silent.
+  pub fn new(source: channel::Source<::Binary>, sink: channel::Sink<::Audio>, channels: uint) -> Decoder {
+    if channels > 6 {
+      panic!("ac3::Decoder: Cannot output more than 6 channels (ARGUMENT)");
+    }
+
+    return Decoder { source: source, sink: sink, channels: channels };
+  }
+
+  pub fn run(&mut self) {
+    let requested = self.channels;
+
+    let mut s = stream::Stream::new(&mut self.source);
+    let sink = &mut self.sink;
+
+    let mut state = box State::new();
+    let mut frame = Vec::with_capacity(3840);
+
+    let mut pending = Vec::new();
+    let mut pending_format: Option<(uint, f64, layout::Layout)> = None;
+
+    let mut output = Vec::new();
+
+    while sync(&mut s, &mut frame) {
+      let format = match state.decode_frame(frame.as_slice()) {
+        Ok(()) => Some(state.downmix(requested, &mut output)),
+        Err(_) => {
+          state.reset();
+
+          if state.nfchans > 0 {
+            let channels = state.output_channels(requested);
+
+            output.truncate(0);
+            output.grow(channels * 1536, 0.0);
+
+            Some((channels, SAMPLE_RATES[state.fscod], state.layout(requested)))
+          } else {
+            None
+          }
+        }
+      };
+
+      if let Some(format) = format {
+        if let Some((channels, sample_rate, ref layout)) = pending_format {
+          write(sink, pending.as_slice(), channels, sample_rate, layout, false);
+        }
+
+        std::mem::swap(&mut pending, &mut output);
+        pending_format = Some(format);
+      }
+    }
+
+    let (channels, sample_rate, layout) = pending_format.unwrap_or((0, 0.0, layout::Unknown));
+
+    write(sink, pending.as_slice(), channels, sample_rate, &layout, true);
+  }
+}
+
+fn write(sink: &mut channel::Sink<::Audio>, samples: &[f64], channels: uint, sample_rate: f64, layout: &layout::Layout, last: bool) {
+  sink.write(|audio| {
+    audio.channels = channels;
+    audio.layout = layout.clone();
+    audio.sample_rate = sample_rate;
+    audio.sample_type = sample_type::Float(32);
+    audio.endian = endian::Big;
+
+    sample::encode(samples, audio);
+
+    audio.last = last;
+  });
+}
+
+/// Finds the next sync frame, and reads all of it into `frame`. Returns
+/// false at end of file, including when the last frame is truncated.
+fn sync(s: &mut stream::Stream, frame: &mut Vec<u8>) -> bool {
+  loop {
+    let mut previous = 0u8;
+
+    loop {
+      let byte = match s.try_read_u8() {
+        Some(byte) => byte,
+        None => return false
+      };
+
+      if previous == 0x0B && byte == 0x77 {
+        break;
+      }
+
+      previous = byte;
+    }
+
+    frame.truncate(0);
+    frame.push_all([0x0B, 0x77]);
+
+    for _ in range(0u, 3) {
+      match s.try_read_u8() {
+        Some(byte) => frame.push(byte),
+        None => return false
+      }
+    }
+
+    let size = match frame_size(frame[4]) {
+      Some(size) => size,
+      None => continue
+    };
+
+    for _ in range(5, size) {
+      match s.try_read_u8() {
+        Some(byte) => frame.push(byte),
+        None => return false
+      }
+    }
+
+    return true;
+  }
+}
+
+/// Returns the size in bytes of a frame with the given `fscod` and
+/// `frmsizecod` byte, or `None` if it is invalid.
+fn frame_size(code: u8) -> Option<uint> {
+  let fscod = (code >> 6) as uint;
+  let frmsizecod = (code & 0x3F) as uint;
+
+  if fscod == 3 || frmsizecod >= 38 {
+    return None;
+  }
+
+  let bit_rate = tables::BIT_RATES[frmsizecod / 2];
+
+  let words = match fscod {
+    0 => bit_rate * 2,
+    1 => bit_rate * 96000 / 44100 + (frmsizecod & 1),
+    _ => bit_rate * 3
+  };
+
+  return Some(words * 2);
+}
+
+/// Delta bit allocation segments for a channel.
+struct Delta {
+  enabled: bool,
+  segments: uint,
+  offset: [uint, ..8],
+  length: [uint, ..8],
+  ba: [uint, ..8]
+}
+
+impl Delta {
+  fn new() -> Delta {
+    return Delta { enabled: false, segments: 0, offset: [0, ..8], length: [0, ..8], ba: [0, ..8] };
+  }
+}
+
+/// Bit allocation parameters shared by every channel.
+struct Allocation {
+  slow_decay: int, fast_decay: int, slow_gain: int, db_per_bit: int, floor: int
+}
+
+/// Pending mantissas of grouped quantizers (bap 1, 2 and 4), which are shared
+/// across the channels of an audio block.
+struct Groups {
+  values: [[f64, ..3], ..3],
+  remaining: [uint, ..3]
+}
+
+/// Decoder state that persists between audio blocks and sync frames.
+struct State {
+  fscod: uint,
+  acmod: uint,
+  nfchans: uint,
+  lfeon: bool,
+  cmixlev: uint,
+  surmixlev: uint,
+
+  blksw: [bool, ..5],
+  dithflag: [bool, ..5],
+  dynrng: [f64, ..2],
+
+  cplinu: bool,
+  chincpl: [bool, ..5],
+  phsflginu: bool,
+  cplbegf: uint,
+  cplendf: uint,
+  cplbndstrc: [bool, ..18],
+  ncplbnd: uint,
+  cplco: [[f64, ..18], ..5],
+  phsflg: [bool, ..18],
+  rematflg: [bool, ..4],
+
+  endmant: [uint, ..7],
+  exps: [[int, ..256], ..7],
+  bap: [[u8, ..256], ..7],
+
+  sdcycod: uint, fdcycod: uint, sgaincod: uint, dbpbcod: uint, floorcod: uint,
+  csnroffst: uint,
+  fsnroffst: [uint, ..7],
+  fgaincod: [uint, ..7],
+  cplfleak: int, cplsleak: int,
+  delta: [Delta, ..7],
+
+  coefficients: [[f64, ..256], ..7],
+  coupled_zero: [bool, ..256],
+  delay: [[f64, ..128], ..6],
+  pcm: [[f64, ..1536], ..6],
+
+  window: [f64, ..256],
+  long: Imdct,
+  short: Imdct,
+  random: u32
+}
+
+impl State {
+  fn new() -> State {
+    return State {
+      fscod: 0, acmod: 0, nfchans: 0, lfeon: false, cmixlev: 0, surmixlev: 0,
+      blksw: [false, ..5], dithflag: [false, ..5], dynrng: [1.0, ..2],
+      cplinu: false, chincpl: [false, ..5], phsflginu: false, cplbegf: 0, cplendf: 0,
+      cplbndstrc: [false, ..18], ncplbnd: 0, cplco: [[0.0, ..18], ..5], phsflg: [false, ..18],
+      rematflg: [false, ..4],
+      endmant: [0, ..7], exps: [[0, ..256], ..7], bap: [[0, ..256], ..7],
+      sdcycod: 0, fdcycod: 0, sgaincod: 0, dbpbcod: 0, floorcod: 0,
+      csnroffst: 0, fsnroffst: [0, ..7], fgaincod: [0, ..7], cplfleak: 0, cplsleak: 0,
+      delta: [Delta::new(), ..7],
+      coefficients: [[0.0, ..256], ..7], coupled_zero: [false, ..256],
+      delay: [[0.0, ..128], ..6], pcm: [[0.0, ..1536], ..6],
+      window: window(),
+      long: Imdct::new(256),
+      short: Imdct::new(128),
+      random: 1
+    };
+  }
+
+  /// Clears the overlap buffers, so that a bad frame does not leak into the
+  /// following ones.
+  fn reset(&mut self) {
+    self.delay = [[0.0, ..128], ..6];
+  }
+
+  fn decode_frame(&mut self, frame: &[u8]) -> Result<(), &'static str> {
+    if frame.len() < 8 {
+      return Err("Invalid frame size");
+    }
+
+    let mut r = stream::Bitslice::new(frame);
+
+    r.skip(32);
+
+    let fscod = r.read_n(2) as uint;
+    let frmsizecod = r.read_n(6) as uint;
+
+    if fscod == 3 || frmsizecod >= 38 {
+      return Err("Invalid fscod or frmsizecod");
+    }
+
+    let bsid = r.read_n(5);
+
+    if bsid > 8 {
+      return Err("Unsupported bsid, E-AC-3 is not supported");
+    }
+
+    r.skip(3); // bsmod
+
+    let acmod = r.read_n(3) as uint;
+
+    self.cmixlev = if acmod & 1 == 1 && acmod != 1 { r.read_n(2) as uint } else { 0 };
+    self.surmixlev = if acmod & 4 == 4 { r.read_n(2) as uint } else { 0 };
+
+    if acmod == 2 {
+      r.skip(2); // dsurmod
+    }
+
+    let lfeon = r.read_bool();
+
+    if acmod != self.acmod || lfeon != self.lfeon || fscod != self.fscod {
+      self.reset();
+    }
+
+    self.fscod = fscod;
+    self.acmod = acmod;
+    self.lfeon = lfeon;
+    self.nfchans = tables::CHANNELS[acmod];
+
+    for _ in range(0u, if acmod == 0 { 2 } else { 1 }) {
+      r.skip(5); // dialnorm
+      if r.read_bool() { r.skip(8); } // compr
+      if r.read_bool() { r.skip(8); } // langcod
+      if r.read_bool() { r.skip(7); } // mixlevel, roomtyp
+    }
+
+    r.skip(2); // copyrightb, origbs
+
+    if r.read_bool() { r.skip(14); } // timecod1 or xbsi1
+    if r.read_bool() { r.skip(14); } // timecod2 or xbsi2
+
+    if r.read_bool() {
+      let addbsil = r.read_n(6) as uint;
+
+      r.skip((addbsil + 1) * 8);
+    }
+
+    let words = frame.len() / 2;
+    let first = ((words >> 1) + (words >> 3)) * 2;
+
+    if crc::crc16(0x8005, 0, frame.slice(2, first)) != 0 {
+      return Err("CRC1 mismatch");
+    }
+
+    if crc::crc16(0x8005, 0, frame.slice_from(2)) != 0 {
+      return Err("CRC2 mismatch");
+    }
+
+    for block in range(0u, 6) {
+      try!(self.decode_block(&mut r, block));
+
+      if r.remaining() == 0 {
+        return Err("Frame overrun");
+      }
+    }
+
+    return Ok(());
+  }
+
+  fn decode_block(&mut self, r: &mut stream::Bitslice, block: uint) -> Result<(), &'static str> {
+    let nfchans = self.nfchans;
+
+    for ch in range(0, nfchans) {
+      self.blksw[ch] = r.read_bool();
+    }
+
+    for ch in range(0, nfchans) {
+      self.dithflag[ch] = r.read_bool();
+    }
+
+    for i in range(0u, if self.acmod == 0 { 2 } else { 1 }) {
+      if r.read_bool() {
+        self.dynrng[i] = dynamic_range(r.read_n(8));
+      } else if block == 0 {
+        self.dynrng[i] = 1.0;
+      }
+    }
+
+    if r.read_bool() {
+      self.cplinu = r.read_bool();
+
+      if self.cplinu {
+        for ch in range(0, nfchans) {
+          self.chincpl[ch] = r.read_bool();
+        }
+
+        self.phsflginu = if self.acmod == 2 { r.read_bool() } else { false };
+
+        self.cplbegf = r.read_n(4) as uint;
+        self.cplendf = r.read_n(4) as uint;
+
+        if self.cplbegf > self.cplendf + 2 {
+          return Err("Invalid coupling range");
+        }
+
+        let subbands = 3 + self.cplendf - self.cplbegf;
+
+        self.ncplbnd = subbands;
+        self.cplbndstrc[0] = false;
+
+        for sb in range(1, subbands) {
+          self.cplbndstrc[sb] = r.read_bool();
+
+          if self.cplbndstrc[sb] {
+            self.ncplbnd -= 1;
+          }
+        }
+      } else {
+        self.chincpl = [false, ..5];
+      }
+    } else if block == 0 {
+      return Err("Missing coupling strategy");
+    }
+
+    let cplstrtmant = self.cplbegf * 12 + 37;
+    let cplendmant = self.cplendf * 12 + 73;
+
+    if self.cplinu {
+      let mut coordinates = false;
+
+      for ch in range(0, nfchans) {
+        if !self.chincpl[ch] {
+          continue;
+        }
+
+        if r.read_bool() {
+          coordinates = true;
+
+          let master = 3 * r.read_n(2) as int;
+
+          for band in range(0, self.ncplbnd) {
+            let exponent = r.read_n(4) as int;
+            let mantissa = r.read_n(4) as f64;
+
+            let value = if exponent == 15 { mantissa / 16.0 } else { (mantissa + 16.0) / 32.0 };
+
+            self.cplco[ch][band] = value * 8.0 * (2.0f64).powi(-(exponent + master) as i32);
+          }
+        } else if block == 0 {
+          return Err("Missing coupling coordinates");
+        }
+      }
+
+      if coordinates {
+        for band in range(0, self.ncplbnd) {
+          self.phsflg[band] = self.acmod == 2 && self.phsflginu && r.read_bool();
+        }
+      }
+    }
+
+    if self.acmod == 2 {
+      if r.read_bool() {
+        let bands = if !self.cplinu || self.cplbegf > 2 { 4 } else if self.cplbegf > 0 { 3 } else { 2 };
+
+        for i in range(0u, 4) {
+          self.rematflg[i] = i < bands && r.read_bool();
+        }
+      } else if block == 0 {
+        self.rematflg = [false, ..4];
+      }
+    }
+
+    let cplexpstr = if self.cplinu { r.read_n(2) as uint } else { 0 };
+
+    let mut chexpstr = [0u, ..5];
+
+    for ch in range(0, nfchans) {
+      chexpstr[ch] = r.read_n(2) as uint;
+    }
+
+    let lfeexpstr = if self.lfeon { r.read_n(1) as uint } else { 0 };
+
+    for ch in range(0, nfchans) {
+      if self.chincpl[ch] && self.cplinu {
+        self.endmant[ch] = cplstrtmant;
+      } else if chexpstr[ch] != 0 {
+        let chbwcod = r.read_n(6) as uint;
+
+        if chbwcod > 60 {
+          return Err("Invalid channel bandwidth");
+        }
+
+        self.endmant[ch] = (chbwcod + 12) * 3 + 37;
+      }
+    }
+
+    if block == 0 && (chexpstr.slice_to(nfchans).iter().any(|&e| e == 0) || (self.cplinu && cplexpstr == 0)) {
+      return Err("Missing exponents");
+    }
+
+    if self.cplinu && cplexpstr != 0 {
+      let absolute = (r.read_n(4) as int) << 1;
+      let groups = (cplendmant - cplstrtmant) / (3 << (cplexpstr - 1));
+
+      exponents(r, cplexpstr, groups, absolute, self.exps[CPL].as_mut_slice(), cplstrtmant);
+    }
+
+    for ch in range(0, nfchans) {
+      if chexpstr[ch] == 0 {
+        continue;
+      }
+
+      let absolute = r.read_n(4) as int;
+
+      let groups = match chexpstr[ch] {
+        1 => (self.endmant[ch] - 1) / 3,
+        2 => (self.endmant[ch] - 1 + 3) / 6,
+        _ => (self.endmant[ch] - 1 + 9) / 12
+      };
+
+      self.exps[ch][0] = absolute;
+
+      exponents(r, chexpstr[ch], groups, absolute, self.exps[ch].as_mut_slice(), 1);
+
+      r.skip(2); // gainrng
+    }
+
+    if self.lfeon && lfeexpstr != 0 {
+      let absolute = r.read_n(4) as int;
+
+      self.exps[LFE][0] = absolute;
+
+      exponents(r, 1, 2, absolute, self.exps[LFE].as_mut_slice(), 1);
+    }
+
+    self.endmant[LFE] = 7;
+    self.endmant[CPL] = cplendmant;
+
+    if r.read_bool() {
+      self.sdcycod = r.read_n(2) as uint;
+      self.fdcycod = r.read_n(2) as uint;
+      self.sgaincod = r.read_n(2) as uint;
+      self.dbpbcod = r.read_n(2) as uint;
+      self.floorcod = r.read_n(3) as uint;
+    } else if block == 0 {
+      return Err("Missing bit allocation parameters");
+    }
+
+    if r.read_bool() {
+      self.csnroffst = r.read_n(6) as uint;
+
+      if self.cplinu {
+        self.fsnroffst[CPL] = r.read_n(4) as uint;
+        self.fgaincod[CPL] = r.read_n(3) as uint;
+      }
+
+      for ch in range(0, nfchans) {
+        self.fsnroffst[ch] = r.read_n(4) as uint;
+        self.fgaincod[ch] = r.read_n(3) as uint;
+      }
+
+      if self.lfeon {
+        self.fsnroffst[LFE] = r.read_n(4) as uint;
+        self.fgaincod[LFE] = r.read_n(3) as uint;
+      }
+    } else if block == 0 {
+      return Err("Missing SNR offsets");
+    }
+
+    if self.cplinu && r.read_bool() {
+      self.cplfleak = r.read_n(3) as int;
+      self.cplsleak = r.read_n(3) as int;
+    }
+
+    if r.read_bool() {
+      let mut modes = [2u, ..7];
+
+      if self.cplinu {
+        modes[CPL] = r.read_n(2) as uint;
+      }
+
+      for ch in range(0, nfchans) {
+        modes[ch] = r.read_n(2) as uint;
+      }
+
+      for &ch in [CPL, 0, 1, 2, 3, 4].iter() {
+        if ch != CPL && ch >= nfchans {
+          continue;
+        }
+
+        let delta = &mut self.delta[ch];
+
+        match modes[ch] {
+          0 => {}
+          1 => {
+            delta.enabled = true;
+            delta.segments = r.read_n(3) as uint + 1;
+
+            for seg in range(0, delta.segments) {
+              delta.offset[seg] = r.read_n(5) as uint;
+              delta.length[seg] = r.read_n(4) as uint;
+              delta.ba[seg] = r.read_n(3) as uint;
+            }
+          }
+          _ => delta.enabled = false
+        }
+      }
+    } else if block == 0 {
+      for delta in self.delta.iter_mut() {
+        delta.enabled = false;
+      }
+    }
+
+    if r.read_bool() {
+      let skipl = r.read_n(9) as uint;
+
+      r.skip(skipl * 8);
+    }
+
+    self.allocate_bits(cplstrtmant, cplendmant);
+    self.read_mantissas(r, cplstrtmant, cplendmant);
+
+    if self.cplinu {
+      self.decouple(cplstrtmant);
+    }
+
+    if self.acmod == 2 {
+      self.rematrix(cplstrtmant);
+    }
+
+    for ch in range(0, nfchans) {
+      let gain = self.dynrng[if self.acmod == 0 { ch } else { 0 }];
+
+      for bin in range(0u, 256) {
+        self.coefficients[ch][bin] *= gain;
+      }
+    }
+
+    for ch in range(0, nfchans) {
+      self.inverse_transform(ch, ch, self.blksw[ch], block);
+    }
+
+    if self.lfeon {
+      self.inverse_transform(LFE, nfchans, false, block);
+    }
+
+    return Ok(());
+  }
+
+  fn allocate_bits(&mut self, cplstrtmant: uint, cplendmant: uint) {
+    let parameters = Allocation {
+      slow_decay: tables::SLOW_DECAY[self.sdcycod],
+      fast_decay: tables::FAST_DECAY[self.fdcycod],
+      slow_gain: tables::SLOW_GAIN[self.sgaincod],
+      db_per_bit: tables::DB_PER_BIT[self.dbpbcod],
+      floor: tables::FLOOR[self.floorcod]
+    };
+
+    let mut silent = self.csnroffst == 0;
+
+    for ch in range(0, self.nfchans) {
+      silent = silent && self.fsnroffst[ch] == 0;
+    }
+
+    let mut channels = Vec::new();
+
+    if self.cplinu {
+      channels.push((CPL, cplstrtmant, cplendmant));
+    }
+
+    for ch in range(0, self.nfchans) {
+      channels.push((ch, 0, self.endmant[ch]));
+    }
+
+    if self.lfeon {
+      channels.push((LFE, 0, 7));
+    }
+
+    for &(ch, start, end) in channels.iter() {
+      if silent {
+        self.bap[ch] = [0, ..256];
+        continue;
+      }
+
+      let snroffset = ((((self.csnroffst as int) - 15) << 4) + self.fsnroffst[ch] as int) << 2;
+      let fast_gain = tables::FAST_GAIN[self.fgaincod[ch]];
+      let leak = if ch == CPL { Some((self.cplfleak, self.cplsleak)) } else { None };
+
+      allocate(self.exps[ch].as_slice(), self.bap[ch].as_mut_slice(), start, end, self.fscod, &parameters,
+               fast_gain, snroffset, leak, ch == LFE, &self.delta[ch]);
+    }
+  }
+
+  fn read_mantissas(&mut self, r: &mut stream::Bitslice, cplstrtmant: uint, cplendmant: uint) {
+    let mut groups = Groups { values: [[0.0, ..3], ..3], remaining: [0, ..3] };
+    let mut coupled = false;
+
+    for ch in range(0, self.nfchans) {
+      for bin in range(0, self.endmant[ch]) {
+        let bap = self.bap[ch][bin];
+
+        let value = if bap == 0 && self.dithflag[ch] { self.dither() } else { mantissa(r, bap, &mut groups) };
+
+        self.coefficients[ch][bin] = value * scale(self.exps[ch][bin]);
+      }
+
+      for bin in range(self.endmant[ch], 256) {
+        self.coefficients[ch][bin] = 0.0;
+      }
+
+      if self.cplinu && self.chincpl[ch] && !coupled {
+        for bin in range(cplstrtmant, cplendmant) {
+          let bap = self.bap[CPL][bin];
+
+          self.coupled_zero[bin] = bap == 0;
+          self.coefficients[CPL][bin] = mantissa(r, bap, &mut groups) * scale(self.exps[CPL][bin]);
+        }
+
+        coupled = true;
+      }
+    }
+
+    if self.lfeon {
+      for bin in range(0u, 7) {
+        let bap = self.bap[LFE][bin];
+
+        self.coefficients[LFE][bin] = mantissa(r, bap, &mut groups) * scale(self.exps[LFE][bin]);
+      }
+
+      for bin in range(7u, 256) {
+        self.coefficients[LFE][bin] = 0.0;
+      }
+    }
+  }
+
+  /// Reconstructs the coupled channels from the coupling channel and their
+  /// coupling coordinates.
+  fn decouple(&mut self, cplstrtmant: uint) {
+    let subbands = 3 + self.cplendf - self.cplbegf;
+
+    for ch in range(0, self.nfchans) {
+      if !self.chincpl[ch] {
+        continue;
+      }
+
+      let mut band = 0;
+
+      for sb in range(0, subbands) {
+        if sb > 0 && !self.cplbndstrc[sb] {
+          band += 1;
+        }
+
+        let mut coordinate = self.cplco[ch][band];
+
+        if ch == 1 && self.phsflg[band] {
+          coordinate = -coordinate;
+        }
+
+        for i in range(0u, 12) {
+          let bin = cplstrtmant + sb * 12 + i;
+
+          let value = if self.coupled_zero[bin] && self.dithflag[ch] {
+            self.dither() * scale(self.exps[CPL][bin])
+          } else {
+            self.coefficients[CPL][bin]
+          };
+
+          self.coefficients[ch][bin] = value * coordinate;
+        }
+      }
+
+      for bin in range(cplstrtmant + subbands * 12, 256) {
+        self.coefficients[ch][bin] = 0.0;
+      }
+    }
+  }
+
+  fn rematrix(&mut self, cplstrtmant: uint) {
+    let bands = [13u, 25, 37, 61, 253];
+
+    let end = if self.cplinu {
+      cplstrtmant
+    } else {
+      std::cmp::min(self.endmant[0], self.endmant[1])
+    };
+
+    for i in range(0u, 4) {
+      if !self.rematflg[i] {
+        continue;
+      }
+
+      for bin in range(bands[i], std::cmp::min(bands[i + 1], end)) {
+        let left = self.coefficients[0][bin];
+        let right = self.coefficients[1][bin];
+
+        self.coefficients[0][bin] = left + right;
+        self.coefficients[1][bin] = left - right;
+      }
+    }
+  }
+
+  /// Transforms the coefficients of `ch` into 256 samples of output channel
+  /// `output`, overlapping them with the previous block.
+  fn inverse_transform(&mut self, ch: uint, output: uint, short: bool, block: uint) {
+    let mut current = [0.0f64, ..256];
+    let mut next = [0.0f64, ..128];
+
+    if short {
+      let mut even = [0.0f64, ..128];
+      let mut odd = [0.0f64, ..128];
+
+      for k in range(0u, 128) {
+        even[k] = self.coefficients[ch][2 * k];
+        odd[k] = self.coefficients[ch][2 * k + 1];
+      }
+
+      self.short.transform(even, current.slice_to_mut(128));
+      self.short.transform(odd, next);
+    } else {
+      self.long.transform(self.coefficients[ch].as_slice(), current);
+
+      for i in range(0u, 128) {
+        next[i] = current[128 + i];
+      }
+    }
+
+    let w = &self.window;
+    let delay = &mut self.delay[output];
+    let pcm = self.pcm[output].slice_mut(block * 256, block * 256 + 256);
+
+    for i in range(0u, 128) {
+      let j = 127 - i;
+
+      let s0 = delay[i];
+      let s1 = current[j];
+
+      pcm[i] = s0 * w[255 - i] - s1 * w[i];
+      pcm[255 - i] = s0 * w[i] + s1 * w[255 - i];
+    }
+
+    *delay = next;
+  }
+
+  fn dither(&mut self) -> f64 {
+    self.random = self.random * 1664525 + 1013904223;
+
+    return ((self.random >> 8) as f64 / 8388608.0 - 1.0) * 0.707;
+  }
+
+  /// The number of channels in the output for a request of `requested`.
+  fn output_channels(&self, requested: uint) -> uint {
+    return match requested {
+      0 => self.nfchans + if self.lfeon { 1 } else { 0 },
+      n => n
+    };
+  }
+
+  /// Mixes the decoded frame into `output`, interleaved, and returns the
+  /// number of channels, sample rate and channel layout.
+  fn downmix(&self, requested: uint, output: &mut Vec<f64>) -> (uint, f64, layout::Layout) {
+    let positions = self.positions();
+    let matrix = self.matrix(requested, positions.as_slice());
+    let channels = matrix.len();
+
+    output.truncate(0);
+    output.reserve(channels * 1536);
+
+    for n in range(0u, 1536) {
+      for row in matrix.iter() {
+        let mut value = 0.0;
+
+        for (d, &gain) in row.iter().enumerate() {
+          if gain != 0.0 {
+            value += gain * self.pcm[d][n];
+          }
+        }
+
+        output.push(value);
+      }
+    }
+
+    return (channels, SAMPLE_RATES[self.fscod], self.layout(requested));
+  }
+
+  /// The layout of the output channels.
+  fn layout(&self, requested: uint) -> layout::Layout {
+    match requested {
+      1 => return layout::Layout::mono(),
+      2 => return layout::Layout::stereo(),
+      _ => {}
+    }
+
+    let positions = if requested == 0 { self.positions() } else { speakers(requested) };
+    let order = [Left, Right, Center, LowFrequency, LeftSurround, RightSurround, Surround];
+
+    return layout::Speakers(order.iter().filter(|p| positions.contains(*p)).map(|p| match *p {
+      Left => layout::FrontLeft,
+      Right => layout::FrontRight,
+      Center => layout::FrontCenter,
+      LowFrequency => layout::LowFrequency,
+      LeftSurround => layout::BackLeft,
+      RightSurround => layout::BackRight,
+      Surround => layout::BackCenter
+    }).collect());
+  }
+
+  /// The speaker positions of the decoded channels, in bitstream order.
+  fn positions(&self) -> Vec<Position> {
+    let mut positions = match self.acmod {
+      0 | 2 => vec![Left, Right],
+      1 => vec![Center],
+      3 => vec![Left, Center, Right],
+      4 => vec![Left, Right, Surround],
+      5 => vec![Left, Center, Right, Surround],
+      6 => vec![Left, Right, LeftSurround, RightSurround],
+      _ => vec![Left, Center, Right, LeftSurround, RightSurround]
+    };
+
+    if self.lfeon {
+      positions.push(LowFrequency);
+    }
+
+    return positions;
+  }
+
+  /// Builds the mixing matrix from decoded channels to output channels.
+  fn matrix(&self, requested: uint, positions: &[Position]) -> Vec<Vec<f64>> {
+    let native = positions.len();
+
+    if requested == 0 {
+      let order = [Left, Right, Center, LowFrequency, LeftSurround, RightSurround, Surround];
+      let mut matrix = Vec::new();
+
+      for position in order.iter() {
+        for (d, p) in positions.iter().enumerate() {
+          if p == position {
+            let mut row = Vec::from_elem(native, 0.0);
+
+            *row.get_mut(d) = 1.0;
+            matrix.push(row);
+          }
+        }
+      }
+
+      return matrix;
+    }
+
+    let clev = tables::CENTER_LEVELS[self.cmixlev];
+    let slev = tables::SURROUND_LEVELS[self.surmixlev];
+
+    if requested > 2 {
+      let targets = speakers(requested);
+      let mut matrix = Vec::from_fn(targets.len(), |_| Vec::from_elem(native, 0.0));
+
+      for (d, p) in positions.iter().enumerate() {
+        let gains = match *p {
+          _ if targets.contains(p) => vec![(*p, 1.0)],
+          Center => vec![(Left, clev), (Right, clev)],
+          Surround if targets.contains(&LeftSurround) => vec![(LeftSurround, 0.707), (RightSurround, 0.707)],
+          Surround => vec![(Left, slev * 0.707), (Right, slev * 0.707)],
+          LeftSurround => vec![(Left, slev)],
+          RightSurround => vec![(Right, slev)],
+          _ => Vec::new()
+        };
+
+        for &(target, gain) in gains.iter() {
+          let t = targets.iter().position(|x| *x == target).unwrap();
+
+          *matrix.get_mut(t).get_mut(d) = gain;
+        }
+      }
+
+      return matrix;
+    }
+
+    let mut left = Vec::from_elem(native, 0.0);
+    let mut right = Vec::from_elem(native, 0.0);
+
+    for (d, p) in positions.iter().enumerate() {
+      let (l, r) = match *p {
+        Left => (1.0, 0.0),
+        Right => (0.0, 1.0),
+        Center if self.acmod == 1 => (1.0, 1.0),
+        Center => (clev, clev),
+        LeftSurround => (slev, 0.0),
+        RightSurround => (0.0, slev),
+        Surround => (slev * 0.707, slev * 0.707),
+        LowFrequency => (0.0, 0.0)
+      };
+
+      *left.get_mut(d) = l;
+      *right.get_mut(d) = r;
+    }
+
+    let total = left.iter().fold(0.0, |a, &b| a + b);
+
+    if total > 1.0 {
+      for d in range(0, native) {
+        *left.get_mut(d) /= total;
+        *right.get_mut(d) /= total;
+      }
+    }
+
+    if requested == 2 {
+      return vec![left, right];
+    }
+
+    let mono = range(0, native).map(|d| if self.acmod == 1 { left[d] } else { (left[d] + right[d]) / 2.0 }).collect();
+
+    return vec![mono];
+  }
+}
+
+/// The speakers output for a request of 3 to 6 channels, in WAVE order.
+fn speakers(requested: uint) -> Vec<Position> {
+  return match requested {
+    3 => vec![Left, Right, Center],
+    4 => vec![Left, Right, LeftSurround, RightSurround],
+    5 => vec![Left, Right, Center, LeftSurround, RightSurround],
+    _ => vec![Left, Right, Center, LowFrequency, LeftSurround, RightSurround]
+  };
+}
+
+/// Decodes `groups` groups of differential exponents into `exps`, starting
+/// at `start`.
+fn exponents(r: &mut stream::Bitslice, strategy: uint, groups: uint, absolute: int, exps: &mut [int], start: uint) {
+  let size = 1u << (strategy - 1);
+
+  let mut previous = absolute;
+  let mut bin = start;
+
+  for _ in range(0, groups) {
+    let code = r.read_n(7) as int;
+
+    for &d in [code / 25, (code % 25) / 5, code % 5].iter() {
+      previous += d - 2;
+
+      for _ in range(0, size) {
+        if bin < exps.len() {
+          exps[bin] = previous;
+        }
+
+        bin += 1;
+      }
+    }
+  }
+}
+
+/// The parametric bit allocation of A/52 section 7.2, computing `bap` for the
+/// bins `start` to `end`.
+fn allocate(exps: &[int], bap: &mut [u8], start: uint, end: uint, fscod: uint, p: &Allocation, fast_gain: int,
+            snroffset: int, leak: Option<(int, int)>, lfe: bool, delta: &Delta) {
+  let mut psd = [0i, ..256];
+  let mut bndpsd = [0i, ..50];
+  let mut excite = [0i, ..50];
+  let mut mask = [0i, ..50];
+
+  for bin in range(start, end) {
+    psd[bin] = 3072 - (exps[bin] << 7);
+  }
+
+  let mut j = start;
+  let mut k = band(start);
+
+  loop {
+    let lastbin = std::cmp::min(tables::BAND_START[k + 1], end);
+
+    bndpsd[k] = psd[j];
+    j += 1;
+
+    while j < lastbin {
+      bndpsd[k] = log_add(bndpsd[k], psd[j]);
+      j += 1;
+    }
+
+    k += 1;
+
+    if end <= lastbin {
+      break;
+    }
+  }
+
+  let bndstrt = band(start);
+  let bndend = band(end - 1) + 1;
+
+  let mut fastleak;
+  let mut slowleak;
+  let begin;
+
+  match leak {
+    None => {
+      let mut lowcomp = low_compensation(0, bndpsd[0], bndpsd[1], 0);
+      excite[0] = bndpsd[0] - fast_gain - lowcomp;
+
+      lowcomp = low_compensation(lowcomp, bndpsd[1], bndpsd[2], 1);
+      excite[1] = bndpsd[1] - fast_gain - lowcomp;
+
+      let mut next = 7;
+
+      fastleak = 0;
+      slowleak = 0;
+
+      for bin in range(2u, 7) {
+        if !(lfe && bin == 6) {
+          lowcomp = low_compensation(lowcomp, bndpsd[bin], bndpsd[bin + 1], bin);
+        }
+
+        fastleak = bndpsd[bin] - fast_gain;
+        slowleak = bndpsd[bin] - p.slow_gain;
+        excite[bin] = fastleak - lowcomp;
+
+        if !(lfe && bin == 6) && bndpsd[bin] <= bndpsd[bin + 1] {
+          next = bin + 1;
+          break;
+        }
+      }
+
+      for bin in range(next, std::cmp::min(bndend, 22)) {
+        if !(lfe && bin == 6) {
+          lowcomp = low_compensation(lowcomp, bndpsd[bin], bndpsd[bin + 1], bin);
+        }
+
+        fastleak = std::cmp::max(fastleak - p.fast_decay, bndpsd[bin] - fast_gain);
+        slowleak = std::cmp::max(slowleak - p.slow_decay, bndpsd[bin] - p.slow_gain);
+        excite[bin] = std::cmp::max(fastleak - lowcomp, slowleak);
+      }
+
+      begin = 22;
+    }
+    Some((fast, slow)) => {
+      fastleak = (fast << 8) + 768;
+      slowleak = (slow << 8) + 768;
+      begin = bndstrt;
+    }
+  }
+
+  for bin in range(begin, bndend) {
+    fastleak = std::cmp::max(fastleak - p.fast_decay, bndpsd[bin] - fast_gain);
+    slowleak = std::cmp::max(slowleak - p.slow_decay, bndpsd[bin] - p.slow_gain);
+    excite[bin] = std::cmp::max(fastleak, slowleak);
+  }
+
+  for bin in range(bndstrt, bndend) {
+    if bndpsd[bin] < p.db_per_bit {
+      excite[bin] += (p.db_per_bit - bndpsd[bin]) >> 2;
+    }
+
+    mask[bin] = std::cmp::max(excite[bin], tables::HEARING_THRESHOLD[fscod][bin]);
+  }
+
+  if delta.enabled {
+    let mut b = 0;
+
+    for seg in range(0, delta.segments) {
+      b += delta.offset[seg];
+
+      let d = if delta.ba[seg] >= 4 { (delta.ba[seg] as int - 3) << 7 } else { (delta.ba[seg] as int - 4) << 7 };
+
+      for _ in range(0, delta.length[seg]) {
+        if b < 50 {
+          mask[b] += d;
+        }
+
+        b += 1;
+      }
+    }
+  }
+
+  let mut i = start;
+  let mut j = band(start);
+
+  loop {
+    let lastbin = std::cmp::min(tables::BAND_START[j + 1], end);
+
+    let mut m = mask[j] - snroffset - p.floor;
+
+    if m < 0 {
+      m = 0;
+    }
+
+    m = (m & 0x1FE0) + p.floor;
+
+    while i < lastbin {
+      let address = std::cmp::min(std::cmp::max((psd[i] - m) >> 5, 0), 63);
+
+      bap[i] = tables::BAP[address as uint];
+      i += 1;
+    }
+
+    j += 1;
+
+    if end <= lastbin {
+      break;
+    }
+  }
+}
+
+/// The critical band containing `bin`.
+fn band(bin: uint) -> uint {
+  let mut b = 0;
+
+  while tables::BAND_START[b + 1] <= bin {
+    b += 1;
+  }
+
+  return b;
+}
+
+fn log_add(a: int, b: int) -> int {
+  let c = a - b;
+  let address = std::cmp::min(std::num::abs(c) >> 1, 255) as uint;
+
+  return if c >= 0 { a + tables::LOG_ADD[address] } else { b + tables::LOG_ADD[address] };
+}
+
+fn low_compensation(a: int, b0: int, b1: int, bin: uint) -> int {
+  if bin < 7 {
+    if b0 + 256 == b1 {
+      return 384;
+    } else if b0 > b1 {
+      return std::cmp::max(0, a - 64);
+    }
+  } else if bin < 20 {
+    if b0 + 256 == b1 {
+      return 320;
+    } else if b0 > b1 {
+      return std::cmp::max(0, a - 64);
+    }
+  } else {
+    return std::cmp::max(0, a - 128);
+  }
+
+  return a;
+}
+
+/// Reads and dequantizes a mantissa with bit allocation pointer `bap`.
+fn mantissa(r: &mut stream::Bitslice, bap: u8, groups: &mut Groups) -> f64 {
+  return match bap {
+    0 => 0.0,
+    1 => grouped(r, groups, 0, 5, 3, 3),
+    2 => grouped(r, groups, 1, 7, 5, 3),
+    3 => level(r.read_n(3) as uint, 7),
+    4 => grouped(r, groups, 2, 7, 11, 2),
+    5 => level(r.read_n(4) as uint, 15),
+    _ => {
+      let bits = tables::MANTISSA_BITS[bap as uint];
+
+      r.read_n_signed(bits) as f64 / (1u << (bits - 1)) as f64
+    }
+  };
+}
+
+/// Reads a mantissa from a group of `count` mantissas with `levels` levels,
+/// that are packed together into `bits` bits.
+fn grouped(r: &mut stream::Bitslice, groups: &mut Groups, index: uint, bits: uint, levels: uint, count: uint) -> f64 {
+  if groups.remaining[index] == 0 {
+    let mut code = r.read_n(bits) as uint;
+
+    for i in range(0, count) {
+      groups.values[index][count - i - 1] = level(code % levels, levels);
+      code /= levels;
+    }
+
+    groups.remaining[index] = count;
+  }
+
+  let value = groups.values[index][count - groups.remaining[index]];
+
+  groups.remaining[index] -= 1;
+
+  return value;
+}
+
+/// Dequantizes a symmetric mantissa.
+fn level(code: uint, levels: uint) -> f64 {
+  return (2.0 * code as f64 - (levels - 1) as f64) / levels as f64;
+}
+
+fn scale(exponent: int) -> f64 {
+  return 1.0 / (1u32 << std::cmp::min(std::cmp::max(exponent, 0), 24) as uint) as f64;
+}
+
+fn dynamic_range(code: u32) -> f64 {
+  let x = ((code as u8) as i8 >> 5) as i32;
+  let y = (code & 0x1F) as f64;
+
+  return (2.0f64).powi(x) * (32.0 + y) / 32.0;
+}
+
+/// The middle half of an inverse MDCT with `m` coefficients, which
+/// determines the rest of it by symmetry. This is the DCT-IV of the
+/// coefficients, reversed and negated, done with an m/2-point complex FFT as
+/// in A/52 section 7.9.4.
+struct Imdct {
+  fft: fft::Fft,
+
+  /// e^(-iπ(j + 1/4)/m) and e^(-iπj/m), for j below m/2.
+  pre: Vec<fft::Complex>,
+  post: Vec<fft::Complex>
+}
+
+impl Imdct {
+  fn new(m: uint) -> Imdct {
+    let n = m / 2;
+
+    return Imdct {
+      fft: fft::Fft::new(n),
+      pre: Vec::from_fn(n, |j| fft::Complex::polar(-PI * (j as f64 + 0.25) / m as f64)),
+      post: Vec::from_fn(n, |j| fft::Complex::polar(-PI * j as f64 / m as f64))
+    };
+  }
+
+  /// Computes `output[i]`, the sum of `input[k] * cos(π/m (i + m + 1/2)
+  /// (k + 1/2))` over k.
+  fn transform(&self, input: &[f64], output: &mut [f64]) {
+    let m = output.len();
+    let n = m / 2;
+
+    let mut data = Vec::from_fn(n, |j| fft::Complex::new(input[2 * j], input[m - 1 - 2 * j]).mul(&self.pre[j]));
+
+    self.fft.forward(data.as_mut_slice());
+
+    for j in range(0, n) {
+      let x = data[j].mul(&self.post[j]);
+
+      output[2 * j] = x.im;
+      output[m - 1 - 2 * j] = -x.re;
+    }
+  }
+}
+
+/// The Kaiser-Bessel derived window with alpha 5.
+fn window() -> [f64, ..256] {
+  let n = 256u;
+  let alpha = 5.0 * PI / n as f64;
+
+  let mut local = [0.0f64, ..256];
+  let mut total = 1.0;
+
+  for i in range(0, n) {
+    local[i] = fft::bessel_i0((4.0 * alpha * alpha * (i * (n - i)) as f64).sqrt());
+    total += local[i];
+  }
+
+  let mut w = [0.0f64, ..256];
+  let mut sum = 0.0;
+
+  for i in range(0, n) {
+    sum += local[i];
+    w[i] = (sum / total).sqrt();
+  }
+
+  return w;
+}
+
+#[cfg(test)]
+mod tests {
+  use std::f64::consts::PI;
+
+  use channel;
+  use buffer;
+
+  #[test]
+  fn test_frame_size() {
+    assert_eq!(super::frame_size(0x00), Some(128));
+    assert_eq!(super::frame_size(0x1C), Some(1536));
+    assert_eq!(super::frame_size(0x40 | 0x1C), Some(1670));
+    assert_eq!(super::frame_size(0x40 | 0x1D), Some(1672));
+    assert_eq!(super::frame_size(0x80 | 0x25), Some(3840));
+    assert_eq!(super::frame_size(0xC0), None);
+    assert_eq!(super::frame_size(0x26), None);
+  }
+
+  #[test]
+  fn test_window() {
+    let w = super::window();
+
+    for i in range(0u, 256) {
+      assert!((w[i] * w[i] + w[255 - i] * w[255 - i] - 1.0).abs() < 1e-9);
+    }
+  }
+
+  #[test]
+  fn test_imdct() {
+    for &m in [128u, 256].iter() {
+      let input = Vec::from_fn(m, |k| ((k * 7919) % 101) as f64 / 50.0 - 1.0);
+      let mut output = Vec::from_elem(m, 0.0);
+
+      super::Imdct::new(m).transform(input.as_slice(), output.as_mut_slice());
+
+      for i in range(0, m) {
+        let expected = range(0, m).fold(0.0, |a, k| {
+          a + input[k] * (PI / m as f64 * ((i + m) as f64 + 0.5) * (k as f64 + 0.5)).cos()
+        });
+
+        assert!((output[i] - expected).abs() < 1e-9);
+      }
+    }
+  }
+
+  #[test]
+  fn test_exponents() {
+    let data = [(2 * 25 + 3 * 5 + 1) << 1];
+    let mut r = ::stream::Bitslice::new(data);
+    let mut exps = [0i, ..7];
+
+    super::exponents(&mut r, 2, 1, 10, exps, 1);
+
+    assert_eq!(exps.as_slice(), [0i, 10, 10, 11, 11, 10, 10].as_slice());
+  }
+
+  #[test]
+  fn test_dynamic_range() {
+    assert_eq!(super::dynamic_range(0x00), 1.0);
+    assert_eq!(super::dynamic_range(0x20), 2.0);
+    assert_eq!(super::dynamic_range(0xE0), 0.5);
+    assert_eq!(super::dynamic_range(0x10), 1.5);
+  }
+
+  #[test]
+  fn test_matrix() {
+    let mut state = box super::State::new();
+
+    // A stereo stream, as between the 5.1 programmes of a broadcast.
+    state.acmod = 2;
+
+    let matrix = state.matrix(6, state.positions().as_slice());
+
+    assert_eq!(matrix.len(), 6);
+    assert_eq!(matrix[0], vec![1.0, 0.0]);
+    assert_eq!(matrix[1], vec![0.0, 1.0]);
+
+    for row in matrix.slice_from(2).iter() {
+      assert_eq!(*row, vec![0.0, 0.0]);
+    }
+
+    // 3/1 into quadraphonic folds the centre into the front and spreads
+    // the surround.
+    state.acmod = 5;
+
+    let matrix = state.matrix(4, state.positions().as_slice());
+
+    assert_eq!(matrix, vec![
+      vec![1.0, 0.707, 0.0, 0.0],
+      vec![0.0, 0.707, 1.0, 0.0],
+      vec![0.0, 0.0, 0.0, 0.707],
+      vec![0.0, 0.0, 0.0, 0.707]
+    ]);
+  }
+
+  #[test]
+  fn test_no_sync() {
+    let (sink, source) = channel::create::<::Binary>(1);
+    let (audio_sink, mut audio_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() { buffer::Buffer::new(vec![0u8, 1, 2, 3, 0x0B], 4096, sink).run(); });
+    spawn(proc() { super::Decoder::new(source, audio_sink, 2).run(); });
+
+    audio_source.read(|audio| {
+      assert_eq!(audio.last, true);
+      assert_eq!(audio.data.len(), 0);
+    });
+  }
+}
@@ -9,6 +9,36 @@ pub mod file;
 pub mod buffer;
 pub mod stdout;
 pub mod caf;
+pub mod dsf;
+pub mod dff;
+pub mod dsd;
+pub mod ac3;
+pub mod mpeg;
+pub mod gsm;
+pub mod tracker;
+pub mod resample;
+pub mod mix;
+pub mod gain;
+pub mod loudness;
+pub mod replaygain;
+pub mod filter;
+pub mod dynamics;
+pub mod fft;
+pub mod silence;
+pub mod stretch;
+pub mod concat;
+pub mod mixer;
+pub mod segment;
+pub mod generator;
+pub mod waveform;
+pub mod fingerprint;
+pub mod tempo;
+pub mod pitch;
+pub mod denoise;
+
+pub mod sample;
+pub mod layout;
+pub mod crc;
 
 pub trait Initialize {
   fn initialize() -> Self;
@@ -41,7 +71,11 @@ pub mod endian {
 pub mod sample_type {
   #[deriving(Show,PartialEq)]
   pub enum SampleType {
-    Unknown, Unsigned(uint), Signed(uint), Float(uint)
+    Unknown, Unsigned(uint), Signed(uint), Float(uint),
+
+    /// 1-bit Direct Stream Digital. Data is interleaved per byte, one byte
+    /// (8 samples, most significant bit first) per channel.
+    Dsd
   }
 
   pub fn size(t: SampleType) -> uint {
@@ -49,14 +83,20 @@ pub mod sample_type {
       Unknown => 0,
       Unsigned(n) => n,
       Signed(n) => n,
-      Float(n) => n
+      Float(n) => n,
+      Dsd => 1
     };
   }
 }
 
+/// Interleaved samples. Nodes that process audio write it out with the
+/// sample type and endianness it was read with, unless documented otherwise.
 pub struct Audio {
   pub last: bool,
   pub channels: uint,
+
+  /// What the channels are, if known.
+  pub layout: layout::Layout,
   pub sample_rate: f64,
   pub endian: endian::Endian,
   pub sample_type: sample_type::SampleType,
@@ -68,6 +108,7 @@ impl Initialize for Audio {
     return Audio {
       last: false,
       channels: 0,
+      layout: layout::Unknown,
       sample_rate: 0.0,
       endian: endian::Big,
       sample_type: sample_type::Unknown,
@@ -78,6 +119,7 @@ impl Initialize for Audio {
   fn reinitialize(&mut self) {
     self.last = false;
     self.channels = 0;
+    self.layout = layout::Unknown;
     self.sample_rate = 0.0;
     self.endian = endian::Big;
     self.sample_type = sample_type::Unknown;
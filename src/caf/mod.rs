@@ -29,6 +29,10 @@ impl Muxer {
             panic!("caf::Muxer: Unknown sample type");
           }
 
+          if let ::sample_type::Dsd = audio.sample_type {
+            panic!("caf::Muxer: DSD must be converted to PCM first (dsd::Decimator)");
+          }
+
           sink.write(|binary| {
             let d = &mut binary.data;
 
@@ -74,6 +78,31 @@ impl Muxer {
             }
           });
 
+          if let Some((tag, bitmap)) = audio.layout.tag(audio.channels) {
+            if audio.layout.channels().map_or(false, |n| n != audio.channels) {
+              panic!("caf::Muxer: Channel layout does not match the channel count (INPUT)");
+            }
+
+            let labels = if tag == ::layout::TAG_USE_CHANNEL_DESCRIPTIONS { audio.layout.labels() } else { Vec::new() };
+
+            sink.write(|binary| {
+              let d = &mut binary.data;
+
+              d.push_all(b"chan");
+
+              push_be_u64(d, (12 + 20 * labels.len()) as u64);
+              push_be_u32(d, tag);
+              push_be_u32(d, bitmap);
+              push_be_u32(d, labels.len() as u32);
+
+              for &label in labels.iter() {
+                // Label, flags and three zero coordinates.
+                push_be_u32(d, label);
+                d.grow(16, 0);
+              }
+            });
+          }
+
           sink.write(|binary| {
             let d = &mut binary.data;
 
@@ -99,3 +128,70 @@ impl Muxer {
     }
   }
 }
+
+fn push_be_u32(d: &mut Vec<u8>, value: u32) {
+  for i in range(0u, 4) {
+    d.push((value >> (24 - 8 * i)) as u8);
+  }
+}
+
+fn push_be_u64(d: &mut Vec<u8>, value: u64) {
+  push_be_u32(d, (value >> 32) as u32);
+  push_be_u32(d, value as u32);
+}
+
+#[cfg(test)]
+mod tests {
+  use channel;
+  use layout;
+  use sample_type;
+
+  fn header(layout: layout::Layout, channels: uint) -> Vec<u8> {
+    let (mut sink, source) = channel::create::<::Audio>(1);
+    let (binary_sink, mut binary_source) = channel::create::<::Binary>(1);
+
+    spawn(proc() {
+      sink.write(|audio| {
+        audio.channels = channels;
+        audio.layout = layout.clone();
+        audio.sample_rate = 48000.0;
+        audio.sample_type = sample_type::Signed(16);
+        audio.last = true;
+      });
+    });
+
+    spawn(proc() {
+      super::Muxer::new(source, binary_sink).run();
+    });
+
+    let mut data = Vec::new();
+    let mut last = false;
+
+    while !last {
+      binary_source.read(|binary| {
+        data.push_all(binary.data.as_slice());
+        last = binary.last;
+      });
+    }
+
+    return data;
+  }
+
+  #[test]
+  fn test_chan() {
+    let data = header(layout::Layout::surround51(), 6);
+    let chan = data.slice(52, 76);
+
+    assert_eq!(chan.slice(0, 4), b"chan");
+    assert_eq!(chan.slice(4, 12), [0u8, 0, 0, 0, 0, 0, 0, 12].as_slice());
+    assert_eq!(chan.slice(12, 24), [0u8, 1, 0, 0, 0, 0, 0, 0x3F, 0, 0, 0, 0].as_slice());
+    assert_eq!(data.slice(76, 80), b"data");
+
+    let data = header(layout::Speakers(vec![layout::FrontRight, layout::FrontLeft]), 2);
+
+    assert_eq!(data.slice(60, 76), [0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 2].as_slice());
+    assert_eq!(data.slice(116, 120), b"data");
+
+    assert_eq!(header(layout::Unknown, 2).slice(52, 56), b"data");
+  }
+}
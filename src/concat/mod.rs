@@ -0,0 +1,364 @@
+use std::f64::consts::FRAC_PI_2;
+
+use channel;
+use endian;
+use layout;
+use mix;
+use resample;
+use sample;
+use sample_type;
+
+/// How the gains of the two sources change across a crossfade.
+#[deriving(Show,PartialEq)]
+pub enum Shape {
+  /// Gains that sum to one, which suits correlated material such as two
+  /// takes of the same part.
+  Linear,
+
+  /// Gains whose squares sum to one, which keeps the loudness of
+  /// uncorrelated material even.
+  EqualPower
+}
+
+impl Shape {
+  /// The gains of the outgoing and incoming sources at `x`, from 0 at the
+  /// start of the crossfade to 1 at its end.
+  pub fn gains(&self, x: f64) -> (f64, f64) {
+    let x = x.max(0.0).min(1.0);
+
+    return match *self {
+      Linear => (1.0 - x, x),
+      EqualPower => ((x * FRAC_PI_2).cos(), (x * FRAC_PI_2).sin())
+    };
+  }
+}
+
+/// A crossfade of `length` seconds between each source and the next.
+#[deriving(Show,PartialEq)]
+pub struct Crossfade {
+  pub shape: Shape,
+  pub length: f64
+}
+
+/// What to do with sources whose format differs from the first.
+#[deriving(Show,PartialEq)]
+pub enum Mismatch {
+  /// Panic on a different sample rate, channel count or sample type.
+  Reject,
+
+  /// Resample to the first sample rate, remix to the first channel count
+  /// with `mix::Matrix::convert`, and encode to the first sample type.
+  Convert
+}
+
+struct Format {
+  channels: uint,
+  layout: layout::Layout,
+  sample_rate: f64,
+  sample_type: sample_type::SampleType,
+  endian: endian::Endian
+}
+
+/// Converts the samples of one source to the output format.
+struct Converter {
+  matrix: Option<mix::Matrix>,
+  varispeed: Option<resample::Varispeed>,
+  remixed: Vec<f64>
+}
+
+impl Converter {
+  fn new(index: uint, audio: &::Audio, format: &Format, mismatch: Mismatch) -> Converter {
+    let mut converter = Converter { matrix: None, varispeed: None, remixed: Vec::new() };
+
+    if audio.channels != format.channels {
+      if mismatch == Reject {
+        panic!("concat::Concat: Source {} has {} channels, expected {} (INPUT)", index, audio.channels, format.channels);
+      }
+
+      converter.matrix = Some(mix::Matrix::convert(audio.channels, format.channels));
+    }
+
+    if audio.sample_rate != format.sample_rate {
+      if mismatch == Reject {
+        panic!("concat::Concat: Source {} has sample rate {}, expected {} (INPUT)", index, audio.sample_rate, format.sample_rate);
+      }
+
+      converter.varispeed = Some(resample::Varispeed::new(format.channels, format.sample_rate / audio.sample_rate, resample::High));
+    }
+
+    if audio.sample_type != format.sample_type && mismatch == Reject {
+      panic!("concat::Concat: Source {} has sample type {}, expected {} (INPUT)", index, audio.sample_type, format.sample_type);
+    }
+
+    return converter;
+  }
+
+  /// Converts `samples` in place.
+  fn convert(&mut self, samples: &mut Vec<f64>, last: bool) {
+    match self.matrix {
+      Some(ref matrix) => {
+        matrix.apply(samples.as_slice(), &mut self.remixed);
+        samples.clone_from(&self.remixed);
+      },
+      None => ()
+    }
+
+    match self.varispeed {
+      Some(ref mut varispeed) => {
+        varispeed.process(samples.as_slice(), last, &mut self.remixed);
+        samples.clone_from(&self.remixed);
+      },
+      None => ()
+    }
+  }
+}
+
+/// Plays sources one after another as a single stream, optionally
+/// crossfading from each to the next.
+///
+/// The output has the format of the first source's audio, and other
+/// sources are checked or converted against it as `mismatch` asks. With a
+/// crossfade, the end of each source is held back until the next one
+/// starts. A crossfade is shortened when either source is shorter than it.
+/// Only the final output packet, sent when the last source ends, is marked
+/// `last`.
+pub struct Concat {
+  sources: Vec<channel::Source<::Audio>>,
+  sink: channel::Sink<::Audio>,
+  crossfade: Option<Crossfade>,
+  mismatch: Mismatch
+}
+
+impl Concat {
+  pub fn new(sources: Vec<channel::Source<::Audio>>, sink: channel::Sink<::Audio>, crossfade: Option<Crossfade>, mismatch: Mismatch) -> Concat {
+    if sources.is_empty() {
+      panic!("concat::Concat: No sources (ARGUMENT)");
+    }
+
+    match crossfade {
+      Some(ref crossfade) if !(crossfade.length >= 0.0) => {
+        panic!("concat::Concat: Crossfade length must not be negative (ARGUMENT)");
+      },
+      _ => ()
+    }
+
+    return Concat { sources: sources, sink: sink, crossfade: crossfade, mismatch: mismatch };
+  }
+
+  pub fn run(&mut self) {
+    let crossfade = &self.crossfade;
+    let mismatch = self.mismatch;
+    let sources = &mut self.sources;
+    let sink = &mut self.sink;
+    let count = sources.len();
+
+    let mut format: Option<Format> = None;
+
+    // The crossfade length, in samples of the output.
+    let mut fade = 0;
+
+    // The end of the sources so far, held back for the next crossfade.
+    let mut held = Vec::new();
+
+    let mut samples = Vec::new();
+    let mut output = Vec::new();
+
+    for (index, source) in sources.iter_mut().enumerate() {
+      let end = index + 1 == count;
+      let mut converter: Option<Converter> = None;
+
+      // The start of this source, collected until it covers the crossfade.
+      let mut head = Vec::new();
+      let mut mixing = index > 0 && crossfade.is_some();
+
+      let mut last = false;
+
+      while !last {
+        source.read(|audio| {
+          last = audio.last;
+
+          samples.truncate(0);
+          output.truncate(0);
+
+          if !audio.data.is_empty() {
+            if format.is_none() {
+              format = Some(Format {
+                channels: audio.channels,
+                layout: audio.layout.clone(),
+                sample_rate: audio.sample_rate,
+                sample_type: audio.sample_type,
+                endian: audio.endian
+              });
+
+              fade = match *crossfade {
+                Some(ref crossfade) => (crossfade.length * audio.sample_rate).round() as uint * audio.channels,
+                None => 0
+              };
+            }
+
+            if converter.is_none() {
+              converter = Some(Converter::new(index, audio, format.as_ref().unwrap(), mismatch));
+            }
+
+            sample::decode(audio, &mut samples);
+          }
+
+          match converter {
+            Some(ref mut converter) => converter.convert(&mut samples, last),
+            None => ()
+          }
+
+          if mixing {
+            head.push_all(samples.as_slice());
+            samples.truncate(0);
+
+            if head.len() >= fade || last {
+              let channels = format.as_ref().map_or(1, |f| f.channels);
+              let shape = &crossfade.as_ref().unwrap().shape;
+              let overlap = ::std::cmp::min(::std::cmp::min(held.len(), head.len()), fade) / channels * channels;
+              let start = held.len() - overlap;
+              let frames = overlap / channels;
+
+              output.push_all(held.slice_to(start));
+
+              // The fade runs from the first frame of the overlap to the
+              // last, so that each source is at full gain at its end.
+              for i in range(0, overlap) {
+                let x = if frames > 1 { (i / channels) as f64 / (frames - 1) as f64 } else { 0.5 };
+                let (a, b) = shape.gains(x);
+
+                output.push(held[start + i] * a + head[i] * b);
+              }
+
+              held.truncate(0);
+              samples.push_all(head.slice_from(overlap));
+              mixing = false;
+            }
+          }
+
+          held.push_all(samples.as_slice());
+
+          if end && last {
+            output.push_all(held.as_slice());
+            held.truncate(0);
+          } else if held.len() > fade {
+            let ready = held.len() - fade;
+
+            output.push_all(held.slice_to(ready));
+
+            let rest = held.slice_from(ready).to_vec();
+            held = rest;
+          }
+
+          sink.write(|out| {
+            match format {
+              Some(ref format) => {
+                out.channels = format.channels;
+                out.layout = format.layout.clone();
+                out.sample_rate = format.sample_rate;
+                out.sample_type = format.sample_type;
+                out.endian = format.endian;
+              },
+              None => {
+                out.channels = audio.channels;
+                out.layout = audio.layout.clone();
+                out.sample_rate = audio.sample_rate;
+                out.sample_type = audio.sample_type;
+                out.endian = audio.endian;
+              }
+            }
+
+            out.last = end && last;
+
+            if !output.is_empty() {
+              sample::encode(output.as_slice(), out);
+            }
+          });
+        });
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use channel;
+  use endian;
+  use layout;
+  use sample::testing;
+  use sample_type;
+
+  use Initialize;
+
+  fn source(channels: uint, sample_rate: f64, input: Vec<f64>) -> channel::Source<::Audio> {
+    return testing::source(input, 3, channels, sample_rate, sample_type::Float(32), endian::Big);
+  }
+
+  fn run(sources: Vec<channel::Source<::Audio>>, crossfade: Option<super::Crossfade>, mismatch: super::Mismatch) -> (Vec<f64>, uint) {
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() {
+      super::Concat::new(sources, out_sink, crossfade, mismatch).run();
+    });
+
+    let mut channels = 0;
+    let output = testing::collect_with(&mut out_source, |audio| channels = audio.channels);
+
+    return (output, channels);
+  }
+
+  #[test]
+  fn test_shape() {
+    let (a, b) = super::EqualPower.gains(0.3);
+
+    assert!((a * a + b * b - 1.0).abs() < 1e-12);
+    assert_eq!(super::Linear.gains(0.25), (0.75, 0.25));
+  }
+
+  #[test]
+  fn test_concat() {
+    let sources = vec![source(1, 10.0, vec![1.0, 2.0, 3.0, 4.0]), source(1, 10.0, vec![5.0, 6.0, 7.0, 8.0, 9.0])];
+    let (output, _) = run(sources, None, super::Reject);
+
+    assert_eq!(output, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+  }
+
+  #[test]
+  fn test_crossfade() {
+    let sources = vec![
+      source(1, 10.0, vec![1.0, 1.0, 1.0, 1.0, 1.0, 1.0]),
+      source(1, 10.0, vec![0.0, 0.0, 0.0, 0.0, 0.0])
+    ];
+    let (output, _) = run(sources, Some(super::Crossfade { shape: super::Linear, length: 0.4 }), super::Reject);
+
+    let expected = [1.0, 1.0, 1.0, 2.0 / 3.0, 1.0 / 3.0, 0.0, 0.0];
+
+    assert_eq!(output.len(), expected.len());
+
+    for (&x, &y) in output.iter().zip(expected.iter()) {
+      assert!((x - y).abs() < 1e-6);
+    }
+  }
+
+  #[test]
+  fn test_convert() {
+    let sources = vec![source(2, 10.0, vec![0.5, 0.5]), source(1, 10.0, vec![0.25, 0.75])];
+    let (output, channels) = run(sources, None, super::Convert);
+
+    assert_eq!(channels, 2);
+    assert_eq!(output, vec![0.5, 0.5, 0.25, 0.25, 0.75, 0.75]);
+  }
+
+  #[test]
+  #[should_fail]
+  fn test_reject() {
+    let mut audio: ::Audio = Initialize::initialize();
+    let format = super::Format { channels: 2, layout: layout::Unknown, sample_rate: 10.0, sample_type: sample_type::Float(32), endian: endian::Big };
+
+    audio.channels = 1;
+    audio.sample_rate = 10.0;
+    audio.sample_type = sample_type::Float(32);
+
+    super::Converter::new(1, &audio, &format, super::Reject);
+  }
+}
@@ -0,0 +1,58 @@
+/// Computes a CRC-16 of `data`, most significant bit first, using the
+/// generator `polynomial` and starting from `crc`.
+///
+/// AC-3 and MPEG audio both use the polynomial x^16 + x^15 + x^2 + 1 (0x8005),
+/// with initial values of 0 and 0xFFFF respectively.
+pub fn crc16(polynomial: u16, crc: u16, data: &[u8]) -> u16 {
+  let mut crc = crc;
+
+  for &byte in data.iter() {
+    crc ^= (byte as u16) << 8;
+
+    for _ in range(0u, 8) {
+      crc = if crc & 0x8000 != 0 { (crc << 1) ^ polynomial } else { crc << 1 };
+    }
+  }
+
+  return crc;
+}
+
+/// Like `crc16`, but covers only the first `bits` bits of `data`, for
+/// checksums that end in the middle of a byte.
+pub fn crc16_bits(polynomial: u16, crc: u16, data: &[u8], bits: uint) -> u16 {
+  let mut crc = crc16(polynomial, crc, data.slice_to(bits / 8));
+
+  for i in range(0, bits % 8) {
+    let bit = ((data[bits / 8] >> (7 - i)) & 1) as u16;
+
+    crc = if (crc >> 15) ^ bit == 1 { (crc << 1) ^ polynomial } else { crc << 1 };
+  }
+
+  return crc;
+}
+
+#[cfg(test)]
+mod tests {
+  #[test]
+  fn test_crc16() {
+    assert_eq!(super::crc16(0x8005, 0, b"123456789"), 0xFEE8);
+    assert_eq!(super::crc16(0x8005, 0xFFFF, b"123456789"), 0xAEE7);
+  }
+
+  #[test]
+  fn test_residue() {
+    let mut data = Vec::from_slice(b"aurora");
+    let crc = super::crc16(0x8005, 0, data.as_slice());
+
+    data.push((crc >> 8) as u8);
+    data.push(crc as u8);
+
+    assert_eq!(super::crc16(0x8005, 0, data.as_slice()), 0);
+  }
+
+  #[test]
+  fn test_crc16_bits() {
+    assert_eq!(super::crc16_bits(0x8005, 0xFFFF, b"123456789", 72), 0xAEE7);
+    assert_eq!(super::crc16_bits(0x8005, 0xFFFF, [0xA5u8, 0xF0], 12), super::crc16(0x8005, 0xFFFF, [0xA5u8, 0xF0]));
+  }
+}
@@ -0,0 +1,536 @@
+use std;
+
+use channel;
+use fft;
+use sample;
+
+/// Where to learn what the noise sounds like. Times are in seconds.
+#[deriving(Clone,Show,PartialEq)]
+pub enum Noise {
+  /// A stretch of the input that holds only noise.
+  Region(f64, f64),
+
+  /// The quietest fifth of the frames in the first seconds of the input.
+  Quietest(f64)
+}
+
+/// How to reduce noise.
+#[deriving(Clone,Show,PartialEq)]
+pub struct Settings {
+  pub noise: Noise,
+
+  /// The attenuation in dB of what is gated as noise.
+  pub reduction: f64,
+
+  /// How many standard deviations above its mean level noise may reach in
+  /// a bin before it is taken as signal.
+  pub sensitivity: f64,
+
+  /// How far, in seconds and in Hz either way, the gate is smoothed.
+  pub time_smoothing: f64,
+  pub frequency_smoothing: f64,
+
+  /// The proportion of the processed signal in the output, from 0 to 1.
+  pub wet: f64
+}
+
+impl Settings {
+  /// Reduces noise by 18 dB, with the gate at 1.5 standard deviations
+  /// and smoothed over 30 ms and 50 Hz.
+  pub fn new(noise: Noise) -> Settings {
+    return Settings { noise: noise, reduction: 18.0, sensitivity: 1.5, time_smoothing: 0.03, frequency_smoothing: 50.0, wet: 1.0 };
+  }
+
+  pub fn reduction(mut self, db: f64) -> Settings {
+    self.reduction = db;
+
+    return self;
+  }
+
+  pub fn sensitivity(mut self, deviations: f64) -> Settings {
+    self.sensitivity = deviations;
+
+    return self;
+  }
+
+  pub fn smoothing(mut self, time: f64, frequency: f64) -> Settings {
+    if !(time >= 0.0) || !(frequency >= 0.0) {
+      panic!("denoise::Settings: Smoothing must not be negative (ARGUMENT)");
+    }
+
+    self.time_smoothing = time;
+    self.frequency_smoothing = frequency;
+
+    return self;
+  }
+
+  pub fn wet(mut self, wet: f64) -> Settings {
+    if !(wet >= 0.0 && wet <= 1.0) {
+      panic!("denoise::Settings: Wet must be between 0 and 1 (ARGUMENT)");
+    }
+
+    self.wet = wet;
+
+    return self;
+  }
+}
+
+fn decibels(x: &fft::Complex) -> f64 {
+  return 20.0 * (x.norm() + 1e-12).log10();
+}
+
+/// The level of noise in each bin: the mean and standard deviation of its
+/// magnitude in dB.
+struct Profile {
+  mean: Vec<f64>,
+  deviation: Vec<f64>
+}
+
+impl Profile {
+  fn learn(frames: &[Vec<fft::Complex>]) -> Profile {
+    let bins = frames[0].len();
+    let n = frames.len() as f64;
+
+    let mean: Vec<f64> = range(0, bins).map(|k| frames.iter().fold(0.0, |a, f| a + decibels(&f[k])) / n).collect();
+    let deviation = range(0, bins).map(|k| {
+      (frames.iter().fold(0.0, |a, f| a + (decibels(&f[k]) - mean[k]).powi(2)) / n).sqrt()
+    }).collect();
+
+    return Profile { mean: mean, deviation: deviation };
+  }
+}
+
+/// Gates the spectrum of one channel and resynthesizes it.
+struct Channel {
+  fft: fft::RealFft,
+  window: Vec<f64>,
+  size: uint,
+  hop: uint,
+  profile: Profile,
+
+  /// Input, with silence before it, from padded sample `offset`.
+  input: Vec<f64>,
+  offset: uint,
+
+  /// Spectra and gates of frames from frame `first`.
+  spectra: Vec<Vec<fft::Complex>>,
+  gates: Vec<Vec<f64>>,
+  first: uint,
+
+  /// The next frame to resynthesize.
+  next: uint,
+
+  /// Output from padded sample `emitted`.
+  output: Vec<f64>,
+  emitted: uint,
+
+  frame: Vec<f64>,
+  bins: Vec<fft::Complex>,
+  synthesized: Vec<f64>
+}
+
+impl Channel {
+  fn new(size: uint, profile: Profile) -> Channel {
+    let hop = size / 4;
+
+    return Channel {
+      fft: fft::RealFft::new(size),
+      window: fft::Hann.coefficients(size),
+      size: size,
+      hop: hop,
+      profile: profile,
+      input: Vec::from_elem(size - hop, 0.0),
+      offset: 0,
+      spectra: Vec::new(),
+      gates: Vec::new(),
+      first: 0,
+      next: 0,
+      output: Vec::new(),
+      emitted: 0,
+      frame: Vec::from_elem(size, 0.0),
+      bins: Vec::new(),
+      synthesized: Vec::new()
+    };
+  }
+
+  /// Adds input samples, and appends the output samples that are complete
+  /// to `output`. At the end, the output is as long as the input.
+  fn process(&mut self, samples: &[f64], last: bool, settings: &Settings, sample_rate: f64, output: &mut Vec<f64>) {
+    let (size, hop) = (self.size, self.hop);
+    let padding = size - hop;
+    let span = (settings.time_smoothing * sample_rate / hop as f64).round() as uint;
+    let spread = (settings.frequency_smoothing * size as f64 / sample_rate).round() as uint;
+    let floor = (10.0f64).powf(-settings.reduction / 20.0);
+
+    self.input.push_all(samples);
+
+    let length = self.offset + self.input.len();
+
+    if last {
+      self.input.grow(size, 0.0);
+    }
+
+    // Analyze each frame, and gate each bin that stays within the noise.
+    let mut start = (self.first + self.spectra.len()) * hop;
+
+    while start + size <= self.offset + self.input.len() {
+      for i in range(0, size) {
+        *self.frame.get_mut(i) = self.input[start - self.offset + i] * self.window[i];
+      }
+
+      self.fft.forward(self.frame.as_slice(), &mut self.bins);
+
+      let profile = &self.profile;
+      let gate = self.bins.iter().enumerate().map(|(k, x)| {
+        if decibels(x) > profile.mean[k] + settings.sensitivity * profile.deviation[k] { 1.0 } else { floor }
+      }).collect();
+
+      self.spectra.push(self.bins.clone());
+      self.gates.push(gate);
+
+      start += hop;
+    }
+
+    let analyzed = self.first + self.spectra.len();
+
+    // Resynthesize each frame whose smoothing window has been analyzed.
+    while self.next < analyzed && (last || self.next + span < analyzed) {
+      let k = self.next;
+      let from = if k > span { k - span } else { 0 };
+      let to = std::cmp::min(k + span + 1, analyzed);
+
+      let mut bins = self.spectra[k - self.first].clone();
+      let count = bins.len();
+
+      for b in range(0, count) {
+        let low = if b > spread { b - spread } else { 0 };
+        let high = std::cmp::min(b + spread + 1, count);
+        let mut sum = 0.0;
+
+        for t in range(from, to) {
+          sum += self.gates[t - self.first].slice(low, high).iter().fold(0.0, |a, &g| a + g);
+        }
+
+        let gate = sum / ((to - from) * (high - low)) as f64;
+        let gain = 1.0 - settings.wet + settings.wet * gate;
+
+        let scaled = bins[b].scale(gain);
+
+        *bins.get_mut(b) = scaled;
+      }
+
+      self.fft.inverse(bins.as_slice(), &mut self.synthesized);
+
+      let at = k * hop - self.emitted;
+
+      if self.output.len() < at + size {
+        let missing = at + size - self.output.len();
+        self.output.grow(missing, 0.0);
+      }
+
+      // The Hann window squared overlaps to 1.5 at a quarter size hop.
+      for i in range(0, size) {
+        *self.output.get_mut(at + i) += self.synthesized[i] * self.window[i] / 1.5;
+      }
+
+      self.next += 1;
+    }
+
+    // Samples before the next frame are complete.
+    let complete = if last { length } else { self.next * hop };
+
+    if complete > self.emitted {
+      let count = std::cmp::min(complete - self.emitted, self.output.len());
+
+      for i in range(0, count) {
+        if self.emitted + i >= padding {
+          output.push(self.output[i]);
+        }
+      }
+
+      let rest = self.output.slice_from(count).to_vec();
+
+      self.output = rest;
+      self.emitted += count;
+    }
+
+    // Drop what no frame still needs.
+    let needed = if self.next > span { self.next - span } else { 0 };
+
+    if needed > self.first {
+      let drop = needed - self.first;
+
+      self.spectra = self.spectra.slice_from(drop).to_vec();
+      self.gates = self.gates.slice_from(drop).to_vec();
+      self.first = needed;
+    }
+
+    let keep = std::cmp::min((self.first + self.spectra.len()) * hop, self.offset + self.input.len());
+
+    if keep > self.offset {
+      self.input = self.input.slice_from(keep - self.offset).to_vec();
+      self.offset = keep;
+    }
+  }
+}
+
+/// Reduces stationary noise, such as hiss and hum, by spectral gating.
+///
+/// A noise profile is learned per channel, and then each bin of each frame
+/// of the spectrum whose level stays within the noise is attenuated. The
+/// gate is smoothed over time and frequency to avoid the warbling of
+/// isolated bins. The input is held back until the noise has been heard,
+/// and the output is as long as the input. If there is less noise than a
+/// frame of the spectrum, about 46 ms, the input passes through unchanged.
+pub struct Denoiser {
+  source: channel::Source<::Audio>,
+  sink: channel::Sink<::Audio>,
+  settings: Settings
+}
+
+impl Denoiser {
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, settings: Settings) -> Denoiser {
+    match settings.noise {
+      Region(start, end) if !(start >= 0.0 && end > start) => panic!("denoise::Denoiser: Bad noise region (ARGUMENT)"),
+      Quietest(seconds) if !(seconds > 0.0) => panic!("denoise::Denoiser: Bad noise duration (ARGUMENT)"),
+      _ => ()
+    }
+
+    return Denoiser { source: source, sink: sink, settings: settings };
+  }
+
+  /// Learns a profile per channel from the interleaved samples held back,
+  /// if they hold a frame of noise.
+  fn learn(settings: &Settings, held: &[f64], channels: uint, sample_rate: f64, size: uint) -> Option<Vec<Profile>> {
+    let frames = held.len() / channels;
+    let hop = size / 4;
+
+    let (start, end) = match settings.noise {
+      Region(start, end) => ((start * sample_rate) as uint, std::cmp::min((end * sample_rate) as uint, frames)),
+      Quietest(seconds) => (0, std::cmp::min((seconds * sample_rate) as uint, frames))
+    };
+
+    if start + size > end {
+      return None;
+    }
+
+    let mut fft = fft::RealFft::new(size);
+    let window = fft::Hann.coefficients(size);
+    let mut frame = Vec::from_elem(size, 0.0);
+    let positions: Vec<uint> = std::iter::range_step(start, end - size + 1, hop).collect();
+
+    let mut energies = Vec::from_elem(positions.len(), 0.0f64);
+    let mut spectra: Vec<Vec<Vec<fft::Complex>>> = Vec::from_fn(channels, |_| Vec::new());
+
+    for c in range(0, channels) {
+      for (j, &p) in positions.iter().enumerate() {
+        for i in range(0, size) {
+          *frame.get_mut(i) = held[(p + i) * channels + c] * window[i];
+        }
+
+        let mut bins = Vec::new();
+
+        fft.forward(frame.as_slice(), &mut bins);
+        *energies.get_mut(j) += frame.iter().fold(0.0, |a, &x| a + x * x);
+        spectra.get_mut(c).push(bins);
+      }
+    }
+
+    let chosen: Vec<uint> = match settings.noise {
+      Region(_, _) => range(0, positions.len()).collect(),
+      Quietest(_) => {
+        let mut order: Vec<uint> = range(0, positions.len()).collect();
+
+        order.sort_by(|&a, &b| energies[a].partial_cmp(&energies[b]).unwrap());
+        order.truncate(std::cmp::max(positions.len() / 5, 1));
+        order
+      }
+    };
+
+    return Some(spectra.iter().map(|frames| {
+      let noise: Vec<Vec<fft::Complex>> = chosen.iter().map(|&j| frames[j].clone()).collect();
+
+      Profile::learn(noise.as_slice())
+    }).collect());
+  }
+
+  pub fn run(&mut self) {
+    let settings = &self.settings;
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    let horizon = match settings.noise {
+      Region(_, end) => end,
+      Quietest(seconds) => seconds
+    };
+
+    let mut held = Vec::new();
+    let mut states: Vec<Channel> = Vec::new();
+    let mut passing = false;
+
+    let mut samples = Vec::new();
+    let mut channel = Vec::new();
+    let mut processed: Vec<Vec<f64>> = Vec::new();
+    let mut output = Vec::new();
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        samples.truncate(0);
+        output.truncate(0);
+
+        if !audio.data.is_empty() {
+          sample::decode(audio, &mut samples);
+        }
+
+        if states.is_empty() && !passing {
+          held.push_all(samples.as_slice());
+
+          let heard = held.len() / std::cmp::max(audio.channels, 1);
+
+          if !held.is_empty() && (last || heard as f64 >= horizon * audio.sample_rate) {
+            let mut size = 64;
+
+            while (size as f64) < audio.sample_rate * 0.046 {
+              size *= 2;
+            }
+
+            match Denoiser::learn(settings, held.as_slice(), audio.channels, audio.sample_rate, size) {
+              Some(profiles) => {
+                states = profiles.into_iter().map(|profile| Channel::new(size, profile)).collect();
+                processed = Vec::from_fn(audio.channels, |_| Vec::new());
+              },
+              None => passing = true
+            }
+
+            samples.truncate(0);
+            samples.push_all(held.as_slice());
+            held.truncate(0);
+          }
+        }
+
+        if passing {
+          output.push_all(samples.as_slice());
+        } else if !states.is_empty() {
+          let channels = states.len();
+
+          if !samples.is_empty() && audio.channels != channels {
+            panic!("denoise::Denoiser: Channel count changed (INPUT)");
+          }
+
+          for (c, state) in states.iter_mut().enumerate() {
+            channel.truncate(0);
+
+            for frame in samples.as_slice().chunks(channels) {
+              channel.push(frame[c]);
+            }
+
+            let out = processed.get_mut(c);
+
+            out.truncate(0);
+            state.process(channel.as_slice(), last, settings, audio.sample_rate, out);
+          }
+
+          let frames = processed.iter().map(|p| p.len()).min().unwrap_or(0);
+
+          for i in range(0, frames) {
+            for c in range(0, channels) {
+              output.push(processed[c][i]);
+            }
+          }
+        }
+
+        sink.write(|out| {
+          out.channels = audio.channels;
+          out.layout = audio.layout.clone();
+          out.sample_rate = audio.sample_rate;
+          out.sample_type = audio.sample_type;
+          out.endian = audio.endian;
+          out.last = last;
+
+          if !output.is_empty() {
+            sample::encode(output.as_slice(), out);
+          }
+        });
+      });
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::f64::consts::PI;
+
+  use channel;
+  use endian;
+  use sample::testing;
+  use sample_type;
+
+  /// Half a second of noise at -40 dBFS, then a second and a half of a
+  /// 1 kHz tone at -12 dBFS over it, at 16 kHz.
+  fn input() -> Vec<f64> {
+    let mut state = 1u32;
+
+    return Vec::from_fn(32000, |i| {
+      state = state * 1664525 + 1013904223;
+
+      let noise = ((state >> 8) as f64 / (1u32 << 24) as f64 - 0.5) * 0.01 * (12.0f64).sqrt();
+      let tone = if i >= 8000 { 0.25 * (2.0 * PI * 1000.0 * i as f64 / 16000.0).sin() } else { 0.0 };
+
+      noise + tone
+    });
+  }
+
+  fn denoise(settings: super::Settings, input: Vec<f64>) -> Vec<f64> {
+    let source = testing::source(input, 1000, 1, 16000.0, sample_type::Float(64), endian::Little);
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() {
+      super::Denoiser::new(source, out_sink, settings).run();
+    });
+
+    return testing::collect(&mut out_source);
+  }
+
+  fn rms(samples: &[f64]) -> f64 {
+    return (samples.iter().fold(0.0, |a, &x| a + x * x) / samples.len() as f64).sqrt();
+  }
+
+  #[test]
+  fn test_denoise() {
+    let input = input();
+    let output = denoise(super::Settings::new(super::Region(0.0, 0.5)), input.clone());
+
+    assert_eq!(output.len(), input.len());
+
+    let noise = 20.0 * (rms(output.slice(1600, 6400)) / rms(input.slice(1600, 6400))).log10();
+    let tone = 20.0 * (rms(output.slice(16000, 30000)) / rms(input.slice(16000, 30000))).log10();
+
+    assert!(noise < -10.0);
+    assert!(tone.abs() < 1.0);
+  }
+
+  #[test]
+  fn test_short() {
+    // 20 ms holds no frame of noise to learn from.
+    let input = input().slice_to(320).to_vec();
+
+    assert_eq!(denoise(super::Settings::new(super::Quietest(1.0)), input.clone()), input);
+    assert_eq!(denoise(super::Settings::new(super::Region(0.0, 0.5)), input.clone()), input);
+  }
+
+  #[test]
+  fn test_dry() {
+    let input = input();
+    let output = denoise(super::Settings::new(super::Quietest(1.0)).wet(0.0), input.clone());
+
+    assert_eq!(output.len(), input.len());
+
+    for (x, y) in output.iter().zip(input.iter()) {
+      assert!((x - y).abs() < 1e-9);
+    }
+  }
+}
@@ -0,0 +1,211 @@
+use std;
+
+use channel;
+use layout;
+use stream;
+use sample_type;
+
+/// Demuxes Philips DSDIFF (.dff) files into `sample_type::Dsd` audio.
+///
+/// Only uncompressed DSD is supported, DST compressed files panic.
+pub struct Demuxer {
+  source: channel::Source<::Binary>,
+  sink: channel::Sink<::Audio>,
+  chunk: uint
+}
+
+impl Demuxer {
+  /// Creates a demuxer that emits `chunk` bytes per channel in each packet.
+  pub fn new(source: channel::Source<::Binary>, sink: channel::Sink<::Audio>, chunk: uint) -> Demuxer {
+    return Demuxer { source: source, sink: sink, chunk: chunk };
+  }
+
+  pub fn run(&mut self) {
+    let chunk = self.chunk;
+
+    let mut s = stream::Stream::new(&mut self.source);
+    let sink = &mut self.sink;
+
+    let mut id = [0u8, ..4];
+
+    s.read(id);
+    s.read_be_u64();
+
+    let mut form = [0u8, ..4];
+
+    s.read(form);
+
+    if id.as_slice() != b"FRM8" || form.as_slice() != b"DSD " {
+      panic!("dff::Demuxer: Not a DSDIFF file (INPUT)");
+    }
+
+    let mut channels = 0;
+    let mut channel_layout = layout::Unknown;
+    let mut sample_rate = 0;
+    let size;
+
+    loop {
+      s.read(id);
+
+      let length = s.read_be_u64() as uint;
+      let padded = length + (length & 1);
+
+      if id.as_slice() == b"PROP" {
+        s.read(form);
+
+        if form.as_slice() != b"SND " {
+          s.skip(padded - 4);
+          continue;
+        }
+
+        let mut remaining = padded - 4;
+
+        while remaining > 0 {
+          s.read(id);
+
+          let sub_length = s.read_be_u64() as uint;
+          let sub_padded = sub_length + (sub_length & 1);
+
+          if id.as_slice() == b"FS  " {
+            sample_rate = s.read_be_u32();
+            s.skip(sub_padded - 4);
+          } else if id.as_slice() == b"CHNL" {
+            channels = s.read_be_u16() as uint;
+
+            let mut speakers = Vec::with_capacity(channels);
+
+            for _ in range(0, channels) {
+              s.read(form);
+
+              match speaker(form) {
+                Some(speaker) => speakers.push(speaker),
+                None => {}
+              }
+            }
+
+            // Any channel without a known position makes them all discrete.
+            channel_layout = if speakers.len() == channels { layout::Speakers(speakers) } else { layout::Discrete };
+
+            s.skip(sub_padded - 2 - 4 * channels);
+          } else if id.as_slice() == b"CMPR" {
+            s.read(form);
+
+            if form.as_slice() != b"DSD " {
+              panic!("dff::Demuxer: Unsupported compression (INPUT)");
+            }
+
+            s.skip(sub_padded - 4);
+          } else {
+            s.skip(sub_padded);
+          }
+
+          remaining -= 12 + sub_padded;
+        }
+      } else if id.as_slice() == b"DSD " {
+        size = length;
+        break;
+      } else if id.as_slice() == b"DST " {
+        panic!("dff::Demuxer: DST compression is not supported (INPUT)");
+      } else {
+        s.skip(padded);
+      }
+    }
+
+    if channels == 0 || sample_rate == 0 {
+      panic!("dff::Demuxer: Missing PROP chunk (INPUT)");
+    }
+
+    let frame = chunk * channels;
+    let mut remaining = size - size % channels;
+
+    loop {
+      let length = std::cmp::min(frame, remaining);
+
+      remaining -= length;
+
+      let last = remaining == 0;
+
+      sink.write(|audio| {
+        audio.channels = channels;
+        audio.layout = channel_layout.clone();
+        audio.sample_rate = sample_rate as f64;
+        audio.sample_type = sample_type::Dsd;
+        audio.data.grow(length, 0);
+
+        s.read(audio.data.as_mut_slice());
+
+        audio.last = last;
+      });
+
+      if last {
+        break;
+      }
+    }
+
+    while s.try_skip(4096).is_some() {}
+  }
+}
+
+/// Returns the speaker for a DSDIFF channel ID.
+fn speaker(id: &[u8]) -> Option<layout::Speaker> {
+  if id == b"SLFT" || id == b"MLFT" {
+    return Some(layout::FrontLeft);
+  } else if id == b"SRGT" || id == b"MRGT" {
+    return Some(layout::FrontRight);
+  } else if id == b"C   " {
+    return Some(layout::FrontCenter);
+  } else if id == b"LFE " {
+    return Some(layout::LowFrequency);
+  } else if id == b"LS  " {
+    return Some(layout::BackLeft);
+  } else if id == b"RS  " {
+    return Some(layout::BackRight);
+  }
+
+  return None;
+}
+
+#[cfg(test)]
+mod tests {
+  use channel;
+  use buffer;
+  use layout;
+  use sample_type;
+
+  fn be(value: u64, n: uint) -> Vec<u8> {
+    return range(0, n).map(|i| (value >> (8 * (n - i - 1))) as u8).collect();
+  }
+
+  #[test]
+  fn test_demux() {
+    let mut f = Vec::new();
+
+    f.push_all(b"FRM8"); f.push_all(be(0, 8).as_slice()); f.push_all(b"DSD ");
+    f.push_all(b"FVER"); f.push_all(be(4, 8).as_slice()); f.push_all(be(0x01050000, 4).as_slice());
+    f.push_all(b"PROP"); f.push_all(be(4 + 16 + 22 + 16, 8).as_slice()); f.push_all(b"SND ");
+    f.push_all(b"FS  "); f.push_all(be(4, 8).as_slice()); f.push_all(be(5644800, 4).as_slice());
+    f.push_all(b"CHNL"); f.push_all(be(10, 8).as_slice()); f.push_all(be(2, 2).as_slice()); f.push_all(b"SLFTSRGT");
+    f.push_all(b"CMPR"); f.push_all(be(4, 8).as_slice()); f.push_all(b"DSD ");
+    f.push_all(b"DSD "); f.push_all(be(6, 8).as_slice()); f.push_all([1u8, 2, 3, 4, 5, 6]);
+
+    let (sink, source) = channel::create::<::Binary>(1);
+    let (audio_sink, mut audio_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() { buffer::Buffer::new(f, 7, sink).run(); });
+    spawn(proc() { super::Demuxer::new(source, audio_sink, 2).run(); });
+
+    audio_source.read(|audio| {
+      assert_eq!(audio.last, false);
+      assert_eq!(audio.channels, 2);
+      assert_eq!(audio.layout, layout::Layout::stereo());
+      assert_eq!(audio.sample_rate, 5644800.0);
+      assert_eq!(audio.sample_type, sample_type::Dsd);
+      assert_eq!(audio.data, vec![1u8, 2, 3, 4]);
+    });
+
+    audio_source.read(|audio| {
+      assert_eq!(audio.last, true);
+      assert_eq!(audio.data, vec![5u8, 6]);
+    });
+  }
+}
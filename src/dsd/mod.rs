@@ -0,0 +1,303 @@
+use std;
+use std::f64::consts::PI;
+
+use channel;
+use layout;
+use sample;
+use sample_type;
+
+/// The DSD idle pattern, which decodes to silence.
+static SILENCE: u8 = 0x69;
+
+/// Converts 1-bit `sample_type::Dsd` audio into multi-bit PCM.
+///
+/// Each output sample is produced by a linear phase windowed-sinc low pass
+/// filter, evaluated every `ratio` input bits. A ratio of 32 turns DSD64 into
+/// 88.2 kHz and DSD128 into 176.4 kHz. The filter is evaluated a byte at a
+/// time using a lookup table, so the cost is independent of the bit depth.
+///
+/// The output has the same number of samples as the input had bits, divided
+/// by `ratio`, and is compensated for the group delay of the filter.
+pub struct Decimator {
+  source: channel::Source<::Audio>,
+  sink: channel::Sink<::Audio>,
+  ratio: uint,
+  sample_type: sample_type::SampleType,
+  table: Vec<f64>,
+  taps: uint
+}
+
+impl Decimator {
+  /// Creates a decimator, `ratio` must be a multiple of 8. The output is
+  /// encoded as `sample_type`.
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, ratio: uint, sample_type: sample_type::SampleType) -> Decimator {
+    if ratio == 0 || ratio % 8 != 0 {
+      panic!("dsd::Decimator: Ratio must be a multiple of 8 (ARGUMENT)");
+    }
+
+    let (table, taps) = design(ratio);
+
+    return Decimator {
+      source: source, sink: sink, ratio: ratio, sample_type: sample_type, table: table, taps: taps
+    };
+  }
+
+  pub fn run(&mut self) {
+    let step = self.ratio / 8;
+    let taps = self.taps;
+    let output_type = self.sample_type;
+    let ratio = self.ratio;
+
+    let table = &self.table;
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    let mut histories: Vec<Vec<u8>> = Vec::new();
+    let mut format = (0.0, layout::Unknown);
+    let mut received = 0u;
+    let mut produced = 0u;
+    let mut output = Vec::new();
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        // Empty packets, such as one that only ends the stream, may carry
+        // no format, so the format of the stream so far is kept.
+        if !audio.data.is_empty() {
+          if audio.sample_type != sample_type::Dsd {
+            panic!("dsd::Decimator: Input must be DSD (INPUT)");
+          }
+
+          if histories.len() != audio.channels {
+            histories = Vec::from_fn(audio.channels, |_| Vec::from_elem(taps / 2, SILENCE));
+          }
+
+          format = (audio.sample_rate / ratio as f64, audio.layout.clone());
+        }
+
+        let channels = histories.len();
+
+        if channels == 0 {
+          sink.write(|out| {
+            out.channels = audio.channels;
+            out.layout = audio.layout.clone();
+            out.sample_rate = audio.sample_rate / ratio as f64;
+            out.sample_type = output_type;
+            out.last = last;
+          });
+
+          return;
+        }
+
+        let bytes = audio.data.len() / channels;
+
+        for c in range(0, channels) {
+          let h = histories.get_mut(c);
+
+          for i in range(0, bytes) {
+            h.push(audio.data[i * channels + c]);
+          }
+
+          if last {
+            h.grow(taps / 2, SILENCE);
+          }
+        }
+
+        received += bytes;
+
+        let length = histories[0].len();
+        let fitting = if length >= taps { (length - taps) / step + 1 } else { 0 };
+        let available = std::cmp::min(fitting, received / step - produced);
+
+        output.truncate(0);
+        output.grow(available * channels, 0.0);
+
+        for c in range(0, channels) {
+          let h = histories.get_mut(c);
+
+          for n in range(0, available) {
+            let window = h.slice(n * step, n * step + taps);
+            let mut sum = 0.0;
+
+            for (j, &byte) in window.iter().enumerate() {
+              sum += table[j * 256 + byte as uint];
+            }
+
+            *output.get_mut(n * channels + c) = sum;
+          }
+
+          let consumed = available * step;
+          let remaining = h.len() - consumed;
+
+          for i in range(0, remaining) {
+            *h.get_mut(i) = h[i + consumed];
+          }
+
+          h.truncate(remaining);
+        }
+
+        produced += available;
+
+        let (sample_rate, ref layout) = format;
+
+        sink.write(|out| {
+          out.channels = channels;
+          out.layout = layout.clone();
+          out.sample_rate = sample_rate;
+          out.sample_type = output_type;
+          out.last = last;
+
+          sample::encode(output.as_slice(), out);
+        });
+      });
+    }
+  }
+}
+
+/// Designs the filter for `ratio`, and returns its byte lookup table along
+/// with the filter length in bytes.
+///
+/// `table[j * 256 + b]` is the contribution of byte `b` at byte position `j`.
+fn design(ratio: uint) -> (Vec<f64>, uint) {
+  let taps = ratio * 6;
+  let length = taps * 8;
+
+  let cutoff = 0.45 / ratio as f64;
+  let center = (length - 1) as f64 / 2.0;
+
+  let mut coefficients = Vec::with_capacity(length);
+
+  for i in range(0, length) {
+    let x = i as f64 - center;
+
+    let sinc = if x == 0.0 {
+      2.0 * cutoff
+    } else {
+      (2.0 * PI * cutoff * x).sin() / (PI * x)
+    };
+
+    let phase = 2.0 * PI * i as f64 / (length - 1) as f64;
+    let window = 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+
+    coefficients.push(sinc * window);
+  }
+
+  let sum = coefficients.iter().fold(0.0, |a, &b| a + b);
+
+  let mut table = Vec::with_capacity(taps * 256);
+
+  for j in range(0, taps) {
+    for b in range(0u, 256) {
+      let mut value = 0.0;
+
+      for k in range(0u, 8) {
+        let bit = (b >> (7 - k)) & 1;
+        let sign = if bit == 1 { 1.0 } else { -1.0 };
+
+        value += sign * coefficients[j * 8 + k] / sum;
+      }
+
+      table.push(value);
+    }
+  }
+
+  return (table, taps);
+}
+
+#[cfg(test)]
+mod tests {
+  use channel;
+  use layout;
+  use sample;
+  use sample::testing;
+  use sample_type;
+
+  fn decimate(byte: u8, bytes: uint) -> Vec<f64> {
+    let (mut sink, source) = channel::create::<::Audio>(1);
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() {
+      super::Decimator::new(source, out_sink, 32, sample_type::Float(64)).run();
+    });
+
+    spawn(proc() {
+      sink.write(|audio| {
+        audio.channels = 1;
+        audio.sample_rate = 2822400.0;
+        audio.sample_type = sample_type::Dsd;
+        audio.data.grow(bytes, byte);
+        audio.last = true;
+      });
+    });
+
+    let mut output = Vec::new();
+
+    out_source.read(|audio| {
+      assert_eq!(audio.last, true);
+      assert_eq!(audio.sample_rate, 88200.0);
+
+      sample::decode(audio, &mut output);
+    });
+
+    return output;
+  }
+
+  #[test]
+  fn test_length() {
+    assert_eq!(decimate(0x69, 4000).len(), 1000);
+    assert_eq!(decimate(0x69, 4003).len(), 1000);
+  }
+
+  #[test]
+  fn test_empty_last() {
+    let (mut sink, source) = channel::create::<::Audio>(1);
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() {
+      super::Decimator::new(source, out_sink, 32, sample_type::Float(64)).run();
+    });
+
+    spawn(proc() {
+      sink.write(|audio| {
+        audio.channels = 2;
+        audio.layout = layout::Layout::stereo();
+        audio.sample_rate = 2822400.0;
+        audio.sample_type = sample_type::Dsd;
+        audio.data.grow(8000, 0x69);
+        audio.last = false;
+      });
+
+      sink.write(|audio| {
+        audio.channels = 0;
+        audio.data.truncate(0);
+        audio.last = true;
+      });
+    });
+
+    let output = testing::collect_with(&mut out_source, |audio| {
+      assert_eq!(audio.sample_rate, 88200.0);
+      assert_eq!(audio.layout, layout::Layout::stereo());
+    });
+
+    assert_eq!(output.len(), 2000);
+  }
+
+  #[test]
+  fn test_silence() {
+    for &x in decimate(0x69, 4000).iter() {
+      assert!(x.abs() < 1e-3);
+    }
+  }
+
+  #[test]
+  fn test_full_scale() {
+    let output = decimate(0xFF, 4000);
+
+    for &x in output.slice(100, 900).iter() {
+      assert!((x - 1.0).abs() < 1e-3);
+    }
+  }
+}
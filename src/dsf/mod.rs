@@ -0,0 +1,210 @@
+use std;
+
+use channel;
+use layout;
+use stream;
+use sample_type;
+
+/// Demuxes Sony DSF files into `sample_type::Dsd` audio.
+///
+/// DSF stores each channel in separate blocks (usually of 4096 bytes), and
+/// 1-bit files store the least significant bit first. Both are normalized, so
+/// the output is interleaved per byte, most significant bit first.
+pub struct Demuxer {
+  source: channel::Source<::Binary>,
+  sink: channel::Sink<::Audio>
+}
+
+impl Demuxer {
+  pub fn new(source: channel::Source<::Binary>, sink: channel::Sink<::Audio>) -> Demuxer {
+    return Demuxer { source: source, sink: sink };
+  }
+
+  pub fn run(&mut self) {
+    let mut s = stream::Stream::new(&mut self.source);
+    let sink = &mut self.sink;
+
+    let mut id = [0u8, ..4];
+
+    s.read(id);
+
+    if id.as_slice() != b"DSD " {
+      panic!("dsf::Demuxer: Not a DSF file (INPUT)");
+    }
+
+    let header_size = s.read_le_u64() as uint;
+
+    s.skip(header_size - 12);
+
+    let mut channels = 0;
+    let mut channel_layout = layout::Unknown;
+    let mut sample_rate = 0;
+    let mut lsb_first = true;
+    let mut sample_count = 0;
+    let mut block_size = 0;
+
+    loop {
+      s.read(id);
+
+      let size = s.read_le_u64() as uint;
+
+      if size < 12 {
+        panic!("dsf::Demuxer: Invalid chunk size (INPUT)");
+      }
+
+      if id.as_slice() == b"fmt " {
+        let version = s.read_le_u32();
+        let format = s.read_le_u32();
+
+        if version != 1 || format != 0 {
+          panic!("dsf::Demuxer: Unsupported format version {} or id {} (INPUT)", version, format);
+        }
+
+        channel_layout = channel_type(s.read_le_u32());
+        channels = s.read_le_u32() as uint;
+        sample_rate = s.read_le_u32();
+
+        lsb_first = match s.read_le_u32() {
+          1 => true,
+          8 => false,
+          n => panic!("dsf::Demuxer: Invalid bits per sample {} (INPUT)", n)
+        };
+
+        sample_count = s.read_le_u64();
+        block_size = s.read_le_u32() as uint;
+
+        s.skip(size - 12 - 36);
+      } else if id.as_slice() == b"data" {
+        break;
+      } else {
+        s.skip(size - 12);
+      }
+    }
+
+    if channels == 0 || block_size == 0 {
+      panic!("dsf::Demuxer: Missing fmt chunk (INPUT)");
+    }
+
+    let total = ((sample_count + 7) / 8) as uint;
+    let mut emitted = 0;
+
+    let mut block = Vec::from_elem(block_size * channels, 0u8);
+    let mut packet = Vec::from_elem(block_size * channels, 0u8);
+
+    loop {
+      let length = std::cmp::min(block_size, total - emitted);
+
+      if length > 0 {
+        s.read(block.as_mut_slice());
+      }
+
+      for i in range(0, length) {
+        for c in range(0, channels) {
+          let byte = block[c * block_size + i];
+
+          packet[i * channels + c] = if lsb_first { reverse(byte) } else { byte };
+        }
+      }
+
+      emitted += length;
+
+      let last = emitted == total;
+
+      sink.write(|audio| {
+        audio.channels = channels;
+        audio.layout = channel_layout.clone();
+        audio.sample_rate = sample_rate as f64;
+        audio.sample_type = sample_type::Dsd;
+        audio.data.push_all(packet.slice_to(length * channels));
+        audio.last = last;
+      });
+
+      if last {
+        break;
+      }
+    }
+
+    while s.try_skip(4096).is_some() {}
+  }
+}
+
+/// Returns the layout for a DSF channel type.
+fn channel_type(id: u32) -> layout::Layout {
+  return match id {
+    1 => layout::Layout::mono(),
+    2 => layout::Layout::stereo(),
+    3 => layout::Speakers(vec![layout::FrontLeft, layout::FrontRight, layout::FrontCenter]),
+    4 => layout::Layout::quadraphonic(),
+    5 => layout::Speakers(vec![layout::FrontLeft, layout::FrontRight, layout::FrontCenter, layout::LowFrequency]),
+    6 => layout::Speakers(vec![layout::FrontLeft, layout::FrontRight, layout::FrontCenter, layout::BackLeft, layout::BackRight]),
+    7 => layout::Layout::surround51(),
+    _ => layout::Unknown
+  };
+}
+
+fn reverse(byte: u8) -> u8 {
+  let mut result = 0u8;
+
+  for i in range(0u, 8) {
+    result |= ((byte >> i) & 1) << (7 - i);
+  }
+
+  return result;
+}
+
+#[cfg(test)]
+mod tests {
+  use channel;
+  use buffer;
+  use sample_type;
+
+  fn le(value: u64, n: uint) -> Vec<u8> {
+    return range(0, n).map(|i| (value >> (8 * i)) as u8).collect();
+  }
+
+  fn file(channels: uint, samples: u64, block: uint, data: &[u8]) -> Vec<u8> {
+    let mut f = Vec::new();
+
+    f.push_all(b"DSD "); f.push_all(le(28, 8).as_slice()); f.push_all(le(0, 8).as_slice()); f.push_all(le(0, 8).as_slice());
+
+    f.push_all(b"fmt "); f.push_all(le(52, 8).as_slice());
+    f.push_all(le(1, 4).as_slice()); f.push_all(le(0, 4).as_slice()); f.push_all(le(2, 4).as_slice());
+    f.push_all(le(channels as u64, 4).as_slice()); f.push_all(le(2822400, 4).as_slice()); f.push_all(le(1, 4).as_slice());
+    f.push_all(le(samples, 8).as_slice()); f.push_all(le(block as u64, 4).as_slice()); f.push_all(le(0, 4).as_slice());
+
+    f.push_all(b"data"); f.push_all(le(12 + data.len() as u64, 8).as_slice());
+    f.push_all(data);
+
+    return f;
+  }
+
+  #[test]
+  fn test_interleave() {
+    let data = file(2, 24, 4, [0x01u8, 0x02, 0x03, 0x00, 0x80, 0x40, 0xC0, 0x00]);
+
+    let (sink, source) = channel::create::<::Binary>(1);
+    let (audio_sink, mut audio_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() { buffer::Buffer::new(data, 4096, sink).run(); });
+    spawn(proc() { super::Demuxer::new(source, audio_sink).run(); });
+
+    audio_source.read(|audio| {
+      assert_eq!(audio.last, true);
+      assert_eq!(audio.channels, 2);
+      assert_eq!(audio.sample_rate, 2822400.0);
+      assert_eq!(audio.sample_type, sample_type::Dsd);
+      assert_eq!(audio.data, vec![0x80u8, 0x01, 0x40, 0x02, 0xC0, 0x03]);
+    });
+  }
+
+  #[test]
+  fn test_reverse() {
+    assert_eq!(super::reverse(0x01), 0x80);
+    assert_eq!(super::reverse(0x69), 0x96);
+    assert_eq!(super::reverse(0xF0), 0x0F);
+
+    for i in range(0u, 256) {
+      assert_eq!(super::reverse(super::reverse(i as u8)), i as u8);
+    }
+  }
+}
@@ -0,0 +1,654 @@
+use std;
+use std::collections::RingBuf;
+
+use channel;
+use loudness;
+use sample;
+
+/// The static curve of a dynamics processor: how many dB of gain to apply
+/// to a level in dB, relative to the threshold.
+#[deriving(Clone,Show,PartialEq)]
+pub enum Curve {
+  /// Reduces levels above the threshold by a ratio, with a soft knee of a
+  /// width in dB.
+  Compress(f64, f64),
+
+  /// Reduces levels below the threshold by a ratio, with a soft knee of a
+  /// width in dB.
+  Expand(f64, f64),
+
+  /// Attenuates levels below the threshold by a range in dB.
+  Gate(f64)
+}
+
+impl Curve {
+  /// The gain in dB for a level `over` dB above the threshold.
+  pub fn gain(&self, over: f64) -> f64 {
+    return match *self {
+      Compress(ratio, knee) => {
+        if 2.0 * over < -knee {
+          0.0
+        } else if knee > 0.0 && 2.0 * over.abs() <= knee {
+          (1.0 / ratio - 1.0) * (over + knee / 2.0) * (over + knee / 2.0) / (2.0 * knee)
+        } else {
+          over * (1.0 / ratio - 1.0)
+        }
+      },
+      Expand(ratio, knee) => {
+        if 2.0 * over > knee {
+          0.0
+        } else if knee > 0.0 && 2.0 * over.abs() <= knee {
+          (1.0 - ratio) * (over - knee / 2.0) * (over - knee / 2.0) / (2.0 * knee)
+        } else {
+          over * (ratio - 1.0)
+        }
+      },
+      Gate(range) => if over < 0.0 { -range.abs() } else { 0.0 }
+    };
+  }
+
+  /// Whether the gain falls as the level rises, so that the attack time
+  /// applies to falling gain.
+  fn downward(&self) -> bool {
+    return match *self {
+      Compress(..) => true,
+      _ => false
+    };
+  }
+}
+
+/// Settings for a compressor, expander or gate. Levels are in dBFS and
+/// times in seconds.
+#[deriving(Clone,Show,PartialEq)]
+pub struct Settings {
+  pub curve: Curve,
+  pub threshold: f64,
+
+  /// How long the gain takes to follow a rising level, and a falling one
+  /// after the hold time.
+  pub attack: f64,
+  pub hold: f64,
+  pub release: f64,
+
+  /// Gain in dB added after processing.
+  pub makeup: f64,
+
+  /// Whether all channels share the gain derived from the loudest one, so
+  /// that the stereo image does not shift.
+  pub linked: bool
+}
+
+impl Settings {
+  pub fn compressor(threshold: f64, ratio: f64, knee: f64, attack: f64, release: f64, makeup: f64) -> Settings {
+    if ratio < 1.0 {
+      panic!("dynamics::Settings: Ratio must be at least 1 (ARGUMENT)");
+    }
+
+    return Settings { curve: Compress(ratio, knee), threshold: threshold, attack: attack, hold: 0.0, release: release, makeup: makeup, linked: true };
+  }
+
+  pub fn expander(threshold: f64, ratio: f64, knee: f64, attack: f64, release: f64) -> Settings {
+    if ratio < 1.0 {
+      panic!("dynamics::Settings: Ratio must be at least 1 (ARGUMENT)");
+    }
+
+    return Settings { curve: Expand(ratio, knee), threshold: threshold, attack: attack, hold: 0.0, release: release, makeup: 0.0, linked: true };
+  }
+
+  pub fn gate(threshold: f64, range: f64, attack: f64, hold: f64, release: f64) -> Settings {
+    return Settings { curve: Gate(range), threshold: threshold, attack: attack, hold: hold, release: release, makeup: 0.0, linked: true };
+  }
+
+  /// Returns the settings with each channel detected and processed alone.
+  pub fn unlinked(mut self) -> Settings {
+    self.linked = false;
+
+    return self;
+  }
+}
+
+/// The smoothing coefficient of a one pole filter with a time constant of
+/// `time` seconds.
+fn coefficient(time: f64, sample_rate: f64) -> f64 {
+  if time <= 0.0 {
+    return 0.0;
+  }
+
+  return (-1.0 / (time * sample_rate)).exp();
+}
+
+fn db(x: f64) -> f64 {
+  return 20.0 * x.abs().max(1e-10).log10();
+}
+
+/// Audio read from a sidechain source, to detect levels from instead of the
+/// audio being processed.
+struct Sidechain {
+  source: channel::Source<::Audio>,
+  channels: uint,
+  samples: Vec<f64>,
+  decoded: Vec<f64>,
+  last: bool
+}
+
+impl Sidechain {
+  fn new(source: channel::Source<::Audio>) -> Sidechain {
+    return Sidechain { source: source, channels: 0, samples: Vec::new(), decoded: Vec::new(), last: false };
+  }
+
+  /// Moves `frames` frames into `output`, reading more packets as needed
+  /// and padding with silence after the end of the sidechain.
+  fn take(&mut self, frames: uint, output: &mut Vec<f64>) -> uint {
+    let Sidechain { ref mut source, ref mut channels, ref mut samples, ref mut decoded, ref mut last } = *self;
+
+    while !*last && (*channels == 0 || samples.len() < frames * *channels) {
+      source.read(|audio| {
+        *last = audio.last;
+
+        if !audio.data.is_empty() {
+          if *channels != 0 && audio.channels != *channels {
+            panic!("dynamics::Sidechain: Channel count changed (INPUT)");
+          }
+
+          *channels = audio.channels;
+
+          sample::decode(audio, decoded);
+          samples.push_all(decoded.as_slice());
+        }
+      });
+    }
+
+    let channels = std::cmp::max(*channels, 1);
+    let available = std::cmp::min(samples.len(), frames * channels);
+
+    output.truncate(0);
+    output.push_all(samples.slice_to(available));
+    output.grow(frames * channels - available, 0.0);
+
+    let rest = samples.slice_from(available).to_vec();
+    *samples = rest;
+
+    return channels;
+  }
+
+  /// Reads the rest of the sidechain, so that its writer is not left
+  /// blocked once the input has ended.
+  fn drain(&mut self) {
+    while !self.last {
+      let last = &mut self.last;
+
+      self.source.read(|audio| {
+        *last = audio.last;
+      });
+    }
+
+    self.samples.truncate(0);
+  }
+}
+
+/// The level of channel `c` of frame `i` of `detect`, or of its loudest
+/// channel if `linked`.
+fn level(detect: &[f64], channels: uint, i: uint, c: uint, linked: bool) -> f64 {
+  let frame = detect.slice(i * channels, (i + 1) * channels);
+
+  if linked {
+    return frame.iter().fold(0.0, |a: f64, &x| a.max(x.abs()));
+  }
+
+  return frame[c % channels].abs();
+}
+
+/// A feed-forward compressor, expander or gate.
+///
+/// The gain is computed in dB from the peak level of each sample, and then
+/// smoothed. Levels are detected from the input, or from a sidechain whose
+/// channels pair up with those of the input when unlinked.
+pub struct Dynamics {
+  source: channel::Source<::Audio>,
+  sink: channel::Sink<::Audio>,
+  sidechain: Option<Sidechain>,
+  settings: Settings
+}
+
+impl Dynamics {
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, settings: Settings) -> Dynamics {
+    return Dynamics { source: source, sink: sink, sidechain: None, settings: settings };
+  }
+
+  /// Detects levels from `sidechain`, which must have the sample rate of
+  /// `source`.
+  pub fn with_sidechain(source: channel::Source<::Audio>, sidechain: channel::Source<::Audio>, sink: channel::Sink<::Audio>, settings: Settings) -> Dynamics {
+    return Dynamics { source: source, sink: sink, sidechain: Some(Sidechain::new(sidechain)), settings: settings };
+  }
+
+  pub fn run(&mut self) {
+    let settings = &self.settings;
+    let sidechain = &mut self.sidechain;
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    let mut samples = Vec::new();
+    let mut detect = Vec::new();
+
+    // Per channel (or one, if linked) gain in dB and hold time left.
+    let mut gains: Vec<f64> = Vec::new();
+    let mut holds: Vec<uint> = Vec::new();
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        samples.truncate(0);
+
+        if !audio.data.is_empty() {
+          let channels = audio.channels;
+          let states = if settings.linked { 1 } else { channels };
+
+          if gains.len() != states {
+            gains = Vec::from_elem(states, 0.0);
+            holds = Vec::from_elem(states, 0);
+          }
+
+          sample::decode(audio, &mut samples);
+
+          let frames = samples.len() / channels;
+          let detect_channels = match *sidechain {
+            Some(ref mut sidechain) => sidechain.take(frames, &mut detect),
+            None => {
+              detect.truncate(0);
+              detect.push_all(samples.as_slice());
+              channels
+            }
+          };
+
+          let attack = coefficient(settings.attack, audio.sample_rate);
+          let release = coefficient(settings.release, audio.sample_rate);
+          let hold = (settings.hold * audio.sample_rate) as uint;
+          let makeup = settings.makeup;
+          let downward = settings.curve.downward();
+
+          for i in range(0, frames) {
+            for s in range(0, states) {
+              let over = db(level(detect.as_slice(), detect_channels, i, s, settings.linked)) - settings.threshold;
+              let target = settings.curve.gain(over);
+              let current = gains[s];
+              let attacking = if downward { target < current } else { target > current };
+
+              // The hold restarts for as long as the level keeps the gain
+              // where it is, not only while the gain is still moving.
+              let holding = if downward { target <= current } else { over >= 0.0 };
+
+              if holding {
+                *holds.get_mut(s) = hold;
+              }
+
+              let gain = if attacking {
+                target + attack * (current - target)
+              } else if holds[s] > 0 {
+                *holds.get_mut(s) -= 1;
+                current
+              } else {
+                target + release * (current - target)
+              };
+
+              *gains.get_mut(s) = gain;
+
+              let factor = (10.0f64).powf((gain + makeup) / 20.0);
+
+              if settings.linked {
+                for c in range(0, channels) {
+                  *samples.get_mut(i * channels + c) *= factor;
+                }
+              } else {
+                *samples.get_mut(i * channels + s) *= factor;
+              }
+            }
+          }
+        }
+
+        sink.write(|out| {
+          out.channels = audio.channels;
+          out.layout = audio.layout.clone();
+          out.sample_rate = audio.sample_rate;
+          out.sample_type = audio.sample_type;
+          out.endian = audio.endian;
+          out.last = last;
+
+          if !samples.is_empty() {
+            sample::encode(samples.as_slice(), out);
+          }
+        });
+      });
+    }
+
+    match *sidechain {
+      Some(ref mut sidechain) => sidechain.drain(),
+      None => ()
+    }
+  }
+}
+
+/// A look-ahead brickwall limiter, which keeps all channels at or below a
+/// ceiling by delaying the audio and lowering the gain before each peak.
+///
+/// In true peak mode the peaks between samples, found by oversampling 4
+/// times, are limited too. The output is the same length as the input.
+pub struct Limiter {
+  source: channel::Source<::Audio>,
+  sink: channel::Sink<::Audio>,
+  sidechain: Option<Sidechain>,
+  ceiling: f64,
+  lookahead: f64,
+  release: f64,
+  true_peak: bool
+}
+
+impl Limiter {
+  /// Limits to `ceiling` dBFS, looking `lookahead` seconds ahead and
+  /// releasing over `release` seconds.
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, ceiling: f64, lookahead: f64, release: f64, true_peak: bool) -> Limiter {
+    return Limiter { source: source, sink: sink, sidechain: None, ceiling: ceiling, lookahead: lookahead, release: release, true_peak: true_peak };
+  }
+
+  /// Detects peaks from `sidechain`, which must have the sample rate of
+  /// `source`.
+  pub fn with_sidechain(source: channel::Source<::Audio>, sidechain: channel::Source<::Audio>, sink: channel::Sink<::Audio>, ceiling: f64, lookahead: f64, release: f64, true_peak: bool) -> Limiter {
+    let mut limiter = Limiter::new(source, sink, ceiling, lookahead, release, true_peak);
+
+    limiter.sidechain = Some(Sidechain::new(sidechain));
+
+    return limiter;
+  }
+
+  pub fn run(&mut self) {
+    let ceiling = (10.0f64).powf(self.ceiling / 20.0);
+    let (lookahead, release, true_peak) = (self.lookahead, self.release, self.true_peak);
+    let sidechain = &mut self.sidechain;
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    let mut state: Option<State> = None;
+    let mut samples = Vec::new();
+    let mut detect = Vec::new();
+    let mut output = Vec::new();
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        output.truncate(0);
+
+        if !audio.data.is_empty() {
+          if state.is_none() {
+            state = Some(State::new(audio.channels, audio.sample_rate, ceiling, lookahead, release, true_peak));
+          }
+
+          sample::decode(audio, &mut samples);
+
+          let frames = samples.len() / audio.channels;
+          let state = state.as_mut().unwrap();
+
+          match *sidechain {
+            Some(ref mut sidechain) => {
+              let channels = sidechain.take(frames, &mut detect);
+              state.process(samples.as_slice(), detect.as_slice(), channels, &mut output);
+            },
+            None => state.process(samples.as_slice(), samples.as_slice(), audio.channels, &mut output)
+          }
+        }
+
+        if last {
+          match state {
+            Some(ref mut state) => state.flush(&mut output),
+            None => ()
+          }
+        }
+
+        sink.write(|out| {
+          out.channels = audio.channels;
+          out.layout = audio.layout.clone();
+          out.sample_rate = audio.sample_rate;
+          out.sample_type = audio.sample_type;
+          out.endian = audio.endian;
+          out.last = last;
+
+          if !output.is_empty() {
+            sample::encode(output.as_slice(), out);
+          }
+        });
+      });
+    }
+
+    match *sidechain {
+      Some(ref mut sidechain) => sidechain.drain(),
+      None => ()
+    }
+  }
+}
+
+/// The state of a `Limiter`.
+struct State {
+  channels: uint,
+  ceiling: f64,
+  release: f64,
+
+  /// Frames of look-ahead, and of total delay.
+  lookahead: uint,
+  delay: uint,
+
+  /// Oversamplers for true peak detection, one per detected channel.
+  peaks: Option<Vec<loudness::TruePeak>>,
+
+  /// The delayed audio, the required gains with the minimum of the last
+  /// `lookahead + 1` at the front, and the sum of the last `lookahead + 1`
+  /// minimums.
+  audio: RingBuf<f64>,
+  minimums: RingBuf<(uint, f64)>,
+  history: RingBuf<f64>,
+  sum: f64,
+
+  position: uint,
+  gain: f64
+}
+
+impl State {
+  fn new(channels: uint, sample_rate: f64, ceiling: f64, lookahead: f64, release: f64, true_peak: bool) -> State {
+    let frames = std::cmp::max((lookahead * sample_rate) as uint, 1);
+    let peaks = if true_peak { Some(Vec::new()) } else { None };
+    let delay = frames + if true_peak { loudness::TruePeak::new().delay() } else { 0 };
+
+    let mut history = RingBuf::new();
+
+    for _ in range(0, frames + 1) {
+      history.push_back(1.0);
+    }
+
+    return State {
+      channels: channels,
+      ceiling: ceiling,
+      release: coefficient(release, sample_rate),
+      lookahead: frames,
+      delay: delay,
+      peaks: peaks,
+      audio: RingBuf::new(),
+      minimums: RingBuf::new(),
+      history: history,
+      sum: (frames + 1) as f64,
+      position: 0,
+      gain: 1.0
+    };
+  }
+
+  /// Limits interleaved `samples`, detecting peaks from `detect`, and adds
+  /// the delayed output to `output`.
+  fn process(&mut self, samples: &[f64], detect: &[f64], detect_channels: uint, output: &mut Vec<f64>) {
+    for (frame, detected) in samples.chunks(self.channels).zip(detect.chunks(detect_channels)) {
+      let peak = match self.peaks {
+        Some(ref mut peaks) => {
+          if peaks.len() != detected.len() {
+            *peaks = Vec::from_fn(detected.len(), |_| loudness::TruePeak::new());
+          }
+
+          peaks.iter_mut().zip(detected.iter()).fold(0.0, |a: f64, (p, &x)| a.max(p.process(x)))
+        },
+        None => detected.iter().fold(0.0, |a: f64, &x| a.max(x.abs()))
+      };
+
+      self.step(frame, peak, output);
+    }
+  }
+
+  fn step(&mut self, frame: &[f64], peak: f64, output: &mut Vec<f64>) {
+    let required = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+
+    // The minimum of the required gains over the look-ahead window.
+    while self.minimums.back().map_or(false, |&(_, g)| g >= required) {
+      self.minimums.pop_back();
+    }
+
+    self.minimums.push_back((self.position, required));
+
+    while self.minimums.front().map_or(false, |&(p, _)| p + self.lookahead < self.position) {
+      self.minimums.pop_front();
+    }
+
+    let (_, minimum) = *self.minimums.front().unwrap();
+
+    // Averaging the minimums over the window ramps the gain down over the
+    // look-ahead, reaching the required gain when the peak is output.
+    self.sum += minimum - self.history.pop_front().unwrap();
+    self.history.push_back(minimum);
+
+    let smoothed = self.sum / (self.lookahead + 1) as f64;
+
+    self.gain = if smoothed < self.gain {
+      smoothed
+    } else {
+      smoothed + self.release * (self.gain - smoothed)
+    };
+
+    self.position += 1;
+
+    for &x in frame.iter() {
+      self.audio.push_back(x);
+    }
+
+    if self.audio.len() > self.delay * self.channels {
+      for _ in range(0, self.channels) {
+        let x = self.audio.pop_front().unwrap();
+
+        output.push((x * self.gain).max(-self.ceiling).min(self.ceiling));
+      }
+    }
+  }
+
+  /// Outputs the audio still in the delay line.
+  fn flush(&mut self, output: &mut Vec<f64>) {
+    let channels = self.channels;
+    let silence = Vec::from_elem(channels, 0.0);
+    let remaining = std::cmp::min(self.delay, self.position);
+
+    for _ in range(0, remaining) {
+      self.process(silence.as_slice(), silence.as_slice(), channels, output);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::f64::consts::PI;
+
+  use channel;
+  use endian;
+  use sample::testing;
+  use sample_type;
+
+  #[test]
+  fn test_curve() {
+    let compress = super::Compress(4.0, 6.0);
+
+    assert_eq!(compress.gain(-10.0), 0.0);
+    assert_eq!(compress.gain(20.0), -15.0);
+    assert!((compress.gain(0.0) + 0.5625).abs() < 1e-12);
+
+    let expand = super::Expand(2.0, 0.0);
+
+    assert_eq!(expand.gain(-10.0), -10.0);
+    assert_eq!(expand.gain(10.0), 0.0);
+    assert_eq!(super::Gate(80.0).gain(-1.0), -80.0);
+  }
+
+  fn run(settings: Option<super::Settings>, input: Vec<f64>) -> Vec<f64> {
+    let source = testing::source(input, 500, 2, 48000.0, sample_type::Float(32), endian::Big);
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() {
+      match settings {
+        Some(settings) => super::Dynamics::new(source, out_sink, settings).run(),
+        None => super::Limiter::new(source, out_sink, -6.0206, 0.001, 0.05, true).run()
+      }
+    });
+
+    return testing::collect(&mut out_source);
+  }
+
+  fn sine(amplitude: f64, frames: uint) -> Vec<f64> {
+    return Vec::from_fn(frames * 2, |i| amplitude * (2.0 * PI * 1000.0 * (i / 2) as f64 / 48000.0).sin());
+  }
+
+  fn peak(samples: &[f64]) -> f64 {
+    return samples.iter().fold(0.0, |a: f64, &x| a.max(x.abs()));
+  }
+
+  #[test]
+  fn test_compressor() {
+    // 0 dBFS into a hard-knee 2:1 compressor at -20 dBFS comes out at -10.
+    let settings = super::Settings::compressor(-20.0, 2.0, 0.0, 0.001, 0.1, 0.0);
+    let output = run(Some(settings), sine(1.0, 9600));
+
+    assert!((peak(output.slice_from(9600)) - 0.31623).abs() < 0.01);
+  }
+
+  #[test]
+  fn test_gate() {
+    let settings = super::Settings::gate(-40.0, 60.0, 0.001, 0.01, 0.01);
+    let output = run(Some(settings), sine(0.001, 9600));
+
+    assert!(peak(output.slice_from(9600)) < 0.001 * 0.0011);
+  }
+
+  #[test]
+  fn test_gate_hold() {
+    // With no attack the gain settles at once, and must still be held
+    // after a long tone drops below the threshold.
+    let settings = super::Settings::gate(-40.0, 60.0, 0.0, 0.1, 0.01);
+    let mut input = sine(0.5, 48000);
+
+    input.push_all(sine(0.001, 24000).as_slice());
+
+    let output = run(Some(settings), input);
+
+    assert!(peak(output.slice(96000, 100000)) > 0.0009);
+    assert!(peak(output.slice_from(124800)) < 0.001 * 0.01);
+  }
+
+  #[test]
+  fn test_limiter() {
+    // A step from -20 dBFS to 0 dBFS is held at the -6 dB ceiling without
+    // overshoot, and the output keeps the length of the input.
+    let mut input = sine(0.1, 4800);
+
+    input.push_all(sine(1.0, 4800).as_slice());
+
+    let output = run(None, input.clone());
+
+    assert_eq!(output.len(), input.len());
+    assert!(peak(output.as_slice()) <= 0.5 + 1e-6);
+    assert!(peak(output.slice_from(12000)) > 0.45);
+    assert!((peak(output.slice(2000, 8000)) - 0.1).abs() < 0.001);
+  }
+}
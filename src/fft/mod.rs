@@ -0,0 +1,716 @@
+use std;
+use std::f64::consts::PI;
+
+use channel;
+use sample;
+
+#[deriving(Clone,Show,PartialEq)]
+pub struct Complex {
+  pub re: f64,
+  pub im: f64
+}
+
+impl Complex {
+  pub fn new(re: f64, im: f64) -> Complex {
+    return Complex { re: re, im: im };
+  }
+
+  /// The complex number of magnitude 1 at `angle` radians.
+  pub fn polar(angle: f64) -> Complex {
+    return Complex { re: angle.cos(), im: angle.sin() };
+  }
+
+  pub fn add(&self, other: &Complex) -> Complex {
+    return Complex { re: self.re + other.re, im: self.im + other.im };
+  }
+
+  pub fn mul(&self, other: &Complex) -> Complex {
+    return Complex { re: self.re * other.re - self.im * other.im, im: self.re * other.im + self.im * other.re };
+  }
+
+  pub fn scale(&self, factor: f64) -> Complex {
+    return Complex { re: self.re * factor, im: self.im * factor };
+  }
+
+  pub fn conj(&self) -> Complex {
+    return Complex { re: self.re, im: -self.im };
+  }
+
+  pub fn norm(&self) -> f64 {
+    return (self.re * self.re + self.im * self.im).sqrt();
+  }
+
+  pub fn arg(&self) -> f64 {
+    return self.im.atan2(self.re);
+  }
+}
+
+/// The largest prime factor done with a direct butterfly; sizes with larger
+/// prime factors use Bluestein's algorithm.
+static MAX_RADIX: uint = 13;
+
+/// Splits `size` into radices, fours first, and returns whether every
+/// factor is at most `MAX_RADIX`.
+fn factor(size: uint) -> (Vec<uint>, bool) {
+  let mut factors = Vec::new();
+  let mut n = size;
+
+  while n % 4 == 0 {
+    factors.push(4);
+    n /= 4;
+  }
+
+  let mut p = 2;
+
+  while n > 1 {
+    while n % p == 0 {
+      factors.push(p);
+      n /= p;
+    }
+
+    p += 1;
+  }
+
+  let small = factors.iter().all(|&f| f <= MAX_RADIX);
+
+  return (factors, small);
+}
+
+/// Bluestein's algorithm, which computes a DFT of any size as a convolution
+/// done with a power of two FFT.
+struct Bluestein {
+  chirp: Vec<Complex>,
+  filter: Vec<Complex>,
+  fft: Box<Fft>
+}
+
+impl Bluestein {
+  fn new(size: uint) -> Bluestein {
+    let mut length = 1;
+
+    while length < 2 * size - 1 {
+      length *= 2;
+    }
+
+    // k² is taken modulo 2n to keep the angles accurate for large k.
+    let chirp: Vec<Complex> = Vec::from_fn(size, |k| Complex::polar(-PI * ((k * k) % (2 * size)) as f64 / size as f64));
+
+    let mut filter = Vec::from_elem(length, Complex::new(0.0, 0.0));
+
+    for k in range(0, size) {
+      *filter.get_mut(k) = chirp[k].conj();
+
+      if k > 0 {
+        *filter.get_mut(length - k) = chirp[k].conj();
+      }
+    }
+
+    let fft = box Fft::new(length);
+
+    fft.forward(filter.as_mut_slice());
+
+    return Bluestein { chirp: chirp, filter: filter, fft: fft };
+  }
+
+  fn forward(&self, data: &mut [Complex]) {
+    let length = self.filter.len();
+    let mut buffer = Vec::from_elem(length, Complex::new(0.0, 0.0));
+
+    for (k, x) in data.iter().enumerate() {
+      *buffer.get_mut(k) = x.mul(&self.chirp[k]);
+    }
+
+    self.fft.forward(buffer.as_mut_slice());
+
+    for (x, h) in buffer.iter_mut().zip(self.filter.iter()) {
+      *x = x.mul(h);
+    }
+
+    self.fft.inverse(buffer.as_mut_slice());
+
+    for (k, x) in data.iter_mut().enumerate() {
+      *x = buffer[k].mul(&self.chirp[k]);
+    }
+  }
+}
+
+/// A complex FFT of any size: mixed radix for sizes whose prime factors are
+/// small, and Bluestein's algorithm otherwise.
+pub struct Fft {
+  size: uint,
+  factors: Vec<uint>,
+
+  /// e^(-2πik/n) for k below the size.
+  twiddles: Vec<Complex>,
+  bluestein: Option<Bluestein>
+}
+
+impl Fft {
+  pub fn new(size: uint) -> Fft {
+    if size == 0 {
+      panic!("fft::Fft: Size must be positive (ARGUMENT)");
+    }
+
+    let (factors, small) = factor(size);
+    let twiddles = Vec::from_fn(size, |k| Complex::polar(-2.0 * PI * k as f64 / size as f64));
+    let bluestein = if small { None } else { Some(Bluestein::new(size)) };
+
+    return Fft { size: size, factors: factors, twiddles: twiddles, bluestein: bluestein };
+  }
+
+  pub fn size(&self) -> uint {
+    return self.size;
+  }
+
+  /// Transforms `data` in place.
+  pub fn forward(&self, data: &mut [Complex]) {
+    if data.len() != self.size {
+      panic!("fft::Fft: Expected {} values, got {} (ARGUMENT)", self.size, data.len());
+    }
+
+    match self.bluestein {
+      Some(ref bluestein) => bluestein.forward(data),
+      None => {
+        if self.size > 1 {
+          let input = data.to_vec();
+
+          self.work(input.as_slice(), 0, 1, data, self.factors.as_slice());
+        }
+      }
+    }
+  }
+
+  /// Inverse transforms `data` in place, scaled by 1/n so that it undoes
+  /// `forward`.
+  pub fn inverse(&self, data: &mut [Complex]) {
+    for x in data.iter_mut() {
+      *x = x.conj();
+    }
+
+    self.forward(data);
+
+    let scale = 1.0 / self.size as f64;
+
+    for x in data.iter_mut() {
+      *x = x.conj().scale(scale);
+    }
+  }
+
+  /// Decimation in time: transforms the values of `input` from `start` at
+  /// `stride` into `output`, by transforming each of the first factor's
+  /// interleaved subsequences and combining them with a butterfly.
+  fn work(&self, input: &[Complex], start: uint, stride: uint, output: &mut [Complex], factors: &[uint]) {
+    let p = factors[0];
+    let m = output.len() / p;
+
+    if m == 1 {
+      for k in range(0, p) {
+        output[k] = input[start + k * stride].clone();
+      }
+    } else {
+      for q in range(0, p) {
+        self.work(input, start + q * stride, stride * p, output.slice_mut(q * m, (q + 1) * m), factors.slice_from(1));
+      }
+    }
+
+    let mut scratch = Vec::from_elem(p, Complex::new(0.0, 0.0));
+
+    for u in range(0, m) {
+      for q in range(0, p) {
+        *scratch.get_mut(q) = output[q * m + u].clone();
+      }
+
+      for q1 in range(0, p) {
+        let k = q1 * m + u;
+        let mut sum = scratch[0].clone();
+
+        for q in range(1, p) {
+          sum = sum.add(&scratch[q].mul(&self.twiddles[(q * k * stride) % self.size]));
+        }
+
+        output[k] = sum;
+      }
+    }
+  }
+}
+
+/// An FFT of real input, giving the n/2 + 1 bins up to the Nyquist
+/// frequency.
+pub struct RealFft {
+  fft: Fft,
+  buffer: Vec<Complex>
+}
+
+impl RealFft {
+  pub fn new(size: uint) -> RealFft {
+    return RealFft { fft: Fft::new(size), buffer: Vec::from_elem(size, Complex::new(0.0, 0.0)) };
+  }
+
+  pub fn size(&self) -> uint {
+    return self.fft.size;
+  }
+
+  /// Transforms `input` into `output`, which is replaced.
+  pub fn forward(&mut self, input: &[f64], output: &mut Vec<Complex>) {
+    if input.len() != self.fft.size {
+      panic!("fft::RealFft: Expected {} values, got {} (ARGUMENT)", self.fft.size, input.len());
+    }
+
+    for (x, &y) in self.buffer.iter_mut().zip(input.iter()) {
+      *x = Complex::new(y, 0.0);
+    }
+
+    self.fft.forward(self.buffer.as_mut_slice());
+
+    output.truncate(0);
+    output.push_all(self.buffer.slice_to(self.fft.size / 2 + 1));
+  }
+
+  /// Inverse transforms the n/2 + 1 bins of `input` into `output`, which is
+  /// replaced.
+  pub fn inverse(&mut self, input: &[Complex], output: &mut Vec<f64>) {
+    let size = self.fft.size;
+
+    if input.len() != size / 2 + 1 {
+      panic!("fft::RealFft: Expected {} bins, got {} (ARGUMENT)", size / 2 + 1, input.len());
+    }
+
+    for k in range(0, size) {
+      *self.buffer.get_mut(k) = if k < input.len() { input[k].clone() } else { input[size - k].conj() };
+    }
+
+    self.fft.inverse(self.buffer.as_mut_slice());
+
+    output.truncate(0);
+    output.extend(self.buffer.iter().map(|x| x.re));
+  }
+}
+
+#[deriving(Clone,Show,PartialEq)]
+pub enum Window {
+  Rectangular,
+  Hann,
+  Hamming,
+
+  /// The 4 term Blackman-Harris window, with sidelobes at -92 dB.
+  BlackmanHarris,
+
+  /// The Kaiser window with a beta.
+  Kaiser(f64)
+}
+
+impl Window {
+  /// The periodic window of `size` points, for spectral analysis.
+  pub fn coefficients(&self, size: uint) -> Vec<f64> {
+    let n = size as f64;
+
+    return Vec::from_fn(size, |i| {
+      let x = 2.0 * PI * i as f64 / n;
+
+      match *self {
+        Rectangular => 1.0,
+        Hann => 0.5 - 0.5 * x.cos(),
+        Hamming => 0.54 - 0.46 * x.cos(),
+        BlackmanHarris => 0.35875 - 0.48829 * x.cos() + 0.14128 * (2.0 * x).cos() - 0.01168 * (3.0 * x).cos(),
+        Kaiser(beta) => {
+          let t = 2.0 * i as f64 / n - 1.0;
+
+          bessel_i0(beta * (1.0 - t * t).sqrt()) / bessel_i0(beta)
+        }
+      }
+    });
+  }
+}
+
+/// The modified Bessel function of the first kind, of order 0, as used by
+/// Kaiser windows.
+pub fn bessel_i0(x: f64) -> f64 {
+  let mut sum = 1.0;
+  let mut term = 1.0;
+  let mut k = 1.0;
+
+  while term > sum * 1e-12 {
+    term *= (x / (2.0 * k)) * (x / (2.0 * k));
+    sum += term;
+    k += 1.0;
+  }
+
+  return sum;
+}
+
+/// One frame of a short-time Fourier transform: the spectrum of each
+/// channel over `size` frames from `position`.
+pub struct Spectrum {
+  pub last: bool,
+  pub channels: uint,
+  pub sample_rate: f64,
+
+  /// The FFT size, and the first frame of the window.
+  pub size: uint,
+  pub position: uint,
+
+  /// The magnitudes and phases of the size/2 + 1 bins, channel after
+  /// channel.
+  pub magnitudes: Vec<f64>,
+  pub phases: Vec<f64>
+}
+
+impl ::Initialize for Spectrum {
+  fn initialize() -> Spectrum {
+    return Spectrum { last: false, channels: 0, sample_rate: 0.0, size: 0, position: 0, magnitudes: Vec::new(), phases: Vec::new() };
+  }
+
+  fn reinitialize(&mut self) {
+    self.last = false;
+    self.channels = 0;
+    self.sample_rate = 0.0;
+    self.size = 0;
+    self.position = 0;
+    self.magnitudes.truncate(0);
+    self.phases.truncate(0);
+  }
+}
+
+impl Spectrum {
+  /// The frequency of bin `k` in Hz.
+  pub fn frequency(&self, k: uint) -> f64 {
+    return k as f64 * self.sample_rate / self.size as f64;
+  }
+}
+
+/// Turns audio into windowed spectra of `size` frames every `hop` frames.
+///
+/// The first window starts at the first frame, and windows continue while
+/// they start before the end, with the last ones padded with silence. The
+/// last spectrum is marked `last`; audio without frames gives a single
+/// empty spectrum.
+pub struct Stft {
+  source: channel::Source<::Audio>,
+  sink: channel::Sink<Spectrum>,
+  size: uint,
+  hop: uint,
+  window: Window
+}
+
+impl Stft {
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<Spectrum>, size: uint, hop: uint, window: Window) -> Stft {
+    if size == 0 || hop == 0 {
+      panic!("fft::Stft: Size and hop must be positive (ARGUMENT)");
+    }
+
+    return Stft { source: source, sink: sink, size: size, hop: hop, window: window };
+  }
+
+  pub fn run(&mut self) {
+    let (size, hop) = (self.size, self.hop);
+    let window = self.window.coefficients(size);
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    let mut fft = RealFft::new(size);
+    let mut buffers: Vec<Vec<f64>> = Vec::new();
+    let mut samples = Vec::new();
+    let mut windowed = Vec::with_capacity(size);
+    let mut bins = Vec::new();
+
+    let mut channels = 0u;
+    let mut sample_rate = 0.0;
+    let mut position = 0u;
+
+    // Frames still to drop when the hop is longer than what was buffered.
+    let mut skip = 0u;
+
+    // The last spectrum taken, with its position.
+    let mut pending: Option<(uint, Vec<f64>, Vec<f64>)> = None;
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        if !audio.data.is_empty() {
+          if channels != 0 && audio.channels != channels {
+            panic!("fft::Stft: Channel count changed (INPUT)");
+          }
+
+          channels = audio.channels;
+          sample_rate = audio.sample_rate;
+
+          if buffers.len() != channels {
+            buffers = Vec::from_fn(channels, |_| Vec::new());
+          }
+
+          sample::decode(audio, &mut samples);
+
+          for (i, &x) in samples.iter().enumerate() {
+            buffers.get_mut(i % channels).push(x);
+          }
+
+          let skipped = std::cmp::min(skip, buffers[0].len());
+
+          for buffer in buffers.iter_mut() {
+            let rest = buffer.slice_from(skipped).to_vec();
+            *buffer = rest;
+          }
+
+          skip -= skipped;
+        }
+
+        loop {
+          let available = if buffers.is_empty() { 0 } else { buffers[0].len() };
+          let ready = available >= size || (last && available > 0);
+
+          if !ready {
+            break;
+          }
+
+          let done = last && available <= hop;
+          let mut magnitudes = Vec::with_capacity(channels * (size / 2 + 1));
+          let mut phases = Vec::with_capacity(channels * (size / 2 + 1));
+
+          for buffer in buffers.iter() {
+            windowed.truncate(0);
+            windowed.extend(buffer.iter().zip(window.iter()).map(|(&x, &w)| x * w));
+            windowed.grow(size - std::cmp::min(available, size), 0.0);
+
+            fft.forward(windowed.as_slice(), &mut bins);
+
+            magnitudes.extend(bins.iter().map(|b| b.norm()));
+            phases.extend(bins.iter().map(|b| b.arg()));
+          }
+
+          // A spectrum is held back until the next one, as only then is it
+          // known not to be the last: with a hop of at least the size, the
+          // audio can run out just as a window is taken.
+          match pending.take() {
+            Some(spectrum) => write(&mut *sink, channels, sample_rate, size, spectrum, false),
+            None => ()
+          }
+
+          pending = Some((position, magnitudes, phases));
+
+          let dropped = std::cmp::min(hop, available);
+
+          for buffer in buffers.iter_mut() {
+            let rest = buffer.slice_from(dropped).to_vec();
+            *buffer = rest;
+          }
+
+          skip = hop - dropped;
+          position += hop;
+
+          if done {
+            break;
+          }
+        }
+
+        if last {
+          let spectrum = pending.take().unwrap_or((position, Vec::new(), Vec::new()));
+
+          write(&mut *sink, channels, sample_rate, size, spectrum, true);
+        }
+      });
+    }
+  }
+}
+
+fn write(sink: &mut channel::Sink<Spectrum>, channels: uint, sample_rate: f64, size: uint, spectrum: (uint, Vec<f64>, Vec<f64>), last: bool) {
+  let (position, magnitudes, phases) = spectrum;
+
+  sink.write(|out| {
+    out.channels = channels;
+    out.sample_rate = sample_rate;
+    out.size = size;
+    out.position = position;
+    out.last = last;
+    out.magnitudes.push_all(magnitudes.as_slice());
+    out.phases.push_all(phases.as_slice());
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use std::f64::consts::PI;
+
+  use channel;
+  use endian;
+  use sample;
+  use sample::testing;
+  use sample_type;
+
+  fn dft(input: &[super::Complex]) -> Vec<super::Complex> {
+    let n = input.len();
+
+    return Vec::from_fn(n, |k| {
+      input.iter().enumerate().fold(super::Complex::new(0.0, 0.0), |a, (j, x)| {
+        a.add(&x.mul(&super::Complex::polar(-2.0 * PI * ((j * k) % n) as f64 / n as f64)))
+      })
+    });
+  }
+
+  #[test]
+  fn test_factor() {
+    assert_eq!(super::factor(96), (vec![4, 4, 2, 3], true));
+    assert_eq!(super::factor(34), (vec![2, 17], false));
+  }
+
+  #[test]
+  fn test_fft() {
+    for &size in [1u, 2, 8, 12, 30, 49, 17, 97, 34].iter() {
+      let input = Vec::from_fn(size, |i| super::Complex::new((i as f64 * 0.37).sin(), (i as f64 * 0.11).cos()));
+      let expected = dft(input.as_slice());
+      let fft = super::Fft::new(size);
+      let mut data = input.clone();
+
+      fft.forward(data.as_mut_slice());
+
+      for (a, b) in data.iter().zip(expected.iter()) {
+        assert!((a.re - b.re).abs() < 1e-9 && (a.im - b.im).abs() < 1e-9, "size {}", size);
+      }
+
+      fft.inverse(data.as_mut_slice());
+
+      for (a, b) in data.iter().zip(input.iter()) {
+        assert!((a.re - b.re).abs() < 1e-9 && (a.im - b.im).abs() < 1e-9, "size {}", size);
+      }
+    }
+  }
+
+  #[test]
+  fn test_real_fft() {
+    let input = Vec::from_fn(10, |i| (i as f64 * 0.5).cos());
+    let mut fft = super::RealFft::new(10);
+    let mut bins = Vec::new();
+    let mut output = Vec::new();
+
+    fft.forward(input.as_slice(), &mut bins);
+    assert_eq!(bins.len(), 6);
+
+    fft.inverse(bins.as_slice(), &mut output);
+
+    for (a, b) in output.iter().zip(input.iter()) {
+      assert!((a - *b).abs() < 1e-12);
+    }
+  }
+
+  #[test]
+  fn test_window() {
+    let hann = super::Hann.coefficients(4);
+
+    for (a, b) in hann.iter().zip([0.0, 0.5, 1.0, 0.5].iter()) {
+      assert!((*a - *b).abs() < 1e-12);
+    }
+
+    let kaiser = super::Kaiser(8.0).coefficients(8);
+
+    assert!((kaiser[4] - 1.0).abs() < 1e-12);
+    assert!(kaiser[0] < 0.01);
+
+    let blackman_harris = super::BlackmanHarris.coefficients(8);
+
+    assert!((blackman_harris[4] - 1.0).abs() < 1e-12);
+  }
+
+  #[test]
+  fn test_stft() {
+    let (mut sink, source) = channel::create::<::Audio>(1);
+    let (out_sink, mut out_source) = channel::create::<super::Spectrum>(1);
+
+    spawn(proc() {
+      super::Stft::new(source, out_sink, 64, 32, super::Hann).run();
+    });
+
+    spawn(proc() {
+      sink.write(|audio| {
+        audio.channels = 1;
+        audio.sample_rate = 8000.0;
+        audio.sample_type = sample_type::Float(32);
+        audio.endian = endian::Big;
+        audio.last = true;
+
+        // 1 kHz is bin 8 of a 64 point FFT at 8 kHz.
+        let input = Vec::from_fn(160, |i| (2.0 * PI * 1000.0 * i as f64 / 8000.0).sin());
+
+        sample::encode(input.as_slice(), audio);
+      });
+    });
+
+    let mut positions = Vec::new();
+    let mut last = false;
+
+    while !last {
+      out_source.read(|spectrum| {
+        last = spectrum.last;
+        positions.push(spectrum.position);
+
+        assert_eq!(spectrum.magnitudes.len(), 33);
+
+        let peak = range(0u, 33).max_by(|&k| (spectrum.magnitudes[k] * 1e6) as u64).unwrap();
+
+        assert_eq!(peak, 8);
+        assert_eq!(spectrum.frequency(peak), 1000.0);
+      });
+    }
+
+    assert_eq!(positions, vec![0, 32, 64, 96, 128]);
+  }
+
+  #[test]
+  fn test_stft_long_hop() {
+    // The hop is longer than both the window and the packets.
+    let input = Vec::from_fn(15, |i| i as f64 / 16.0);
+    let source = testing::source(input, 5, 1, 8000.0, sample_type::Float(32), endian::Big);
+    let (out_sink, mut out_source) = channel::create::<super::Spectrum>(1);
+
+    spawn(proc() {
+      super::Stft::new(source, out_sink, 4, 6, super::Rectangular).run();
+    });
+
+    let mut positions = Vec::new();
+    let mut sums = Vec::new();
+    let mut last = false;
+
+    while !last {
+      out_source.read(|spectrum| {
+        last = spectrum.last;
+        positions.push(spectrum.position);
+        sums.push(spectrum.magnitudes[0] * 16.0);
+      });
+    }
+
+    // Each window holds the frames from its position.
+    assert_eq!(positions, vec![0, 6, 12]);
+
+    for (&sum, &expected) in sums.iter().zip([6.0, 30.0, 39.0].iter()) {
+      assert!((sum - expected).abs() < 1e-9);
+    }
+  }
+
+  #[test]
+  fn test_stft_even_hop() {
+    // The last packet falls wholly between the last window and the next
+    // hop, so the window before it must be marked last.
+    let input = Vec::from_fn(16, |i| i as f64 / 16.0);
+    let source = testing::source(input, 4, 1, 8000.0, sample_type::Float(32), endian::Big);
+    let (out_sink, mut out_source) = channel::create::<super::Spectrum>(1);
+
+    spawn(proc() {
+      super::Stft::new(source, out_sink, 4, 8, super::Rectangular).run();
+    });
+
+    let mut positions = Vec::new();
+    let mut last = false;
+
+    while !last {
+      out_source.read(|spectrum| {
+        last = spectrum.last;
+        positions.push(spectrum.position);
+
+        assert_eq!(spectrum.magnitudes.len(), 3);
+      });
+    }
+
+    assert_eq!(positions, vec![0, 6]);
+  }
+}
@@ -0,0 +1,347 @@
+use std;
+use std::f64::consts::PI;
+
+use channel;
+use sample;
+
+/// Filter responses, after Robert Bristow-Johnson's Audio EQ Cookbook.
+/// Gains are in dB.
+#[deriving(Clone,Show,PartialEq)]
+pub enum Kind {
+  LowPass,
+  HighPass,
+
+  /// Band pass with 0 dB gain at the center frequency.
+  BandPass,
+  Notch,
+  Peaking(f64),
+  LowShelf(f64),
+  HighShelf(f64),
+  AllPass
+}
+
+/// Normalized coefficients of a second order section, so that a0 is 1.
+#[deriving(Clone,Show,PartialEq)]
+pub struct Coefficients {
+  pub b0: f64, pub b1: f64, pub b2: f64,
+  pub a1: f64, pub a2: f64
+}
+
+impl Coefficients {
+  fn normalize(b0: f64, b1: f64, b2: f64, a0: f64, a1: f64, a2: f64) -> Coefficients {
+    return Coefficients { b0: b0 / a0, b1: b1 / a0, b2: b2 / a0, a1: a1 / a0, a2: a2 / a0 };
+  }
+
+  /// Designs a biquad of `kind` at `frequency`. For shelves, `q` is the
+  /// shelf slope's Q.
+  pub fn design(kind: Kind, frequency: f64, q: f64, sample_rate: f64) -> Coefficients {
+    if frequency <= 0.0 || frequency >= sample_rate / 2.0 || q <= 0.0 {
+      panic!("filter::Coefficients: Frequency must be below Nyquist and Q positive (ARGUMENT)");
+    }
+
+    let w0 = 2.0 * PI * frequency / sample_rate;
+    let cos = w0.cos();
+    let alpha = w0.sin() / (2.0 * q);
+
+    return match kind {
+      LowPass => Coefficients::normalize((1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+      HighPass => Coefficients::normalize((1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+      BandPass => Coefficients::normalize(alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+      Notch => Coefficients::normalize(1.0, -2.0 * cos, 1.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+      AllPass => Coefficients::normalize(1.0 - alpha, -2.0 * cos, 1.0 + alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+      Peaking(gain) => {
+        let a = (10.0f64).powf(gain / 40.0);
+
+        Coefficients::normalize(1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a, 1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a)
+      },
+      LowShelf(gain) => {
+        let a = (10.0f64).powf(gain / 40.0);
+        let s = 2.0 * a.sqrt() * alpha;
+
+        Coefficients::normalize(
+          a * ((a + 1.0) - (a - 1.0) * cos + s),
+          2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+          a * ((a + 1.0) - (a - 1.0) * cos - s),
+          (a + 1.0) + (a - 1.0) * cos + s,
+          -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+          (a + 1.0) + (a - 1.0) * cos - s)
+      },
+      HighShelf(gain) => {
+        let a = (10.0f64).powf(gain / 40.0);
+        let s = 2.0 * a.sqrt() * alpha;
+
+        Coefficients::normalize(
+          a * ((a + 1.0) + (a - 1.0) * cos + s),
+          -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+          a * ((a + 1.0) + (a - 1.0) * cos - s),
+          (a + 1.0) - (a - 1.0) * cos + s,
+          2.0 * ((a - 1.0) - (a + 1.0) * cos),
+          (a + 1.0) - (a - 1.0) * cos - s)
+      }
+    };
+  }
+
+  /// A first order low or high pass section, for odd order Butterworth
+  /// filters.
+  fn first_order(kind: Kind, frequency: f64, sample_rate: f64) -> Coefficients {
+    let k = (PI * frequency / sample_rate).tan();
+
+    return match kind {
+      LowPass => Coefficients::normalize(k, k, 0.0, 1.0 + k, k - 1.0, 0.0),
+      HighPass => Coefficients::normalize(1.0, -1.0, 0.0, 1.0 + k, k - 1.0, 0.0),
+      _ => panic!("filter::Coefficients: Only low and high pass have first order sections (ARGUMENT)")
+    };
+  }
+
+  /// The magnitude of the response at `frequency`.
+  pub fn response(&self, frequency: f64, sample_rate: f64) -> f64 {
+    let w = 2.0 * PI * frequency / sample_rate;
+
+    let re = self.b0 + self.b1 * w.cos() + self.b2 * (2.0 * w).cos();
+    let im = self.b1 * w.sin() + self.b2 * (2.0 * w).sin();
+    let numerator = (re * re + im * im).sqrt();
+
+    let re = 1.0 + self.a1 * w.cos() + self.a2 * (2.0 * w).cos();
+    let im = self.a1 * w.sin() + self.a2 * (2.0 * w).sin();
+    let denominator = (re * re + im * im).sqrt();
+
+    return numerator / denominator;
+  }
+}
+
+/// A Butterworth low or high pass of `order`, as second order sections
+/// and a first order section for odd orders.
+pub fn butterworth(kind: Kind, order: uint, frequency: f64, sample_rate: f64) -> Vec<Coefficients> {
+  if order == 0 || (kind != LowPass && kind != HighPass) {
+    panic!("filter::butterworth: Expected a low or high pass of positive order (ARGUMENT)");
+  }
+
+  let mut sections = Vec::with_capacity((order + 1) / 2);
+
+  for k in range(0, order / 2) {
+    let q = 1.0 / (2.0 * ((2 * k + 1) as f64 * PI / (2 * order) as f64).sin());
+
+    sections.push(Coefficients::design(kind.clone(), frequency, q, sample_rate));
+  }
+
+  if order % 2 == 1 {
+    sections.push(Coefficients::first_order(kind, frequency, sample_rate));
+  }
+
+  return sections;
+}
+
+/// A Linkwitz-Riley low or high pass of even `order`: two Butterworth
+/// filters of half the order in series. Low and high passes at the same
+/// frequency sum to a flat magnitude response, for crossovers.
+pub fn linkwitz_riley(kind: Kind, order: uint, frequency: f64, sample_rate: f64) -> Vec<Coefficients> {
+  if order == 0 || order % 2 == 1 {
+    panic!("filter::linkwitz_riley: Order must be even and positive (ARGUMENT)");
+  }
+
+  let mut sections = butterworth(kind.clone(), order / 2, frequency, sample_rate);
+
+  sections.push_all(butterworth(kind, order / 2, frequency, sample_rate).as_slice());
+
+  return sections;
+}
+
+/// A second order section in transposed direct form II.
+#[deriving(Clone)]
+pub struct Biquad {
+  coefficients: Coefficients,
+  z1: f64,
+  z2: f64
+}
+
+impl Biquad {
+  pub fn new(coefficients: Coefficients) -> Biquad {
+    return Biquad { coefficients: coefficients, z1: 0.0, z2: 0.0 };
+  }
+
+  pub fn process(&mut self, x: f64) -> f64 {
+    let c = &self.coefficients;
+    let y = c.b0 * x + self.z1;
+
+    self.z1 = c.b1 * x - c.a1 * y + self.z2;
+    self.z2 = c.b2 * x - c.a2 * y;
+
+    return y;
+  }
+
+  /// Clears the filter's memory of past samples.
+  pub fn reset(&mut self) {
+    self.z1 = 0.0;
+    self.z2 = 0.0;
+  }
+}
+
+/// One band of an `Equalizer`, designed once the sample rate is known.
+#[deriving(Clone,Show,PartialEq)]
+pub enum Band {
+  /// A biquad of a kind at a frequency, with a Q.
+  Rbj(Kind, f64, f64),
+
+  /// A Butterworth low or high pass of an order at a frequency.
+  Butterworth(Kind, uint, f64),
+
+  /// A Linkwitz-Riley low or high pass of an even order at a frequency.
+  LinkwitzRiley(Kind, uint, f64)
+}
+
+impl Band {
+  pub fn design(&self, sample_rate: f64) -> Vec<Coefficients> {
+    return match *self {
+      Rbj(ref kind, frequency, q) => vec![Coefficients::design(kind.clone(), frequency, q, sample_rate)],
+      Butterworth(ref kind, order, frequency) => butterworth(kind.clone(), order, frequency, sample_rate),
+      LinkwitzRiley(ref kind, order, frequency) => linkwitz_riley(kind.clone(), order, frequency, sample_rate)
+    };
+  }
+}
+
+/// Filters each channel through a series of bands.
+///
+/// Filter state carries over between packets, and is reset when the number
+/// of channels or the sample rate changes.
+pub struct Equalizer {
+  source: channel::Source<::Audio>,
+  sink: channel::Sink<::Audio>,
+  bands: Vec<Band>
+}
+
+impl Equalizer {
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, bands: Vec<Band>) -> Equalizer {
+    return Equalizer { source: source, sink: sink, bands: bands };
+  }
+
+  pub fn run(&mut self) {
+    let bands = &self.bands;
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    let mut format = (0u, 0.0f64);
+    let mut filters: Vec<Vec<Biquad>> = Vec::new();
+    let mut samples = Vec::new();
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        samples.truncate(0);
+
+        if !audio.data.is_empty() {
+          if format != (audio.channels, audio.sample_rate) {
+            format = (audio.channels, audio.sample_rate);
+
+            let sections: Vec<Biquad> = bands.iter().flat_map(|b| b.design(audio.sample_rate).into_iter()).map(|c| Biquad::new(c)).collect();
+
+            filters = Vec::from_elem(audio.channels, sections);
+          }
+
+          sample::decode(audio, &mut samples);
+
+          for frame in samples.as_mut_slice().chunks_mut(std::cmp::max(audio.channels, 1)) {
+            for (x, sections) in frame.iter_mut().zip(filters.iter_mut()) {
+              *x = sections.iter_mut().fold(*x, |x, s| s.process(x));
+            }
+          }
+        }
+
+        sink.write(|out| {
+          out.channels = audio.channels;
+          out.layout = audio.layout.clone();
+          out.sample_rate = audio.sample_rate;
+          out.sample_type = audio.sample_type;
+          out.endian = audio.endian;
+          out.last = last;
+
+          if !samples.is_empty() {
+            sample::encode(samples.as_slice(), out);
+          }
+        });
+      });
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::f64::consts::PI;
+
+  use channel;
+  use endian;
+  use sample::testing;
+  use sample_type;
+
+  fn db(sections: &[super::Coefficients], frequency: f64) -> f64 {
+    return sections.iter().fold(0.0, |a, c| a + 20.0 * c.response(frequency, 48000.0).log10());
+  }
+
+  #[test]
+  fn test_design() {
+    let peaking = super::Coefficients::design(super::Peaking(6.0), 1000.0, 1.0, 48000.0);
+    let shelf = super::Coefficients::design(super::LowShelf(-12.0), 200.0, 0.707, 48000.0);
+    let notch = super::Coefficients::design(super::Notch, 1000.0, 10.0, 48000.0);
+    let allpass = super::Coefficients::design(super::AllPass, 1000.0, 0.707, 48000.0);
+
+    assert!((db([peaking], 1000.0) - 6.0).abs() < 1e-9);
+    assert!(db([peaking], 20000.0).abs() < 0.1);
+    assert!((db([shelf], 20.0) + 12.0).abs() < 0.1);
+    assert!(db([shelf], 10000.0).abs() < 0.1);
+    assert!(notch.response(1000.0, 48000.0) < 1e-9);
+    assert!(db([allpass], 5000.0).abs() < 1e-9);
+  }
+
+  #[test]
+  fn test_butterworth() {
+    let lowpass = super::butterworth(super::LowPass, 5, 1000.0, 48000.0);
+
+    assert_eq!(lowpass.len(), 3);
+    assert!((db(lowpass.as_slice(), 1000.0) + 3.0103).abs() < 1e-3);
+    assert!((db(lowpass.as_slice(), 4000.0) + 60.0).abs() < 2.0);
+    assert!(db(lowpass.as_slice(), 100.0).abs() < 1e-3);
+  }
+
+  #[test]
+  fn test_linkwitz_riley() {
+    let lowpass = super::linkwitz_riley(super::LowPass, 4, 1000.0, 48000.0);
+    let highpass = super::linkwitz_riley(super::HighPass, 4, 1000.0, 48000.0);
+
+    assert!((db(lowpass.as_slice(), 1000.0) + 6.0206).abs() < 1e-3);
+    assert!((db(highpass.as_slice(), 1000.0) + 6.0206).abs() < 1e-3);
+  }
+
+  #[test]
+  #[should_fail]
+  fn test_linkwitz_riley_order() {
+    super::linkwitz_riley(super::HighPass, 3, 1000.0, 48000.0);
+  }
+
+  #[test]
+  fn test_equalizer() {
+    // Splitting the input between packets must not change the output.
+    let input = Vec::from_fn(2000, |i| (2.0 * PI * 3000.0 * (i / 2) as f64 / 48000.0).sin() * 0.5);
+    let bands = vec![super::Rbj(super::Peaking(-6.0), 3000.0, 2.0), super::Butterworth(super::HighPass, 3, 100.0)];
+    let mut outputs = Vec::new();
+
+    for &frames in [1000u, 499].iter() {
+      let source = testing::source(input.clone(), frames, 2, 48000.0, sample_type::Float(64), endian::Little);
+      let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+      let bands = bands.clone();
+
+      spawn(proc() {
+        super::Equalizer::new(source, out_sink, bands).run();
+      });
+
+      outputs.push(testing::collect(&mut out_source));
+    }
+
+    assert_eq!(outputs[0], outputs[1]);
+
+    let peak = outputs[0].slice_from(1000).iter().fold(0.0, |a: f64, &x| a.max(x.abs()));
+
+    assert!((peak - 0.25).abs() < 0.01);
+  }
+}
@@ -0,0 +1,484 @@
+use std;
+use std::comm::Sender;
+use std::f64::consts::PI;
+
+use channel;
+use fft;
+use resample;
+use sample;
+
+/// The parameters of Chromaprint's default algorithm, `TEST2`.
+static SAMPLE_RATE: f64 = 11025.0;
+static FRAME: uint = 4096;
+static HOP: uint = FRAME / 3;
+static MIN_FREQUENCY: f64 = 28.0;
+static MAX_FREQUENCY: f64 = 3520.0;
+static ALGORITHM: u8 = 1;
+
+static CHROMA_FILTER: [f64, ..5] = [0.25, 0.75, 1.0, 0.75, 0.25];
+
+/// A classifier: a filter of a kind over chroma bands `y` to `y + height`
+/// and `width` frames, and the thresholds dividing its value into four.
+struct Classifier {
+  kind: uint,
+  y: uint,
+  height: uint,
+  width: uint,
+  thresholds: [f64, ..3]
+}
+
+static CLASSIFIERS: [Classifier, ..16] = [
+  Classifier { kind: 0, y: 4, height: 3, width: 15, thresholds: [1.98215, 2.35817, 2.63523] },
+  Classifier { kind: 4, y: 4, height: 6, width: 15, thresholds: [-1.03809, -0.651211, -0.282167] },
+  Classifier { kind: 1, y: 0, height: 4, width: 16, thresholds: [-0.298702, 0.119262, 0.558497] },
+  Classifier { kind: 3, y: 8, height: 2, width: 12, thresholds: [-0.105439, 0.0153946, 0.135898] },
+  Classifier { kind: 3, y: 4, height: 4, width: 8, thresholds: [-0.142891, 0.0258736, 0.200632] },
+  Classifier { kind: 4, y: 0, height: 3, width: 5, thresholds: [-0.826319, -0.590612, -0.368214] },
+  Classifier { kind: 1, y: 2, height: 2, width: 9, thresholds: [-0.557409, -0.233035, 0.0534525] },
+  Classifier { kind: 2, y: 7, height: 3, width: 4, thresholds: [-0.0646826, 0.00620476, 0.0784847] },
+  Classifier { kind: 2, y: 6, height: 2, width: 16, thresholds: [-0.192387, -0.029699, 0.215855] },
+  Classifier { kind: 2, y: 1, height: 3, width: 2, thresholds: [-0.0397818, -0.00568076, 0.0292026] },
+  Classifier { kind: 5, y: 10, height: 1, width: 15, thresholds: [-0.53823, -0.369934, -0.190235] },
+  Classifier { kind: 3, y: 6, height: 2, width: 10, thresholds: [-0.124877, 0.0296483, 0.139239] },
+  Classifier { kind: 2, y: 1, height: 1, width: 14, thresholds: [-0.101475, 0.0225617, 0.256689] },
+  Classifier { kind: 3, y: 5, height: 6, width: 4, thresholds: [-0.0401408, 0.0218021, 0.0792021] },
+  Classifier { kind: 1, y: 9, height: 2, width: 12, thresholds: [-0.0617519, 0.00441386, 0.0853553] },
+  Classifier { kind: 3, y: 4, height: 2, width: 14, thresholds: [-0.0703281, -0.0128424, 0.0561839] }
+];
+
+/// The widest classifier, which is how many chroma frames each
+/// subfingerprint covers.
+static WIDTH: uint = 16;
+
+/// Running sums of chroma frames, so that the sum over any rectangle of
+/// frames and bands takes four lookups.
+struct Image {
+  sums: Vec<[f64, ..13]>
+}
+
+impl Image {
+  fn new() -> Image {
+    return Image { sums: vec![[0.0, ..13]] };
+  }
+
+  fn push(&mut self, row: &[f64, ..12]) {
+    let mut sums = *self.sums.last().unwrap();
+    let mut across = 0.0;
+
+    for b in range(0, 12) {
+      across += row[b];
+      sums[b + 1] += across;
+    }
+
+    self.sums.push(sums);
+  }
+
+  fn rows(&self) -> uint {
+    return self.sums.len() - 1;
+  }
+
+  /// The sum over frames `x1` to `x2` and bands `y1` to `y2`, exclusive.
+  fn area(&self, x1: uint, y1: uint, x2: uint, y2: uint) -> f64 {
+    let a = &self.sums[x1];
+    let b = &self.sums[x2];
+
+    return b[y2] - b[y1] - a[y2] + a[y1];
+  }
+}
+
+impl Classifier {
+  fn apply(&self, image: &Image, x: uint) -> f64 {
+    let (y, w, h) = (self.y, self.width, self.height);
+
+    let (a, b) = match self.kind {
+      0 => (image.area(x, y, x + w, y + h), 0.0),
+      1 => (image.area(x, y + h / 2, x + w, y + h), image.area(x, y, x + w, y + h / 2)),
+      2 => (image.area(x + w / 2, y, x + w, y + h), image.area(x, y, x + w / 2, y + h)),
+      3 => (
+        image.area(x, y + h / 2, x + w / 2, y + h) + image.area(x + w / 2, y, x + w, y + h / 2),
+        image.area(x, y, x + w / 2, y + h / 2) + image.area(x + w / 2, y + h / 2, x + w, y + h)
+      ),
+      4 => (
+        image.area(x, y + h / 3, x + w, y + 2 * h / 3),
+        image.area(x, y, x + w, y + h / 3) + image.area(x, y + 2 * h / 3, x + w, y + h)
+      ),
+      _ => (
+        image.area(x + w / 3, y, x + 2 * w / 3, y + h),
+        image.area(x, y, x + w / 3, y + h) + image.area(x + 2 * w / 3, y, x + w, y + h)
+      )
+    };
+
+    return (1.0 + a).ln() - (1.0 + b).ln();
+  }
+
+  /// The Gray-coded quarter that `value` falls in.
+  fn quantize(&self, value: f64) -> u32 {
+    let t = &self.thresholds;
+
+    return if value < t[1] {
+      if value < t[0] { 0 } else { 1 }
+    } else {
+      if value < t[2] { 3 } else { 2 }
+    };
+  }
+}
+
+/// Turns audio at 11025 Hz into filtered, normalized chroma frames.
+struct Chroma {
+  fft: fft::RealFft,
+  window: Vec<f64>,
+  notes: Vec<(uint, uint)>,
+  buffer: Vec<f64>,
+  frame: Vec<f64>,
+  bins: Vec<fft::Complex>,
+  recent: Vec<[f64, ..12]>
+}
+
+impl Chroma {
+  fn new() -> Chroma {
+    let index = |f: f64| (FRAME as f64 * f / SAMPLE_RATE).round() as uint;
+
+    // The pitch class of each bin in range.
+    let notes = range(index(MIN_FREQUENCY), index(MAX_FREQUENCY)).map(|i| {
+      let octave = (i as f64 * SAMPLE_RATE / FRAME as f64 / (440.0 / 16.0)).log2();
+
+      (i, (12.0 * (octave - octave.floor())) as uint)
+    }).collect();
+
+    // Chromaprint's Hamming window is symmetric.
+    let window = Vec::from_fn(FRAME, |i| 0.54 - 0.46 * (2.0 * PI * i as f64 / (FRAME - 1) as f64).cos());
+
+    return Chroma {
+      fft: fft::RealFft::new(FRAME),
+      window: window,
+      notes: notes,
+      buffer: Vec::new(),
+      frame: Vec::from_elem(FRAME, 0.0),
+      bins: Vec::new(),
+      recent: Vec::new()
+    };
+  }
+
+  /// Adds mono samples, adding a row to `image` for each complete frame.
+  fn add(&mut self, samples: &[f64], image: &mut Image) {
+    self.buffer.push_all(samples);
+
+    let mut start = 0;
+
+    while start + FRAME <= self.buffer.len() {
+      for i in range(0, FRAME) {
+        // Chromaprint works on 16 bit samples, and its silence threshold
+        // depends on their scale.
+        *self.frame.get_mut(i) = self.buffer[start + i] * 32768.0 * self.window[i];
+      }
+
+      self.fft.forward(self.frame.as_slice(), &mut self.bins);
+
+      let mut features = [0.0, ..12];
+
+      for &(i, note) in self.notes.iter() {
+        let bin = &self.bins[i];
+
+        features[note] += bin.re * bin.re + bin.im * bin.im;
+      }
+
+      self.recent.push(features);
+
+      if self.recent.len() == CHROMA_FILTER.len() {
+        let mut row = [0.0, ..12];
+
+        for (features, &k) in self.recent.iter().zip(CHROMA_FILTER.iter()) {
+          for b in range(0, 12) {
+            row[b] += features[b] * k;
+          }
+        }
+
+        let norm = row.iter().fold(0.0, |a, &x| a + x * x).sqrt();
+
+        for x in row.iter_mut() {
+          *x = if norm < 0.01 { 0.0 } else { *x / norm };
+        }
+
+        image.push(&row);
+        self.recent.remove(0);
+      }
+
+      start += HOP;
+    }
+
+    let rest = self.buffer.slice_from(start).to_vec();
+    self.buffer = rest;
+  }
+}
+
+/// A Chromaprint fingerprint, of the `TEST2` algorithm that AcoustID uses.
+#[deriving(Clone,Show,PartialEq)]
+pub struct Fingerprint {
+  /// Subfingerprints, one per 1365 samples at 11025 Hz.
+  pub raw: Vec<u32>,
+
+  /// The duration of the audio, in seconds.
+  pub duration: f64
+}
+
+/// Writes values of a few bits, least significant first.
+struct Bits {
+  data: Vec<u8>,
+  value: u32,
+  count: uint
+}
+
+impl Bits {
+  fn write(&mut self, x: uint, bits: uint) {
+    self.value |= (x as u32) << self.count;
+    self.count += bits;
+
+    while self.count >= 8 {
+      self.data.push(self.value as u8);
+      self.value >>= 8;
+      self.count -= 8;
+    }
+  }
+
+  fn flush(&mut self) {
+    if self.count > 0 {
+      self.data.push(self.value as u8);
+    }
+
+    self.value = 0;
+    self.count = 0;
+  }
+}
+
+static BASE64: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+impl Fingerprint {
+  /// The fingerprint compressed as by `chromaprint_encode_fingerprint`:
+  /// the distances between the set bits of each change from the last
+  /// subfingerprint, in 3 bits each, with larger distances in 5 more bits.
+  pub fn compressed(&self) -> Vec<u8> {
+    let count = self.raw.len();
+    let mut distances = Vec::new();
+    let mut previous = 0;
+
+    for &x in self.raw.iter() {
+      let mut changed = x ^ previous;
+      let mut bit = 1;
+      let mut last = 0;
+
+      while changed != 0 {
+        if changed & 1 != 0 {
+          distances.push(bit - last);
+          last = bit;
+        }
+
+        changed >>= 1;
+        bit += 1;
+      }
+
+      distances.push(0);
+      previous = x;
+    }
+
+    let mut bits = Bits { data: vec![ALGORITHM, (count >> 16) as u8, (count >> 8) as u8, count as u8], value: 0, count: 0 };
+
+    for &d in distances.iter() {
+      bits.write(std::cmp::min(d, 7), 3);
+    }
+
+    bits.flush();
+
+    for &d in distances.iter().filter(|&&d| d >= 7) {
+      bits.write(d - 7, 5);
+    }
+
+    bits.flush();
+
+    return bits.data;
+  }
+
+  /// The compressed fingerprint in URL-safe base64 without padding, as
+  /// AcoustID and `fpcalc` show it.
+  pub fn encoded(&self) -> String {
+    let data = self.compressed();
+    let mut text = String::with_capacity((data.len() * 4 + 2) / 3);
+
+    for chunk in data.as_slice().chunks(3) {
+      let n = chunk.iter().enumerate().fold(0u32, |a, (i, &b)| a | b as u32 << (16 - 8 * i));
+
+      for i in range(0, chunk.len() + 1) {
+        text.push(BASE64[(n >> (18 - 6 * i) & 0x3F) as uint] as char);
+      }
+    }
+
+    return text;
+  }
+}
+
+/// Fingerprints audio passing through with Chromaprint's algorithm, and
+/// sends the fingerprint to `report` at the end of the stream.
+///
+/// Audio is mixed to mono and resampled to 11025 Hz first. The resampler
+/// is not Chromaprint's, so fingerprints can differ from `fpcalc`'s in a
+/// few bits, which AcoustID matching tolerates.
+pub struct Fingerprinter {
+  source: channel::Source<::Audio>,
+  sink: channel::Sink<::Audio>,
+  report: Sender<Fingerprint>
+}
+
+impl Fingerprinter {
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, report: Sender<Fingerprint>) -> Fingerprinter {
+    return Fingerprinter { source: source, sink: sink, report: report };
+  }
+
+  pub fn run(&mut self) {
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    let mut chroma = Chroma::new();
+    let mut image = Image::new();
+    let mut varispeed: Option<Option<resample::Varispeed>> = None;
+
+    let mut samples = Vec::new();
+    let mut mono = Vec::new();
+    let mut resampled = Vec::new();
+    let mut frames = 0;
+    let mut sample_rate = 0.0;
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        mono.truncate(0);
+
+        if !audio.data.is_empty() {
+          if varispeed.is_none() {
+            varispeed = Some(if audio.sample_rate == SAMPLE_RATE {
+              None
+            } else {
+              Some(resample::Varispeed::new(1, SAMPLE_RATE / audio.sample_rate, resample::Medium))
+            });
+          }
+
+          sample::decode(audio, &mut samples);
+
+          for frame in samples.as_slice().chunks(audio.channels) {
+            mono.push(frame.iter().fold(0.0, |a, &x| a + x) / audio.channels as f64);
+          }
+
+          frames += mono.len();
+          sample_rate = audio.sample_rate;
+        }
+
+        match varispeed {
+          Some(Some(ref mut varispeed)) => {
+            varispeed.process(mono.as_slice(), last, &mut resampled);
+            chroma.add(resampled.as_slice(), &mut image);
+          },
+          _ => chroma.add(mono.as_slice(), &mut image)
+        }
+
+        sink.write(|out| {
+          out.channels = audio.channels;
+          out.layout = audio.layout.clone();
+          out.sample_rate = audio.sample_rate;
+          out.sample_type = audio.sample_type;
+          out.endian = audio.endian;
+          out.last = last;
+          out.data.push_all(audio.data.as_slice());
+        });
+      });
+    }
+
+    let count = if image.rows() < WIDTH { 0 } else { image.rows() - WIDTH + 1 };
+
+    let raw = range(0, count).map(|x| {
+      CLASSIFIERS.iter().fold(0u32, |bits, classifier| bits << 2 | classifier.quantize(classifier.apply(&image, x)))
+    }).collect();
+
+    let duration = if frames == 0 { 0.0 } else { frames as f64 / sample_rate };
+
+    self.report.send(Fingerprint { raw: raw, duration: duration });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::comm;
+  use std::f64::consts::PI;
+
+  use channel;
+  use generator;
+  use sample_type;
+
+  #[test]
+  fn test_compressed() {
+    let fingerprint = super::Fingerprint { raw: vec![1, 3], duration: 0.0 };
+
+    assert_eq!(fingerprint.compressed(), vec![1, 0, 0, 2, 0x81, 0]);
+    assert_eq!(fingerprint.encoded().as_slice(), "AQAAAoEA");
+
+    let fingerprint = super::Fingerprint { raw: vec![0x80000001, 0x80000001, 5], duration: 0.0 };
+
+    assert_eq!(fingerprint.compressed(), vec![1, 0, 0, 3, 0x39, 0xB0, 0x03, 0xD8, 0x02]);
+    assert_eq!(fingerprint.encoded().as_slice(), "AQAAAzmwA9gC");
+  }
+
+  #[test]
+  fn test_quiet() {
+    // A tone at -90 dB is above Chromaprint's silence threshold.
+    let amplitude = (10.0f64).powf(-90.0 / 20.0);
+    let samples = Vec::from_fn(4 * super::FRAME, |i| amplitude * (2.0 * PI * 440.0 * i as f64 / super::SAMPLE_RATE).sin());
+
+    let mut chroma = super::Chroma::new();
+    let mut image = super::Image::new();
+
+    chroma.add(samples.as_slice(), &mut image);
+
+    assert!(image.rows() > 0);
+
+    for x in range(0, image.rows()) {
+      assert!(image.area(x, 0, x + 1, 12) > 0.0);
+    }
+  }
+
+  fn fingerprint(amplitude: f64) -> super::Fingerprint {
+    let (sink, source) = channel::create::<::Audio>(1);
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+    let (report, fingerprint) = comm::channel();
+
+    spawn(proc() {
+      generator::Generator::new(sink, generator::Sweep(100.0, 3000.0, generator::Logarithmic), 10.0, 11025.0, 2, sample_type::Signed(16)).amplitude(amplitude).run();
+    });
+
+    spawn(proc() {
+      super::Fingerprinter::new(source, out_sink, report).run();
+    });
+
+    let mut last = false;
+
+    while !last {
+      out_source.read(|audio| last = audio.last);
+    }
+
+    return fingerprint.recv();
+  }
+
+  #[test]
+  fn test_fingerprinter() {
+    let loud = fingerprint(0.0);
+
+    // 78 frames of 4096 samples, less 4 for the chroma filter, less 15 for
+    // the classifiers.
+    assert_eq!(loud.raw.len(), 59);
+    assert_eq!(loud.duration, 10.0);
+
+    let quiet = fingerprint(-12.0);
+    let differing = loud.raw.iter().zip(quiet.raw.iter()).fold(0, |a, (&x, &y)| a + (x ^ y).count_ones());
+
+    assert!(differing < 59 * 32 / 20);
+  }
+}
@@ -0,0 +1,289 @@
+use std;
+use std::f64::consts::PI;
+
+use channel;
+use sample;
+
+/// The shape of a fade.
+#[deriving(Show,PartialEq)]
+pub enum Curve {
+  Linear,
+
+  /// Linear in decibels over a 60 dB range, which sounds even.
+  Logarithmic,
+
+  /// A raised cosine, which starts and ends smoothly.
+  SCurve
+}
+
+impl Curve {
+  /// The gain at `x`, from 0 at the start of a fade in to 1 at its end.
+  pub fn value(&self, x: f64) -> f64 {
+    let x = x.max(0.0).min(1.0);
+
+    return match *self {
+      Linear => x,
+      Logarithmic => ((10.0f64).powf(3.0 * x) - 1.0) / 999.0,
+      SCurve => (1.0 - (PI * x).cos()) / 2.0
+    };
+  }
+}
+
+#[deriving(Show,PartialEq)]
+pub enum Direction {
+  In, Out
+}
+
+/// A fade over `length` frames starting at frame `start`. Audio before a
+/// fade in and after a fade out is silent.
+#[deriving(Show,PartialEq)]
+pub struct Fade {
+  pub direction: Direction,
+  pub start: uint,
+  pub length: uint,
+  pub curve: Curve
+}
+
+impl Fade {
+  fn at(&self, position: uint) -> f64 {
+    let x = if position < self.start {
+      0.0
+    } else if position >= self.start + self.length {
+      1.0
+    } else {
+      (position - self.start) as f64 / self.length as f64
+    };
+
+    return match self.direction {
+      In => self.curve.value(x),
+      Out => self.curve.value(1.0 - x)
+    };
+  }
+}
+
+/// The gain changes to apply to a stream: a constant gain, fades and a
+/// breakpoint envelope, all multiplied together.
+#[deriving(Show,PartialEq)]
+pub struct Automation {
+  gain: f64,
+  fades: Vec<Fade>,
+
+  /// (frame, linear gain) points, sorted by frame.
+  envelope: Vec<(uint, f64)>
+}
+
+impl Automation {
+  /// Automation that leaves the audio unchanged.
+  pub fn new() -> Automation {
+    return Automation { gain: 1.0, fades: Vec::new(), envelope: Vec::new() };
+  }
+
+  /// Adds a constant gain in dB.
+  pub fn gain(mut self, db: f64) -> Automation {
+    self.gain *= (10.0f64).powf(db / 20.0);
+
+    return self;
+  }
+
+  pub fn fade_in(mut self, start: uint, length: uint, curve: Curve) -> Automation {
+    self.fades.push(Fade { direction: In, start: start, length: length, curve: curve });
+
+    return self;
+  }
+
+  pub fn fade_out(mut self, start: uint, length: uint, curve: Curve) -> Automation {
+    self.fades.push(Fade { direction: Out, start: start, length: length, curve: curve });
+
+    return self;
+  }
+
+  /// Sets a breakpoint envelope of (frame, linear gain) points. The gain
+  /// is interpolated linearly between points, and held before the first
+  /// and after the last.
+  pub fn envelope(mut self, points: Vec<(uint, f64)>) -> Automation {
+    for i in range(1, points.len()) {
+      let (previous, _) = points[i - 1];
+      let (current, _) = points[i];
+
+      if current < previous {
+        panic!("gain::Automation: Envelope points must be sorted (ARGUMENT)");
+      }
+    }
+
+    self.envelope = points;
+
+    return self;
+  }
+
+  /// The gain at frame `position`.
+  pub fn at(&self, position: uint) -> f64 {
+    return self.at_from(position, &mut 0);
+  }
+
+  /// Like `at`, but resumes the search for the envelope point from
+  /// `cursor`, which starts at 0 and is shared by calls whose positions
+  /// never decrease.
+  fn at_from(&self, position: uint, cursor: &mut uint) -> f64 {
+    let fades = self.fades.iter().fold(1.0, |a, fade| a * fade.at(position));
+
+    return self.gain * fades * self.envelope_at(position, cursor);
+  }
+
+  /// `cursor` is left at the number of points at or before `position`.
+  fn envelope_at(&self, position: uint, cursor: &mut uint) -> f64 {
+    let points = self.envelope.as_slice();
+
+    if points.is_empty() {
+      return 1.0;
+    }
+
+    while *cursor < points.len() {
+      let (frame, _) = points[*cursor];
+
+      if frame > position {
+        break;
+      }
+
+      *cursor += 1;
+    }
+
+    let i = *cursor;
+
+    if i == 0 {
+      let (_, gain) = points[0];
+      return gain;
+    } else if i == points.len() {
+      let (_, gain) = points[i - 1];
+      return gain;
+    }
+
+    let (x0, y0) = points[i - 1];
+    let (x1, y1) = points[i];
+
+    return y0 + (y1 - y0) * (position - x0) as f64 / (x1 - x0) as f64;
+  }
+}
+
+/// Applies an `Automation` to audio, counting frames from the start of the
+/// stream.
+pub struct Gain {
+  source: channel::Source<::Audio>,
+  sink: channel::Sink<::Audio>,
+  automation: Automation
+}
+
+impl Gain {
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, automation: Automation) -> Gain {
+    return Gain { source: source, sink: sink, automation: automation };
+  }
+
+  pub fn run(&mut self) {
+    let automation = &self.automation;
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    let mut samples = Vec::new();
+    let mut position = 0u;
+    let mut cursor = 0u;
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        let channels = audio.channels;
+
+        samples.truncate(0);
+
+        if !audio.data.is_empty() {
+          sample::decode(audio, &mut samples);
+        }
+
+        for (i, frame) in samples.as_mut_slice().chunks_mut(std::cmp::max(channels, 1)).enumerate() {
+          let gain = automation.at_from(position + i, &mut cursor);
+
+          for x in frame.iter_mut() {
+            *x *= gain;
+          }
+        }
+
+        position += samples.len() / std::cmp::max(channels, 1);
+
+        sink.write(|out| {
+          out.channels = channels;
+          out.layout = audio.layout.clone();
+          out.sample_rate = audio.sample_rate;
+          out.sample_type = audio.sample_type;
+          out.endian = audio.endian;
+          out.last = last;
+
+          if !samples.is_empty() {
+            sample::encode(samples.as_slice(), out);
+          }
+        });
+      });
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use channel;
+  use endian;
+  use sample::testing;
+  use sample_type;
+
+  #[test]
+  fn test_curve() {
+    for &curve in [super::Linear, super::Logarithmic, super::SCurve].iter() {
+      assert_eq!(curve.value(0.0), 0.0);
+      assert!((curve.value(1.0) - 1.0).abs() < 1e-12);
+    }
+
+    assert_eq!(super::SCurve.value(0.5), 0.5);
+    assert!((super::Logarithmic.value(2.0 / 3.0) - 0.099099).abs() < 1e-6);
+  }
+
+  #[test]
+  fn test_automation() {
+    let automation = super::Automation::new()
+      .gain(-6.0206)
+      .fade_in(10, 10, super::Linear)
+      .fade_out(100, 100, super::Linear)
+      .envelope(vec![(50, 1.0), (60, 2.0)]);
+
+    assert_eq!(automation.at(5), 0.0);
+    assert!((automation.at(15) - 0.25).abs() < 1e-4);
+    assert!((automation.at(55) - 0.75).abs() < 1e-4);
+    assert!((automation.at(150) - 0.5).abs() < 1e-4);
+    assert_eq!(automation.at(250), 0.0);
+  }
+
+  #[test]
+  fn test_gain() {
+    let source = testing::source(Vec::from_elem(12, 1.0), 3, 2, 44100.0, sample_type::Float(32), endian::Big);
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() {
+      super::Gain::new(source, out_sink, super::Automation::new().fade_in(0, 4, super::Linear)).run();
+    });
+
+    let output = testing::collect(&mut out_source);
+
+    assert_eq!(output, vec![0.0, 0.0, 0.25, 0.25, 0.5, 0.5, 0.75, 0.75, 1.0, 1.0, 1.0, 1.0]);
+  }
+
+  #[test]
+  fn test_envelope() {
+    // The envelope is followed across packets.
+    let source = testing::source(Vec::from_elem(6, 1.0), 2, 1, 44100.0, sample_type::Float(32), endian::Big);
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() {
+      super::Gain::new(source, out_sink, super::Automation::new().envelope(vec![(1, 0.5), (3, 0.25), (4, 1.0)])).run();
+    });
+
+    assert_eq!(testing::collect(&mut out_source), vec![0.5, 0.5, 0.375, 0.25, 1.0, 1.0]);
+  }
+}
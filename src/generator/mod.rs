@@ -0,0 +1,365 @@
+use std;
+use std::f64::consts::PI;
+
+use channel;
+use endian;
+use layout;
+use sample;
+use sample_type;
+
+/// Frames per packet of generated audio.
+static PACKET: uint = 4096;
+
+#[deriving(Show,PartialEq)]
+pub enum Spacing {
+  Linear, Logarithmic
+}
+
+/// A test signal. Frequencies are in Hz.
+#[deriving(Show,PartialEq)]
+pub enum Signal {
+  Sine(f64),
+
+  /// Band-limited waveforms, summed from their harmonics below the Nyquist
+  /// frequency so that they do not alias.
+  Square(f64),
+  Sawtooth(f64),
+  Triangle(f64),
+
+  WhiteNoise,
+
+  /// Noise falling at 3 dB per octave.
+  PinkNoise,
+
+  /// Noise falling at 6 dB per octave.
+  BrownNoise,
+
+  /// A sine sweeping from the first frequency to the second over the
+  /// duration of the signal.
+  Sweep(f64, f64, Spacing),
+
+  /// A single full-scale sample at the start, then silence.
+  Impulse,
+
+  /// The tone pair for a telephone key: one of `0`-`9`, `*`, `#` and
+  /// `A`-`D`.
+  Dtmf(char),
+
+  Silence
+}
+
+/// The row and column frequencies of a DTMF key.
+fn dtmf(key: char) -> (f64, f64) {
+  let keys = "123A456B789C*0#D";
+
+  return match keys.chars().position(|k| k == key) {
+    Some(i) => ([697.0, 770.0, 852.0, 941.0][i / 4], [1209.0, 1336.0, 1477.0, 1633.0][i % 4]),
+    None => panic!("generator::Signal: Bad DTMF key {} (ARGUMENT)", key)
+  };
+}
+
+/// An xorshift64* generator, so that noise depends only on its seed.
+struct Random {
+  state: u64
+}
+
+impl Random {
+  fn new(seed: u64) -> Random {
+    // The state must not be zero.
+    let state = seed ^ 0x9E3779B97F4A7C15;
+
+    return Random { state: if state == 0 { 0x9E3779B97F4A7C15 } else { state } };
+  }
+
+  /// A uniform value in [-1, 1).
+  fn next(&mut self) -> f64 {
+    self.state ^= self.state >> 12;
+    self.state ^= self.state << 25;
+    self.state ^= self.state >> 27;
+
+    let x = self.state * 0x2545F4914F6CDD1D;
+
+    return (x >> 11) as f64 / (1u64 << 52) as f64 - 1.0;
+  }
+}
+
+/// Produces the samples of a signal one at a time.
+struct Oscillator {
+  signal: Signal,
+  sample_rate: f64,
+  frames: uint,
+  position: uint,
+  random: Random,
+
+  /// Harmonic numbers and amplitudes, for band-limited waveforms.
+  harmonics: Vec<(f64, f64)>,
+
+  /// Filter state, for pink and brown noise.
+  state: [f64, ..7]
+}
+
+impl Oscillator {
+  fn new(signal: Signal, sample_rate: f64, frames: uint, seed: u64) -> Oscillator {
+    let harmonics = match signal {
+      Square(f) | Sawtooth(f) | Triangle(f) => {
+        let count = (sample_rate / 2.0 / f).ceil() as uint;
+
+        range(1, count).filter_map(|k| {
+          let k = k as f64;
+
+          match signal {
+            Square(_) if k % 2.0 == 1.0 => Some((k, 4.0 / PI / k)),
+            Sawtooth(_) => Some((k, 2.0 / PI / k * if k % 2.0 == 1.0 { 1.0 } else { -1.0 })),
+            Triangle(_) if k % 2.0 == 1.0 => Some((k, 8.0 / (PI * PI) / (k * k) * if k % 4.0 == 1.0 { 1.0 } else { -1.0 })),
+            _ => None
+          }
+        }).collect()
+      },
+      Dtmf(key) => {
+        dtmf(key);
+        Vec::new()
+      },
+      _ => Vec::new()
+    };
+
+    return Oscillator { signal: signal, sample_rate: sample_rate, frames: frames, position: 0, random: Random::new(seed), harmonics: harmonics, state: [0.0, ..7] };
+  }
+
+  fn next(&mut self) -> f64 {
+    let t = self.position as f64 / self.sample_rate;
+    let duration = self.frames as f64 / self.sample_rate;
+
+    let x = match self.signal {
+      Sine(f) => (2.0 * PI * f * t).sin(),
+      Square(f) | Sawtooth(f) | Triangle(f) => {
+        self.harmonics.iter().fold(0.0, |a, &(k, g)| a + g * (2.0 * PI * k * f * t).sin())
+      },
+      WhiteNoise => self.random.next(),
+      PinkNoise => {
+        // Paul Kellet's refined filter, scaled to about unity peak.
+        let white = self.random.next();
+        let b = &mut self.state;
+
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.1538520;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+
+        let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+
+        b[6] = white * 0.115926;
+
+        pink * 0.11
+      },
+      BrownNoise => {
+        // Leaky integration of white noise, scaled to about unity peak.
+        let white = self.random.next();
+        let b = &mut self.state;
+
+        b[0] = 0.998 * b[0] + white * 0.02;
+        b[0] * 3.5
+      },
+      Sweep(start, end, spacing) => {
+        let phase = match spacing {
+          Linear => start * t + (end - start) * t * t / (2.0 * duration),
+          Logarithmic => {
+            let k = (end / start).ln();
+
+            start * duration / k * ((t / duration * k).exp() - 1.0)
+          }
+        };
+
+        (2.0 * PI * phase).sin()
+      },
+      Impulse => if self.position == 0 { 1.0 } else { 0.0 },
+      Dtmf(key) => {
+        let (row, column) = dtmf(key);
+
+        ((2.0 * PI * row * t).sin() + (2.0 * PI * column * t).sin()) / 2.0
+      },
+      Silence => 0.0
+    };
+
+    self.position += 1;
+
+    return x;
+  }
+}
+
+/// Generates a test signal as a source of audio.
+///
+/// Every channel carries the same signal, at full scale unless an
+/// amplitude is given. Noise is the same for the same seed.
+pub struct Generator {
+  sink: channel::Sink<::Audio>,
+  signal: Signal,
+  duration: f64,
+  sample_rate: f64,
+  channels: uint,
+  sample_type: sample_type::SampleType,
+  amplitude: f64,
+  seed: u64
+}
+
+impl Generator {
+  /// Generates `duration` seconds of `signal`.
+  pub fn new(sink: channel::Sink<::Audio>, signal: Signal, duration: f64, sample_rate: f64, channels: uint, sample_type: sample_type::SampleType) -> Generator {
+    if !(duration >= 0.0) || !(sample_rate > 0.0) || channels == 0 {
+      panic!("generator::Generator: Duration, sample rate and channels must be positive (ARGUMENT)");
+    }
+
+    match sample_type {
+      sample_type::Unknown | sample_type::Dsd => panic!("generator::Generator: PCM sample type required (ARGUMENT)"),
+      _ => ()
+    }
+
+    match signal {
+      Sweep(start, end, Logarithmic) if !(start > 0.0 && end > 0.0 && start != end) => {
+        panic!("generator::Generator: Logarithmic sweeps need distinct positive frequencies (ARGUMENT)");
+      },
+      _ => ()
+    }
+
+    return Generator {
+      sink: sink,
+      signal: signal,
+      duration: duration,
+      sample_rate: sample_rate,
+      channels: channels,
+      sample_type: sample_type,
+      amplitude: 1.0,
+      seed: 0
+    };
+  }
+
+  /// Returns the generator, with the signal at `db` dBFS.
+  pub fn amplitude(mut self, db: f64) -> Generator {
+    self.amplitude = (10.0f64).powf(db / 20.0);
+
+    return self;
+  }
+
+  pub fn seed(mut self, seed: u64) -> Generator {
+    self.seed = seed;
+
+    return self;
+  }
+
+  pub fn run(&mut self) {
+    let channels = self.channels;
+    let sample_rate = self.sample_rate;
+    let sample_type = self.sample_type;
+    let amplitude = self.amplitude;
+    let sink = &mut self.sink;
+
+    let frames = (self.duration * sample_rate).round() as uint;
+    let mut oscillator = Oscillator::new(self.signal, sample_rate, frames, self.seed);
+    let mut samples = Vec::with_capacity(PACKET * channels);
+    let mut position = 0;
+
+    loop {
+      let count = std::cmp::min(PACKET, frames - position);
+
+      samples.truncate(0);
+
+      for _ in range(0, count) {
+        let x = oscillator.next() * amplitude;
+
+        for _ in range(0, channels) {
+          samples.push(x);
+        }
+      }
+
+      position += count;
+
+      let last = position == frames;
+
+      sink.write(|audio| {
+        audio.channels = channels;
+        audio.layout = layout::Layout::default_for(channels);
+        audio.sample_rate = sample_rate;
+        audio.sample_type = sample_type;
+        audio.endian = endian::Little;
+        audio.last = last;
+
+        if !samples.is_empty() {
+          sample::encode(samples.as_slice(), audio);
+        }
+      });
+
+      if last {
+        break;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::f64::consts::PI;
+
+  use channel;
+  use sample::testing;
+  use sample_type;
+
+  fn generate(signal: super::Signal, duration: f64, channels: uint, seed: u64) -> Vec<f64> {
+    let (sink, mut source) = channel::create::<::Audio>(1);
+
+    spawn(proc() {
+      super::Generator::new(sink, signal, duration, 8000.0, channels, sample_type::Float(64)).seed(seed).run();
+    });
+
+    return testing::collect(&mut source);
+  }
+
+  #[test]
+  fn test_sine() {
+    let output = generate(super::Sine(1000.0), 1.0, 2, 0);
+
+    assert_eq!(output.len(), 16000);
+    assert_eq!(output[0], output[1]);
+    assert!((output[4] - (2.0 * PI * 1000.0 * 2.0 / 8000.0).sin()).abs() < 1e-12);
+  }
+
+  #[test]
+  fn test_square() {
+    let output = generate(super::Square(1000.0), 0.01, 1, 0);
+    let peak = output.iter().fold(0.0f64, |a, &x| a.max(x.abs()));
+
+    // Only the first and third harmonics fit below 4 kHz, and they peak at
+    // an eighth of a period.
+    assert!((peak - 8.0 * (2.0f64).sqrt() / (3.0 * PI)).abs() < 1e-9);
+  }
+
+  #[test]
+  fn test_noise() {
+    let a = generate(super::PinkNoise, 0.5, 1, 1);
+    let b = generate(super::PinkNoise, 0.5, 1, 1);
+    let c = generate(super::PinkNoise, 0.5, 1, 2);
+
+    assert_eq!(a, b);
+    assert!(a != c);
+  }
+
+  #[test]
+  fn test_zero_state() {
+    let mut random = super::Random::new(0x9E3779B97F4A7C15);
+
+    assert!(random.next() != random.next());
+  }
+
+  #[test]
+  #[should_fail]
+  fn test_logarithmic_sweep() {
+    let (sink, _source) = channel::create::<::Audio>(1);
+
+    super::Generator::new(sink, super::Sweep(0.0, 1000.0, super::Logarithmic), 1.0, 8000.0, 1, sample_type::Float(64));
+  }
+
+  #[test]
+  fn test_empty() {
+    assert_eq!(generate(super::Silence, 0.0, 1, 0), vec![]);
+  }
+}
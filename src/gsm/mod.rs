@@ -0,0 +1,1176 @@
+use std;
+
+use channel;
+use endian;
+use layout;
+use sample;
+use sample_type;
+use stream;
+
+/// How GSM frames are laid out in the binary stream.
+#[deriving(Show,PartialEq)]
+pub enum Packing {
+  /// 33 bytes per frame, most significant bit first, starting with the
+  /// 0xD signature nibble. This is the layout of raw .gsm files.
+  Standard,
+
+  /// 65 bytes per pair of frames, least significant bit first, without a
+  /// signature. This is the Microsoft layout of WAV format 0x0031.
+  Wav49
+}
+
+impl Packing {
+  /// The number of bytes in one packed block.
+  pub fn block_size(&self) -> uint {
+    return match *self { Standard => 33, Wav49 => 65 };
+  }
+
+  /// The number of frames, of 160 samples each, in one packed block.
+  pub fn frames(&self) -> uint {
+    return match *self { Standard => 1, Wav49 => 2 };
+  }
+}
+
+/// The number of samples in a frame, 20 ms at 8 kHz.
+static FRAME: uint = 160;
+
+/// The number of frames the decoder puts in each packet.
+static FRAMES_PER_PACKET: uint = 25;
+
+static MIN: i16 = -32768;
+static MAX: i16 = 32767;
+
+static LAR_BITS: [uint, ..8] = [6, 6, 5, 5, 4, 4, 3, 3];
+
+static LAR_A: [i16, ..8] = [20480, 20480, 20480, 20480, 13964, 15360, 8534, 9036];
+static LAR_B: [i16, ..8] = [0, 0, 2048, -2560, 94, -1792, -341, -1144];
+static LAR_MIC: [i16, ..8] = [-32, -32, -16, -16, -8, -8, -4, -4];
+static LAR_MAC: [i16, ..8] = [31, 31, 15, 15, 7, 7, 3, 3];
+static LAR_INVA: [i16, ..8] = [13107, 13107, 13107, 13107, 19223, 17476, 31454, 29708];
+
+/// Decision levels and quantized values of the long term predictor gain.
+static DLB: [i16, ..4] = [6554, 16384, 26214, 32767];
+static QLB: [i16, ..4] = [3277, 11469, 21299, 32767];
+
+/// The impulse response of the weighting filter.
+static H: [i16, ..11] = [-134, -374, 0, 2054, 5741, 8192, 5741, 2054, 0, -374, -134];
+
+static NRFAC: [i16, ..8] = [29128, 26215, 23832, 21846, 20165, 18725, 17476, 16384];
+static FAC: [i16, ..8] = [18431, 20479, 22527, 24575, 26623, 28671, 30719, 32767];
+
+/// Decodes GSM 06.10 full rate speech into 8 kHz mono 16-bit audio.
+///
+/// A standard frame without the signature nibble decodes to silence. A
+/// truncated block at the end of the input is ignored.
+pub struct Decoder {
+  source: channel::Source<::Binary>,
+  sink: channel::Sink<::Audio>,
+  packing: Packing
+}
+
+impl Decoder {
+  pub fn new(source: channel::Source<::Binary>, sink: channel::Sink<::Audio>, packing: Packing) -> Decoder {
+    return Decoder { source: source, sink: sink, packing: packing };
+  }
+
+  pub fn run(&mut self) {
+    let packing = self.packing;
+
+    let mut s = stream::Stream::new(&mut self.source);
+    let sink = &mut self.sink;
+
+    let mut synthesis = Synthesis::new();
+    let mut block = [0u8, ..65];
+    let mut samples = [0i16, ..160];
+    let mut output = Vec::with_capacity(FRAME * FRAMES_PER_PACKET);
+
+    loop {
+      let mut eof = false;
+
+      output.truncate(0);
+
+      while output.len() < FRAME * FRAMES_PER_PACKET {
+        if !fill(&mut s, block.slice_to_mut(packing.block_size())) {
+          eof = true;
+          break;
+        }
+
+        let mut position = 0;
+
+        for _ in range(0, packing.frames()) {
+          let valid = match packing {
+            Standard => get_bits(block, &mut position, 4, false) == 0xD,
+            Wav49 => true
+          };
+
+          let frame = unpack(|n| get_bits(block, &mut position, n, packing == Wav49));
+
+          if valid {
+            synthesis.decode(&frame, samples);
+            output.push_all(samples.as_slice());
+          } else {
+            output.grow(FRAME, 0);
+          }
+        }
+      }
+
+      sink.write(|audio| {
+        audio.channels = 1;
+        audio.layout = layout::Layout::mono();
+        audio.sample_rate = 8000.0;
+        audio.sample_type = sample_type::Signed(16);
+        audio.endian = endian::Big;
+
+        for &x in output.iter() {
+          audio.data.push((x >> 8) as u8);
+          audio.data.push(x as u8);
+        }
+
+        audio.last = eof;
+      });
+
+      if eof {
+        break;
+      }
+    }
+  }
+}
+
+/// Encodes 8 kHz mono audio as GSM 06.10 full rate speech.
+///
+/// The input is converted to 16-bit, and the end is padded with silence to
+/// a whole block.
+pub struct Encoder {
+  source: channel::Source<::Audio>,
+  sink: channel::Sink<::Binary>,
+  packing: Packing
+}
+
+impl Encoder {
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Binary>, packing: Packing) -> Encoder {
+    return Encoder { source: source, sink: sink, packing: packing };
+  }
+
+  pub fn run(&mut self) {
+    let packing = self.packing;
+    let size = FRAME * packing.frames();
+
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    let mut analysis = Analysis::new();
+    let mut input = Vec::new();
+    let mut pending: Vec<i16> = Vec::new();
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        if audio.data.len() > 0 && (audio.channels != 1 || audio.sample_rate != 8000.0) {
+          panic!("gsm::Encoder: Input must be 8 kHz mono (INPUT)");
+        }
+
+        last = audio.last;
+
+        if audio.data.len() > 0 {
+          sample::decode(audio, &mut input);
+
+          for &x in input.iter() {
+            pending.push((x * 32768.0).round().max(MIN as f64).min(MAX as f64) as i16);
+          }
+        }
+
+        if last && pending.len() % size != 0 {
+          let padding = size - pending.len() % size;
+
+          pending.grow(padding, 0);
+        }
+
+        let blocks = pending.len() / size;
+
+        sink.write(|binary| {
+          for b in range(0, blocks) {
+            let start = binary.data.len();
+
+            binary.data.grow(packing.block_size(), 0);
+
+            let bytes = binary.data.slice_from_mut(start);
+            let mut position = 0;
+
+            for f in range(0, packing.frames()) {
+              let offset = (b * packing.frames() + f) * FRAME;
+              let frame = analysis.encode(pending.slice(offset, offset + FRAME));
+
+              if packing == Standard {
+                put_bits(bytes, &mut position, 4, 0xD, false);
+              }
+
+              pack(&frame, |n, value| put_bits(bytes, &mut position, n, value, packing == Wav49));
+            }
+          }
+
+          binary.last = last;
+        });
+
+        let consumed = blocks * size;
+        let remaining = pending.len() - consumed;
+
+        for i in range(0, remaining) {
+          *pending.get_mut(i) = pending[i + consumed];
+        }
+
+        pending.truncate(remaining);
+      });
+    }
+  }
+}
+
+/// Reads exactly the length of `buffer`, returns false on end of file.
+fn fill(s: &mut stream::Stream, buffer: &mut [u8]) -> bool {
+  let mut read = 0;
+
+  while read < buffer.len() {
+    match s.try_read(buffer.slice_from_mut(read)) {
+      Some(n) => read += n,
+      None => return false
+    }
+  }
+
+  return true;
+}
+
+/// The parameters of one 20 ms frame, as transmitted.
+struct Frame {
+  /// Coded log area ratios.
+  lar: [i16, ..8],
+
+  /// Long term predictor lag, gain, RPE grid position and block maximum,
+  /// for each of the four subframes.
+  nc: [i16, ..4],
+  bc: [i16, ..4],
+  mc: [i16, ..4],
+  xmaxc: [i16, ..4],
+
+  /// RPE pulses, 13 per subframe.
+  xmc: [i16, ..52]
+}
+
+impl Frame {
+  fn new() -> Frame {
+    return Frame { lar: [0, ..8], nc: [0, ..4], bc: [0, ..4], mc: [0, ..4], xmaxc: [0, ..4], xmc: [0, ..52] };
+  }
+}
+
+/// Reads the fields of a frame in transmission order, using `get` to read
+/// the given number of bits.
+fn unpack(get: |uint| -> i16) -> Frame {
+  let mut frame = Frame::new();
+
+  for i in range(0u, 8) {
+    frame.lar[i] = get(LAR_BITS[i]);
+  }
+
+  for k in range(0u, 4) {
+    frame.nc[k] = get(7);
+    frame.bc[k] = get(2);
+    frame.mc[k] = get(2);
+    frame.xmaxc[k] = get(6);
+
+    for i in range(0u, 13) {
+      frame.xmc[k * 13 + i] = get(3);
+    }
+  }
+
+  return frame;
+}
+
+/// Writes the fields of a frame in transmission order, using `put` to write
+/// the given number of bits of a value.
+fn pack(frame: &Frame, put: |uint, i16|) {
+  for i in range(0u, 8) {
+    put(LAR_BITS[i], frame.lar[i]);
+  }
+
+  for k in range(0u, 4) {
+    put(7, frame.nc[k]);
+    put(2, frame.bc[k]);
+    put(2, frame.mc[k]);
+    put(6, frame.xmaxc[k]);
+
+    for i in range(0u, 13) {
+      put(3, frame.xmc[k * 13 + i]);
+    }
+  }
+}
+
+fn get_bits(data: &[u8], position: &mut uint, n: uint, lsb_first: bool) -> i16 {
+  let mut value = 0i16;
+
+  for i in range(0, n) {
+    let p = *position + i;
+
+    if lsb_first {
+      value |= (((data[p / 8] >> (p % 8)) & 1) as i16) << i;
+    } else {
+      value = (value << 1) | ((data[p / 8] >> (7 - p % 8)) & 1) as i16;
+    }
+  }
+
+  *position += n;
+
+  return value;
+}
+
+fn put_bits(data: &mut [u8], position: &mut uint, n: uint, value: i16, lsb_first: bool) {
+  for i in range(0, n) {
+    let p = *position + i;
+    let bit = if lsb_first { (value >> i) & 1 } else { (value >> (n - 1 - i)) & 1 } as u8;
+
+    if lsb_first {
+      data[p / 8] |= bit << (p % 8);
+    } else {
+      data[p / 8] |= bit << (7 - p % 8);
+    }
+  }
+
+  *position += n;
+}
+
+/// The state of the encoder, the RPE-LTP analysis of section 4.2.
+struct Analysis {
+  /// Reconstructed short term residual, 120 samples of history and the
+  /// current frame.
+  dp: [i16, ..280],
+
+  /// Offset compensation and preemphasis.
+  z1: i16,
+  l_z2: i32,
+  mp: i16,
+
+  /// Short term analysis filter.
+  u: [i16, ..8],
+  lar: [i16, ..8]
+}
+
+impl Analysis {
+  fn new() -> Analysis {
+    return Analysis { dp: [0, ..280], z1: 0, l_z2: 0, mp: 0, u: [0, ..8], lar: [0, ..8] };
+  }
+
+  fn encode(&mut self, s: &[i16]) -> Frame {
+    let mut frame = Frame::new();
+    let mut so = [0i16, ..160];
+
+    self.preprocess(s, so);
+
+    lpc_analysis(so, &mut frame.lar);
+
+    self.short_term_filter(&frame.lar, so);
+
+    // The weighting filter looks 5 samples to either side of the subframe.
+    let mut e = [0i16, ..50];
+
+    for k in range(0u, 4) {
+      let d = so.slice(k * 40, k * 40 + 40);
+      let base = 120 + k * 40;
+
+      let (nc, bc) = ltp_parameters(d, self.dp.slice(base - 120, base));
+
+      frame.nc[k] = nc;
+      frame.bc[k] = bc;
+
+      let bp = QLB[bc as uint];
+
+      for i in range(0u, 40) {
+        let dpp = mult_r(bp, self.dp[base + i - nc as uint]);
+
+        self.dp[base + i] = dpp;
+        e[5 + i] = sub(d[i], dpp);
+      }
+
+      let (xmaxc, mc) = rpe_encoding(e, frame.xmc.slice_mut(k * 13, k * 13 + 13));
+
+      frame.xmaxc[k] = xmaxc;
+      frame.mc[k] = mc;
+
+      for i in range(0u, 40) {
+        self.dp[base + i] = add(e[5 + i], self.dp[base + i]);
+      }
+    }
+
+    for i in range(0u, 120) {
+      self.dp[i] = self.dp[i + 160];
+    }
+
+    return frame;
+  }
+
+  /// Downscaling, offset compensation and preemphasis (4.2.1 to 4.2.3).
+  fn preprocess(&mut self, s: &[i16], so: &mut [i16]) {
+    for k in range(0u, 160) {
+      let scaled = (s[k] >> 3) << 2;
+      let s1 = scaled - self.z1;
+
+      self.z1 = scaled;
+
+      let msp = (self.l_z2 >> 15) as i16;
+      let lsp = (self.l_z2 - ((msp as i32) << 15)) as i16;
+      let l_s2 = ((s1 as i32) << 15) + mult_r(lsp, 32735) as i32;
+
+      self.l_z2 = l_add(msp as i32 * 32735, l_s2);
+
+      let l_temp = l_add(self.l_z2, 16384);
+      let msp = mult_r(self.mp, -28180);
+
+      self.mp = (l_temp >> 15) as i16;
+
+      so[k] = add(self.mp, msp);
+    }
+  }
+
+  /// Filters `s` in place through the short term analysis filter, with the
+  /// coefficients interpolated from the previous frame (4.2.8 to 4.2.10).
+  fn short_term_filter(&mut self, lar: &[i16, ..8], s: &mut [i16]) {
+    let current = decode_lar(lar);
+
+    for &(start, end) in SEGMENTS.iter() {
+      let rp = interpolate(start, &self.lar, &current);
+
+      for k in range(start, end) {
+        let mut di = s[k];
+        let mut sav = di;
+
+        for i in range(0u, 8) {
+          let ui = self.u[i];
+
+          self.u[i] = sav;
+          sav = add(ui, mult_r(rp[i], di));
+          di = add(di, mult_r(rp[i], ui));
+        }
+
+        s[k] = di;
+      }
+    }
+
+    self.lar = current;
+  }
+}
+
+/// The state of the decoder, the RPE-LTP synthesis of section 4.3.
+struct Synthesis {
+  /// Reconstructed short term residual, 120 samples of history and the
+  /// current subframe.
+  dp: [i16, ..160],
+
+  /// The last valid long term predictor lag.
+  nrp: i16,
+
+  /// Short term synthesis filter.
+  v: [i16, ..9],
+  lar: [i16, ..8],
+
+  /// Deemphasis.
+  msr: i16
+}
+
+impl Synthesis {
+  fn new() -> Synthesis {
+    return Synthesis { dp: [0, ..160], nrp: 40, v: [0, ..9], lar: [0, ..8], msr: 0 };
+  }
+
+  fn decode(&mut self, frame: &Frame, s: &mut [i16]) {
+    let mut wt = [0i16, ..160];
+
+    for k in range(0u, 4) {
+      let mut erp = [0i16, ..40];
+
+      rpe_decoding(frame.xmaxc[k], frame.mc[k], frame.xmc.slice(k * 13, k * 13 + 13), erp);
+
+      let nr = if frame.nc[k] < 40 || frame.nc[k] > 120 { self.nrp } else { frame.nc[k] };
+      let brp = QLB[frame.bc[k] as uint];
+
+      self.nrp = nr;
+
+      for i in range(0u, 40) {
+        let drpp = mult_r(brp, self.dp[120 + i - nr as uint]);
+
+        self.dp[120 + i] = add(erp[i], drpp);
+        wt[k * 40 + i] = self.dp[120 + i];
+      }
+
+      for i in range(0u, 120) {
+        self.dp[i] = self.dp[i + 40];
+      }
+    }
+
+    let current = decode_lar(&frame.lar);
+
+    for &(start, end) in SEGMENTS.iter() {
+      let rp = interpolate(start, &self.lar, &current);
+
+      for k in range(start, end) {
+        let mut sri = wt[k];
+
+        for i in range(0u, 8).rev() {
+          sri = sub(sri, mult_r(rp[i], self.v[i]));
+          self.v[i + 1] = add(self.v[i], mult_r(rp[i], sri));
+        }
+
+        self.v[0] = sri;
+        s[k] = sri;
+      }
+    }
+
+    self.lar = current;
+
+    // Deemphasis, then truncation and upscaling (4.3.5 to 4.3.7).
+    for k in range(0u, 160) {
+      self.msr = add(s[k], mult_r(self.msr, 28180));
+      s[k] = add(self.msr, self.msr) & !7;
+    }
+  }
+}
+
+/// The parts of a frame over which the short term filter coefficients are
+/// interpolated.
+static SEGMENTS: [(uint, uint), ..4] = [(0, 13), (13, 27), (27, 40), (40, 160)];
+
+/// Computes the coded log area ratios of a preprocessed frame (4.2.4 to
+/// 4.2.7). `s` is scaled in place, as in the reference implementation.
+fn lpc_analysis(s: &mut [i16], lar: &mut [i16, ..8]) {
+  let mut smax = 0;
+
+  for &x in s.iter() {
+    smax = std::cmp::max(smax, abs(x));
+  }
+
+  let scalauto = if smax == 0 { 0 } else { 4 - norm((smax as i32) << 16) };
+
+  if scalauto > 0 {
+    for x in s.iter_mut() {
+      *x = mult_r(*x, 16384 >> (scalauto - 1) as uint);
+    }
+  }
+
+  let mut acf = [0i32, ..9];
+
+  for k in range(0u, 9) {
+    let mut sum = 0i64;
+
+    for i in range(k, 160) {
+      sum += s[i] as i64 * s[i - k] as i64;
+    }
+
+    acf[k] = std::cmp::min(sum << 1, std::i32::MAX as i64) as i32;
+  }
+
+  if scalauto > 0 {
+    for x in s.iter_mut() {
+      *x = *x << scalauto as uint;
+    }
+  }
+
+  let mut r = [0i16, ..8];
+
+  reflection_coefficients(&acf, &mut r);
+
+  for i in range(0u, 8) {
+    // Transformation to log area ratios.
+    let mut temp = abs(r[i]);
+
+    temp = if temp < 22118 {
+      temp >> 1
+    } else if temp < 31130 {
+      temp - 11059
+    } else {
+      (temp - 26112) << 2
+    };
+
+    if r[i] < 0 {
+      temp = -temp;
+    }
+
+    // Quantization and coding.
+    temp = add(add(mult(LAR_A[i], temp), LAR_B[i]), 256) >> 9;
+
+    lar[i] = if temp > LAR_MAC[i] {
+      LAR_MAC[i] - LAR_MIC[i]
+    } else if temp < LAR_MIC[i] {
+      0
+    } else {
+      temp - LAR_MIC[i]
+    };
+  }
+}
+
+/// Computes the reflection coefficients with the Schur recursion (4.2.5).
+fn reflection_coefficients(l_acf: &[i32, ..9], r: &mut [i16, ..8]) {
+  if l_acf[0] == 0 {
+    return;
+  }
+
+  let temp = norm(l_acf[0]);
+
+  let mut acf = [0i16, ..9];
+
+  for i in range(0u, 9) {
+    acf[i] = ((l_acf[i] << temp as uint) >> 16) as i16;
+  }
+
+  let mut k = [0i16, ..9];
+  let mut p = [0i16, ..9];
+
+  for i in range(1u, 8) {
+    k[i] = acf[i];
+  }
+
+  for i in range(0u, 9) {
+    p[i] = acf[i];
+  }
+
+  for n in range(1u, 9) {
+    let temp = abs(p[1]);
+
+    if p[0] < temp {
+      for i in range(n - 1, 8) {
+        r[i] = 0;
+      }
+
+      return;
+    }
+
+    r[n - 1] = div(temp, p[0]);
+
+    if p[1] > 0 {
+      r[n - 1] = -r[n - 1];
+    }
+
+    if n == 8 {
+      return;
+    }
+
+    p[0] = add(p[0], mult_r(p[1], r[n - 1]));
+
+    for m in range(1u, 9 - n) {
+      p[m] = add(p[m + 1], mult_r(k[m], r[n - 1]));
+      k[m] = add(k[m], mult_r(p[m + 1], r[n - 1]));
+    }
+  }
+}
+
+/// Decodes coded log area ratios (4.2.9).
+fn decode_lar(lar: &[i16, ..8]) -> [i16, ..8] {
+  let mut decoded = [0i16, ..8];
+
+  for i in range(0u, 8) {
+    let mut temp = add(lar[i], LAR_MIC[i]) << 10;
+
+    temp = sub(temp, LAR_B[i] << 1);
+    temp = mult_r(LAR_INVA[i], temp);
+
+    decoded[i] = add(temp, temp);
+  }
+
+  return decoded;
+}
+
+/// Interpolates the decoded log area ratios for the segment starting at
+/// `start`, and converts them to reflection coefficients (4.2.9 and
+/// 4.2.10).
+fn interpolate(start: uint, previous: &[i16, ..8], current: &[i16, ..8]) -> [i16, ..8] {
+  let mut rp = [0i16, ..8];
+
+  for i in range(0u, 8) {
+    let larp = match start {
+      0 => add((previous[i] >> 2) + (current[i] >> 2), previous[i] >> 1),
+      13 => add(previous[i] >> 1, current[i] >> 1),
+      27 => add((previous[i] >> 2) + (current[i] >> 2), current[i] >> 1),
+      _ => current[i]
+    };
+
+    let temp = abs(larp);
+
+    let magnitude = if temp < 11059 {
+      temp << 1
+    } else if temp < 20070 {
+      temp + 11059
+    } else {
+      add(temp >> 2, 26112)
+    };
+
+    rp[i] = if larp < 0 { -magnitude } else { magnitude };
+  }
+
+  return rp;
+}
+
+/// Computes the long term predictor lag and coded gain of a subframe `d`,
+/// from the 120 previous samples of reconstructed residual `dp` (4.2.11).
+fn ltp_parameters(d: &[i16], dp: &[i16]) -> (i16, i16) {
+  let mut dmax = 0;
+
+  for &x in d.iter() {
+    dmax = std::cmp::max(dmax, abs(x));
+  }
+
+  let temp = if dmax == 0 { 0 } else { norm((dmax as i32) << 16) };
+  let scal = if temp > 6 { 0 } else { 6 - temp };
+
+  let mut wt = [0i16, ..40];
+
+  for k in range(0u, 40) {
+    wt[k] = d[k] >> scal as uint;
+  }
+
+  let mut l_max = 0i64;
+  let mut nc = 40u;
+
+  for lambda in range(40u, 121) {
+    let mut l_result = 0i64;
+
+    for k in range(0u, 40) {
+      l_result += wt[k] as i64 * dp[120 + k - lambda] as i64;
+    }
+
+    if l_result > l_max {
+      nc = lambda;
+      l_max = l_result;
+    }
+  }
+
+  l_max = (l_max << 1) >> (6 - scal) as uint;
+
+  let mut l_power = 0i64;
+
+  for k in range(0u, 40) {
+    let l_temp = (dp[120 + k - nc] >> 3) as i64;
+
+    l_power += l_temp * l_temp;
+  }
+
+  l_power <<= 1;
+
+  if l_max <= 0 {
+    return (nc as i16, 0);
+  }
+
+  if l_max >= l_power {
+    return (nc as i16, 3);
+  }
+
+  let temp = norm(l_power as i32) as uint;
+  let r = ((l_max << temp) >> 16) as i16;
+  let s = ((l_power << temp) >> 16) as i16;
+
+  let mut bc = 0;
+
+  while bc < 3 && r > mult(s, DLB[bc]) {
+    bc += 1;
+  }
+
+  return (nc as i16, bc as i16);
+}
+
+/// Weights the residual `e` (with 5 samples of padding on either side),
+/// selects the RPE grid, and quantizes it into `xmc`. On return `e` holds
+/// the reconstructed residual. Returns the coded block maximum and grid
+/// position (4.2.13 to 4.2.18).
+fn rpe_encoding(e: &mut [i16, ..50], xmc: &mut [i16]) -> (i16, i16) {
+  let mut x = [0i16, ..40];
+
+  for k in range(0u, 40) {
+    let mut l_result = 4096i32;
+
+    for i in range(0u, 11) {
+      l_result += e[k + i] as i32 * H[i] as i32;
+    }
+
+    l_result >>= 13;
+
+    x[k] = std::cmp::max(MIN as i32, std::cmp::min(MAX as i32, l_result)) as i16;
+  }
+
+  let mut em = 0i32;
+  let mut mc = 0u;
+
+  for m in range(0u, 4) {
+    let mut l_result = 0i32;
+
+    for i in range(0u, 13) {
+      let l_temp = (x[m + 3 * i] >> 2) as i32;
+
+      l_result += l_temp * l_temp;
+    }
+
+    l_result <<= 1;
+
+    if l_result > em {
+      mc = m;
+      em = l_result;
+    }
+  }
+
+  let mut xm = [0i16, ..13];
+  let mut xmax = 0;
+
+  for i in range(0u, 13) {
+    xm[i] = x[mc + 3 * i];
+    xmax = std::cmp::max(xmax, abs(xm[i]));
+  }
+
+  let mut exp = 0i16;
+  let mut temp = xmax >> 9;
+  let mut itest = false;
+
+  for _ in range(0u, 6) {
+    itest = itest || temp <= 0;
+    temp >>= 1;
+
+    if !itest {
+      exp += 1;
+    }
+  }
+
+  let xmaxc = add(xmax >> (exp + 5) as uint, exp << 3);
+  let (exp, mant) = exp_mant(xmaxc);
+
+  for i in range(0u, 13) {
+    let temp = ((xm[i] as i32) << (6 - exp) as uint) as i16;
+
+    xmc[i] = (mult(temp, NRFAC[mant as uint]) >> 12) + 4;
+  }
+
+  let mut ep = [0i16, ..40];
+
+  rpe_positioning(mc as i16, xmc, mant, exp, ep);
+
+  for i in range(0u, 40) {
+    e[5 + i] = ep[i];
+  }
+
+  return (xmaxc, mc as i16);
+}
+
+fn rpe_decoding(xmaxc: i16, mc: i16, xmc: &[i16], erp: &mut [i16]) {
+  let (exp, mant) = exp_mant(xmaxc);
+
+  rpe_positioning(mc, xmc, mant, exp, erp);
+}
+
+/// Dequantizes the RPE pulses `xmc`, and places them on grid `mc` of `ep`
+/// (4.2.16 and 4.2.17).
+fn rpe_positioning(mc: i16, xmc: &[i16], mant: i16, exp: i16, ep: &mut [i16]) {
+  let temp1 = FAC[mant as uint];
+  let temp2 = sub(6, exp);
+  let temp3 = asl(1, sub(temp2, 1));
+
+  for k in range(0u, 40) {
+    ep[k] = 0;
+  }
+
+  for i in range(0u, 13) {
+    let temp = ((xmc[i] << 1) - 7) << 12;
+    let temp = add(mult_r(temp1, temp), temp3);
+
+    ep[mc as uint + 3 * i] = asr(temp, temp2);
+  }
+}
+
+/// Splits a coded block maximum into exponent and mantissa (4.2.15).
+fn exp_mant(xmaxc: i16) -> (i16, i16) {
+  let mut exp = if xmaxc > 15 { (xmaxc >> 3) - 1 } else { 0 };
+  let mut mant = xmaxc - (exp << 3);
+
+  if mant == 0 {
+    return (-4, 7);
+  }
+
+  while mant <= 7 {
+    mant = mant << 1 | 1;
+    exp -= 1;
+  }
+
+  return (exp, mant - 8);
+}
+
+fn saturate(x: i32) -> i16 {
+  return std::cmp::max(MIN as i32, std::cmp::min(MAX as i32, x)) as i16;
+}
+
+fn add(a: i16, b: i16) -> i16 {
+  return saturate(a as i32 + b as i32);
+}
+
+fn sub(a: i16, b: i16) -> i16 {
+  return saturate(a as i32 - b as i32);
+}
+
+fn mult(a: i16, b: i16) -> i16 {
+  if a == MIN && b == MIN {
+    return MAX;
+  }
+
+  return ((a as i32 * b as i32) >> 15) as i16;
+}
+
+fn mult_r(a: i16, b: i16) -> i16 {
+  if a == MIN && b == MIN {
+    return MAX;
+  }
+
+  return ((a as i32 * b as i32 + 16384) >> 15) as i16;
+}
+
+fn abs(a: i16) -> i16 {
+  return if a == MIN { MAX } else if a < 0 { -a } else { a };
+}
+
+fn l_add(a: i32, b: i32) -> i32 {
+  return std::cmp::max(std::i32::MIN as i64, std::cmp::min(std::i32::MAX as i64, a as i64 + b as i64)) as i32;
+}
+
+/// Returns the number of left shifts needed to normalize `a`, so that it
+/// lies in [0x40000000, 0x7FFFFFFF] or [-0x80000000, -0x40000000].
+fn norm(a: i32) -> i16 {
+  if a <= -0x40000000 {
+    return 0;
+  }
+
+  let mut a = if a < 0 { !a } else { a };
+  let mut n = 0;
+
+  while a != 0 && a < 0x40000000 {
+    a <<= 1;
+    n += 1;
+  }
+
+  return n;
+}
+
+/// Divides `num` by `denum` in Q15, with 0 <= num <= denum.
+fn div(num: i16, denum: i16) -> i16 {
+  if num == 0 {
+    return 0;
+  }
+
+  let mut l_num = num as i32;
+  let l_denum = denum as i32;
+  let mut result = 0i16;
+
+  for _ in range(0u, 15) {
+    result <<= 1;
+    l_num <<= 1;
+
+    if l_num >= l_denum {
+      l_num -= l_denum;
+      result += 1;
+    }
+  }
+
+  return result;
+}
+
+fn asr(a: i16, n: i16) -> i16 {
+  if n >= 16 {
+    return if a < 0 { -1 } else { 0 };
+  }
+
+  if n <= -16 {
+    return 0;
+  }
+
+  if n < 0 {
+    return a << (-n) as uint;
+  }
+
+  return a >> n as uint;
+}
+
+fn asl(a: i16, n: i16) -> i16 {
+  if n >= 16 {
+    return 0;
+  }
+
+  if n <= -16 {
+    return if a < 0 { -1 } else { 0 };
+  }
+
+  if n < 0 {
+    return asr(a, -n);
+  }
+
+  return a << n as uint;
+}
+
+#[cfg(test)]
+mod tests {
+  use std;
+
+  use channel;
+  use buffer;
+  use endian;
+  use sample;
+  use sample_type;
+
+  fn frame() -> super::Frame {
+    let mut frame = super::Frame::new();
+
+    frame.lar = [33, 17, 20, 9, 11, 4, 5, 2];
+
+    for k in range(0u, 4) {
+      frame.nc[k] = 40 + 20 * k as i16;
+      frame.bc[k] = k as i16;
+      frame.mc[k] = 3 - k as i16;
+      frame.xmaxc[k] = 60 - k as i16;
+    }
+
+    for i in range(0u, 52) {
+      frame.xmc[i] = (i % 8) as i16;
+    }
+
+    return frame;
+  }
+
+  fn round_trip(lsb_first: bool) {
+    let original = frame();
+    let mut data = [0u8, ..33];
+    let mut position = 0;
+
+    super::pack(&original, |n, value| super::put_bits(data, &mut position, n, value, lsb_first));
+
+    assert_eq!(position, 260);
+
+    position = 0;
+
+    let unpacked = super::unpack(|n| super::get_bits(data, &mut position, n, lsb_first));
+
+    assert_eq!(unpacked.lar.as_slice(), original.lar.as_slice());
+    assert_eq!(unpacked.nc.as_slice(), original.nc.as_slice());
+    assert_eq!(unpacked.bc.as_slice(), original.bc.as_slice());
+    assert_eq!(unpacked.mc.as_slice(), original.mc.as_slice());
+    assert_eq!(unpacked.xmaxc.as_slice(), original.xmaxc.as_slice());
+    assert_eq!(unpacked.xmc.as_slice(), original.xmc.as_slice());
+  }
+
+  #[test]
+  fn test_packing() {
+    round_trip(false);
+    round_trip(true);
+
+    let mut data = [0u8, ..2];
+    let mut position = 0;
+
+    super::put_bits(data, &mut position, 4, 0xD, false);
+    super::put_bits(data, &mut position, 6, 0x21, false);
+
+    assert_eq!(data.as_slice(), [0xD8u8, 0x40].as_slice());
+
+    let mut data = [0u8, ..2];
+    let mut position = 0;
+
+    super::put_bits(data, &mut position, 6, 0x21, true);
+    super::put_bits(data, &mut position, 6, 0x3F, true);
+
+    assert_eq!(data.as_slice(), [0xE1u8, 0x0F].as_slice());
+  }
+
+  #[test]
+  fn test_arithmetic() {
+    assert_eq!(super::norm(1), 30);
+    assert_eq!(super::norm(0x40000000), 0);
+    assert_eq!(super::norm(-2), 30);
+    assert_eq!(super::norm(-0x40000000), 0);
+
+    assert_eq!(super::div(1, 2), 16384);
+    assert_eq!(super::div(3, 4), 24576);
+
+    assert_eq!(super::mult_r(-32768, -32768), 32767);
+    assert_eq!(super::add(32000, 1000), 32767);
+    assert_eq!(super::sub(-32000, 1000), -32768);
+
+    assert_eq!(super::exp_mant(0), (-4, 7));
+    assert_eq!(super::exp_mant(63), (6, 7));
+  }
+
+  fn codec(packing: super::Packing, input: Vec<i16>) -> Vec<f64> {
+    let (mut sink, source) = channel::create::<::Audio>(1);
+    let (binary_sink, binary_source) = channel::create::<::Binary>(1);
+    let (audio_sink, mut audio_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() {
+      sink.write(|audio| {
+        audio.channels = 1;
+        audio.sample_rate = 8000.0;
+        audio.sample_type = sample_type::Signed(16);
+        audio.endian = endian::Little;
+
+        for &x in input.iter() {
+          audio.data.push(x as u8);
+          audio.data.push((x >> 8) as u8);
+        }
+
+        audio.last = true;
+      });
+    });
+
+    spawn(proc() { super::Encoder::new(source, binary_sink, packing).run(); });
+    spawn(proc() { super::Decoder::new(binary_source, audio_sink, packing).run(); });
+
+    let mut output = Vec::new();
+    let mut samples = Vec::new();
+    let mut last = false;
+
+    while !last {
+      audio_source.read(|audio| {
+        last = audio.last;
+
+        if audio.data.len() > 0 {
+          assert_eq!(audio.channels, 1);
+          assert_eq!(audio.sample_rate, 8000.0);
+
+          sample::decode(audio, &mut samples);
+          output.push_all(samples.as_slice());
+        }
+      });
+    }
+
+    return output;
+  }
+
+  #[test]
+  fn test_silence() {
+    let output = codec(super::Standard, Vec::from_elem(1000, 0i16));
+
+    assert_eq!(output.len(), 1120);
+    assert!(output.iter().all(|&x| x.abs() < 1e-2));
+  }
+
+  #[test]
+  fn test_sine() {
+    for &packing in [super::Standard, super::Wav49].iter() {
+      let input: Vec<i16> = range(0u, 4000).map(|i| {
+        (8000.0 * (2.0 * std::f64::consts::PI * 400.0 * i as f64 / 8000.0).sin()) as i16
+      }).collect();
+
+      let output = codec(packing, input.clone());
+
+      assert_eq!(output.len(), if packing == super::Standard { 4000 } else { 4160 });
+
+      let mut product = 0.0;
+      let mut input_energy = 0.0;
+      let mut output_energy = 0.0;
+
+      for i in range(800u, 3200) {
+        let x = input[i] as f64 / 32768.0;
+        let y = output[i];
+
+        product += x * y;
+        input_energy += x * x;
+        output_energy += y * y;
+      }
+
+      assert!(product / (input_energy * output_energy).sqrt() > 0.8);
+      assert!(output_energy > input_energy * 0.25 && output_energy < input_energy * 4.0);
+    }
+  }
+}
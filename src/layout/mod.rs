@@ -0,0 +1,241 @@
+/// Speaker positions, in the order of the WAVE_FORMAT_EXTENSIBLE channel
+/// mask bits. CoreAudio channel bitmaps use the same bits, and its channel
+/// labels 1 to 18 the same order.
+#[deriving(Clone,Show,PartialEq)]
+pub enum Speaker {
+  FrontLeft, FrontRight, FrontCenter, LowFrequency, BackLeft, BackRight,
+  FrontLeftOfCenter, FrontRightOfCenter, BackCenter, SideLeft, SideRight,
+  TopCenter, TopFrontLeft, TopFrontCenter, TopFrontRight, TopBackLeft,
+  TopBackCenter, TopBackRight
+}
+
+static SPEAKERS: [Speaker, ..18] = [
+  FrontLeft, FrontRight, FrontCenter, LowFrequency, BackLeft, BackRight,
+  FrontLeftOfCenter, FrontRightOfCenter, BackCenter, SideLeft, SideRight,
+  TopCenter, TopFrontLeft, TopFrontCenter, TopFrontRight, TopBackLeft,
+  TopBackCenter, TopBackRight
+];
+
+/// CoreAudio layout tags.
+pub static TAG_USE_CHANNEL_DESCRIPTIONS: u32 = 0;
+pub static TAG_USE_CHANNEL_BITMAP: u32 = 1 << 16;
+pub static TAG_MONO: u32 = (100 << 16) | 1;
+pub static TAG_STEREO: u32 = (101 << 16) | 2;
+pub static TAG_QUADRAPHONIC: u32 = (108 << 16) | 4;
+pub static TAG_MPEG_5_1_A: u32 = (121 << 16) | 6;
+pub static TAG_MPEG_7_1_C: u32 = (128 << 16) | 8;
+pub static TAG_DISCRETE_IN_ORDER: u32 = 147 << 16;
+
+impl Speaker {
+  /// The WAVE channel mask (and CoreAudio channel bitmap) bit.
+  pub fn bit(&self) -> u32 {
+    return 1 << (*self as uint);
+  }
+
+  /// The CoreAudio channel label.
+  pub fn label(&self) -> u32 {
+    return *self as u32 + 1;
+  }
+
+  /// Returns the speaker for a CoreAudio channel label, if it has one.
+  ///
+  /// The surround labels 5 and 6 are the back speakers, as in 5.1, so the
+  /// rear surround labels 33 and 34 that 7.1 adds are the side speakers.
+  pub fn from_label(label: u32) -> Option<Speaker> {
+    return match label {
+      l if l >= 1 && l <= 18 => Some(SPEAKERS[l as uint - 1]),
+      33 => Some(SideLeft),
+      34 => Some(SideRight),
+      42 => Some(FrontCenter),
+      _ => None
+    };
+  }
+}
+
+/// What the channels of an `Audio` stream are.
+#[deriving(Clone,Show,PartialEq)]
+pub enum Layout {
+  /// Nothing is known beyond the number of channels.
+  Unknown,
+
+  /// The channels are not speaker feeds, e.g. separate microphones.
+  Discrete,
+
+  /// Each channel feeds the speaker at the same index.
+  Speakers(Vec<Speaker>)
+}
+
+impl Layout {
+  pub fn mono() -> Layout {
+    return Speakers(vec![FrontCenter]);
+  }
+
+  pub fn stereo() -> Layout {
+    return Speakers(vec![FrontLeft, FrontRight]);
+  }
+
+  pub fn quadraphonic() -> Layout {
+    return Speakers(vec![FrontLeft, FrontRight, BackLeft, BackRight]);
+  }
+
+  pub fn surround51() -> Layout {
+    return Speakers(vec![FrontLeft, FrontRight, FrontCenter, LowFrequency, BackLeft, BackRight]);
+  }
+
+  pub fn surround71() -> Layout {
+    return Speakers(vec![FrontLeft, FrontRight, FrontCenter, LowFrequency, BackLeft, BackRight, SideLeft, SideRight]);
+  }
+
+  /// The usual layout for `channels` channels, or `Unknown` if there is
+  /// none.
+  pub fn default_for(channels: uint) -> Layout {
+    return match channels {
+      1 => Layout::mono(),
+      2 => Layout::stereo(),
+      4 => Layout::quadraphonic(),
+      6 => Layout::surround51(),
+      8 => Layout::surround71(),
+      _ => Unknown
+    };
+  }
+
+  /// Returns the layout for a WAVE channel mask, whose channels are in bit
+  /// order. A zero mask means no channel has a position.
+  pub fn from_mask(mask: u32) -> Layout {
+    if mask == 0 {
+      return Discrete;
+    }
+
+    return Speakers(SPEAKERS.iter().filter(|s| mask & s.bit() != 0).map(|&s| s).collect());
+  }
+
+  /// Returns the WAVE channel mask, if the layout can be expressed as one:
+  /// the speakers must be distinct and in bit order.
+  pub fn mask(&self) -> Option<u32> {
+    return match *self {
+      Unknown => None,
+      Discrete => Some(0),
+      Speakers(ref speakers) => {
+        let mut mask = 0u32;
+
+        for speaker in speakers.iter() {
+          if speaker.bit() <= mask {
+            return None;
+          }
+
+          mask |= speaker.bit();
+        }
+
+        Some(mask)
+      }
+    };
+  }
+
+  /// Returns the layout for a CoreAudio layout tag, with `bitmap` used by
+  /// `TAG_USE_CHANNEL_BITMAP`. Tags that need channel descriptions, or that
+  /// are not known, give `None`.
+  pub fn from_tag(tag: u32, bitmap: u32) -> Option<Layout> {
+    if tag == TAG_USE_CHANNEL_BITMAP {
+      return Some(Layout::from_mask(bitmap));
+    } else if tag == TAG_MONO {
+      return Some(Layout::mono());
+    } else if tag == TAG_STEREO {
+      return Some(Layout::stereo());
+    } else if tag == TAG_QUADRAPHONIC {
+      return Some(Layout::quadraphonic());
+    } else if tag == TAG_MPEG_5_1_A {
+      return Some(Layout::surround51());
+    } else if tag == TAG_MPEG_7_1_C {
+      // L R C LFE Ls Rs Rls Rrs, with labels as for `Speaker::from_label`.
+      return Some(Layout::surround71());
+    } else if tag & 0xFFFF0000 == TAG_DISCRETE_IN_ORDER {
+      return Some(Discrete);
+    }
+
+    return None;
+  }
+
+  /// Returns the CoreAudio layout tag and bitmap for `channels` channels.
+  /// Layouts without a tag give `TAG_USE_CHANNEL_DESCRIPTIONS`, and
+  /// `labels()` gives the channel descriptions; `Unknown` gives `None`.
+  pub fn tag(&self, channels: uint) -> Option<(u32, u32)> {
+    if *self == Layout::mono() {
+      return Some((TAG_MONO, 0));
+    } else if *self == Layout::stereo() {
+      return Some((TAG_STEREO, 0));
+    }
+
+    return match *self {
+      Unknown => None,
+      Discrete => Some((TAG_DISCRETE_IN_ORDER | channels as u32, 0)),
+      Speakers(_) => match self.mask() {
+        Some(mask) => Some((TAG_USE_CHANNEL_BITMAP, mask)),
+        None => Some((TAG_USE_CHANNEL_DESCRIPTIONS, 0))
+      }
+    };
+  }
+
+  /// The CoreAudio channel labels, one per channel.
+  pub fn labels(&self) -> Vec<u32> {
+    return match *self {
+      Speakers(ref speakers) => speakers.iter().map(|s| s.label()).collect(),
+      _ => Vec::new()
+    };
+  }
+
+  /// The number of channels the layout describes, if it is known.
+  pub fn channels(&self) -> Option<uint> {
+    return match *self {
+      Speakers(ref speakers) => Some(speakers.len()),
+      _ => None
+    };
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  #[test]
+  fn test_mask() {
+    assert_eq!(super::Layout::surround51().mask(), Some(0x3F));
+    assert_eq!(super::Layout::surround71().mask(), Some(0x63F));
+    assert_eq!(super::Layout::from_mask(0x60F), super::Speakers(vec![super::FrontLeft, super::FrontRight, super::FrontCenter, super::LowFrequency, super::SideLeft, super::SideRight]));
+    assert_eq!(super::Speakers(vec![super::FrontRight, super::FrontLeft]).mask(), None);
+    assert_eq!(super::Unknown.mask(), None);
+    assert_eq!(super::Discrete.mask(), Some(0));
+  }
+
+  #[test]
+  fn test_tag() {
+    assert_eq!(super::Layout::stereo().tag(2), Some((super::TAG_STEREO, 0)));
+    assert_eq!(super::Layout::surround51().tag(6), Some((super::TAG_USE_CHANNEL_BITMAP, 0x3F)));
+    assert_eq!(super::Discrete.tag(3), Some(((147 << 16) | 3, 0)));
+    assert_eq!(super::Speakers(vec![super::FrontRight, super::FrontLeft]).tag(2), Some((super::TAG_USE_CHANNEL_DESCRIPTIONS, 0)));
+
+    assert_eq!(super::Layout::from_tag(super::TAG_MPEG_5_1_A, 0), Some(super::Layout::surround51()));
+    assert_eq!(super::Layout::from_tag(super::TAG_USE_CHANNEL_BITMAP, 0x3), Some(super::Layout::stereo()));
+    assert_eq!(super::Layout::from_tag(super::TAG_DISCRETE_IN_ORDER | 16, 0), Some(super::Discrete));
+    assert_eq!(super::Layout::from_tag(super::TAG_USE_CHANNEL_DESCRIPTIONS, 0), None);
+  }
+
+  #[test]
+  fn test_label() {
+    assert_eq!(super::FrontLeft.label(), 1);
+    assert_eq!(super::SideLeft.label(), 10);
+    assert_eq!(super::Speaker::from_label(10), Some(super::SideLeft));
+    assert_eq!(super::Speaker::from_label(100), None);
+  }
+
+  #[test]
+  fn test_round_trip() {
+    // 7.1 as a tag, and as the labels L R C LFE Ls Rs Rls Rrs.
+    let layout = super::Layout::from_tag(super::TAG_MPEG_7_1_C, 0).unwrap();
+    let labels = [1u32, 2, 3, 4, 5, 6, 33, 34];
+
+    assert_eq!(super::Speakers(labels.iter().map(|&l| super::Speaker::from_label(l).unwrap()).collect()), layout);
+
+    let (tag, bitmap) = layout.tag(8).unwrap();
+
+    assert_eq!(super::Layout::from_tag(tag, bitmap), Some(layout.clone()));
+    assert_eq!(super::Speakers(layout.labels().iter().map(|&l| super::Speaker::from_label(l).unwrap()).collect()), layout);
+  }
+}
@@ -0,0 +1,552 @@
+use std;
+use std::comm::Sender;
+use std::f64::consts::PI;
+
+use channel;
+use gain;
+use layout;
+use sample;
+
+/// Loudness measured over a whole stream as in ITU-R BS.1770-4 and EBU
+/// R128. Loudness is in LUFS, ranges in LU and peaks in dBFS. Silent
+/// streams give an integrated loudness of negative infinity.
+#[deriving(Clone,Show,PartialEq)]
+pub struct Loudness {
+  pub integrated: f64,
+  pub range: f64,
+
+  /// The largest momentary (400 ms) and short-term (3 s) loudness.
+  pub momentary_max: f64,
+  pub short_term_max: f64,
+
+  pub sample_peak: f64,
+
+  /// The peak of the signal oversampled 4 times, in dBTP.
+  pub true_peak: f64
+}
+
+fn lufs(energy: f64) -> f64 {
+  return -0.691 + 10.0 * energy.log10();
+}
+
+fn db(x: f64) -> f64 {
+  return 20.0 * x.log10();
+}
+
+fn mean(energies: &[f64]) -> f64 {
+  return energies.iter().fold(0.0, |a, &e| a + e) / energies.len() as f64;
+}
+
+/// The integrated loudness of gating block energies, which may come from
+/// several meters to measure several streams as one.
+pub fn gated(blocks: &[f64]) -> f64 {
+  let absolute: Vec<f64> = blocks.iter().map(|&e| e).filter(|&e| lufs(e) > -70.0).collect();
+
+  if absolute.is_empty() {
+    return std::f64::NEG_INFINITY;
+  }
+
+  let threshold = lufs(mean(absolute.as_slice())) - 10.0;
+  let relative: Vec<f64> = absolute.iter().map(|&e| e).filter(|&e| lufs(e) > threshold).collect();
+
+  return lufs(mean(relative.as_slice()));
+}
+
+/// A direct form I biquad.
+struct Biquad {
+  b: [f64, ..3],
+  a: [f64, ..2],
+  x: [f64, ..2],
+  y: [f64, ..2]
+}
+
+impl Biquad {
+  fn new(b: [f64, ..3], a: [f64, ..2]) -> Biquad {
+    return Biquad { b: b, a: a, x: [0.0, 0.0], y: [0.0, 0.0] };
+  }
+
+  fn process(&mut self, x: f64) -> f64 {
+    let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1] - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+
+    self.x = [x, self.x[0]];
+    self.y = [y, self.y[0]];
+
+    return y;
+  }
+}
+
+/// The K-weighting pre-filter and RLB high-pass for `sample_rate`, with
+/// the analog prototypes of BS.1770 matched at any rate.
+fn k_weighting(sample_rate: f64) -> (Biquad, Biquad) {
+  let k = (PI * 1681.974450955533 / sample_rate).tan();
+  let q = 0.7071752369554196;
+  let vh = (10.0f64).powf(3.999843853973347 / 20.0);
+  let vb = vh.powf(0.4996667741545416);
+  let a0 = 1.0 + k / q + k * k;
+
+  let shelf = Biquad::new(
+    [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+    [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+
+  let k = (PI * 38.13547087602444 / sample_rate).tan();
+  let q = 0.5003270373238773;
+  let a0 = 1.0 + k / q + k * k;
+
+  let highpass = Biquad::new([1.0, -2.0, 1.0], [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+
+  return (shelf, highpass);
+}
+
+/// The weight of each channel: surround channels count 1.41 times, and the
+/// LFE not at all. Channels of an unknown layout are assumed to be in the
+/// usual order for their count.
+fn weights(channels: uint, layout: &layout::Layout) -> Vec<f64> {
+  let layout = match *layout {
+    layout::Unknown => layout::Layout::default_for(channels),
+    ref layout => layout.clone()
+  };
+
+  return match layout {
+    layout::Speakers(ref speakers) if speakers.len() == channels => speakers.iter().map(|&s| {
+      match s {
+        layout::LowFrequency => 0.0,
+        layout::BackLeft | layout::BackRight | layout::SideLeft | layout::SideRight => 1.41,
+        _ => 1.0
+      }
+    }).collect(),
+    _ => Vec::from_elem(channels, 1.0)
+  };
+}
+
+/// Taps either side of the sample being interpolated by the true peak
+/// oversampler.
+static TAPS: uint = 6;
+
+/// Finds the peak of one channel oversampled 4 times, with a Hann windowed
+/// sinc interpolator.
+pub struct TruePeak {
+  /// The last `2 * TAPS` samples, written twice so that they can be read
+  /// in order from `next` without wrapping.
+  history: Vec<f64>,
+  next: uint,
+  table: Vec<f64>,
+  peak: f64
+}
+
+impl TruePeak {
+  pub fn new() -> TruePeak {
+    let mut table = Vec::with_capacity(3 * 2 * TAPS);
+
+    for phase in range(1u, 4) {
+      for j in range(0, 2 * TAPS) {
+        let t = (TAPS - 1) as f64 + phase as f64 / 4.0 - j as f64;
+        let window = 0.5 * (1.0 + (PI * t / TAPS as f64).cos());
+
+        table.push(window * (PI * t).sin() / (PI * t));
+      }
+    }
+
+    return TruePeak { history: Vec::from_elem(4 * TAPS, 0.0), next: 0, table: table, peak: 0.0 };
+  }
+
+  /// Adds a sample, and returns the peak between the sample `delay()`
+  /// samples back and the one after it.
+  pub fn process(&mut self, x: f64) -> f64 {
+    let length = 2 * TAPS;
+
+    *self.history.get_mut(self.next) = x;
+    *self.history.get_mut(self.next + length) = x;
+    self.next = (self.next + 1) % length;
+
+    let history = self.history.slice(self.next, self.next + length);
+    let mut peak = history[TAPS - 1].abs();
+
+    for taps in self.table.as_slice().chunks(2 * TAPS) {
+      let y = taps.iter().zip(history.iter()).fold(0.0, |a, (&t, &x)| a + t * x);
+
+      peak = peak.max(y.abs());
+    }
+
+    self.peak = self.peak.max(peak);
+
+    return peak;
+  }
+
+  pub fn delay(&self) -> uint {
+    return TAPS;
+  }
+
+  /// The largest peak so far.
+  pub fn peak(&self) -> f64 {
+    return self.peak;
+  }
+
+  /// Interpolates the last samples.
+  fn flush(&mut self) {
+    for _ in range(0, TAPS) {
+      self.process(0.0);
+    }
+  }
+}
+
+/// Measures the loudness of interleaved samples.
+pub struct Meter {
+  channels: uint,
+  weights: Vec<f64>,
+  filters: Vec<(Biquad, Biquad)>,
+  peaks: Vec<TruePeak>,
+  sample_peak: f64,
+
+  /// Frames per 100 ms step, and the weighted energy of the current step.
+  step: uint,
+  frames: uint,
+  energy: f64,
+
+  /// The mean energy of each step, and of each 400 ms and 3 s block.
+  steps: Vec<f64>,
+  momentary: Vec<f64>,
+  short_term: Vec<f64>
+}
+
+impl Meter {
+  pub fn new(channels: uint, sample_rate: f64, layout: &layout::Layout) -> Meter {
+    if channels == 0 || sample_rate <= 0.0 {
+      panic!("loudness::Meter: Invalid channels or sample rate (ARGUMENT)");
+    }
+
+    return Meter {
+      channels: channels,
+      weights: weights(channels, layout),
+      filters: Vec::from_fn(channels, |_| k_weighting(sample_rate)),
+      peaks: Vec::from_fn(channels, |_| TruePeak::new()),
+      sample_peak: 0.0,
+      step: (sample_rate / 10.0).round() as uint,
+      frames: 0,
+      energy: 0.0,
+      steps: Vec::new(),
+      momentary: Vec::new(),
+      short_term: Vec::new()
+    };
+  }
+
+  /// Adds interleaved samples.
+  pub fn add(&mut self, samples: &[f64]) {
+    for frame in samples.chunks(self.channels) {
+      for (c, &x) in frame.iter().enumerate() {
+        let (ref mut shelf, ref mut highpass) = *self.filters.get_mut(c);
+        let y = highpass.process(shelf.process(x));
+
+        self.energy += self.weights[c] * y * y;
+        self.peaks.get_mut(c).process(x);
+        self.sample_peak = self.sample_peak.max(x.abs());
+      }
+
+      self.frames += 1;
+
+      if self.frames == self.step {
+        self.steps.push(self.energy / self.step as f64);
+        self.frames = 0;
+        self.energy = 0.0;
+
+        let steps = self.steps.as_slice();
+
+        if steps.len() >= 4 {
+          self.momentary.push(mean(steps.slice_from(steps.len() - 4)));
+        }
+
+        if steps.len() >= 30 {
+          self.short_term.push(mean(steps.slice_from(steps.len() - 30)));
+        }
+      }
+    }
+  }
+
+  /// The loudness of the last 400 ms.
+  pub fn momentary(&self) -> f64 {
+    return self.momentary.last().map_or(std::f64::NEG_INFINITY, |&e| lufs(e));
+  }
+
+  /// The loudness of the last 3 s.
+  pub fn short_term(&self) -> f64 {
+    return self.short_term.last().map_or(std::f64::NEG_INFINITY, |&e| lufs(e));
+  }
+
+  /// The gated loudness of everything added so far.
+  pub fn integrated(&self) -> f64 {
+    return gated(self.momentary.as_slice());
+  }
+
+  /// The energy of each 400 ms gating block so far, which `gated` combines
+  /// into an integrated loudness.
+  pub fn blocks(&self) -> &[f64] {
+    return self.momentary.as_slice();
+  }
+
+  /// The loudness range of everything added so far, as in EBU Tech 3342:
+  /// the spread between the 10th and 95th percentiles of the gated
+  /// short-term loudness.
+  pub fn range(&self) -> f64 {
+    let absolute: Vec<f64> = self.short_term.iter().map(|&e| e).filter(|&e| lufs(e) > -70.0).collect();
+
+    if absolute.is_empty() {
+      return 0.0;
+    }
+
+    let threshold = lufs(mean(absolute.as_slice())) - 20.0;
+    let mut loudness: Vec<f64> = absolute.iter().map(|&e| lufs(e)).filter(|&l| l > threshold).collect();
+
+    if loudness.is_empty() {
+      return 0.0;
+    }
+
+    loudness.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let last = (loudness.len() - 1) as f64;
+
+    return loudness[(last * 0.95).round() as uint] - loudness[(last * 0.1).round() as uint];
+  }
+
+  /// Finishes measuring, and returns the loudness of everything added.
+  pub fn finish(&mut self) -> Loudness {
+    for peak in self.peaks.iter_mut() {
+      peak.flush();
+    }
+
+    let true_peak = self.peaks.iter().fold(0.0, |a: f64, p| a.max(p.peak()));
+    let momentary_max = self.momentary.iter().fold(0.0, |a: f64, &e| a.max(e));
+    let short_term_max = self.short_term.iter().fold(0.0, |a: f64, &e| a.max(e));
+
+    return Loudness {
+      integrated: self.integrated(),
+      range: self.range(),
+      momentary_max: lufs(momentary_max),
+      short_term_max: lufs(short_term_max),
+      sample_peak: db(self.sample_peak),
+      true_peak: db(true_peak)
+    };
+  }
+}
+
+enum Report {
+  LoudnessReport(Sender<Loudness>),
+  MeterReport(Sender<Option<Meter>>)
+}
+
+/// Passes audio through unchanged while measuring its loudness, and sends
+/// the measurement to `report` at the end of the stream.
+pub struct Analyzer {
+  source: channel::Source<::Audio>,
+  sink: channel::Sink<::Audio>,
+  report: Report
+}
+
+impl Analyzer {
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, report: Sender<Loudness>) -> Analyzer {
+    return Analyzer { source: source, sink: sink, report: LoudnessReport(report) };
+  }
+
+  /// An analyzer that sends the finished meter instead, so that other
+  /// measures can be taken from its blocks. It sends `None` if the stream
+  /// held no audio.
+  pub fn with_meter(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, report: Sender<Option<Meter>>) -> Analyzer {
+    return Analyzer { source: source, sink: sink, report: MeterReport(report) };
+  }
+
+  pub fn run(&mut self) {
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    let mut meter: Option<Meter> = None;
+    let mut samples = Vec::new();
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        if !audio.data.is_empty() {
+          if meter.is_none() {
+            meter = Some(Meter::new(audio.channels, audio.sample_rate, &audio.layout));
+          }
+
+          sample::decode(audio, &mut samples);
+          meter.as_mut().unwrap().add(samples.as_slice());
+        }
+
+        sink.write(|out| {
+          out.channels = audio.channels;
+          out.layout = audio.layout.clone();
+          out.sample_rate = audio.sample_rate;
+          out.sample_type = audio.sample_type;
+          out.endian = audio.endian;
+          out.last = last;
+          out.data.push_all(audio.data.as_slice());
+        });
+      });
+    }
+
+    match self.report {
+      LoudnessReport(ref report) => {
+        let loudness = match meter {
+          Some(ref mut meter) => meter.finish(),
+          None => Loudness {
+            integrated: std::f64::NEG_INFINITY,
+            range: 0.0,
+            momentary_max: std::f64::NEG_INFINITY,
+            short_term_max: std::f64::NEG_INFINITY,
+            sample_peak: std::f64::NEG_INFINITY,
+            true_peak: std::f64::NEG_INFINITY
+          }
+        };
+
+        report.send(loudness);
+      },
+      MeterReport(ref report) => report.send(meter)
+    }
+  }
+}
+
+/// The gain in dB that brings audio measured as `loudness` to `target`
+/// LUFS, reduced if needed to keep its true peak at or below `ceiling`
+/// dBTP. Silence is left alone.
+pub fn normalization(loudness: &Loudness, target: f64, ceiling: f64) -> f64 {
+  if loudness.integrated == std::f64::NEG_INFINITY {
+    return 0.0;
+  }
+
+  return (target - loudness.integrated).min(ceiling - loudness.true_peak);
+}
+
+/// The second pass of loudness normalization: applies the gain given by
+/// `normalization` for a measurement made by a first pass with `Analyzer`.
+///
+/// The second pass must read the audio again. It cannot take the output of
+/// the `Analyzer`, which blocks on its sink before it sends its report.
+pub struct Normalizer {
+  gain: gain::Gain
+}
+
+impl Normalizer {
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, loudness: &Loudness, target: f64, ceiling: f64) -> Normalizer {
+    let automation = gain::Automation::new().gain(normalization(loudness, target, ceiling));
+
+    return Normalizer { gain: gain::Gain::new(source, sink, automation) };
+  }
+
+  pub fn run(&mut self) {
+    self.gain.run();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::comm;
+  use std::f64::consts::PI;
+
+  use channel;
+  use endian;
+  use layout;
+  use sample::testing;
+  use sample_type;
+
+  fn sine(frequency: f64, amplitude: f64, seconds: f64) -> Vec<f64> {
+    let frames = (48000.0 * seconds) as uint;
+
+    return Vec::from_fn(frames * 2, |i| amplitude * (2.0 * PI * frequency * (i / 2) as f64 / 48000.0).sin());
+  }
+
+  #[test]
+  fn test_sine() {
+    // A full scale 1 kHz sine in both channels of stereo is 0 LUFS, as
+    // K-weighting adds the 0.691 dB the loudness formula takes off at 1 kHz.
+    let mut meter = super::Meter::new(2, 48000.0, &layout::Layout::stereo());
+
+    meter.add(sine(1000.0, 1.0, 20.0).as_slice());
+
+    let loudness = meter.finish();
+
+    assert!(loudness.integrated.abs() < 0.05);
+    assert!(loudness.short_term_max.abs() < 0.05);
+    assert!(loudness.range < 0.1);
+    assert!(loudness.sample_peak.abs() < 0.01);
+  }
+
+  #[test]
+  fn test_gating() {
+    // Silence is gated out, so it does not lower the integrated loudness.
+    let mut meter = super::Meter::new(2, 48000.0, &layout::Layout::stereo());
+
+    meter.add(sine(1000.0, 0.1, 10.0).as_slice());
+    meter.add(Vec::from_elem(960000, 0.0).as_slice());
+
+    assert!((meter.integrated() + 20.0).abs() < 0.1);
+  }
+
+  #[test]
+  fn test_range() {
+    // EBU Tech 3342 case 1: 20 s at -20 dBFS then 20 s at -30 dBFS.
+    let mut meter = super::Meter::new(2, 48000.0, &layout::Layout::stereo());
+
+    meter.add(sine(1000.0, 0.1, 20.0).as_slice());
+    meter.add(sine(1000.0, 0.1 / 10.0f64.sqrt(), 20.0).as_slice());
+
+    assert!((meter.range() - 10.0).abs() < 0.1);
+  }
+
+  #[test]
+  fn test_true_peak() {
+    // A sine at a quarter of the sample rate, sampled 45 degrees off its
+    // peaks, has samples at -3 dBFS but a true peak of 0 dBTP.
+    let mut meter = super::Meter::new(1, 48000.0, &layout::Layout::mono());
+    let samples = Vec::from_fn(48000, |i| (PI / 2.0 * i as f64 + PI / 4.0).sin());
+
+    meter.add(samples.as_slice());
+
+    let loudness = meter.finish();
+
+    assert!((loudness.sample_peak + 3.01).abs() < 0.01);
+    assert!(loudness.true_peak.abs() < 0.1);
+  }
+
+  #[test]
+  fn test_weights() {
+    assert_eq!(super::weights(6, &layout::Unknown), vec![1.0, 1.0, 1.0, 0.0, 1.41, 1.41]);
+    assert_eq!(super::weights(3, &layout::Unknown), vec![1.0, 1.0, 1.0]);
+  }
+
+  #[test]
+  fn test_normalizer() {
+    // The Analyzer only reports at the end of the stream, so the second
+    // pass reads the audio again.
+    let input = sine(1000.0, 0.1, 5.0);
+
+    let source = testing::source(input.clone(), 4800, 2, 48000.0, sample_type::Float(32), endian::Big);
+    let (analyzed_sink, mut analyzed_source) = channel::create::<::Audio>(1);
+    let (report, measured) = comm::channel();
+
+    spawn(proc() {
+      super::Analyzer::new(source, analyzed_sink, report).run();
+    });
+
+    testing::collect(&mut analyzed_source);
+
+    let loudness = measured.recv();
+
+    assert!((loudness.integrated + 20.0).abs() < 0.05);
+
+    let source = testing::source(input, 4800, 2, 48000.0, sample_type::Float(32), endian::Big);
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() {
+      super::Normalizer::new(source, out_sink, &loudness, -23.0, -1.0).run();
+    });
+
+    let mut meter = super::Meter::new(2, 48000.0, &layout::Layout::stereo());
+
+    meter.add(testing::collect(&mut out_source).as_slice());
+
+    assert!((meter.integrated() + 23.0).abs() < 0.05);
+  }
+
+}
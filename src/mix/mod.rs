@@ -0,0 +1,332 @@
+use std::f64::consts::FRAC_1_SQRT_2;
+
+use channel;
+use layout;
+use sample;
+
+/// A mixing matrix, giving the gain from each input channel to each output
+/// channel.
+///
+/// Multichannel presets use the WAVE channel order: L, R, C, LFE, then the
+/// surround channels (Ls, Rs for 5.1; Lb, Rb, Ls, Rs for 7.1).
+#[deriving(Clone,Show,PartialEq)]
+pub struct Matrix {
+  inputs: uint,
+  outputs: uint,
+
+  /// Gains by output channel, then input channel.
+  gains: Vec<f64>,
+
+  /// The layout of the output, for presets.
+  layout: layout::Layout
+}
+
+impl Matrix {
+  /// Creates a matrix from `gains`, with one row of `inputs` gains per
+  /// output channel.
+  pub fn new(inputs: uint, outputs: uint, gains: Vec<f64>) -> Matrix {
+    if inputs == 0 || outputs == 0 {
+      panic!("mix::Matrix: Channel counts must be positive (ARGUMENT)");
+    }
+
+    if gains.len() != inputs * outputs {
+      panic!("mix::Matrix: Expected {} gains, got {} (ARGUMENT)", inputs * outputs, gains.len());
+    }
+
+    return Matrix { inputs: inputs, outputs: outputs, gains: gains, layout: layout::Unknown };
+  }
+
+  /// Returns the matrix, with its output declared to have `layout`.
+  pub fn with_layout(mut self, layout: layout::Layout) -> Matrix {
+    self.layout = layout;
+
+    return self;
+  }
+
+  /// The identity matrix, which passes `channels` channels through.
+  pub fn identity(channels: uint) -> Matrix {
+    return Matrix::new(channels, channels, Vec::from_fn(channels * channels, |i| {
+      if i / channels == i % channels { 1.0 } else { 0.0 }
+    }));
+  }
+
+  /// Builds a matrix that takes output channel `i` from input channel
+  /// `channels[i]`, which reorders, duplicates or extracts channels.
+  pub fn select(inputs: uint, channels: &[uint]) -> Matrix {
+    let mut gains = Vec::from_elem(inputs * channels.len(), 0.0);
+
+    for (i, &c) in channels.iter().enumerate() {
+      if c >= inputs {
+        panic!("mix::Matrix: Channel {} out of range for {} inputs (ARGUMENT)", c, inputs);
+      }
+
+      *gains.get_mut(i * inputs + c) = 1.0;
+    }
+
+    return Matrix::new(inputs, channels.len(), gains);
+  }
+
+  /// Copies mono to both stereo channels.
+  pub fn mono_to_stereo() -> Matrix {
+    return Matrix::new(1, 2, vec![1.0, 1.0]).with_layout(layout::Layout::stereo());
+  }
+
+  /// Averages the stereo channels.
+  pub fn stereo_to_mono() -> Matrix {
+    return Matrix::new(2, 1, vec![0.5, 0.5]).with_layout(layout::Layout::mono());
+  }
+
+  /// Downmixes 5.1 to stereo as in ITU-R BS.775: the center and surround
+  /// channels are added at -3 dB, and the LFE is dropped.
+  pub fn surround51_to_stereo() -> Matrix {
+    let k = FRAC_1_SQRT_2;
+
+    return Matrix::new(6, 2, vec![
+      1.0, 0.0, k, 0.0, k, 0.0,
+      0.0, 1.0, k, 0.0, 0.0, k
+    ]).with_layout(layout::Layout::stereo());
+  }
+
+  /// Downmixes 7.1 to 5.1, folding each back channel into the surround
+  /// channel on its side at equal power.
+  pub fn surround71_to_51() -> Matrix {
+    let k = FRAC_1_SQRT_2;
+
+    return Matrix::new(8, 6, vec![
+      1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+      0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+      0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+      0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0,
+      0.0, 0.0, 0.0, 0.0, k, 0.0, k, 0.0,
+      0.0, 0.0, 0.0, 0.0, 0.0, k, 0.0, k
+    ]).with_layout(layout::Layout::surround51());
+  }
+
+  /// The matrix usually used to go from `inputs` to `outputs` channels: a
+  /// preset where there is one, and otherwise one that keeps the channels
+  /// both have and leaves any others silent.
+  pub fn convert(inputs: uint, outputs: uint) -> Matrix {
+    return match (inputs, outputs) {
+      (i, o) if i == o => Matrix::identity(i),
+      (1, 2) => Matrix::mono_to_stereo(),
+      (2, 1) => Matrix::stereo_to_mono(),
+      (6, 2) => Matrix::surround51_to_stereo(),
+      (8, 6) => Matrix::surround71_to_51(),
+      _ => Matrix::new(inputs, outputs, Vec::from_fn(inputs * outputs, |i| {
+        if i / inputs == i % inputs { 1.0 } else { 0.0 }
+      }))
+    };
+  }
+
+  pub fn inputs(&self) -> uint {
+    return self.inputs;
+  }
+
+  pub fn outputs(&self) -> uint {
+    return self.outputs;
+  }
+
+  pub fn gain(&self, output: uint, input: uint) -> f64 {
+    return self.gains[output * self.inputs + input];
+  }
+
+  /// Returns the matrix scaled so that no output can exceed full scale when
+  /// the inputs do not.
+  pub fn normalized(&self) -> Matrix {
+    let mut largest = 0.0f64;
+
+    for row in self.gains.as_slice().chunks(self.inputs) {
+      largest = largest.max(row.iter().fold(0.0, |a, &g| a + g.abs()));
+    }
+
+    if largest <= 1.0 {
+      return self.clone();
+    }
+
+    return Matrix::new(self.inputs, self.outputs, self.gains.iter().map(|&g| g / largest).collect()).with_layout(self.layout.clone());
+  }
+
+  /// Returns the layout of the output, for input with layout `input`.
+  /// Presets have a fixed layout, and matrices that only select channels
+  /// keep the positions of the channels they select.
+  pub fn output_layout(&self, input: &layout::Layout) -> layout::Layout {
+    if self.layout != layout::Unknown {
+      return self.layout.clone();
+    }
+
+    let mut selected = Vec::with_capacity(self.outputs);
+
+    for row in self.gains.as_slice().chunks(self.inputs) {
+      let ones = row.iter().filter(|&&g| g == 1.0).count();
+      let zeros = row.iter().filter(|&&g| g == 0.0).count();
+
+      if ones != 1 || zeros != self.inputs - 1 {
+        return layout::Unknown;
+      }
+
+      selected.push(row.iter().position(|&g| g == 1.0).unwrap());
+    }
+
+    return match *input {
+      layout::Speakers(ref speakers) if speakers.len() == self.inputs => {
+        layout::Speakers(selected.iter().map(|&i| speakers[i]).collect())
+      },
+      layout::Discrete => layout::Discrete,
+      _ => layout::Unknown
+    };
+  }
+
+  /// Mixes interleaved `input` frames into `output`, which is replaced.
+  pub fn apply(&self, input: &[f64], output: &mut Vec<f64>) {
+    let frames = input.len() / self.inputs;
+
+    output.truncate(0);
+    output.reserve(frames * self.outputs);
+
+    for frame in input.chunks(self.inputs).take(frames) {
+      for row in self.gains.as_slice().chunks(self.inputs) {
+        output.push(row.iter().zip(frame.iter()).fold(0.0, |a, (&g, &x)| a + g * x));
+      }
+    }
+  }
+}
+
+/// Changes the number of channels of audio by applying a mixing matrix.
+///
+/// Integer output is clipped if the matrix has gains above unity.
+pub struct Remixer {
+  source: channel::Source<::Audio>,
+  sink: channel::Sink<::Audio>,
+  matrix: Matrix
+}
+
+impl Remixer {
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, matrix: Matrix) -> Remixer {
+    return Remixer { source: source, sink: sink, matrix: matrix };
+  }
+
+  pub fn run(&mut self) {
+    let matrix = &self.matrix;
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    let mut input = Vec::new();
+    let mut output = Vec::new();
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        if audio.channels != matrix.inputs && !audio.data.is_empty() {
+          panic!("mix::Remixer: Expected {} channels, got {} (INPUT)", matrix.inputs, audio.channels);
+        }
+
+        output.truncate(0);
+
+        if !audio.data.is_empty() {
+          sample::decode(audio, &mut input);
+          matrix.apply(input.as_slice(), &mut output);
+        }
+
+        sink.write(|out| {
+          out.channels = matrix.outputs;
+          out.layout = matrix.output_layout(&audio.layout);
+          out.sample_rate = audio.sample_rate;
+          out.sample_type = audio.sample_type;
+          out.endian = audio.endian;
+          out.last = last;
+
+          if output.len() > 0 {
+            sample::encode(output.as_slice(), out);
+          }
+        });
+      });
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::f64::consts::FRAC_1_SQRT_2;
+
+  use channel;
+  use endian;
+  use layout;
+  use sample;
+  use sample_type;
+
+  #[test]
+  fn test_apply() {
+    let mut output = Vec::new();
+
+    super::Matrix::stereo_to_mono().apply([1.0, 0.0, 0.5, 0.5], &mut output);
+    assert_eq!(output, vec![0.5, 0.5]);
+
+    super::Matrix::select(4, [2, 0]).apply([0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8], &mut output);
+    assert_eq!(output, vec![0.3, 0.1, 0.7, 0.5]);
+
+    super::Matrix::surround51_to_stereo().apply([0.0, 0.0, 1.0, 1.0, 0.0, 0.5], &mut output);
+    assert_eq!(output, vec![FRAC_1_SQRT_2, FRAC_1_SQRT_2 * 1.5]);
+  }
+
+  #[test]
+  fn test_normalized() {
+    let matrix = super::Matrix::surround51_to_stereo().normalized();
+    let sum = range(0u, 6).fold(0.0, |a, i| a + matrix.gain(0, i));
+
+    assert!((sum - 1.0).abs() < 1e-12);
+    assert_eq!(super::Matrix::stereo_to_mono().normalized(), super::Matrix::stereo_to_mono());
+  }
+
+  #[test]
+  fn test_output_layout() {
+    let surround = layout::Layout::surround51();
+
+    assert_eq!(super::Matrix::surround51_to_stereo().output_layout(&surround), layout::Layout::stereo());
+    assert_eq!(super::Matrix::select(6, [3, 2]).output_layout(&surround), layout::Speakers(vec![layout::LowFrequency, layout::FrontCenter]));
+    assert_eq!(super::Matrix::select(6, [3]).output_layout(&layout::Discrete), layout::Discrete);
+    assert_eq!(super::Matrix::new(2, 1, vec![0.3, 0.7]).output_layout(&layout::Layout::stereo()), layout::Unknown);
+  }
+
+  #[test]
+  #[should_fail]
+  fn test_select_range() {
+    super::Matrix::select(2, [0, 2]);
+  }
+
+  #[test]
+  fn test_remixer() {
+    let (mut sink, source) = channel::create::<::Audio>(1);
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() {
+      super::Remixer::new(source, out_sink, super::Matrix::select(16, [3])).run();
+    });
+
+    spawn(proc() {
+      sink.write(|audio| {
+        audio.channels = 16;
+        audio.sample_rate = 48000.0;
+        audio.sample_type = sample_type::Signed(24);
+        audio.endian = endian::Little;
+        audio.last = true;
+
+        let input = Vec::from_fn(32, |i| (i as f64) / 64.0);
+
+        sample::encode(input.as_slice(), audio);
+      });
+    });
+
+    out_source.read(|audio| {
+      let mut output = Vec::new();
+
+      sample::decode(audio, &mut output);
+
+      assert_eq!(audio.channels, 1);
+      assert_eq!(audio.sample_type, sample_type::Signed(24));
+      assert_eq!(output, vec![3.0 / 64.0, 19.0 / 64.0]);
+    });
+  }
+}
@@ -0,0 +1,277 @@
+use std;
+use std::f64::consts::FRAC_PI_4;
+
+use channel;
+use endian;
+use layout;
+use mix;
+use sample;
+use sample_type;
+
+/// Where and how loud an input is in the mix.
+#[deriving(Clone,Show,PartialEq)]
+pub struct Placement {
+  /// The output frame at which the input starts.
+  pub offset: uint,
+
+  /// The linear gain.
+  pub gain: f64,
+
+  /// From -1 (left) through 0 (center) to 1 (right), for stereo output.
+  pub pan: f64
+}
+
+impl Placement {
+  /// An input starting at the first frame, at unity gain, panned center.
+  pub fn new() -> Placement {
+    return Placement { offset: 0, gain: 1.0, pan: 0.0 };
+  }
+
+  /// Returns the placement, starting at output frame `offset`.
+  pub fn offset(mut self, offset: uint) -> Placement {
+    self.offset = offset;
+
+    return self;
+  }
+
+  /// Returns the placement, with a gain of `db` dB added.
+  pub fn gain(mut self, db: f64) -> Placement {
+    self.gain *= (10.0f64).powf(db / 20.0);
+
+    return self;
+  }
+
+  pub fn pan(mut self, pan: f64) -> Placement {
+    if !(pan >= -1.0 && pan <= 1.0) {
+      panic!("mixer::Placement: Pan must be between -1 and 1 (ARGUMENT)");
+    }
+
+    self.pan = pan;
+
+    return self;
+  }
+
+  /// The matrix taking `inputs` channels to `outputs` channels with this
+  /// gain and pan. Mono is panned to stereo at equal power, and stereo is
+  /// balanced by turning down the opposite side. Pan is ignored for other
+  /// channel counts.
+  pub fn matrix(&self, inputs: uint, outputs: uint) -> mix::Matrix {
+    let angle = (self.pan + 1.0) * FRAC_PI_4;
+
+    let gains = match (inputs, outputs) {
+      (1, 2) => vec![angle.cos(), angle.sin()],
+      (2, 2) => vec![(1.0 - self.pan).min(1.0), 0.0, 0.0, (1.0 + self.pan).min(1.0)],
+      _ => {
+        let matrix = mix::Matrix::convert(inputs, outputs);
+
+        Vec::from_fn(inputs * outputs, |i| matrix.gain(i / inputs, i % inputs))
+      }
+    };
+
+    return mix::Matrix::new(inputs, outputs, gains.iter().map(|&g| g * self.gain).collect());
+  }
+}
+
+/// An input with its audio so far, mixed to the output channels.
+struct Track {
+  source: channel::Source<::Audio>,
+  placement: Placement,
+  matrix: Option<mix::Matrix>,
+
+  /// Output frames from output frame `start`.
+  buffer: Vec<f64>,
+  start: uint,
+
+  finished: bool
+}
+
+impl Track {
+  /// The output frame after the last one received.
+  fn end(&self, channels: uint) -> uint {
+    return self.start + self.buffer.len() / channels;
+  }
+}
+
+/// Sums inputs, each placed at an offset with a gain and pan, into one
+/// float stream with `channels` channels.
+///
+/// Inputs are read as the output needs them, so their packets may be of
+/// any size. The output continues until every input has ended, and inputs
+/// must share a sample rate. Inputs with other channel counts are remixed
+/// with `mix::Matrix::convert`.
+pub struct Mixer {
+  tracks: Vec<Track>,
+  sink: channel::Sink<::Audio>,
+  channels: uint
+}
+
+impl Mixer {
+  pub fn new(inputs: Vec<(channel::Source<::Audio>, Placement)>, sink: channel::Sink<::Audio>, channels: uint) -> Mixer {
+    if inputs.is_empty() || channels == 0 {
+      panic!("mixer::Mixer: Inputs and channels are required (ARGUMENT)");
+    }
+
+    let tracks = inputs.into_iter().map(|(source, placement)| {
+      Track { source: source, start: placement.offset, placement: placement, matrix: None, buffer: Vec::new(), finished: false }
+    }).collect();
+
+    return Mixer { tracks: tracks, sink: sink, channels: channels };
+  }
+
+  pub fn run(&mut self) {
+    let channels = self.channels;
+    let tracks = &mut self.tracks;
+    let sink = &mut self.sink;
+
+    let mut sample_rate = 0.0;
+    let mut samples = Vec::new();
+    let mut mixed = Vec::new();
+    let mut output = Vec::new();
+
+    // The next output frame.
+    let mut position = 0;
+
+    loop {
+      // The unfinished track that has received the least, which limits the
+      // output.
+      let mut limit = None;
+
+      for (i, track) in tracks.iter().enumerate() {
+        if !track.finished && limit.map_or(true, |(_, end)| track.end(channels) < end) {
+          limit = Some((i, track.end(channels)));
+        }
+      }
+
+      match limit {
+        Some((i, end)) if end <= position => {
+          let track = tracks.get_mut(i);
+          let mut finished = false;
+
+          {
+            let buffer = &mut track.buffer;
+            let matrix = &mut track.matrix;
+            let placement = &track.placement;
+
+            track.source.read(|audio| {
+              finished = audio.last;
+
+              if audio.data.is_empty() {
+                return;
+              }
+
+              if sample_rate == 0.0 {
+                sample_rate = audio.sample_rate;
+              } else if audio.sample_rate != sample_rate {
+                panic!("mixer::Mixer: Input {} has sample rate {}, expected {} (INPUT)", i, audio.sample_rate, sample_rate);
+              }
+
+              if matrix.as_ref().map_or(true, |m| m.inputs() != audio.channels) {
+                *matrix = Some(placement.matrix(audio.channels, channels));
+              }
+
+              sample::decode(audio, &mut samples);
+              matrix.as_ref().unwrap().apply(samples.as_slice(), &mut mixed);
+
+              buffer.push_all(mixed.as_slice());
+            });
+          }
+
+          track.finished = finished;
+
+          continue;
+        },
+        _ => ()
+      }
+
+      let last = limit.is_none();
+
+      let ready = match limit {
+        Some((_, end)) => end,
+        None => tracks.iter().fold(position, |a, t| std::cmp::max(a, t.end(channels)))
+      };
+
+      output.truncate(0);
+      output.grow((ready - position) * channels, 0.0);
+
+      for track in tracks.iter_mut() {
+        let from = std::cmp::max(track.start, position);
+        let to = std::cmp::min(track.end(channels), ready);
+
+        for frame in range(from, to) {
+          for c in range(0, channels) {
+            *output.get_mut((frame - position) * channels + c) += track.buffer[(frame - track.start) * channels + c];
+          }
+        }
+
+        if track.start < ready {
+          let count = std::cmp::min(ready - track.start, track.buffer.len() / channels);
+          let rest = track.buffer.slice_from(count * channels).to_vec();
+
+          track.buffer = rest;
+          track.start += count;
+        }
+      }
+
+      position = ready;
+
+      sink.write(|out| {
+        out.channels = channels;
+        out.layout = layout::Layout::default_for(channels);
+        out.sample_rate = sample_rate;
+        out.sample_type = sample_type::Float(32);
+        out.endian = endian::Big;
+        out.last = last;
+
+        if !output.is_empty() {
+          sample::encode(output.as_slice(), out);
+        }
+      });
+
+      if last {
+        break;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use channel;
+  use endian;
+  use sample::testing;
+  use sample_type;
+
+  fn source(channels: uint, packet: uint, input: Vec<f64>) -> channel::Source<::Audio> {
+    return testing::source(input, packet, channels, 10.0, sample_type::Signed(16), endian::Little);
+  }
+
+  #[test]
+  fn test_matrix() {
+    let matrix = super::Placement::new().pan(-1.0).matrix(1, 2);
+
+    assert_eq!(matrix.gain(0, 0), 1.0);
+    assert!(matrix.gain(1, 0).abs() < 1e-12);
+
+    let matrix = super::Placement::new().gain(-6.0).pan(0.5).matrix(2, 2);
+
+    assert!((matrix.gain(0, 0) - 0.5 * 0.501187).abs() < 1e-6);
+    assert!((matrix.gain(1, 1) - 0.501187).abs() < 1e-6);
+  }
+
+  #[test]
+  fn test_mixer() {
+    let inputs = vec![
+      (source(1, 3, vec![0.5, 0.5, 0.5, 0.5, 0.5, 0.5, 0.5]), super::Placement::new()),
+      (source(1, 2, vec![0.25, 0.25, 0.25, 0.25, 0.25]), super::Placement::new().offset(4))
+    ];
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() {
+      super::Mixer::new(inputs, out_sink, 1).run();
+    });
+
+    let output = testing::collect_with(&mut out_source, |audio| assert_eq!(audio.sample_type, sample_type::Float(32)));
+
+    assert_eq!(output, vec![0.5, 0.5, 0.5, 0.5, 0.75, 0.75, 0.75, 0.25, 0.25]);
+  }
+}
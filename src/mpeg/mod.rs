@@ -0,0 +1,789 @@
+use std;
+use std::f64::consts::PI;
+
+use channel;
+use crc;
+use endian;
+use layout;
+use sample;
+use sample_type;
+use stream;
+
+mod tables;
+
+#[deriving(Show,PartialEq)]
+pub enum Version {
+  Mpeg1, Mpeg2, Mpeg25
+}
+
+#[deriving(Show,PartialEq)]
+pub enum Mode {
+  Stereo, JointStereo, DualChannel, Mono
+}
+
+static BIT_RATES: [[[uint, ..15], ..3], ..2] = [
+  [
+    [0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448],
+    [0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384],
+    [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320]
+  ],
+  [
+    [0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160]
+  ]
+];
+
+static SAMPLE_RATES: [uint, ..3] = [44100, 48000, 32000];
+
+/// The header of an MPEG audio frame, shared by all layers.
+#[deriving(Show,PartialEq)]
+pub struct Header {
+  pub version: Version,
+  pub layer: uint,
+  pub protected: bool,
+  pub bit_rate: uint,
+  pub sample_rate: uint,
+  pub padding: bool,
+  pub mode: Mode,
+  pub mode_extension: uint,
+  pub copyright: bool,
+  pub original: bool,
+  pub emphasis: uint
+}
+
+impl Header {
+  /// Parses the 32-bit big endian word at the start of a frame. Returns
+  /// `None` if there is no sync word, or if a field is invalid. Free format
+  /// streams are not supported.
+  pub fn parse(word: u32) -> Option<Header> {
+    if word >> 21 != 0x7FF {
+      return None;
+    }
+
+    let version = match (word >> 19) & 3 {
+      0 => Mpeg25,
+      2 => Mpeg2,
+      3 => Mpeg1,
+      _ => return None
+    };
+
+    let layer = match (word >> 17) & 3 {
+      0 => return None,
+      n => 4 - n as uint
+    };
+
+    let bit_rate_index = ((word >> 12) & 15) as uint;
+    let sample_rate_index = ((word >> 10) & 3) as uint;
+
+    if bit_rate_index == 0 || bit_rate_index == 15 || sample_rate_index == 3 {
+      return None;
+    }
+
+    let bit_rate = BIT_RATES[if version == Mpeg1 { 0 } else { 1 }][layer - 1][bit_rate_index];
+
+    let sample_rate = match version {
+      Mpeg1 => SAMPLE_RATES[sample_rate_index],
+      Mpeg2 => SAMPLE_RATES[sample_rate_index] / 2,
+      Mpeg25 => SAMPLE_RATES[sample_rate_index] / 4
+    };
+
+    let mode = match (word >> 6) & 3 {
+      0 => Stereo,
+      1 => JointStereo,
+      2 => DualChannel,
+      _ => Mono
+    };
+
+    return Some(Header {
+      version: version,
+      layer: layer,
+      protected: (word >> 16) & 1 == 0,
+      bit_rate: bit_rate,
+      sample_rate: sample_rate,
+      padding: (word >> 9) & 1 == 1,
+      mode: mode,
+      mode_extension: ((word >> 4) & 3) as uint,
+      copyright: (word >> 3) & 1 == 1,
+      original: (word >> 2) & 1 == 1,
+      emphasis: (word & 3) as uint
+    });
+  }
+
+  pub fn channels(&self) -> uint {
+    return if self.mode == Mono { 1 } else { 2 };
+  }
+
+  /// The number of samples per channel in the frame.
+  pub fn samples(&self) -> uint {
+    return match self.layer {
+      1 => 384,
+      2 => 1152,
+      _ => if self.version == Mpeg1 { 1152 } else { 576 }
+    };
+  }
+
+  /// The size of the frame in bytes, including the header.
+  pub fn frame_size(&self) -> uint {
+    let padding = if self.padding { 1 } else { 0 };
+
+    return match self.layer {
+      1 => (12000 * self.bit_rate / self.sample_rate + padding) * 4,
+      _ => self.samples() / 8 * 1000 * self.bit_rate / self.sample_rate + padding
+    };
+  }
+}
+
+/// Quantization classes of Layer II, as (levels, bits, grouped).
+static CLASSES: [(uint, uint, bool), ..17] = [
+  (3, 5, true), (5, 7, true), (7, 3, false), (9, 10, true), (15, 4, false), (31, 5, false), (63, 6, false),
+  (127, 7, false), (255, 8, false), (511, 9, false), (1023, 10, false), (2047, 11, false), (4095, 12, false),
+  (8191, 13, false), (16383, 14, false), (32767, 15, false), (65535, 16, false)
+];
+
+static ALLOCATION_0: &'static [u8] = &[0, 2, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+static ALLOCATION_1: &'static [u8] = &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 16];
+static ALLOCATION_2: &'static [u8] = &[0, 1, 2, 3, 4, 5, 16];
+static ALLOCATION_3: &'static [u8] = &[0, 1, 16];
+static ALLOCATION_4: &'static [u8] = &[0, 1, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+static ALLOCATION_5: &'static [u8] = &[0, 1, 3, 4, 5, 6, 7];
+static ALLOCATION_6: &'static [u8] = &[0, 1, 3];
+
+/// Selects the Layer II allocation table (B.2a to B.2d, and the MPEG-2 low
+/// sample rate table) for a frame, and returns it with its subband limit.
+fn allocation_table(header: &Header) -> (uint, uint) {
+  if header.version != Mpeg1 {
+    return (4, 30);
+  }
+
+  let bit_rate = header.bit_rate / header.channels();
+
+  if (header.sample_rate == 48000 && bit_rate >= 56) || (bit_rate >= 56 && bit_rate <= 80) {
+    return (0, 27);
+  } else if header.sample_rate != 48000 && bit_rate >= 96 {
+    return (1, 30);
+  } else if header.sample_rate != 32000 && bit_rate <= 48 {
+    return (2, 8);
+  } else {
+    return (3, 12);
+  }
+}
+
+/// Returns the number of allocation bits of subband `sb` in `table`, and the
+/// quantization class for each allocation value above zero.
+fn allocation(table: uint, sb: uint) -> (uint, &'static [u8]) {
+  return match table {
+    0 | 1 => {
+      if sb < 3 { (4, ALLOCATION_0) } else if sb < 11 { (4, ALLOCATION_1) } else if sb < 23 { (3, ALLOCATION_2) } else { (2, ALLOCATION_3) }
+    }
+    2 | 3 => {
+      if sb < 2 { (4, ALLOCATION_4) } else { (3, ALLOCATION_5) }
+    }
+    _ => {
+      if sb < 4 { (4, ALLOCATION_4) } else if sb < 11 { (3, ALLOCATION_5) } else { (2, ALLOCATION_6) }
+    }
+  };
+}
+
+/// Decodes MPEG-1 and MPEG-2 (low sample rate) Layer I and Layer II audio
+/// into float audio, one packet per frame.
+///
+/// Frames that fail their CRC are replaced by silence. Layer III is not
+/// supported.
+pub struct Decoder {
+  source: channel::Source<::Binary>,
+  sink: channel::Sink<::Audio>
+}
+
+impl Decoder {
+  pub fn new(source: channel::Source<::Binary>, sink: channel::Sink<::Audio>) -> Decoder {
+    return Decoder { source: source, sink: sink };
+  }
+
+  pub fn run(&mut self) {
+    let mut s = stream::Stream::new(&mut self.source);
+    let sink = &mut self.sink;
+
+    let mut synthesis = [Synthesis::new(), Synthesis::new()];
+    let mut frame = Vec::with_capacity(4096);
+
+    let mut pending = Vec::new();
+    let mut pending_format: Option<(uint, f64)> = None;
+
+    let mut output = Vec::new();
+
+    loop {
+      let header = match sync(&mut s, &mut frame) {
+        Some(header) => header,
+        None => break
+      };
+
+      if header.layer == 3 {
+        panic!("mpeg::Decoder: Layer III is not supported (INPUT)");
+      }
+
+      let channels = header.channels();
+
+      output.truncate(0);
+      output.grow(header.samples() * channels, 0.0);
+
+      let result = if header.layer == 1 {
+        decode_layer1(&header, frame.as_slice(), synthesis.as_mut_slice(), output.as_mut_slice())
+      } else {
+        decode_layer2(&header, frame.as_slice(), synthesis.as_mut_slice(), output.as_mut_slice())
+      };
+
+      if result.is_err() {
+        for x in output.iter_mut() {
+          *x = 0.0;
+        }
+
+        for filter in synthesis.iter_mut() {
+          filter.reset();
+        }
+      }
+
+      match pending_format {
+        Some((channels, sample_rate)) => write(sink, pending.as_slice(), channels, sample_rate, false),
+        None => {}
+      }
+
+      std::mem::swap(&mut pending, &mut output);
+      pending_format = Some((channels, header.sample_rate as f64));
+    }
+
+    let (channels, sample_rate) = pending_format.unwrap_or((0, 0.0));
+
+    write(sink, pending.as_slice(), channels, sample_rate, true);
+  }
+}
+
+fn write(sink: &mut channel::Sink<::Audio>, samples: &[f64], channels: uint, sample_rate: f64, last: bool) {
+  sink.write(|audio| {
+    audio.channels = channels;
+    audio.layout = layout::Layout::default_for(channels);
+    audio.sample_rate = sample_rate;
+    audio.sample_type = sample_type::Float(32);
+    audio.endian = endian::Big;
+
+    sample::encode(samples, audio);
+
+    audio.last = last;
+  });
+}
+
+/// Finds the next frame, and reads all of it into `frame`. Returns `None` at
+/// end of file, including when the last frame is truncated.
+fn sync(s: &mut stream::Stream, frame: &mut Vec<u8>) -> Option<Header> {
+  let mut word = 0u32;
+
+  loop {
+    word = (word << 8) | match s.try_read_u8() {
+      Some(byte) => byte as u32,
+      None => return None
+    };
+
+    let header = match Header::parse(word) {
+      Some(header) => header,
+      None => continue
+    };
+
+    frame.truncate(0);
+    frame.push_all([(word >> 24) as u8, (word >> 16) as u8, (word >> 8) as u8, word as u8]);
+
+    for _ in range(4, header.frame_size()) {
+      match s.try_read_u8() {
+        Some(byte) => frame.push(byte),
+        None => return None
+      }
+    }
+
+    return Some(header);
+  }
+}
+
+/// Verifies the CRC of a protected frame, which covers the last two bytes of
+/// the header and the first `bits` bits of side information.
+fn check(frame: &[u8], bits: uint) -> Result<(), &'static str> {
+  let crc = crc::crc16(0x8005, 0xFFFF, frame.slice(2, 4));
+  let crc = crc::crc16_bits(0x8005, crc, frame.slice_from(6), bits);
+
+  if crc != ((frame[4] as u16) << 8 | frame[5] as u16) {
+    return Err("CRC mismatch");
+  }
+
+  return Ok(());
+}
+
+fn scale_factor(index: u32) -> f64 {
+  if index >= 63 {
+    return 0.0;
+  }
+
+  return 2.0 * (2.0f64).powf(-(index as f64) / 3.0);
+}
+
+/// Dequantizes a sample with `levels` levels.
+fn level(code: u32, levels: uint) -> f64 {
+  return (2.0 * code as f64 - (levels - 1) as f64) / levels as f64;
+}
+
+fn decode_layer1(header: &Header, frame: &[u8], synthesis: &mut [Synthesis], output: &mut [f64]) -> Result<(), &'static str> {
+  let channels = header.channels();
+  let bound = if header.mode == JointStereo { (header.mode_extension + 1) * 4 } else { 32 };
+
+  let mut r = stream::Bitslice::new(frame);
+
+  r.skip(if header.protected { 48 } else { 32 });
+
+  let mut allocation = [[0u, ..32], ..2];
+  let mut scale = [[0.0f64, ..32], ..2];
+
+  for sb in range(0u, 32) {
+    for ch in range(0, if sb < bound { channels } else { 1 }) {
+      allocation[ch][sb] = r.read_n(4) as uint;
+
+      if allocation[ch][sb] == 15 {
+        return Err("Invalid allocation");
+      }
+    }
+
+    if sb >= bound {
+      allocation[1][sb] = allocation[0][sb];
+    }
+  }
+
+  if header.protected {
+    try!(check(frame, r.position() - 48));
+  }
+
+  for sb in range(0u, 32) {
+    for ch in range(0, channels) {
+      if allocation[ch][sb] != 0 {
+        scale[ch][sb] = scale_factor(r.read_n(6));
+      }
+    }
+  }
+
+  let mut samples = [[0.0f64, ..32], ..2];
+
+  for s in range(0u, 12) {
+    for sb in range(0u, 32) {
+      for ch in range(0, if sb < bound { channels } else { 1 }) {
+        let bits = allocation[ch][sb];
+
+        if bits == 0 {
+          samples[ch][sb] = 0.0;
+
+          if sb >= bound && channels == 2 {
+            samples[1][sb] = 0.0;
+          }
+
+          continue;
+        }
+
+        let value = level(r.read_n(bits + 1), (1 << (bits + 1)) - 1);
+
+        samples[ch][sb] = value * scale[ch][sb];
+
+        if sb >= bound && channels == 2 {
+          samples[1][sb] = value * scale[1][sb];
+        }
+      }
+    }
+
+    for ch in range(0, channels) {
+      synthesis[ch].synthesize(samples[ch].as_slice(), output.slice_from_mut(s * 32 * channels), ch, channels);
+    }
+  }
+
+  return Ok(());
+}
+
+fn decode_layer2(header: &Header, frame: &[u8], synthesis: &mut [Synthesis], output: &mut [f64]) -> Result<(), &'static str> {
+  let channels = header.channels();
+  let (table, sblimit) = allocation_table(header);
+  let bound = std::cmp::min(sblimit, if header.mode == JointStereo { (header.mode_extension + 1) * 4 } else { 32 });
+
+  let mut r = stream::Bitslice::new(frame);
+
+  r.skip(if header.protected { 48 } else { 32 });
+
+  let mut allocation = [[0u, ..32], ..2];
+  let mut scfsi = [[0u, ..32], ..2];
+  let mut scale = [[[0.0f64, ..3], ..32], ..2];
+
+  for sb in range(0, sblimit) {
+    let (bits, _) = allocation(table, sb);
+
+    for ch in range(0, if sb < bound { channels } else { 1 }) {
+      allocation[ch][sb] = r.read_n(bits) as uint;
+    }
+
+    if sb >= bound {
+      allocation[1][sb] = allocation[0][sb];
+    }
+  }
+
+  for sb in range(0, sblimit) {
+    for ch in range(0, channels) {
+      if allocation[ch][sb] != 0 {
+        scfsi[ch][sb] = r.read_n(2) as uint;
+      }
+    }
+  }
+
+  if header.protected {
+    try!(check(frame, r.position() - 48));
+  }
+
+  for sb in range(0, sblimit) {
+    for ch in range(0, channels) {
+      if allocation[ch][sb] == 0 {
+        continue;
+      }
+
+      let s = &mut scale[ch][sb];
+
+      match scfsi[ch][sb] {
+        0 => {
+          s[0] = scale_factor(r.read_n(6));
+          s[1] = scale_factor(r.read_n(6));
+          s[2] = scale_factor(r.read_n(6));
+        }
+        1 => {
+          s[0] = scale_factor(r.read_n(6));
+          s[1] = s[0];
+          s[2] = scale_factor(r.read_n(6));
+        }
+        2 => {
+          s[0] = scale_factor(r.read_n(6));
+          s[1] = s[0];
+          s[2] = s[0];
+        }
+        _ => {
+          s[0] = scale_factor(r.read_n(6));
+          s[1] = scale_factor(r.read_n(6));
+          s[2] = s[1];
+        }
+      }
+    }
+  }
+
+  let mut samples = [[[0.0f64, ..32], ..3], ..2];
+
+  for granule in range(0u, 12) {
+    let part = granule / 4;
+
+    for sb in range(0, sblimit) {
+      let (_, classes) = allocation(table, sb);
+
+      for ch in range(0, if sb < bound { channels } else { 1 }) {
+        let index = allocation[ch][sb];
+
+        if index == 0 {
+          for i in range(0u, 3) {
+            samples[ch][i][sb] = 0.0;
+
+            if sb >= bound && channels == 2 {
+              samples[1][i][sb] = 0.0;
+            }
+          }
+
+          continue;
+        }
+
+        let (levels, bits, grouped) = CLASSES[classes[index - 1] as uint];
+        let mut codes = [0u32, ..3];
+
+        if grouped {
+          let mut code = r.read_n(bits);
+
+          for i in range(0u, 3) {
+            codes[i] = code % levels as u32;
+            code /= levels as u32;
+          }
+        } else {
+          for i in range(0u, 3) {
+            codes[i] = r.read_n(bits);
+          }
+        }
+
+        for i in range(0u, 3) {
+          let value = level(codes[i], levels);
+
+          samples[ch][i][sb] = value * scale[ch][sb][part];
+
+          if sb >= bound && channels == 2 {
+            samples[1][i][sb] = value * scale[1][sb][part];
+          }
+        }
+      }
+    }
+
+    for i in range(0u, 3) {
+      let offset = (granule * 3 + i) * 32 * channels;
+
+      for ch in range(0, channels) {
+        synthesis[ch].synthesize(samples[ch][i].as_slice(), output.slice_from_mut(offset), ch, channels);
+      }
+    }
+  }
+
+  return Ok(());
+}
+
+/// The polyphase synthesis filter bank of ISO 11172-3, for one channel.
+struct Synthesis {
+  v: [f64, ..1024],
+  matrix: Vec<f64>
+}
+
+impl Synthesis {
+  fn new() -> Synthesis {
+    let mut matrix = Vec::with_capacity(64 * 32);
+
+    for i in range(0u, 64) {
+      for k in range(0u, 32) {
+        matrix.push((((16 + i) * (2 * k + 1)) as f64 * PI / 64.0).cos());
+      }
+    }
+
+    return Synthesis { v: [0.0, ..1024], matrix: matrix };
+  }
+
+  fn reset(&mut self) {
+    self.v = [0.0, ..1024];
+  }
+
+  /// Synthesizes 32 output samples from one sample of each subband, and
+  /// writes them to `output` interleaved with `channels` channels.
+  fn synthesize(&mut self, samples: &[f64], output: &mut [f64], channel: uint, channels: uint) {
+    for i in range(0u, 1024 - 64).rev() {
+      self.v[i + 64] = self.v[i];
+    }
+
+    for i in range(0u, 64) {
+      let row = self.matrix.slice(i * 32, i * 32 + 32);
+      let mut sum = 0.0;
+
+      for k in range(0u, 32) {
+        sum += row[k] * samples[k];
+      }
+
+      self.v[i] = sum;
+    }
+
+    for j in range(0u, 32) {
+      let mut sum = 0.0;
+
+      for i in range(0u, 8) {
+        sum += self.v[i * 128 + j] * tables::WINDOW[i * 64 + j];
+        sum += self.v[i * 128 + 96 + j] * tables::WINDOW[i * 64 + 32 + j];
+      }
+
+      output[j * channels + channel] = sum;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use channel;
+  use buffer;
+  use sample;
+  use sample::testing;
+
+  // Frames written from test tones, with every 8th (Layer I) or 16th
+  // (Layer II) interleaved output sample as decoded by symphonia 0.5.5 and
+  // minimp3, which agree within 1e-7.
+
+  /// Layer I, 48 kHz stereo at 128 kbit/s.
+  static LAYER1: [u8, ..128] = [
+    0xFF, 0xFF, 0x44, 0x04, 0x46, 0x20, 0x01, 0x00, 0x00, 0x70, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0xA0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x24, 0xB3, 0x11, 0x4D, 0x57, 0x1F, 0xE6, 0xCA, 0x5A, 0x80, 0x76, 0x7C,
+    0x4E, 0x8E, 0x7C, 0x2E, 0xD3, 0x57, 0xE3, 0xB6, 0xD5, 0xBC, 0x42, 0xB5, 0x36, 0x46, 0x0E, 0xDF,
+    0xD6, 0x31, 0xD9, 0x9D, 0x40, 0x65, 0x20, 0x32, 0xFD, 0x5C, 0x0B, 0xC0, 0x34, 0x5A, 0xC9, 0x9A,
+    0x8E, 0x5B, 0xEC, 0x4F, 0x6C, 0x6F, 0x46, 0x3F, 0x57, 0x43, 0xAE, 0x51, 0x24, 0x43, 0x37, 0x46,
+    0xED, 0x82, 0xDF, 0xB6, 0x33, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+  ];
+
+  static LAYER1_PCM: [f64, ..96] = [
+    0.0000000, 0.0000010, 0.0000022, 0.0000014, 0.0000000, -0.0000039, -0.0000144, -0.0000194,
+    -0.0000191, -0.0000172, 0.0000214, 0.0000713, 0.0000970, 0.0001390, 0.0001082, -0.0000022,
+    -0.0000452, -0.0001183, -0.0001249, 0.0000271, 0.0001312, 0.0002245, 0.0002513, 0.0002621,
+    0.0003992, 0.0005627, 0.0011817, 0.0017888, 0.0018597, 0.0020904, 0.0015020, 0.0003951,
+    0.0001953, -0.0000752, 0.0002908, 0.0012633, 0.0016470, 0.0019758, 0.0013005, 0.0015633,
+    0.0033448, 0.0049235, 0.0097412, 0.0139860, 0.0149122, 0.0167402, 0.0149654, 0.0121811,
+    0.0123221, 0.0118122, 0.0136518, 0.0120081, 0.0071315, 0.0023854, -0.0101480, -0.0124432,
+    -0.0030524, 0.0073205, 0.0415432, 0.0761533, 0.0947893, 0.1198344, 0.1134727, 0.0854647,
+    0.0709247, 0.0333414, 0.0135231, 0.0361931, 0.0477198, 0.0955391, 0.1747453, 0.2126279,
+    0.2628566, 0.3114355, 0.2853603, 0.2549812, 0.2241873, 0.1236465, 0.0323174, -0.0211406,
+    -0.1180665, -0.1912129, -0.1886379, -0.2136747, -0.2259626, -0.1519820, -0.1153174, -0.1118936,
+    -0.0436779, -0.0298234, -0.0915444, -0.0929507, -0.1178074, -0.2126243, -0.2235106, -0.2034620
+  ];
+
+  /// Layer II, 48 kHz joint stereo at 128 kbit/s with intensity stereo from
+  /// subband 12, every scale factor selection and a CRC.
+  static LAYER2: [u8, ..384] = [
+    0xFF, 0xFC, 0x84, 0x64, 0x0A, 0xC8, 0x35, 0x19, 0x21, 0x42, 0x80, 0x00, 0x03, 0x00, 0x00, 0x00,
+    0xE0, 0xC8, 0xA0, 0x00, 0x02, 0x80, 0x21, 0x09, 0xC3, 0x0B, 0xD0, 0x7D, 0x12, 0x79, 0xA5, 0xA2,
+    0x61, 0x89, 0x26, 0x72, 0x87, 0xA4, 0x89, 0xE9, 0x9C, 0x93, 0x0A, 0xA8, 0xDA, 0xCB, 0x2E, 0xC3,
+    0xCA, 0xB0, 0xD3, 0x0B, 0xC0, 0xF3, 0xAC, 0xBE, 0xE4, 0x4E, 0x3E, 0xF3, 0x78, 0x67, 0xA4, 0x77,
+    0x3A, 0xB9, 0x96, 0xC5, 0x14, 0xCC, 0x99, 0x4C, 0xEE, 0xC4, 0x9E, 0x85, 0xAF, 0x62, 0x0E, 0xED,
+    0xEA, 0xB9, 0xCA, 0x83, 0x4A, 0x41, 0x9B, 0xDA, 0xE7, 0x8A, 0x4B, 0x01, 0x9C, 0xDE, 0xC2, 0x01,
+    0xB2, 0x3A, 0x2B, 0xD0, 0x29, 0x74, 0x82, 0xFB, 0x7A, 0x51, 0x07, 0x1C, 0x79, 0xB3, 0x5A, 0x0F,
+    0x41, 0xBB, 0x76, 0x4C, 0xFC, 0x5D, 0xDB, 0xB3, 0x8B, 0xA3, 0x31, 0x8A, 0x27, 0xE8, 0x46, 0xDD,
+    0xAA, 0x54, 0xBE, 0x7C, 0xCD, 0x01, 0x9D, 0xD9, 0x93, 0x28, 0x6D, 0x81, 0x2E, 0xAB, 0x9C, 0x32,
+    0x77, 0x0A, 0xA1, 0x90, 0x67, 0x68, 0xA3, 0x90, 0x6E, 0xB3, 0x68, 0xC8, 0xB3, 0xF7, 0x6B, 0x59,
+    0xDA, 0xEB, 0x7A, 0x90, 0x05, 0x70, 0x5A, 0x64, 0x25, 0x0C, 0x1A, 0x0A, 0xC1, 0xA0, 0xE3, 0x2F,
+    0x3A, 0x7B, 0xE6, 0xE6, 0x99, 0xD2, 0x48, 0x29, 0xE8, 0x9B, 0xC8, 0xEC, 0x49, 0x5B, 0xAB, 0xD7,
+    0x4E, 0x32, 0x00, 0x20, 0x82, 0x14, 0x9E, 0x4B, 0xC7, 0x8D, 0x16, 0x00, 0x0D, 0xCB, 0xB0, 0x57,
+    0xA2, 0x50, 0xE4, 0x96, 0xF1, 0xF5, 0x92, 0x8C, 0xBC, 0xC2, 0x9A, 0x4B, 0x74, 0x68, 0xC3, 0x16,
+    0x8A, 0x36, 0xDA, 0x85, 0x89, 0x19, 0x32, 0xC0, 0x38, 0x0B, 0xD0, 0x87, 0x2A, 0xA6, 0x48, 0x47,
+    0xD1, 0x05, 0xCB, 0x16, 0xA0, 0xC8, 0xA9, 0xF5, 0x35, 0x8C, 0xC5, 0x5D, 0x4A, 0x81, 0xA4, 0xE8,
+    0x38, 0x68, 0x2C, 0x1D, 0xB8, 0xDD, 0x6D, 0xB4, 0xDA, 0x01, 0x73, 0x67, 0x6D, 0xEA, 0xB1, 0xA5,
+    0xF1, 0x30, 0x75, 0xBD, 0xD6, 0x65, 0xB1, 0x5E, 0x5C, 0xDF, 0xF6, 0x74, 0x45, 0xA8, 0x60, 0xD9,
+    0xCD, 0x3B, 0x16, 0xE9, 0x43, 0x5A, 0xD9, 0xC6, 0x94, 0x0B, 0x4A, 0x1F, 0x11, 0xDB, 0xB9, 0x5D,
+    0x79, 0xED, 0x7C, 0x8B, 0x11, 0x62, 0xB0, 0x01, 0x8B, 0xF0, 0x7B, 0x60, 0xA2, 0x07, 0xF7, 0x10,
+    0x42, 0x53, 0x80, 0x19, 0xCB, 0x71, 0xBE, 0xC7, 0x49, 0x0C, 0x9E, 0x75, 0xB1, 0x44, 0xB0, 0x00,
+    0xA6, 0xC9, 0x24, 0xA3, 0xD3, 0xE8, 0x17, 0xB0, 0x0F, 0xB5, 0x62, 0x5C, 0x06, 0x5B, 0xF7, 0xC5,
+    0x55, 0x14, 0xA6, 0x00, 0x42, 0x18, 0x53, 0x16, 0x1E, 0xEC, 0xFB, 0xF8, 0xC2, 0x66, 0xD6, 0x68,
+    0x23, 0x71, 0xB5, 0x2E, 0x39, 0xB3, 0xAE, 0x0B, 0xAD, 0x49, 0x05, 0x90, 0x64, 0xF4, 0x40, 0x00
+  ];
+
+  static LAYER2_PCM: [f64, ..144] = [
+    0.0000000, -0.0000006, 0.0000000, 0.0000042, -0.0000155, -0.0000552, 0.0002267, -0.0001636,
+    -0.0000916, 0.0001578, 0.0000535, 0.0002686, -0.0000302, -0.0009789, 0.0035861, -0.0016362,
+    -0.0003331, 0.0021481, 0.0010666, 0.0041497, -0.0000839, -0.0058219, 0.0239774, -0.0060682,
+    0.0052461, 0.0149797, 0.0093071, 0.0277834, -0.0019895, -0.0282102, 0.1590738, -0.0524308,
+    0.0132705, 0.1238177, 0.0631886, 0.1304593, 0.1728469, 0.1096498, 0.1099655, 0.2618578,
+    0.1717895, 0.1477431, 0.1939649, 0.2030010, 0.1633657, 0.2915394, 0.1600010, 0.1330208,
+    0.3265850, 0.1886602, 0.2269225, 0.2876737, 0.1102275, 0.2141598, 0.3006726, 0.0737555,
+    0.1599956, 0.2094269, 0.1060812, 0.2339085, 0.1483099, 0.0257538, 0.1561275, 0.0858912,
+    0.0124772, 0.0858523, -0.0183546, -0.0290825, 0.0424637, 0.0139880, -0.1195794, -0.0517696,
+    -0.0554340, -0.1174331, -0.0472127, -0.1285350, -0.2374960, -0.0656065, -0.1400112, -0.2355642,
+    -0.1425659, -0.2523624, -0.1331146, -0.1730984, -0.1928115, -0.2556800, -0.2111436, -0.2265883,
+    -0.1924639, -0.1908387, -0.2669417, -0.2566152, -0.2202278, -0.1619946, -0.3135348, -0.1418171,
+    -0.2881046, -0.0881439, -0.1427096, -0.1936906, -0.1690152, -0.0922890, -0.1321091, -0.0155148,
+    -0.0785729, -0.1331343, 0.0433950, -0.0833778, 0.0350867, -0.0994410, 0.0052714, 0.0448836,
+    0.1133341, 0.0753632, 0.1239688, 0.0625169, 0.1686986, 0.1512764, 0.1484943, 0.1617777,
+    0.0736783, 0.2496056, 0.1897480, 0.1966314, 0.1678494, 0.2288382, 0.2591467, 0.3117870,
+    0.1229291, 0.1884445, 0.1871075, 0.3256964, 0.3153516, 0.1879308, 0.1228057, 0.1984662,
+    0.3278958, 0.1826962, 0.1425528, 0.0631055, 0.1905694, 0.1985325, 0.2252239, -0.0484275
+  ];
+
+  fn decode(frame: &[u8]) -> Vec<f64> {
+    let frame = frame.to_vec();
+
+    let (sink, source) = channel::create::<::Binary>(1);
+    let (audio_sink, mut audio_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() { buffer::Buffer::new(frame, 4096, sink).run(); });
+    spawn(proc() { super::Decoder::new(source, audio_sink).run(); });
+
+    return testing::collect(&mut audio_source);
+  }
+
+  #[test]
+  fn test_header() {
+    let header = super::Header::parse(0xFFFD9444).unwrap();
+
+    assert_eq!(header.version, super::Mpeg1);
+    assert_eq!(header.layer, 2);
+    assert_eq!(header.protected, false);
+    assert_eq!(header.bit_rate, 128);
+    assert_eq!(header.sample_rate, 48000);
+    assert_eq!(header.mode, super::JointStereo);
+    assert_eq!(header.frame_size(), 384);
+    assert_eq!(header.samples(), 1152);
+
+    let header = super::Header::parse(0xFFFB9064).unwrap();
+
+    assert_eq!(header.layer, 3);
+    assert_eq!(header.sample_rate, 44100);
+    assert_eq!(header.frame_size(), 417);
+
+    assert!(super::Header::parse(0xFFFD0444).is_none());
+    assert!(super::Header::parse(0xFFE00000).is_none());
+    assert!(super::Header::parse(0x12345678).is_none());
+  }
+
+  #[test]
+  fn test_layer1_size() {
+    let header = super::Header::parse(0xFFFFC400).unwrap();
+
+    assert_eq!(header.layer, 1);
+    assert_eq!(header.bit_rate, 384);
+    assert_eq!(header.frame_size(), 384);
+    assert_eq!(header.samples(), 384);
+  }
+
+  #[test]
+  fn test_silent_frame() {
+    // A mono Layer II frame with every allocation set to zero.
+    let mut frame = vec![0xFFu8, 0xFD, 0x94, 0xC4];
+
+    frame.grow(384 - 4, 0);
+
+    let (sink, source) = channel::create::<::Binary>(1);
+    let (audio_sink, mut audio_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() { buffer::Buffer::new(frame, 4096, sink).run(); });
+    spawn(proc() { super::Decoder::new(source, audio_sink).run(); });
+
+    audio_source.read(|audio| {
+      let mut samples = Vec::new();
+
+      sample::decode(audio, &mut samples);
+
+      assert_eq!(audio.last, true);
+      assert_eq!(audio.channels, 1);
+      assert_eq!(audio.sample_rate, 48000.0);
+      assert_eq!(samples.len(), 1152);
+      assert!(samples.iter().all(|&x| x == 0.0));
+    });
+  }
+
+  #[test]
+  fn test_layer1() {
+    let output = decode(LAYER1.as_slice());
+
+    assert_eq!(output.len(), 384 * 2);
+
+    for (i, &expected) in LAYER1_PCM.iter().enumerate() {
+      assert!((output[i * 8] - expected).abs() < 1e-6);
+    }
+  }
+
+  #[test]
+  fn test_layer2() {
+    let output = decode(LAYER2.as_slice());
+
+    assert_eq!(output.len(), 1152 * 2);
+
+    for (i, &expected) in LAYER2_PCM.iter().enumerate() {
+      assert!((output[i * 16] - expected).abs() < 1e-6);
+    }
+  }
+
+  #[test]
+  fn test_crc() {
+    let mut frame = vec![0xFFu8, 0xFC, 0x94, 0xC4, 0x00, 0x00];
+
+    frame.grow(384 - 6, 0);
+
+    let crc = ::crc::crc16(0x8005, 0xFFFF, [0x94u8, 0xC4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+    *frame.get_mut(4) = (crc >> 8) as u8;
+    *frame.get_mut(5) = crc as u8;
+
+    assert!(super::check(frame.as_slice(), 88).is_ok());
+
+    *frame.get_mut(6) = 0x80;
+
+    assert!(super::check(frame.as_slice(), 88).is_err());
+  }
+}
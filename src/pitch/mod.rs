@@ -0,0 +1,556 @@
+use std;
+use std::comm::Sender;
+
+use channel;
+use fft;
+use sample;
+
+#[deriving(Show,PartialEq)]
+pub enum Algorithm {
+  /// YIN with an absolute threshold on the normalized difference, usually
+  /// 0.1 to 0.2.
+  Yin(f64),
+
+  /// Probabilistic YIN: candidates from a spread of thresholds, smoothed
+  /// into a track by a hidden Markov model.
+  Pyin
+}
+
+/// How to track pitch. Sizes are in samples and frequencies in Hz.
+#[deriving(Show,PartialEq)]
+pub struct Settings {
+  pub algorithm: Algorithm,
+
+  /// The frame and hop sizes, or `None` to size them for the sample rate.
+  pub frames: Option<(uint, uint)>,
+  pub min_frequency: f64,
+  pub max_frequency: f64
+}
+
+impl Settings {
+  /// Pitches from 60 to 1000 Hz, in frames twice as long as the longest
+  /// period rounded up to a power of two, every eighth of a frame. That is
+  /// 2048 samples every 256 at 44.1 and 48 kHz.
+  pub fn new(algorithm: Algorithm) -> Settings {
+    return Settings { algorithm: algorithm, frames: None, min_frequency: 60.0, max_frequency: 1000.0 };
+  }
+
+  /// Sets the frame and hop sizes. Frames must be at least twice as long as
+  /// the period of the lowest pitch.
+  pub fn frames(mut self, frame: uint, hop: uint) -> Settings {
+    if frame < 4 || hop == 0 {
+      panic!("pitch::Settings: Frame must be at least 4 samples and hop positive (ARGUMENT)");
+    }
+
+    self.frames = Some((frame, hop));
+
+    return self;
+  }
+
+  pub fn range(mut self, min_frequency: f64, max_frequency: f64) -> Settings {
+    if !(min_frequency > 0.0) || !(max_frequency > min_frequency) {
+      panic!("pitch::Settings: Frequency range must be positive and ascending (ARGUMENT)");
+    }
+
+    self.min_frequency = min_frequency;
+    self.max_frequency = max_frequency;
+
+    return self;
+  }
+}
+
+#[deriving(Clone,Show,PartialEq)]
+pub struct Estimate {
+  /// The sample at the center of the frame.
+  pub position: uint,
+
+  /// The fundamental frequency in Hz, if the frame is voiced.
+  pub frequency: Option<f64>,
+
+  /// The probability that the frame is voiced.
+  pub probability: f64
+}
+
+/// Computes the cumulative mean normalized difference function of YIN
+/// for frames of a fixed size, with the autocorrelation done by FFT.
+struct Difference {
+  frame: uint,
+  window: uint,
+  fft: fft::RealFft,
+  padded: Vec<f64>,
+  a: Vec<fft::Complex>,
+  b: Vec<fft::Complex>,
+  correlation: Vec<f64>,
+  squares: Vec<f64>
+}
+
+impl Difference {
+  fn new(frame: uint) -> Difference {
+    let window = frame / 2;
+    let size = (frame + window).next_power_of_two();
+
+    return Difference {
+      frame: frame,
+      window: window,
+      fft: fft::RealFft::new(size),
+      padded: Vec::from_elem(size, 0.0),
+      a: Vec::new(),
+      b: Vec::new(),
+      correlation: Vec::new(),
+      squares: Vec::new()
+    };
+  }
+
+  /// Writes the normalized difference of `x` for lags up to half the frame
+  /// into `output`.
+  fn compute(&mut self, x: &[f64], output: &mut Vec<f64>) {
+    let w = self.window;
+
+    for (i, p) in self.padded.iter_mut().enumerate() {
+      *p = if i < w { x[i] } else { 0.0 };
+    }
+
+    self.fft.forward(self.padded.as_slice(), &mut self.a);
+
+    for (i, p) in self.padded.iter_mut().enumerate() {
+      *p = if i < self.frame { x[i] } else { 0.0 };
+    }
+
+    self.fft.forward(self.padded.as_slice(), &mut self.b);
+
+    for (a, b) in self.a.iter_mut().zip(self.b.iter()) {
+      *a = a.conj().mul(b);
+    }
+
+    self.fft.inverse(self.a.as_slice(), &mut self.correlation);
+
+    // Energies of each window of `w` samples.
+    self.squares.truncate(0);
+    self.squares.push(x.slice_to(w).iter().fold(0.0, |a, &v| a + v * v));
+
+    for tau in range(1, w) {
+      let e = self.squares[tau - 1] - x[tau - 1] * x[tau - 1] + x[tau + w - 1] * x[tau + w - 1];
+      self.squares.push(e);
+    }
+
+    output.truncate(0);
+    output.push(1.0);
+
+    let mut sum = 0.0;
+
+    for tau in range(1, w) {
+      let d = (self.squares[0] + self.squares[tau] - 2.0 * self.correlation[tau]).max(0.0);
+
+      sum += d;
+      output.push(if sum > 0.0 { d * tau as f64 / sum } else { 1.0 });
+    }
+  }
+}
+
+/// The lag of a minimum of `d` at `tau`, refined with a parabola through
+/// its neighbours.
+fn refine(d: &[f64], tau: uint) -> f64 {
+  if tau == 0 || tau + 1 >= d.len() {
+    return tau as f64;
+  }
+
+  let (a, b, c) = (d[tau - 1], d[tau], d[tau + 1]);
+  let denominator = a - 2.0 * b + c;
+
+  return if denominator > 0.0 { tau as f64 + (0.5 * (a - c) / denominator).max(-1.0).min(1.0) } else { tau as f64 };
+}
+
+/// The first dip of `d` below `threshold` in `low..high`, followed down to
+/// its minimum.
+fn dip(d: &[f64], threshold: f64, low: uint, high: uint) -> Option<uint> {
+  for tau in range(low, high) {
+    if d[tau] < threshold {
+      let mut tau = tau;
+
+      while tau + 1 < high && d[tau + 1] < d[tau] {
+        tau += 1;
+      }
+
+      return Some(tau);
+    }
+  }
+
+  return None;
+}
+
+/// Thresholds for pYIN, and their probabilities under a beta distribution
+/// with mean 0.1.
+fn thresholds() -> Vec<(f64, f64)> {
+  let pdf: Vec<(f64, f64)> = range(1u, 101).map(|i| {
+    let s = i as f64 / 100.0;
+
+    (s, s * (1.0 - s).powi(17))
+  }).collect();
+
+  let total = pdf.iter().fold(0.0, |a, &(_, p)| a + p);
+
+  return pdf.iter().map(|&(s, p)| (s, p / total)).collect();
+}
+
+/// The weight given to the lowest lag when no dip falls below a threshold.
+static ABSOLUTE_MINIMUM: f64 = 0.01;
+
+/// Pitch states per semitone, how far the pitch may move between frames,
+/// and the probability of staying voiced or unvoiced.
+static STEPS: f64 = 5.0;
+static MAX_JUMP: int = 12;
+static STAY: f64 = 0.99;
+
+/// How far the candidates are trusted to show that a frame is voiced.
+static TRUST: f64 = 0.5;
+
+/// Smooths pYIN candidates into a track with the Viterbi algorithm over a
+/// grid of pitches, each voiced or unvoiced.
+struct Smoother {
+  min_frequency: f64,
+  bins: uint,
+
+  /// Per frame, the observation probabilities of the voiced states.
+  observations: Vec<Vec<f64>>,
+
+  /// Per frame, the candidates: frequency and probability.
+  candidates: Vec<Vec<(f64, f64)>>
+}
+
+impl Smoother {
+  fn new(min_frequency: f64, max_frequency: f64) -> Smoother {
+    let bins = ((max_frequency / min_frequency).log2() * 12.0 * STEPS).ceil() as uint + 1;
+
+    return Smoother { min_frequency: min_frequency, bins: bins, observations: Vec::new(), candidates: Vec::new() };
+  }
+
+  fn bin(&self, frequency: f64) -> uint {
+    let b = ((frequency / self.min_frequency).log2() * 12.0 * STEPS).round().max(0.0) as uint;
+
+    return std::cmp::min(b, self.bins - 1);
+  }
+
+  fn add(&mut self, candidates: Vec<(f64, f64)>) {
+    let mut observations = Vec::from_elem(self.bins, 0.0);
+
+    for &(f, p) in candidates.iter() {
+      *observations.get_mut(self.bin(f)) += p;
+    }
+
+    self.observations.push(observations);
+    self.candidates.push(candidates);
+  }
+
+  /// Decodes the track: per frame, the frequency if voiced.
+  fn decode(&self) -> Vec<Option<f64>> {
+    let n = self.observations.len();
+    let states = 2 * self.bins;
+
+    if n == 0 {
+      return Vec::new();
+    }
+
+    let weights: Vec<f64> = range(-MAX_JUMP, MAX_JUMP + 1).map(|j| (MAX_JUMP + 1 - j.abs()) as f64).collect();
+    let total = weights.iter().fold(0.0, |a, &w| a + w);
+    let jumps: Vec<f64> = weights.iter().map(|&w| (w / total).ln()).collect();
+
+    let observe = |t: uint, s: uint| {
+      let voiced: f64 = TRUST * self.observations[t].iter().fold(0.0, |a, &p| a + p);
+      let p = if s < self.bins { TRUST * self.observations[t][s] } else { (1.0 - voiced).max(0.0) / self.bins as f64 };
+
+      (p + 1e-12).ln()
+    };
+
+    let mut scores: Vec<f64> = range(0, states).map(|s| observe(0, s)).collect();
+    let mut paths: Vec<Vec<uint>> = Vec::with_capacity(n);
+    let mut next = Vec::from_elem(states, 0.0);
+
+    for t in range(1, n) {
+      let mut path = Vec::from_elem(states, 0u);
+
+      for s in range(0, states) {
+        let bin = s % self.bins;
+        let voiced = s < self.bins;
+        let mut best = std::f64::NEG_INFINITY;
+        let mut from = 0;
+
+        for (j, &jump) in range(-MAX_JUMP, MAX_JUMP + 1).zip(jumps.iter()) {
+          let b = bin as int + j;
+
+          if b < 0 || b >= self.bins as int {
+            continue;
+          }
+
+          for &v in [true, false].iter() {
+            let previous = if v { b as uint } else { b as uint + self.bins };
+            let switch = if v == voiced { STAY } else { 1.0 - STAY };
+            let score = scores[previous] + jump + switch.ln();
+
+            if score > best {
+              best = score;
+              from = previous;
+            }
+          }
+        }
+
+        *next.get_mut(s) = best + observe(t, s);
+        *path.get_mut(s) = from;
+      }
+
+      std::mem::swap(&mut scores, &mut next);
+      paths.push(path);
+    }
+
+    let mut state = range(0, states).fold(0, |a, s| if scores[s] > scores[a] { s } else { a });
+    let mut decoded = Vec::from_elem(n, None);
+
+    for t in range(0, n).rev() {
+      if state < self.bins {
+        // The candidate nearest the state gives the frequency.
+        let nearest = self.candidates[t].iter().map(|&(f, _)| f).fold(None, |a: Option<f64>, f| {
+          match a {
+            Some(g) if (self.bin(g) as int - state as int).abs() <= (self.bin(f) as int - state as int).abs() => Some(g),
+            _ => Some(f)
+          }
+        });
+
+        *decoded.get_mut(t) = Some(match nearest {
+          Some(f) if (self.bin(f) as int - state as int).abs() <= 1 => f,
+          _ => self.min_frequency * (2.0f64).powf(state as f64 / (12.0 * STEPS))
+        });
+      }
+
+      if t > 0 {
+        state = paths[t - 1][state];
+      }
+    }
+
+    return decoded;
+  }
+}
+
+/// Tracks the fundamental frequency of audio passing through, and sends
+/// the estimates to `report` at the end of the stream. Channels are mixed
+/// to mono.
+pub struct Detector {
+  source: channel::Source<::Audio>,
+  sink: channel::Sink<::Audio>,
+  settings: Settings,
+  report: Sender<Vec<Estimate>>
+}
+
+impl Detector {
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, settings: Settings, report: Sender<Vec<Estimate>>) -> Detector {
+    return Detector { source: source, sink: sink, settings: settings, report: report };
+  }
+
+  pub fn run(&mut self) {
+    let settings = &self.settings;
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    let mut difference: Option<Difference> = None;
+    let mut frame = 0;
+    let mut hop = 0;
+    let mut smoother = Smoother::new(settings.min_frequency, settings.max_frequency);
+    let thresholds = thresholds();
+
+    let mut estimates = Vec::new();
+    let mut samples = Vec::new();
+    let mut buffer = Vec::new();
+    let mut d = Vec::new();
+
+    // The first sample in `buffer`, and the lag range.
+    let mut offset = 0;
+    let mut lags = None;
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        if !audio.data.is_empty() {
+          if lags.is_none() {
+            let low = std::cmp::max((audio.sample_rate / settings.max_frequency).floor() as uint, 2);
+            let high = (audio.sample_rate / settings.min_frequency).ceil() as uint + 1;
+
+            let (f, h) = match settings.frames {
+              Some(frames) => frames,
+              None => {
+                let f = (2 * high).next_power_of_two();
+                (f, f / 8)
+              }
+            };
+
+            if high > f / 2 {
+              panic!("pitch::Detector: Frames of {} samples are too short for {} Hz at {} Hz (INPUT)", f, settings.min_frequency, audio.sample_rate);
+            }
+
+            frame = f;
+            hop = h;
+            difference = Some(Difference::new(frame));
+            lags = Some((low, high, audio.sample_rate));
+          }
+
+          sample::decode(audio, &mut samples);
+
+          for frame in samples.as_slice().chunks(audio.channels) {
+            buffer.push(frame.iter().fold(0.0, |a, &x| a + x) / audio.channels as f64);
+          }
+        }
+
+        let (low, high, sample_rate) = match lags {
+          Some(lags) => lags,
+          None => (0, 0, 0.0)
+        };
+
+        let mut start = 0;
+
+        while frame > 0 && start + frame <= buffer.len() {
+          difference.as_mut().unwrap().compute(buffer.slice(start, start + frame), &mut d);
+
+          let position = offset + start + frame / 2;
+
+          match settings.algorithm {
+            Yin(threshold) => {
+              let estimate = match dip(d.as_slice(), threshold, low, high) {
+                Some(tau) => Estimate { position: position, frequency: Some(sample_rate / refine(d.as_slice(), tau)), probability: 1.0 - d[tau] },
+                None => Estimate { position: position, frequency: None, probability: 0.0 }
+              };
+
+              estimates.push(estimate);
+            },
+            Pyin => {
+              let minimum = range(low, high).fold(low, |a, tau| if d[tau] < d[a] { tau } else { a });
+              let mut candidates: Vec<(uint, f64)> = Vec::new();
+
+              for &(s, p) in thresholds.iter() {
+                let (tau, p) = match dip(d.as_slice(), s, low, high) {
+                  Some(tau) => (tau, p),
+                  None => (minimum, p * ABSOLUTE_MINIMUM)
+                };
+
+                match candidates.iter().position(|&(t, _)| t == tau) {
+                  Some(i) => {
+                    let (_, ref mut q) = *candidates.get_mut(i);
+                    *q += p;
+                  },
+                  None => candidates.push((tau, p))
+                }
+              }
+
+              let voiced = candidates.iter().fold(0.0, |a, &(_, p)| a + p);
+
+              smoother.add(candidates.iter().map(|&(tau, p)| (sample_rate / refine(d.as_slice(), tau), p)).collect());
+              estimates.push(Estimate { position: position, frequency: None, probability: voiced.min(1.0) });
+            }
+          }
+
+          start += hop;
+        }
+
+        let consumed = std::cmp::min(start, buffer.len());
+        let rest = buffer.slice_from(consumed).to_vec();
+
+        buffer = rest;
+        offset += consumed;
+
+        sink.write(|out| {
+          out.channels = audio.channels;
+          out.layout = audio.layout.clone();
+          out.sample_rate = audio.sample_rate;
+          out.sample_type = audio.sample_type;
+          out.endian = audio.endian;
+          out.last = last;
+          out.data.push_all(audio.data.as_slice());
+        });
+      });
+    }
+
+    if settings.algorithm == Pyin {
+      for (estimate, frequency) in estimates.iter_mut().zip(smoother.decode().into_iter()) {
+        estimate.frequency = frequency;
+      }
+    }
+
+    self.report.send(estimates);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::comm;
+
+  use channel;
+  use generator;
+  use sample_type;
+
+  fn detect(settings: super::Settings, signal: generator::Signal) -> Vec<super::Estimate> {
+    return detect_at(settings, signal, 16000.0);
+  }
+
+  fn detect_at(settings: super::Settings, signal: generator::Signal, sample_rate: f64) -> Vec<super::Estimate> {
+    let (sink, source) = channel::create::<::Audio>(1);
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+    let (report, estimates) = comm::channel();
+
+    spawn(proc() {
+      generator::Generator::new(sink, signal, 0.5, sample_rate, 1, sample_type::Float(32)).amplitude(-6.0).run();
+    });
+
+    spawn(proc() {
+      super::Detector::new(source, out_sink, settings, report).run();
+    });
+
+    let mut last = false;
+
+    while !last {
+      out_source.read(|audio| last = audio.last);
+    }
+
+    return estimates.recv();
+  }
+
+  #[test]
+  fn test_yin() {
+    let estimates = detect(super::Settings::new(super::Yin(0.1)).frames(1024, 128), generator::Sawtooth(220.0));
+
+    // (8000 - 1024) / 128 + 1 frames.
+    assert_eq!(estimates.len(), 55);
+    assert_eq!(estimates[0].position, 512);
+
+    for estimate in estimates.iter() {
+      assert!((estimate.frequency.unwrap() - 220.0).abs() < 1.0);
+      assert!(estimate.probability > 0.9);
+    }
+  }
+
+  #[test]
+  fn test_high_rate() {
+    // The default frames grow with the sample rate: 4096 samples every 512
+    // at 96 kHz.
+    let estimates = detect_at(super::Settings::new(super::Yin(0.1)), generator::Sine(220.0), 96000.0);
+
+    assert_eq!(estimates.len(), (48000 - 4096) / 512 + 1);
+    assert_eq!(estimates[0].position, 2048);
+
+    for estimate in estimates.iter() {
+      assert!((estimate.frequency.unwrap() - 220.0).abs() < 1.0);
+    }
+  }
+
+  #[test]
+  fn test_pyin() {
+    let settings = super::Settings::new(super::Pyin).frames(1024, 128).range(80.0, 800.0);
+
+    for estimate in detect(settings, generator::Sine(330.0)).iter() {
+      assert!((estimate.frequency.unwrap() - 330.0).abs() < 1.0);
+      assert!(estimate.probability > 0.9);
+    }
+
+    for estimate in detect(settings, generator::WhiteNoise).iter() {
+      assert_eq!(estimate.frequency, None);
+    }
+  }
+}
@@ -0,0 +1,290 @@
+use std;
+use std::comm;
+use std::comm::{Receiver, Sender};
+
+use channel;
+use loudness;
+
+/// The loudness ReplayGain 2.0 brings audio to, in LUFS.
+pub static REFERENCE: f64 = -18.0;
+
+/// The ReplayGain of one track, in dB, with its sample peak as a linear
+/// amplitude.
+#[deriving(Clone,Show,PartialEq)]
+pub struct Track {
+  pub gain: f64,
+  pub peak: f64,
+
+  /// The gating block energies of the track, for album gain.
+  blocks: Vec<f64>
+}
+
+fn gain(integrated: f64) -> f64 {
+  if integrated == std::f64::NEG_INFINITY {
+    return 0.0;
+  }
+
+  return REFERENCE - integrated;
+}
+
+impl Track {
+  /// The ReplayGain of what `meter` has measured.
+  pub fn from_meter(meter: &mut loudness::Meter) -> Track {
+    let loudness = meter.finish();
+
+    return Track {
+      gain: gain(loudness.integrated),
+      peak: (10.0f64).powf(loudness.sample_peak / 20.0),
+      blocks: meter.blocks().to_vec()
+    };
+  }
+
+  /// The tags for the track alone.
+  pub fn tags(&self) -> Tags {
+    return Tags { track_gain: self.gain, track_peak: self.peak, album: None };
+  }
+}
+
+/// Combines tracks, possibly scanned by separate pipelines, into an album.
+/// The album gain is that of all tracks measured as one, and the album peak
+/// the largest track peak.
+pub struct Album {
+  tracks: Vec<Track>
+}
+
+impl Album {
+  pub fn new() -> Album {
+    return Album { tracks: Vec::new() };
+  }
+
+  pub fn add(&mut self, track: Track) {
+    self.tracks.push(track);
+  }
+
+  pub fn gain(&self) -> f64 {
+    let mut blocks = Vec::new();
+
+    for track in self.tracks.iter() {
+      blocks.push_all(track.blocks.as_slice());
+    }
+
+    return gain(loudness::gated(blocks.as_slice()));
+  }
+
+  pub fn peak(&self) -> f64 {
+    return self.tracks.iter().fold(0.0, |a: f64, t| a.max(t.peak));
+  }
+
+  /// The tags for each track, in the order they were added.
+  pub fn tags(&self) -> Vec<Tags> {
+    let album = Some((self.gain(), self.peak()));
+
+    return self.tracks.iter().map(|t| Tags { track_gain: t.gain, track_peak: t.peak, album: album }).collect();
+  }
+}
+
+/// ReplayGain tags for a track, and its album if it has one.
+#[deriving(Clone,Show,PartialEq)]
+pub struct Tags {
+  pub track_gain: f64,
+  pub track_peak: f64,
+
+  /// The album gain and peak.
+  pub album: Option<(f64, f64)>
+}
+
+impl Tags {
+  /// The tag names and values, with gains in dB to two decimals and peaks
+  /// to six as other taggers write them.
+  pub fn fields(&self) -> Vec<(String, String)> {
+    let mut fields = vec![
+      ("REPLAYGAIN_TRACK_GAIN".to_string(), format!("{:.2} dB", self.track_gain)),
+      ("REPLAYGAIN_TRACK_PEAK".to_string(), format!("{:.6}", self.track_peak))
+    ];
+
+    match self.album {
+      Some((gain, peak)) => {
+        fields.push(("REPLAYGAIN_ALBUM_GAIN".to_string(), format!("{:.2} dB", gain)));
+        fields.push(("REPLAYGAIN_ALBUM_PEAK".to_string(), format!("{:.6}", peak)));
+      },
+      None => ()
+    }
+
+    return fields;
+  }
+
+  /// Vorbis comments, as used by Ogg Vorbis, Opus and FLAC.
+  pub fn vorbis_comments(&self) -> Vec<String> {
+    return self.fields().iter().map(|&(ref name, ref value)| format!("{}={}", name, value)).collect();
+  }
+
+  /// ID3v2.4 TXXX frames, with ISO-8859-1 text.
+  pub fn id3v2_frames(&self) -> Vec<Vec<u8>> {
+    return self.fields().iter().map(|&(ref name, ref value)| {
+      let size = 1 + name.len() + 1 + value.len();
+      let mut frame = Vec::with_capacity(10 + size);
+
+      frame.push_all(b"TXXX");
+
+      // Frame sizes are synchsafe: 7 bits per byte.
+      for shift in [21u, 14, 7, 0].iter() {
+        frame.push(((size >> *shift) & 0x7F) as u8);
+      }
+
+      frame.push_all([0, 0, 0]);
+      frame.push_all(name.as_bytes());
+      frame.push(0);
+      frame.push_all(value.as_bytes());
+
+      frame
+    }).collect();
+  }
+
+  /// APEv2 items, with UTF-8 text values.
+  pub fn ape_items(&self) -> Vec<Vec<u8>> {
+    return self.fields().iter().map(|&(ref name, ref value)| {
+      let mut item = Vec::with_capacity(8 + name.len() + 1 + value.len());
+
+      for shift in [0u, 8, 16, 24].iter() {
+        item.push((value.len() >> *shift) as u8);
+      }
+
+      item.push_all([0, 0, 0, 0]);
+      item.push_all(name.as_bytes());
+      item.push(0);
+      item.push_all(value.as_bytes());
+
+      item
+    }).collect();
+  }
+}
+
+/// Passes audio through unchanged while measuring its ReplayGain, and sends
+/// the result to `report` at the end of the stream.
+pub struct Scanner {
+  analyzer: loudness::Analyzer,
+  meter: Receiver<Option<loudness::Meter>>,
+  report: Sender<Track>
+}
+
+impl Scanner {
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, report: Sender<Track>) -> Scanner {
+    let (sender, meter) = comm::channel();
+
+    return Scanner { analyzer: loudness::Analyzer::with_meter(source, sink, sender), meter: meter, report: report };
+  }
+
+  pub fn run(&mut self) {
+    self.analyzer.run();
+
+    let track = match self.meter.recv() {
+      Some(ref mut meter) => Track::from_meter(meter),
+      None => Track { gain: 0.0, peak: 0.0, blocks: Vec::new() }
+    };
+
+    self.report.send(track);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::comm;
+  use std::f64::consts::PI;
+
+  use channel;
+  use endian;
+  use layout;
+  use loudness;
+  use sample::testing;
+  use sample_type;
+
+  fn track(amplitude: f64, seconds: uint) -> super::Track {
+    let mut meter = loudness::Meter::new(1, 48000.0, &layout::Layout::mono());
+    let samples = Vec::from_fn(48000 * seconds, |i| amplitude * (2.0 * PI * 1000.0 * i as f64 / 48000.0).sin());
+
+    meter.add(samples.as_slice());
+
+    return super::Track::from_meter(&mut meter);
+  }
+
+  #[test]
+  fn test_track() {
+    // A mono 1 kHz sine at amplitude 0.1 is -23 LUFS.
+    let track = track(0.1, 5);
+
+    assert!((track.gain - 5.0).abs() < 0.05);
+    assert!((track.peak - 0.1).abs() < 1e-6);
+  }
+
+  #[test]
+  fn test_scanner() {
+    let input = Vec::from_fn(48000 * 5, |i| 0.1 * (2.0 * PI * 1000.0 * i as f64 / 48000.0).sin());
+
+    let source = testing::source(input.clone(), 4800, 1, 48000.0, sample_type::Float(32), endian::Big);
+    let (sink, mut output) = channel::create::<::Audio>(1);
+    let (report, scanned) = comm::channel();
+
+    spawn(proc() {
+      super::Scanner::new(source, sink, report).run();
+    });
+
+    assert_eq!(testing::collect(&mut output).len(), input.len());
+
+    let track = scanned.recv();
+
+    assert!((track.gain - 5.0).abs() < 0.05);
+    assert!((track.peak - 0.1).abs() < 1e-6);
+  }
+
+  #[test]
+  fn test_silence() {
+    let source = testing::source(Vec::new(), 4800, 1, 48000.0, sample_type::Float(32), endian::Big);
+    let (sink, mut output) = channel::create::<::Audio>(1);
+    let (report, scanned) = comm::channel();
+
+    spawn(proc() {
+      super::Scanner::new(source, sink, report).run();
+    });
+
+    testing::collect(&mut output);
+
+    let track = scanned.recv();
+
+    assert_eq!(track.gain, 0.0);
+    assert_eq!(track.peak, 0.0);
+  }
+
+  #[test]
+  fn test_album() {
+    let mut album = super::Album::new();
+
+    album.add(track(0.1, 10));
+    album.add(track(0.2, 10));
+
+    // The loud track dominates: 10 log10((1 + 4) / 2) dB above the quiet.
+    assert!((album.gain() - (5.0 - 3.979)).abs() < 0.05);
+    assert!((album.peak() - 0.2).abs() < 1e-6);
+
+    let tags = album.tags();
+
+    assert_eq!(tags.len(), 2);
+    assert_eq!(tags[0].album, tags[1].album);
+  }
+
+  #[test]
+  fn test_formats() {
+    let tags = super::Tags { track_gain: -3.5, track_peak: 0.5, album: None };
+
+    assert_eq!(tags.vorbis_comments(), vec!["REPLAYGAIN_TRACK_GAIN=-3.50 dB".to_string(), "REPLAYGAIN_TRACK_PEAK=0.500000".to_string()]);
+
+    let frames = tags.id3v2_frames();
+
+    assert_eq!(frames[0].slice_to(10), b"TXXX\x00\x00\x00\x1F\x00\x00");
+    assert_eq!(frames[0].slice_from(10), b"\x00REPLAYGAIN_TRACK_GAIN\x00-3.50 dB");
+
+    let items = tags.ape_items();
+
+    assert_eq!(items[1].slice_to(8), b"\x08\x00\x00\x00\x00\x00\x00\x00");
+    assert_eq!(items[1].slice_from(8), b"REPLAYGAIN_TRACK_PEAK\x000.500000");
+  }
+}
@@ -0,0 +1,635 @@
+use std;
+use std::f64::consts::PI;
+
+use channel;
+use endian;
+use fft;
+use layout;
+use sample;
+use sample_type;
+
+/// The most filter phases used for a rational ratio. Ratios that need more
+/// use an interpolated table of `PHASES` phases instead.
+static MAX_PHASES: uint = 1024;
+static PHASES: uint = 256;
+
+#[deriving(Show,PartialEq)]
+pub enum Quality {
+  /// Linear interpolation, without low pass filtering. Cheap, but aliases,
+  /// so only suitable for previews.
+  Linear,
+
+  /// Windowed-sinc filters of increasing length and steepness.
+  Low, Medium, High
+}
+
+impl Quality {
+  /// Returns the number of zero crossings on each side of the sinc, the
+  /// cutoff as a fraction of the lower Nyquist frequency, and the Kaiser
+  /// window beta.
+  fn parameters(&self) -> (uint, f64, f64) {
+    return match *self {
+      Linear => (1, 1.0, 0.0),
+      Low => (8, 0.85, 6.0),
+      Medium => (16, 0.91, 8.0),
+      High => (32, 0.95, 10.0)
+    };
+  }
+}
+
+/// Changes the sample rate of audio to `sample_rate`.
+///
+/// Each output sample is computed with a band-limited windowed-sinc filter
+/// centered on its position in the input, so the output is not delayed.
+/// When both rates are integers whose reduced ratio has a small enough
+/// numerator, the filter phases are precomputed exactly (polyphase), else
+/// they are interpolated from a finely sampled table.
+///
+/// The output has exactly `length(frames, input rate, sample_rate)` frames,
+/// including the filter tail, which is flushed when the last packet is
+/// read. Input at `sample_rate` passes through unchanged.
+pub struct Resampler {
+  source: channel::Source<::Audio>,
+  sink: channel::Sink<::Audio>,
+  sample_rate: f64,
+  quality: Quality
+}
+
+impl Resampler {
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, sample_rate: f64, quality: Quality) -> Resampler {
+    if !(sample_rate > 0.0) {
+      panic!("resample::Resampler: Sample rate must be positive (ARGUMENT)");
+    }
+
+    return Resampler { source: source, sink: sink, sample_rate: sample_rate, quality: quality };
+  }
+
+  pub fn run(&mut self) {
+    let output_rate = self.sample_rate;
+    let quality = self.quality;
+
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    let mut state: Option<State> = None;
+    let mut format: Option<(uint, layout::Layout, sample_type::SampleType, endian::Endian)> = None;
+    let mut input = Vec::new();
+    let mut output = Vec::new();
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        output.truncate(0);
+
+        let mut passthrough = false;
+
+        if !audio.data.is_empty() {
+          if state.is_none() {
+            if !(audio.sample_rate > 0.0) {
+              panic!("resample::Resampler: Input sample rate must be positive (INPUT)");
+            }
+
+            state = Some(State::new(audio.channels, audio.sample_rate, output_rate, quality));
+          }
+
+          let state = state.as_mut().unwrap();
+
+          if audio.channels != state.channels || audio.sample_rate != state.input_rate {
+            panic!("resample::Resampler: Channels and sample rate must not change (INPUT)");
+          }
+
+          passthrough = audio.sample_rate == output_rate;
+
+          if !passthrough {
+            sample::decode(audio, &mut input);
+            state.process(input.as_slice(), last, &mut output);
+          }
+
+          format = Some((audio.channels, audio.layout.clone(), audio.sample_type, audio.endian));
+        } else if last {
+          // The rest of the filter goes out with an empty last packet.
+          if let Some(ref mut state) = state {
+            if state.input_rate != output_rate {
+              state.process(&[], true, &mut output);
+            }
+          }
+        }
+
+        let (channels, layout, sample_type, endian) = match format {
+          Some((channels, ref layout, sample_type, endian)) => (channels, layout.clone(), sample_type, endian),
+          None => (audio.channels, audio.layout.clone(), audio.sample_type, audio.endian)
+        };
+
+        sink.write(|out| {
+          out.channels = channels;
+          out.layout = layout.clone();
+          out.sample_rate = output_rate;
+          out.sample_type = sample_type;
+          out.endian = endian;
+          out.last = last;
+
+          if passthrough {
+            out.data.push_all(audio.data.as_slice());
+          } else if !output.is_empty() {
+            sample::encode(output.as_slice(), out);
+          }
+        });
+      });
+    }
+  }
+}
+
+/// Returns the number of frames `frames` input frames at `input_rate`
+/// become when resampled to `output_rate`.
+pub fn length(frames: uint, input_rate: f64, output_rate: f64) -> uint {
+  return match ratio(input_rate, output_rate) {
+    Some((up, down)) => (frames * up + down - 1) / down,
+    None => (frames as f64 * output_rate / input_rate).ceil() as uint
+  };
+}
+
+/// Returns the reduced ratio `(up, down)` of `output_rate` to `input_rate`,
+/// if both are integers.
+fn ratio(input_rate: f64, output_rate: f64) -> Option<(uint, uint)> {
+  if input_rate.fract() != 0.0 || output_rate.fract() != 0.0 || input_rate > 1e9 || output_rate > 1e9 {
+    return None;
+  }
+
+  let (a, b) = (output_rate as uint, input_rate as uint);
+  let divisor = gcd(a, b);
+
+  return Some((a / divisor, b / divisor));
+}
+
+fn gcd(a: uint, b: uint) -> uint {
+  return if b == 0 { a } else { gcd(b, a % b) };
+}
+
+/// How output frames map onto input frames.
+enum Step {
+  /// Output frame n is at input frame n * down / up, exactly.
+  Rational(uint, uint),
+
+  /// Output frame n is at input frame n * step.
+  Irrational(f64)
+}
+
+/// The resampling state of a stream.
+struct State {
+  channels: uint,
+  input_rate: f64,
+  output_rate: f64,
+  step: Step,
+  filter: Filter,
+
+  /// Interleaved input, starting `half - 1` frames before input frame
+  /// `dropped`.
+  history: Vec<f64>,
+  dropped: uint,
+
+  received: uint,
+  produced: uint,
+  weights: Vec<f64>
+}
+
+impl State {
+  fn new(channels: uint, input_rate: f64, output_rate: f64, quality: Quality) -> State {
+    let step = match ratio(input_rate, output_rate) {
+      Some((up, down)) if up <= MAX_PHASES => Rational(up, down),
+      _ => Irrational(input_rate / output_rate)
+    };
+
+    let phases = match step {
+      Rational(up, _) => up,
+      Irrational(_) => PHASES
+    };
+
+    let filter = Filter::new(quality, output_rate / input_rate, phases);
+    let history = Vec::from_elem((filter.half - 1) * channels, 0.0);
+    let taps = filter.taps();
+
+    return State {
+      channels: channels,
+      input_rate: input_rate,
+      output_rate: output_rate,
+      step: step,
+      filter: filter,
+      history: history,
+      dropped: 0,
+      received: 0,
+      produced: 0,
+      weights: Vec::from_elem(taps, 0.0)
+    };
+  }
+
+  /// Returns the input frame at or before output frame `n`, and the
+  /// fractional position past it in filter phases.
+  fn position(&self, n: uint) -> (uint, f64) {
+    return match self.step {
+      Rational(up, down) => ((n * down) / up, ((n * down) % up) as f64),
+      Irrational(step) => {
+        let t = n as f64 * step;
+        let i = t.floor();
+
+        (i as uint, (t - i) * self.filter.phases as f64)
+      }
+    };
+  }
+
+  /// Resamples `input`, replacing `output` with all the frames that can be
+  /// computed so far.
+  fn process(&mut self, input: &[f64], last: bool, output: &mut Vec<f64>) {
+    let channels = self.channels;
+    let half = self.filter.half;
+    let taps = self.filter.taps();
+
+    output.truncate(0);
+
+    if channels == 0 {
+      return;
+    }
+
+    self.history.push_all(input);
+    self.received += input.len() / channels;
+
+    if last {
+      self.history.grow((half + 1) * channels, 0.0);
+    }
+
+    let total = if last {
+      length(self.received, self.input_rate, self.output_rate)
+    } else {
+      std::uint::MAX
+    };
+
+    let frames = self.history.len() / channels;
+
+    while self.produced < total {
+      let (i, phase) = self.position(self.produced);
+
+      if i + taps - 1 - self.dropped >= frames {
+        break;
+      }
+
+      self.filter.weights(phase, self.weights.as_mut_slice());
+
+      let start = (i - self.dropped) * channels;
+
+      for c in range(0, channels) {
+        let mut sum = 0.0;
+
+        for (m, &w) in self.weights.iter().enumerate() {
+          sum += self.history[start + m * channels + c] * w;
+        }
+
+        output.push(sum);
+      }
+
+      self.produced += 1;
+    }
+
+    let (next, _) = self.position(self.produced);
+    let consumed = std::cmp::min(next - self.dropped, frames) * channels;
+    let remaining = self.history.len() - consumed;
+
+    for i in range(0, remaining) {
+      *self.history.get_mut(i) = self.history[i + consumed];
+    }
+
+    self.history.truncate(remaining);
+    self.dropped += consumed / channels;
+  }
+}
+
+/// Frames kept before those the filter needs, so that the filter can grow
+/// when the ratio falls.
+static MARGIN: uint = 256;
+
+/// Resamples by a ratio (output rate over input rate) that may change as it
+/// goes, for varispeed and pitch shifting.
+///
+/// Filter phases are always interpolated from a table, and the filter is
+/// redesigned when the ratio changes. After the last input, output frames
+/// are produced for every position before the end of the input.
+pub struct Varispeed {
+  channels: uint,
+  quality: Quality,
+  ratio: f64,
+  filter: Filter,
+
+  /// Interleaved input from input frame `first`, which is negative at the
+  /// start to hold the silence before the input.
+  history: Vec<f64>,
+  first: int,
+
+  received: uint,
+  position: f64,
+  weights: Vec<f64>
+}
+
+impl Varispeed {
+  pub fn new(channels: uint, ratio: f64, quality: Quality) -> Varispeed {
+    if channels == 0 || !(ratio > 0.0) {
+      panic!("resample::Varispeed: Channels and ratio must be positive (ARGUMENT)");
+    }
+
+    let filter = Filter::new(quality, ratio, PHASES);
+    let half = filter.half;
+    let taps = filter.taps();
+
+    return Varispeed {
+      channels: channels,
+      quality: quality,
+      ratio: ratio,
+      filter: filter,
+      history: Vec::from_elem((half - 1) * channels, 0.0),
+      first: 1 - half as int,
+      received: 0,
+      position: 0.0,
+      weights: Vec::from_elem(taps, 0.0)
+    };
+  }
+
+  pub fn ratio(&self) -> f64 {
+    return self.ratio;
+  }
+
+  /// Changes the ratio for the following output frames.
+  pub fn set_ratio(&mut self, ratio: f64) {
+    if !(ratio > 0.0) {
+      panic!("resample::Varispeed: Ratio must be positive (ARGUMENT)");
+    }
+
+    if ratio == self.ratio {
+      return;
+    }
+
+    self.ratio = ratio;
+    self.filter = Filter::new(self.quality, ratio, PHASES);
+    self.weights = Vec::from_elem(self.filter.taps(), 0.0);
+
+    // Silence stands in for frames dropped before the filter grew.
+    let needed = self.position.floor() as int - self.filter.half as int + 1;
+
+    if needed < self.first {
+      let mut history = Vec::from_elem((self.first - needed) as uint * self.channels, 0.0);
+
+      history.push_all(self.history.as_slice());
+      self.history = history;
+      self.first = needed;
+    }
+  }
+
+  /// Resamples `input`, replacing `output` with all the frames that can be
+  /// computed so far.
+  pub fn process(&mut self, input: &[f64], last: bool, output: &mut Vec<f64>) {
+    let channels = self.channels;
+    let half = self.filter.half;
+    let taps = self.filter.taps();
+
+    output.truncate(0);
+
+    self.history.push_all(input);
+    self.received += input.len() / channels;
+
+    if last {
+      self.history.grow((half + 1) * channels, 0.0);
+    }
+
+    let frames = self.history.len() / channels;
+
+    while !last || self.position < self.received as f64 {
+      let i = self.position.floor();
+      let start = i as int - half as int + 1 - self.first;
+
+      if start as uint + taps > frames {
+        break;
+      }
+
+      self.filter.weights((self.position - i) * self.filter.phases as f64, self.weights.as_mut_slice());
+
+      for c in range(0, channels) {
+        let mut sum = 0.0;
+
+        for (m, &w) in self.weights.iter().enumerate() {
+          sum += self.history[(start as uint + m) * channels + c] * w;
+        }
+
+        output.push(sum);
+      }
+
+      self.position += 1.0 / self.ratio;
+    }
+
+    let keep = self.position.floor() as int - half as int + 1 - MARGIN as int;
+
+    if keep > self.first {
+      let consumed = std::cmp::min((keep - self.first) as uint, frames) * channels;
+      let rest = self.history.slice_from(consumed).to_vec();
+
+      self.history = rest;
+      self.first += (consumed / channels) as int;
+    }
+  }
+}
+
+/// A table of filter phases. Row p holds the `2 * half` weights for an
+/// output frame p / phases input frames past input frame i, applied to
+/// input frames i - half + 1 to i + half.
+struct Filter {
+  half: uint,
+  phases: uint,
+  table: Vec<f64>
+}
+
+impl Filter {
+  /// Designs a filter for resampling by `ratio` (output rate over input
+  /// rate).
+  fn new(quality: Quality, ratio: f64, phases: uint) -> Filter {
+    let (zeros, rolloff, beta) = quality.parameters();
+
+    let linear = quality == Linear;
+    let cutoff = if linear { 1.0 } else { rolloff * ratio.min(1.0) };
+    let width = zeros as f64 / cutoff;
+    let half = std::cmp::max(width.ceil() as uint, 1);
+    let taps = half * 2;
+
+    let mut table = Vec::with_capacity((phases + 1) * taps);
+
+    for p in range(0, phases + 1) {
+      let fraction = p as f64 / phases as f64;
+      let row = table.len();
+
+      for m in range(0, taps) {
+        let x = fraction - (m as f64 - half as f64 + 1.0);
+
+        table.push(if linear { (1.0 - x.abs()).max(0.0) } else { kernel(x, cutoff, width, beta) });
+      }
+
+      let sum = table.slice_from(row).iter().fold(0.0, |a, &b| a + b);
+
+      for w in table.slice_from_mut(row).iter_mut() {
+        *w /= sum;
+      }
+    }
+
+    return Filter { half: half, phases: phases, table: table };
+  }
+
+  fn taps(&self) -> uint {
+    return self.half * 2;
+  }
+
+  /// Writes the weights for `phase` into `weights`, interpolating between
+  /// rows for fractional phases.
+  fn weights(&self, phase: f64, weights: &mut [f64]) {
+    let taps = self.taps();
+    let row = phase.floor() as uint;
+    let fraction = phase - row as f64;
+
+    let a = self.table.slice(row * taps, (row + 1) * taps);
+
+    if fraction == 0.0 {
+      for (w, &x) in weights.iter_mut().zip(a.iter()) {
+        *w = x;
+      }
+
+      return;
+    }
+
+    let b = self.table.slice((row + 1) * taps, (row + 2) * taps);
+
+    for ((w, &x), &y) in weights.iter_mut().zip(a.iter()).zip(b.iter()) {
+      *w = x + (y - x) * fraction;
+    }
+  }
+}
+
+/// A Kaiser windowed sinc with the given cutoff, in cycles per input
+/// sample times two, and half width in input samples.
+fn kernel(x: f64, cutoff: f64, width: f64, beta: f64) -> f64 {
+  if x.abs() >= width {
+    return 0.0;
+  }
+
+  let sinc = if x == 0.0 { cutoff } else { (PI * cutoff * x).sin() / (PI * x) };
+  let r = x / width;
+
+  return sinc * fft::bessel_i0(beta * (1.0 - r * r).sqrt()) / fft::bessel_i0(beta);
+}
+
+#[cfg(test)]
+mod tests {
+  use std::f64::consts::PI;
+
+  use channel;
+  use endian;
+  use sample::testing;
+  use sample_type;
+
+  fn resample(input: Vec<f64>, from: f64, to: f64, quality: super::Quality) -> Vec<f64> {
+    let source = testing::source(input, 1000, 1, from, sample_type::Float(64), endian::Big);
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() {
+      super::Resampler::new(source, out_sink, to, quality).run();
+    });
+
+    return testing::collect_with(&mut out_source, |audio| assert_eq!(audio.sample_rate, to));
+  }
+
+  fn sine(frequency: f64, rate: f64, frames: uint) -> Vec<f64> {
+    return Vec::from_fn(frames, |i| (2.0 * PI * frequency * i as f64 / rate).sin());
+  }
+
+  #[test]
+  fn test_length() {
+    assert_eq!(super::length(44100, 44100.0, 48000.0), 48000);
+    assert_eq!(super::length(1, 44100.0, 48000.0), 2);
+    assert_eq!(super::length(48000, 48000.0, 44100.0), 44100);
+    assert_eq!(super::length(1000, 44100.0, 44100.5), 1000 + 1);
+
+    assert_eq!(resample(Vec::from_elem(4410, 0.0), 44100.0, 48000.0, super::High).len(), 4800);
+    assert_eq!(resample(Vec::from_elem(4801, 0.0), 48000.0, 44100.0, super::Medium).len(), 4411);
+    assert_eq!(resample(Vec::from_elem(3000, 0.0), 44100.0, 12345.6, super::Low).len(), super::length(3000, 44100.0, 12345.6));
+  }
+
+  #[test]
+  fn test_sine() {
+    let output = resample(sine(1000.0, 44100.0, 8820), 44100.0, 48000.0, super::High);
+    let expected = sine(1000.0, 48000.0, 9600);
+
+    for i in range(500u, 9100) {
+      assert!((output[i] - expected[i]).abs() < 1e-3);
+    }
+  }
+
+  #[test]
+  fn test_downsample() {
+    // 20 kHz is above the output Nyquist frequency, and must be removed.
+    let output = resample(sine(20000.0, 96000.0, 9600), 96000.0, 32000.0, super::Medium);
+
+    for &x in output.slice(200, 3000).iter() {
+      assert!(x.abs() < 1e-2);
+    }
+  }
+
+  #[test]
+  fn test_varispeed() {
+    let input = sine(100.0, 8000.0, 4000);
+    let mut varispeed = super::Varispeed::new(1, 2.0, super::Medium);
+    let mut output = Vec::new();
+
+    varispeed.process(input.slice_to(2000), false, &mut output);
+
+    let before = output.len();
+
+    varispeed.set_ratio(0.5);
+    varispeed.process(input.slice_from(2000), true, &mut output);
+
+    // The frames before the change cover half as many input frames, and
+    // the rest of the input is halved.
+    let expected = before + (4000 - before / 2) / 2;
+
+    assert!(((before + output.len()) as int - expected as int).abs() <= 1);
+  }
+
+  #[test]
+  fn test_empty_packets() {
+    // Decoders send empty packets without a format when they find no
+    // frames, first and last.
+    let (mut sink, source) = channel::create::<::Audio>(1);
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() {
+      super::Resampler::new(source, out_sink, 48000.0, super::Medium).run();
+    });
+
+    spawn(proc() {
+      sink.write(|_| {});
+
+      sink.write(|audio| {
+        audio.channels = 1;
+        audio.sample_rate = 44100.0;
+        audio.sample_type = sample_type::Float(64);
+        audio.endian = endian::Big;
+
+        ::sample::encode(Vec::from_elem(4410, 0.0).as_slice(), audio);
+      });
+
+      sink.write(|audio| audio.last = true);
+    });
+
+    assert_eq!(testing::collect(&mut out_source).len(), 4800);
+  }
+
+  #[test]
+  fn test_linear() {
+    let output = resample(vec![0.0, 1.0, 0.0, -1.0], 1000.0, 2000.0, super::Linear);
+
+    assert_eq!(output, vec![0.0, 0.5, 1.0, 0.5, 0.0, -0.5, -1.0, -0.5]);
+  }
+}
@@ -0,0 +1,247 @@
+use std::mem;
+
+use endian;
+use sample_type;
+
+/// Returns the number of frames (samples per channel) in `audio`.
+pub fn frames(audio: &::Audio) -> uint {
+  let bytes = sample_type::size(audio.sample_type) / 8;
+
+  if bytes == 0 || audio.channels == 0 {
+    return 0;
+  }
+
+  return audio.data.len() / (bytes * audio.channels);
+}
+
+/// Decodes the interleaved PCM samples in `audio` into `output` as f64s,
+/// normalized so that full scale is [-1, 1).
+///
+/// `output` is cleared first.
+pub fn decode(audio: &::Audio, output: &mut Vec<f64>) {
+  output.truncate(0);
+
+  let bytes = match audio.sample_type {
+    sample_type::Unsigned(n) | sample_type::Signed(n) if n > 0 && n <= 32 && n % 8 == 0 => n / 8,
+    sample_type::Float(32) => 4,
+    sample_type::Float(64) => 8,
+    sample_type::Dsd => panic!("sample::decode: DSD must be converted to PCM by dsd::Decimator first (ARGUMENT)"),
+    t => panic!("sample::decode: Unsupported sample type {} (ARGUMENT)", t)
+  };
+
+  let count = audio.data.len() / bytes;
+
+  output.reserve(count);
+
+  for i in range(0, count) {
+    let d = audio.data.slice(i * bytes, (i + 1) * bytes);
+
+    let mut raw = 0u64;
+
+    for j in range(0, bytes) {
+      raw = match audio.endian {
+        endian::Big => (raw << 8) | d[j] as u64,
+        endian::Little => raw | ((d[j] as u64) << (8 * j))
+      };
+    }
+
+    let value = match audio.sample_type {
+      sample_type::Unsigned(n) => {
+        let half = (1u64 << (n - 1)) as f64;
+
+        (raw as f64 - half) / half
+      }
+      sample_type::Signed(n) => {
+        let shift = 64 - n;
+        let half = (1u64 << (n - 1)) as f64;
+
+        ((raw << shift) as i64 >> shift) as f64 / half
+      }
+      sample_type::Float(32) => unsafe { mem::transmute::<u32, f32>(raw as u32) } as f64,
+      sample_type::Float(64) => unsafe { mem::transmute::<u64, f64>(raw) },
+      _ => unreachable!()
+    };
+
+    output.push(value);
+  }
+}
+
+/// Encodes the interleaved f64 samples in `input` into `audio.data`, using
+/// `audio.sample_type` and `audio.endian`. Integer formats are clipped.
+///
+/// `audio.data` is replaced.
+pub fn encode(input: &[f64], audio: &mut ::Audio) {
+  let bytes = match audio.sample_type {
+    sample_type::Unsigned(n) | sample_type::Signed(n) if n > 0 && n <= 32 && n % 8 == 0 => n / 8,
+    sample_type::Float(32) => 4,
+    sample_type::Float(64) => 8,
+    t => panic!("sample::encode: Unsupported sample type {} (ARGUMENT)", t)
+  };
+
+  audio.data.truncate(0);
+  audio.data.reserve(input.len() * bytes);
+
+  for &value in input.iter() {
+    let raw = match audio.sample_type {
+      sample_type::Unsigned(n) => {
+        let half = (1u64 << (n - 1)) as f64;
+
+        (quantize(value, half) + half) as u64
+      }
+      sample_type::Signed(n) => {
+        let half = (1u64 << (n - 1)) as f64;
+
+        quantize(value, half) as i64 as u64
+      }
+      sample_type::Float(32) => unsafe { mem::transmute::<f32, u32>(value as f32) } as u64,
+      sample_type::Float(64) => unsafe { mem::transmute::<f64, u64>(value) },
+      _ => unreachable!()
+    };
+
+    for j in range(0, bytes) {
+      let byte = match audio.endian {
+        endian::Big => (raw >> (8 * (bytes - j - 1))) as u8,
+        endian::Little => (raw >> (8 * j)) as u8
+      };
+
+      audio.data.push(byte);
+    }
+  }
+}
+
+fn quantize(value: f64, half: f64) -> f64 {
+  let scaled = (value * half).round();
+
+  if scaled >= half {
+    return half - 1.0;
+  } else if scaled < -half {
+    return -half;
+  } else {
+    return scaled;
+  }
+}
+
+/// Helpers for the tests of nodes that read and write `Audio`.
+#[cfg(test)]
+pub mod testing {
+  use std;
+
+  use channel;
+  use endian;
+  use sample_type;
+
+  /// Returns a source that another task fills with the interleaved
+  /// `samples`, `frames` frames per packet. The last packet is marked, and
+  /// is empty if `samples` is.
+  pub fn source(samples: Vec<f64>, frames: uint, channels: uint, sample_rate: f64, sample_type: sample_type::SampleType, endian: endian::Endian) -> channel::Source<::Audio> {
+    let (mut sink, source) = channel::create::<::Audio>(1);
+
+    spawn(proc() {
+      let chunks: Vec<&[f64]> = samples.as_slice().chunks(frames * channels).collect();
+      let count = std::cmp::max(chunks.len(), 1);
+
+      for i in range(0, count) {
+        sink.write(|audio| {
+          audio.channels = channels;
+          audio.sample_rate = sample_rate;
+          audio.sample_type = sample_type;
+          audio.endian = endian;
+          audio.last = i + 1 == count;
+
+          if i < chunks.len() {
+            super::encode(chunks[i], audio);
+          }
+        });
+      }
+    });
+
+    return source;
+  }
+
+  /// Reads `source` to the end of the stream, and returns the decoded
+  /// samples.
+  pub fn collect(source: &mut channel::Source<::Audio>) -> Vec<f64> {
+    return collect_with(source, |_| ());
+  }
+
+  /// Like `collect`, but also passes each packet to `check`.
+  pub fn collect_with(source: &mut channel::Source<::Audio>, check: |&::Audio|) -> Vec<f64> {
+    let mut output = Vec::new();
+    let mut samples = Vec::new();
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        check(audio);
+
+        samples.truncate(0);
+
+        if !audio.data.is_empty() {
+          super::decode(audio, &mut samples);
+        }
+
+        output.push_all(samples.as_slice());
+      });
+    }
+
+    return output;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use endian;
+  use sample_type;
+
+  use Initialize;
+
+  #[test]
+  fn test_round_trip() {
+    let input = [0.0, 0.5, -0.5, -1.0, 0.25];
+    let types = [sample_type::Signed(16), sample_type::Signed(24), sample_type::Unsigned(8), sample_type::Float(32), sample_type::Float(64)];
+
+    for &t in types.iter() {
+      for &e in [endian::Big, endian::Little].iter() {
+        let mut audio: ::Audio = Initialize::initialize();
+        let mut output = Vec::new();
+
+        audio.channels = 1;
+        audio.sample_type = t;
+        audio.endian = e;
+
+        super::encode(input, &mut audio);
+        super::decode(&audio, &mut output);
+
+        assert_eq!(super::frames(&audio), input.len());
+        assert_eq!(output.as_slice(), input.as_slice());
+      }
+    }
+  }
+
+  #[test]
+  fn test_clip() {
+    let mut audio: ::Audio = Initialize::initialize();
+
+    audio.channels = 1;
+    audio.sample_type = sample_type::Signed(16);
+    audio.endian = endian::Big;
+
+    super::encode([2.0, -2.0], &mut audio);
+
+    assert_eq!(audio.data, vec![0x7Fu8, 0xFF, 0x80, 0x00]);
+  }
+
+  #[test]
+  #[should_fail]
+  fn test_decode_dsd() {
+    let mut audio: ::Audio = Initialize::initialize();
+
+    audio.channels = 1;
+    audio.sample_type = sample_type::Dsd;
+    audio.data.push(0x69);
+
+    super::decode(&audio, &mut Vec::new());
+  }
+}
@@ -0,0 +1,289 @@
+use std;
+
+use channel;
+use sample;
+use silence;
+
+/// Where to cut a stream.
+#[deriving(Clone,Show,PartialEq)]
+pub enum Boundaries {
+  /// Segments of a number of seconds each, the last one shorter.
+  Duration(f64),
+
+  /// Cuts at frame positions, in ascending order.
+  Positions(Vec<uint>),
+
+  /// Cuts at times in seconds, in ascending order, as from `cue_points`.
+  Times(Vec<f64>),
+
+  /// Cuts in the middle of each silence found by a `silence::Detector`
+  /// with these settings. Leading and trailing silence is not cut.
+  Silence(silence::Settings)
+}
+
+impl Boundaries {
+  /// The frame at which segment `index` ends, if known.
+  fn end(&self, index: uint, sample_rate: f64) -> Option<uint> {
+    return match *self {
+      Duration(seconds) => Some(((index + 1) as f64 * seconds * sample_rate).round() as uint),
+      Positions(ref positions) => positions.as_slice().get(index).map(|&p| p),
+      Times(ref times) => times.as_slice().get(index).map(|&t| (t * sample_rate).round() as uint),
+      Silence(_) => None
+    };
+  }
+}
+
+/// Reads the start of each track, in seconds, from a cue sheet: the
+/// `INDEX 01` of each `TRACK`, at 75 CD frames per second. The start of
+/// the first track is left out when it is at zero, so the result can be
+/// used as `Times` directly.
+pub fn cue_points(sheet: &str) -> Vec<f64> {
+  let mut points = Vec::new();
+
+  for line in sheet.lines() {
+    let words: Vec<&str> = line.split(|c: char| c.is_whitespace()).filter(|w| !w.is_empty()).collect();
+
+    if words.len() != 3 || words[0] != "INDEX" || words[1] != "01" {
+      continue;
+    }
+
+    let time: Vec<Option<uint>> = words[2].split(':').map(|n| from_str::<uint>(n)).collect();
+
+    match time.as_slice() {
+      [Some(minutes), Some(seconds), Some(frames)] => {
+        let point = (minutes * 60 + seconds) as f64 + frames as f64 / 75.0;
+
+        if point > 0.0 {
+          points.push(point);
+        }
+      },
+      _ => panic!("segment::cue_points: Bad index time {} (INPUT)", words[2])
+    }
+  }
+
+  return points;
+}
+
+/// A segment being started: its number from zero, and its first frame in
+/// the input.
+#[deriving(Clone,Show,PartialEq)]
+pub struct Segment {
+  pub index: uint,
+  pub start: uint
+}
+
+/// Cuts a stream into segments, each written to its own sink.
+///
+/// The sink for each segment comes from the factory passed to `run`, which
+/// is expected to start the chain that consumes it, such as a
+/// `caf::Muxer` feeding a `file::Output`. Every segment but the last ends
+/// with a `last` packet when the next begins.
+pub struct Segmenter {
+  source: channel::Source<::Audio>,
+  boundaries: Boundaries
+}
+
+impl Segmenter {
+  pub fn new(source: channel::Source<::Audio>, boundaries: Boundaries) -> Segmenter {
+    let ascending = match boundaries {
+      Duration(seconds) => seconds > 0.0,
+      Positions(ref positions) => positions.iter().all(|&p| p > 0) && positions.as_slice().windows(2).all(|w| w[0] < w[1]),
+      Times(ref times) => times.iter().all(|&t| t > 0.0) && times.as_slice().windows(2).all(|w| w[0] < w[1]),
+      Silence(_) => true
+    };
+
+    if !ascending {
+      panic!("segment::Segmenter: Boundaries must be positive and ascending (ARGUMENT)");
+    }
+
+    return Segmenter { source: source, boundaries: boundaries };
+  }
+
+  pub fn run(&mut self, factory: |Segment| -> channel::Sink<::Audio>) {
+    let source = &mut self.source;
+    let boundaries = &self.boundaries;
+
+    let settings = match *boundaries {
+      Silence(ref settings) => Some(settings),
+      _ => None
+    };
+
+    let mut sink = None;
+    let mut detector: Option<silence::Detector> = None;
+    let mut index = 0;
+
+    // Input frames read, and frames written to segments.
+    let mut position = 0;
+    let mut written = 0;
+
+    let mut samples = Vec::new();
+    let mut pending = Vec::new();
+    let mut output = Vec::new();
+
+    // Offsets into `output` at which to start a new segment.
+    let mut cuts = Vec::new();
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        if sink.is_none() {
+          sink = Some(factory(Segment { index: 0, start: 0 }));
+        }
+
+        samples.truncate(0);
+        output.truncate(0);
+        cuts.truncate(0);
+
+        if !audio.data.is_empty() {
+          sample::decode(audio, &mut samples);
+        }
+
+        let channels = std::cmp::max(audio.channels, 1);
+        let frames = samples.len() / channels;
+
+        match settings {
+          Some(settings) => {
+            if detector.is_none() && frames > 0 {
+              detector = Some(silence::Detector::new(settings.threshold, (settings.minimum * audio.sample_rate) as uint));
+            }
+
+            for frame in samples.as_slice().chunks(channels) {
+              let detector = detector.as_mut().unwrap();
+              let ended = detector.push(frame);
+
+              if detector.silent() {
+                pending.push_all(frame);
+                continue;
+              }
+
+              match ended {
+                Some(region) if region.start > 0 => {
+                  let half = (region.end - region.start) / 2 * channels;
+
+                  output.push_all(pending.slice_to(half));
+                  cuts.push(output.len());
+                  output.push_all(pending.slice_from(half));
+                },
+                _ => output.push_all(pending.as_slice())
+              }
+
+              pending.truncate(0);
+              output.push_all(frame);
+            }
+
+            if last {
+              output.push_all(pending.as_slice());
+              pending.truncate(0);
+            }
+          },
+          None => {
+            output.push_all(samples.as_slice());
+
+            loop {
+              match boundaries.end(index + cuts.len(), audio.sample_rate) {
+                Some(end) if end < position + frames => {
+                  cuts.push((std::cmp::max(end, position) - position) * channels);
+                },
+                _ => break
+              }
+            }
+          }
+        }
+
+        position += frames;
+
+        let mut from = 0;
+
+        for &cut in cuts.iter() {
+          send(sink.as_mut().unwrap(), audio, output.slice(from, cut), true);
+
+          written += (cut - from) / channels;
+          index += 1;
+          from = cut;
+
+          sink = Some(factory(Segment { index: index, start: written }));
+        }
+
+        send(sink.as_mut().unwrap(), audio, output.slice_from(from), last);
+
+        written += (output.len() - from) / channels;
+      });
+    }
+  }
+}
+
+/// Writes `samples` to `sink` in the format of `audio`.
+fn send(sink: &mut channel::Sink<::Audio>, audio: &::Audio, samples: &[f64], last: bool) {
+  sink.write(|out| {
+    out.channels = audio.channels;
+    out.layout = audio.layout.clone();
+    out.sample_rate = audio.sample_rate;
+    out.sample_type = audio.sample_type;
+    out.endian = audio.endian;
+    out.last = last;
+
+    if !samples.is_empty() {
+      sample::encode(samples, out);
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use std::comm;
+
+  use channel;
+  use endian;
+  use sample::testing;
+  use sample_type;
+  use silence;
+
+  #[test]
+  fn test_cue_points() {
+    let sheet = "FILE \"album.wav\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00\n  TRACK 02 AUDIO\n    INDEX 00 03:58:50\n    INDEX 01 04:00:15\n";
+
+    assert_eq!(super::cue_points(sheet), vec![240.2]);
+  }
+
+  fn run(boundaries: super::Boundaries, input: Vec<f64>) -> Vec<(super::Segment, Vec<f64>)> {
+    let source = testing::source(input, 4, 1, 10.0, sample_type::Float(32), endian::Big);
+    let mut segments = Vec::new();
+
+    super::Segmenter::new(source, boundaries).run(|segment| {
+      let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+      let (report, receiver) = comm::channel();
+
+      spawn(proc() {
+        report.send(testing::collect(&mut out_source));
+      });
+
+      segments.push((segment, receiver));
+
+      out_sink
+    });
+
+    return segments.into_iter().map(|(segment, receiver)| (segment, receiver.recv())).collect();
+  }
+
+  #[test]
+  fn test_duration() {
+    let segments = run(super::Duration(0.3), Vec::from_fn(10, |i| i as f64 / 16.0));
+
+    assert_eq!(segments.iter().map(|&(ref s, _)| s.start).collect::<Vec<uint>>(), vec![0, 3, 6, 9]);
+    assert_eq!(segments[1], (super::Segment { index: 1, start: 3 }, vec![3.0 / 16.0, 4.0 / 16.0, 5.0 / 16.0]));
+    assert_eq!(segments[3], (super::Segment { index: 3, start: 9 }, vec![9.0 / 16.0]));
+  }
+
+  #[test]
+  fn test_silence() {
+    let input = vec![0.0, 0.5, 0.5, 0.0, 0.0, 0.0, 0.0, 0.5, 0.0, 0.0];
+    let segments = run(super::Silence(silence::Settings::new(-60.0, 0.3)), input);
+
+    assert_eq!(segments.len(), 2);
+    assert_eq!(segments[0], (super::Segment { index: 0, start: 0 }, vec![0.0, 0.5, 0.5, 0.0, 0.0]));
+    assert_eq!(segments[1], (super::Segment { index: 1, start: 5 }, vec![0.0, 0.0, 0.5, 0.0, 0.0]));
+  }
+}
@@ -0,0 +1,290 @@
+use std;
+use std::comm::Sender;
+
+use channel;
+use loudness;
+use sample;
+
+/// A range of input frames, from `start` up to but not including `end`.
+#[deriving(Clone,Show,PartialEq)]
+pub struct Region {
+  pub start: uint,
+  pub end: uint
+}
+
+/// Finds runs of silent frames: frames whose samples are all below a
+/// threshold, in runs of at least a minimum number of frames.
+pub struct Detector {
+  threshold: f64,
+  minimum: uint,
+  position: uint,
+  start: Option<uint>
+}
+
+impl Detector {
+  /// Detects silence below `threshold` dBFS lasting `minimum` frames.
+  pub fn new(threshold: f64, minimum: uint) -> Detector {
+    return Detector { threshold: (10.0f64).powf(threshold / 20.0), minimum: std::cmp::max(minimum, 1), position: 0, start: None };
+  }
+
+  /// Adds a frame, and returns the silent region it ends, if any.
+  pub fn push(&mut self, frame: &[f64]) -> Option<Region> {
+    let silent = frame.iter().all(|x| x.abs() < self.threshold);
+    let mut ended = None;
+
+    if silent {
+      if self.start.is_none() {
+        self.start = Some(self.position);
+      }
+    } else {
+      ended = self.finish();
+      self.start = None;
+    }
+
+    self.position += 1;
+
+    return ended;
+  }
+
+  /// The silent region at the end of the frames so far, if any.
+  pub fn finish(&self) -> Option<Region> {
+    return match self.start {
+      Some(start) if self.position - start >= self.minimum => Some(Region { start: start, end: self.position }),
+      _ => None
+    };
+  }
+
+  /// Whether the last frame added was silent.
+  pub fn silent(&self) -> bool {
+    return self.start.is_some();
+  }
+}
+
+/// What to detect and do about silence. The threshold is in dBFS, and
+/// durations are in seconds.
+#[deriving(Clone,Show,PartialEq)]
+pub struct Settings {
+  pub threshold: f64,
+  pub minimum: f64,
+  pub trim_start: bool,
+  pub trim_end: bool,
+
+  /// Internal silences longer than this are shortened to it.
+  pub max_gap: Option<f64>
+}
+
+impl Settings {
+  /// Detects silence below `threshold` dBFS lasting `minimum` seconds,
+  /// without changing the audio.
+  pub fn new(threshold: f64, minimum: f64) -> Settings {
+    return Settings { threshold: threshold, minimum: minimum, trim_start: false, trim_end: false, max_gap: None };
+  }
+
+  /// Detects silence `offset` dB relative to the integrated loudness of a
+  /// measurement of the same audio.
+  pub fn relative(offset: f64, loudness: &loudness::Loudness, minimum: f64) -> Settings {
+    return Settings::new(loudness.integrated + offset, minimum);
+  }
+
+  /// Returns the settings, also removing leading and trailing silence.
+  pub fn trim(mut self) -> Settings {
+    self.trim_start = true;
+    self.trim_end = true;
+
+    return self;
+  }
+
+  /// Returns the settings, also shortening internal silence to `max_gap`
+  /// seconds. Half of the kept silence is taken from each end of the gap.
+  pub fn shorten(mut self, max_gap: f64) -> Settings {
+    self.max_gap = Some(max_gap);
+
+    return self;
+  }
+}
+
+/// Detects silence, and trims or shortens it as the settings ask.
+///
+/// Silent frames are held back until the end of their run is known, so
+/// trailing silence is only removed at the end of the stream. Silence
+/// regions, in input frames, are sent to `report` at the end of the
+/// stream.
+pub struct Trimmer {
+  source: channel::Source<::Audio>,
+  sink: channel::Sink<::Audio>,
+  settings: Settings,
+  report: Option<Sender<Vec<Region>>>
+}
+
+impl Trimmer {
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, settings: Settings, report: Option<Sender<Vec<Region>>>) -> Trimmer {
+    return Trimmer { source: source, sink: sink, settings: settings, report: report };
+  }
+
+  pub fn run(&mut self) {
+    let settings = &self.settings;
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    let mut detector: Option<Detector> = None;
+    let mut gap = None;
+    let mut regions = Vec::new();
+
+    let mut samples = Vec::new();
+    let mut pending = Vec::new();
+    let mut output = Vec::new();
+
+    // Whether a sound frame has been seen, which ends leading silence.
+    let mut started = false;
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        output.truncate(0);
+
+        if !audio.data.is_empty() {
+          if detector.is_none() {
+            detector = Some(Detector::new(settings.threshold, (settings.minimum * audio.sample_rate) as uint));
+            gap = settings.max_gap.map(|g| (g * audio.sample_rate) as uint * audio.channels);
+          }
+
+          let detector = detector.as_mut().unwrap();
+
+          sample::decode(audio, &mut samples);
+
+          for frame in samples.as_slice().chunks(audio.channels) {
+            let ended = detector.push(frame);
+
+            if detector.silent() {
+              if (settings.trim_start && !started) || settings.trim_end || gap.is_some() {
+                pending.push_all(frame);
+              } else {
+                output.push_all(frame);
+              }
+
+              continue;
+            }
+
+            if !pending.is_empty() {
+              match (ended.is_some(), gap) {
+                (true, _) if !started && settings.trim_start => (),
+                (true, Some(gap)) if started && pending.len() > gap => {
+                  let head = gap / audio.channels / 2 * audio.channels;
+
+                  output.push_all(pending.slice_to(head));
+                  output.push_all(pending.slice_from(pending.len() - (gap - head)));
+                },
+                _ => output.push_all(pending.as_slice())
+              }
+
+              pending.truncate(0);
+            }
+
+            match ended {
+              Some(region) => regions.push(region),
+              None => ()
+            }
+
+            started = true;
+            output.push_all(frame);
+          }
+        }
+
+        if last {
+          let trailing = detector.as_ref().and_then(|d| d.finish());
+
+          match trailing {
+            Some(ref region) => {
+              if !(settings.trim_end || (!started && settings.trim_start)) {
+                output.push_all(pending.as_slice());
+              }
+
+              regions.push(region.clone());
+            },
+            None => output.push_all(pending.as_slice())
+          }
+        }
+
+        sink.write(|out| {
+          out.channels = audio.channels;
+          out.layout = audio.layout.clone();
+          out.sample_rate = audio.sample_rate;
+          out.sample_type = audio.sample_type;
+          out.endian = audio.endian;
+          out.last = last;
+
+          if !output.is_empty() {
+            sample::encode(output.as_slice(), out);
+          }
+        });
+      });
+    }
+
+    match self.report {
+      Some(ref report) => report.send(regions),
+      None => ()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::comm;
+
+  use channel;
+  use endian;
+  use sample::testing;
+  use sample_type;
+
+  #[test]
+  fn test_detector() {
+    let mut detector = super::Detector::new(-40.0, 2);
+    let levels = [0.5, 0.0, 0.5, 0.001, 0.0, 0.001, 0.5, 0.0, 0.0];
+    let mut regions = Vec::new();
+
+    for &x in levels.iter() {
+      match detector.push(&[x, 0.0]) {
+        Some(region) => regions.push(region),
+        None => ()
+      }
+    }
+
+    assert_eq!(regions, vec![super::Region { start: 3, end: 6 }]);
+    assert_eq!(detector.finish(), Some(super::Region { start: 7, end: 9 }));
+  }
+
+  fn run(settings: super::Settings, levels: &[f64]) -> (Vec<f64>, Vec<super::Region>) {
+    // One frame per packet, to check that runs span packets.
+    let source = testing::source(levels.to_vec(), 1, 1, 10.0, sample_type::Float(32), endian::Big);
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+    let (report, regions) = comm::channel();
+
+    spawn(proc() {
+      super::Trimmer::new(source, out_sink, settings, Some(report)).run();
+    });
+
+    let output = testing::collect(&mut out_source);
+
+    return (output, regions.recv());
+  }
+
+  #[test]
+  fn test_trim() {
+    let levels = [0.0, 0.0, 0.0, 0.5, 0.0, 0.5, 0.0, 0.0, 0.0, 0.0, 0.5, 0.0, 0.0];
+    let (output, regions) = run(super::Settings::new(-60.0, 0.2).trim().shorten(0.2), &levels);
+
+    assert_eq!(output, vec![0.5, 0.0, 0.5, 0.0, 0.0, 0.5]);
+    assert_eq!(regions, vec![
+      super::Region { start: 0, end: 3 },
+      super::Region { start: 6, end: 10 },
+      super::Region { start: 11, end: 13 }
+    ]);
+
+    let (output, _) = run(super::Settings::new(-60.0, 0.2), &levels);
+
+    assert_eq!(output, levels.to_vec());
+  }
+}
@@ -71,7 +71,7 @@ impl<'a> Stream<'a> {
       }
     }
 
-    let write_len = std::cmp::min(buffer.len(), self.buffer.len() - self.position);
+    let write_len = std::cmp::min(buffer.len(), self.length - self.position);
 
     {
         let input = self.buffer.slice(self.position, self.position + write_len);
@@ -84,7 +84,7 @@ impl<'a> Stream<'a> {
 
     self.position += write_len;
 
-    assert!(self.position <= self.buffer.len());
+    assert!(self.position <= self.length);
 
     return Some(write_len);
 
@@ -107,11 +107,11 @@ impl<'a> Stream<'a> {
       }
     }
 
-    let skip_len = std::cmp::min(amount, self.buffer.len() - self.position);
+    let skip_len = std::cmp::min(amount, self.length - self.position);
 
     self.position += skip_len;
 
-    assert!(self.position <= self.buffer.len());
+    assert!(self.position <= self.length);
 
     return Some(skip_len);
   }
@@ -159,6 +159,19 @@ impl<'a> Stream<'a> {
     return read;
   }
 
+  /// Reads a u8, returns `None` on end of file.
+  pub fn try_read_u8(&mut self) -> Option<u8> {
+    let mut buffer = [0];
+
+    loop {
+      match self.try_read(buffer) {
+        Some(0) => continue,
+        Some(_) => return Some(buffer[0]),
+        None => return None
+      }
+    }
+  }
+
   /// Reads a u8.
   pub fn read_u8(&mut self) -> u8 {
     let mut buffer = [0];
@@ -363,6 +376,69 @@ impl<'a> Bitstream<'a> {
   }
 }
 
+/// Reads bits, most significant bit first, from a byte slice.
+///
+/// Unlike Bitstream this never blocks, codecs that know their frame size read
+/// the frame from a Stream first and then parse it with a Bitslice. Reading
+/// past the end of the slice returns zeros.
+pub struct Bitslice<'a> {
+  data: &'a [u8], position: uint
+}
+
+impl<'a> Bitslice<'a> {
+  pub fn new(data: &'a [u8]) -> Bitslice<'a> {
+    return Bitslice { data: data, position: 0 };
+  }
+
+  /// The number of bits read so far.
+  pub fn position(&self) -> uint {
+    return self.position;
+  }
+
+  /// The number of bits left in the slice.
+  pub fn remaining(&self) -> uint {
+    let length = self.data.len() * 8;
+
+    return if self.position < length { length - self.position } else { 0 };
+  }
+
+  pub fn read_n(&mut self, n: uint) -> u32 {
+    if n > 32 {
+      panic!("Bitslice: You cannot request more than 32 bits into a u32 (ARGUMENT)");
+    }
+
+    let mut result = 0u32;
+
+    for _ in range(0, n) {
+      let byte = self.position / 8;
+
+      let bit = if byte < self.data.len() {
+        (self.data[byte] >> (7 - self.position % 8)) & 1
+      } else {
+        0
+      };
+
+      result = (result << 1) | bit as u32;
+
+      self.position += 1;
+    }
+
+    return result;
+  }
+
+  pub fn read_n_signed(&mut self, n: uint) -> i32 {
+    return extend_sign_bits(self.read_n(n) as u64, n) as i32;
+  }
+
+  pub fn read_bool(&mut self) -> bool {
+    return self.read_n(1) == 1;
+  }
+
+  pub fn skip(&mut self, n: uint) {
+    self.position += n;
+  }
+}
+
 fn extend_sign(value: u64, n: uint) -> i64 {
   return extend_sign_bits(value, n * 8);
 }
@@ -428,6 +504,16 @@ mod tests {
     assert_eq!(s.read_be_u64(), 0x08090A0B0C0D0E0F);
   }
 
+  #[test]
+  fn test_try_read_u8() {
+    let mut source = prepare!(vec![0x00u8, 0x01]);
+    let mut s = Stream::new(&mut source);
+
+    assert_eq!(s.try_read_u8(), Some(0));
+    assert_eq!(s.try_read_u8(), Some(1));
+    assert_eq!(s.try_read_u8(), None);
+  }
+
   #[test]
   fn test_read_i8() {
     let mut source = prepare!(vec![0xFFu8]);
@@ -507,6 +593,36 @@ mod tests {
     assert_eq!(s.read_be_uint_n(3), 0x030405);
   }
 
+  #[test]
+  fn test_shorter_packet() {
+    let (mut sink, mut source) = channel::create::<::Binary>(2);
+
+    sink.write(|binary| { binary.data.push_all([1u8, 2, 3]); });
+    sink.write(|binary| { binary.data.push(4); binary.last = true; });
+
+    let mut s = Stream::new(&mut source);
+    let mut buffer = [0u8, ..4];
+
+    assert_eq!(s.try_read(buffer), Some(3));
+    assert_eq!(s.try_read(buffer), Some(1));
+    assert_eq!(buffer[0], 4);
+    assert_eq!(s.try_read(buffer), None);
+  }
+
+  #[test]
+  fn test_shorter_packet_skip() {
+    let (mut sink, mut source) = channel::create::<::Binary>(2);
+
+    sink.write(|binary| { binary.data.push_all([1u8, 2, 3]); });
+    sink.write(|binary| { binary.data.push(4); binary.last = true; });
+
+    let mut s = Stream::new(&mut source);
+
+    assert_eq!(s.try_skip(3), Some(3));
+    assert_eq!(s.try_skip(3), Some(1));
+    assert_eq!(s.try_skip(3), None);
+  }
+
   #[test]
   fn test_short_reads() {
     let mut source = prepare!(vec![0xFFu8, 0xAA, 0x44]);
@@ -574,6 +690,21 @@ mod tests {
     assert_eq!(r.read_n(4), 0x1);
   }
 
+  #[test]
+  fn test_bitslice() {
+    let data = [0xEAu8, 0xBD, 0x21];
+    let mut r = super::Bitslice::new(data);
+
+    assert_eq!(r.read_n(4), 0xE);
+    assert_eq!(r.read_n_signed(4), -6);
+    assert_eq!(r.read_bool(), true);
+    r.skip(3);
+    assert_eq!(r.read_n(12), 0xD21);
+    assert_eq!(r.remaining(), 0);
+    assert_eq!(r.read_n(4), 0);
+    assert_eq!(r.position(), 28);
+  }
+
   #[test]
   fn test_stream2() {
     let mut source = prepare!(vec![0x30u8, 0xC8, 0x61]);
@@ -0,0 +1,627 @@
+use std;
+use std::comm::Receiver;
+use std::f64::consts::PI;
+use std::iter::range_step;
+
+use channel;
+use fft;
+use resample;
+use sample;
+
+#[deriving(Show,PartialEq)]
+pub enum Algorithm {
+  /// Waveform similarity overlap-add, which keeps speech intelligible.
+  Wsola,
+
+  /// A phase vocoder with identity phase locking, which suits music.
+  PhaseVocoder
+}
+
+/// How to change audio: `tempo` 2 plays twice as fast, and `pitch` 2 plays
+/// an octave higher.
+#[deriving(Clone,Show,PartialEq)]
+pub struct Ratios {
+  pub tempo: f64,
+  pub pitch: f64
+}
+
+impl Ratios {
+  pub fn new(tempo: f64, pitch: f64) -> Ratios {
+    if !(tempo > 0.0) || !(pitch > 0.0) {
+      panic!("stretch::Ratios: Ratios must be positive (ARGUMENT)");
+    }
+
+    return Ratios { tempo: tempo, pitch: pitch };
+  }
+
+  /// How many times longer the audio gets before it is resampled to change
+  /// the pitch.
+  fn stretch(&self) -> f64 {
+    return self.pitch / self.tempo;
+  }
+}
+
+/// Deinterleaved input from frame `offset`, which reads as silence past
+/// what has been received.
+struct Input {
+  data: Vec<Vec<f64>>,
+  offset: uint,
+  received: uint
+}
+
+impl Input {
+  fn get(&self, c: uint, frame: uint) -> f64 {
+    let data = &self.data[c];
+
+    if frame < self.offset || frame - self.offset >= data.len() {
+      return 0.0;
+    }
+
+    return data[frame - self.offset];
+  }
+
+  /// The sum of the channels of `frame`.
+  fn mono(&self, frame: uint) -> f64 {
+    return range(0, self.data.len()).fold(0.0, |a, c| a + self.get(c, frame));
+  }
+
+  fn drop_before(&mut self, frame: uint) {
+    if frame <= self.offset {
+      return;
+    }
+
+    let count = std::cmp::min(frame - self.offset, self.data[0].len());
+
+    for data in self.data.iter_mut() {
+      let rest = data.slice_from(count).to_vec();
+      *data = rest;
+    }
+
+    self.offset += count;
+  }
+}
+
+/// Produces the windowed output segment for each analysis frame.
+trait Engine {
+  /// Frames per segment, and between output segments.
+  fn size(&self) -> uint;
+  fn hop(&self) -> uint;
+
+  /// Writes the segment for the analysis frame at `position`, `advance`
+  /// frames after the last, into `segment`, one `size()` vector per
+  /// channel.
+  fn synthesize(&mut self, input: &Input, position: uint, advance: uint, segment: &mut Vec<Vec<f64>>);
+}
+
+/// Picks, within a tolerance of each analysis frame, the segment most like
+/// the natural continuation of the last one, and overlaps segments by half
+/// under a Hann window.
+struct Wsola {
+  size: uint,
+  tolerance: uint,
+  window: Vec<f64>,
+  previous: Option<uint>,
+  template: Vec<f64>
+}
+
+/// Samples skipped between those compared when searching for a segment.
+static DECIMATION: uint = 4;
+
+impl Wsola {
+  fn new(sample_rate: f64) -> Wsola {
+    // 30 ms segments.
+    let size = std::cmp::max((sample_rate * 0.03) as uint / 2 * 2, 8);
+
+    return Wsola { size: size, tolerance: size / 4, window: fft::Hann.coefficients(size), previous: None, template: Vec::new() };
+  }
+}
+
+impl Engine for Wsola {
+  fn size(&self) -> uint {
+    return self.size;
+  }
+
+  fn hop(&self) -> uint {
+    return self.size / 2;
+  }
+
+  fn synthesize(&mut self, input: &Input, position: uint, _: uint, segment: &mut Vec<Vec<f64>>) {
+    let start = match self.previous {
+      None => position,
+      Some(previous) => {
+        let natural = previous + self.hop();
+
+        self.template.truncate(0);
+
+        for i in range_step(0, self.size, DECIMATION) {
+          self.template.push(input.mono(natural + i));
+        }
+
+        let low = std::cmp::max(if position > self.tolerance { position - self.tolerance } else { 0 }, input.offset);
+        let mut best = position;
+        let mut score = std::f64::NEG_INFINITY;
+
+        for candidate in range(low, position + self.tolerance + 1) {
+          let mut dot = 0.0;
+          let mut energy = 0.0;
+
+          for (j, &t) in self.template.iter().enumerate() {
+            let x = input.mono(candidate + j * DECIMATION);
+
+            dot += t * x;
+            energy += x * x;
+          }
+
+          let similarity = if energy > 0.0 { dot / energy.sqrt() } else { 0.0 };
+
+          if similarity > score {
+            score = similarity;
+            best = candidate;
+          }
+        }
+
+        best
+      }
+    };
+
+    self.previous = Some(start);
+
+    for (c, output) in segment.iter_mut().enumerate() {
+      for (i, x) in output.iter_mut().enumerate() {
+        *x = input.get(c, start + i) * self.window[i];
+      }
+    }
+  }
+}
+
+/// Moves each spectral peak's phase on by its measured frequency, and keeps
+/// the bins around each peak at their phase relative to it, so that the
+/// sound of each partial holds together.
+struct Vocoder {
+  size: uint,
+  window: Vec<f64>,
+  fft: fft::RealFft,
+  frame: Vec<f64>,
+  bins: Vec<fft::Complex>,
+  output: Vec<f64>,
+  magnitudes: Vec<f64>,
+  phases: Vec<f64>,
+  peaks: Vec<uint>,
+
+  /// Per channel, the last analysis and synthesis phases.
+  analysis: Vec<Vec<f64>>,
+  synthesis: Vec<Vec<f64>>,
+  first: bool
+}
+
+/// Wraps a phase into [-π, π].
+fn principal(phase: f64) -> f64 {
+  return phase - 2.0 * PI * (phase / (2.0 * PI)).round();
+}
+
+fn distance(a: uint, b: uint) -> uint {
+  return if a > b { a - b } else { b - a };
+}
+
+impl Vocoder {
+  fn new(sample_rate: f64, channels: uint) -> Vocoder {
+    // About 46 ms frames: 2048 at 44.1 and 48 kHz.
+    let mut size = 64;
+
+    while (size as f64) < sample_rate * 0.046 {
+      size *= 2;
+    }
+
+    let bins = size / 2 + 1;
+
+    return Vocoder {
+      size: size,
+      window: fft::Hann.coefficients(size),
+      fft: fft::RealFft::new(size),
+      frame: Vec::from_elem(size, 0.0),
+      bins: Vec::new(),
+      output: Vec::new(),
+      magnitudes: Vec::new(),
+      phases: Vec::new(),
+      peaks: Vec::new(),
+      analysis: Vec::from_elem(channels, Vec::from_elem(bins, 0.0)),
+      synthesis: Vec::from_elem(channels, Vec::from_elem(bins, 0.0)),
+      first: true
+    };
+  }
+
+  /// Finds bins louder than the two on either side.
+  fn find_peaks(&mut self) {
+    let m = self.magnitudes.as_slice();
+
+    self.peaks.truncate(0);
+
+    for k in range(0, m.len()) {
+      let louder = range(1u, 3).all(|d| (k < d || m[k] > m[k - d]) && (k + d >= m.len() || m[k] > m[k + d]));
+
+      if louder {
+        self.peaks.push(k);
+      }
+    }
+
+    if self.peaks.is_empty() {
+      self.peaks.extend(range(0, m.len()));
+    }
+  }
+}
+
+impl Engine for Vocoder {
+  fn size(&self) -> uint {
+    return self.size;
+  }
+
+  fn hop(&self) -> uint {
+    return self.size / 4;
+  }
+
+  fn synthesize(&mut self, input: &Input, position: uint, advance: uint, segment: &mut Vec<Vec<f64>>) {
+    let hop = self.hop() as f64;
+
+    // The Hann window squared overlaps to 1.5 at a quarter size hop.
+    let scale = 1.0 / 1.5;
+
+    for c in range(0, segment.len()) {
+      for i in range(0, self.size) {
+        *self.frame.get_mut(i) = input.get(c, position + i) * self.window[i];
+      }
+
+      self.fft.forward(self.frame.as_slice(), &mut self.bins);
+
+      self.magnitudes.truncate(0);
+      self.magnitudes.extend(self.bins.iter().map(|b| b.norm()));
+      self.phases.truncate(0);
+      self.phases.extend(self.bins.iter().map(|b| b.arg()));
+
+      if self.first {
+        *self.synthesis.get_mut(c) = self.phases.clone();
+      } else {
+        self.find_peaks();
+
+        let analysis = self.analysis[c].as_slice();
+        let synthesis = self.synthesis.get_mut(c);
+
+        for &k in self.peaks.iter() {
+          let omega = 2.0 * PI * k as f64 / self.size as f64;
+          let deviation = principal(self.phases[k] - analysis[k] - omega * advance as f64);
+
+          *synthesis.get_mut(k) += hop * (omega + deviation / advance as f64);
+        }
+
+        // Each bin takes its phase from the nearest peak.
+        let mut p = 0;
+
+        for k in range(0, self.phases.len()) {
+          while p + 1 < self.peaks.len() && distance(self.peaks[p + 1], k) < distance(self.peaks[p], k) {
+            p += 1;
+          }
+
+          let peak = self.peaks[p];
+
+          if peak != k {
+            *synthesis.get_mut(k) = synthesis[peak] + self.phases[k] - self.phases[peak];
+          }
+        }
+      }
+
+      *self.analysis.get_mut(c) = self.phases.clone();
+
+      for (k, bin) in self.bins.iter_mut().enumerate() {
+        *bin = fft::Complex::polar(self.synthesis[c][k]).scale(self.magnitudes[k]);
+      }
+
+      self.fft.inverse(self.bins.as_slice(), &mut self.output);
+
+      for (i, x) in segment.get_mut(c).iter_mut().enumerate() {
+        *x = self.output[i] * self.window[i] * scale;
+      }
+    }
+
+    self.first = false;
+  }
+}
+
+/// Overlap-adds the segments of an `Engine` at a fixed hop while moving
+/// through the input at a hop divided by the stretch.
+struct State {
+  engine: Box<Engine + 'static>,
+  channels: uint,
+  size: uint,
+  hop: uint,
+
+  /// Frames read around each analysis frame, besides the segment.
+  reach: uint,
+
+  input: Input,
+
+  /// Per channel output from frame `emitted`.
+  accumulator: Vec<Vec<f64>>,
+  emitted: uint,
+  segment: Vec<Vec<f64>>,
+
+  position: f64,
+  previous: uint,
+  segments: uint,
+
+  /// The index, analysis frame and stretch of the last segment, from which
+  /// the length of the output is found at the end.
+  end: Option<(uint, uint, f64)>,
+
+  /// Output frames to drop for the silence added before the input.
+  skip: uint
+}
+
+impl State {
+  fn new(algorithm: Algorithm, channels: uint, sample_rate: f64, stretch: f64) -> State {
+    let engine: Box<Engine + 'static> = match algorithm {
+      Wsola => box Wsola::new(sample_rate) as Box<Engine + 'static>,
+      PhaseVocoder => box Vocoder::new(sample_rate, channels) as Box<Engine + 'static>
+    };
+
+    let size = engine.size();
+    let hop = engine.hop();
+
+    // Silence before the input, so that its start is covered by as many
+    // overlapping segments as the rest.
+    let padding = size - hop;
+
+    return State {
+      engine: engine,
+      channels: channels,
+      size: size,
+      hop: hop,
+      reach: size / 4 + hop,
+      input: Input { data: Vec::from_elem(channels, Vec::from_elem(padding, 0.0)), offset: 0, received: padding },
+      accumulator: Vec::from_elem(channels, Vec::new()),
+      emitted: 0,
+      segment: Vec::from_elem(channels, Vec::from_elem(size, 0.0)),
+      position: 0.0,
+      previous: 0,
+      segments: 0,
+      end: None,
+      skip: (padding as f64 * stretch).round() as uint
+    };
+  }
+
+  /// Stretches `samples` by `stretch`, replacing `output` with the frames
+  /// that are complete.
+  fn process(&mut self, samples: &[f64], last: bool, stretch: f64, output: &mut Vec<f64>) {
+    let channels = self.channels;
+
+    for (i, &x) in samples.iter().enumerate() {
+      self.input.data.get_mut(i % channels).push(x);
+    }
+
+    self.input.received += samples.len() / channels;
+
+    loop {
+      let p = self.position.round() as uint;
+
+      if last {
+        if p > self.input.received {
+          break;
+        }
+      } else if p + self.size + self.reach > self.input.received {
+        break;
+      }
+
+      let advance = if self.segments == 0 { self.hop } else { std::cmp::max(p - self.previous, 1) };
+
+      self.engine.synthesize(&self.input, p, advance, &mut self.segment);
+
+      let at = self.segments * self.hop - self.emitted;
+
+      for (accumulator, segment) in self.accumulator.iter_mut().zip(self.segment.iter()) {
+        if accumulator.len() < at + self.size {
+          let missing = at + self.size - accumulator.len();
+          accumulator.grow(missing, 0.0);
+        }
+
+        for (i, &x) in segment.iter().enumerate() {
+          *accumulator.get_mut(at + i) += x;
+        }
+      }
+
+      self.end = Some((self.segments, p, stretch));
+      self.segments += 1;
+      self.previous = p;
+      self.position += self.hop as f64 / stretch;
+
+      self.input.drop_before(if p > self.reach { p - self.reach } else { 0 });
+    }
+
+    let complete = match (last, self.end) {
+      (true, Some((k, p, stretch))) => k * self.hop + ((self.input.received - p) as f64 * stretch).round() as uint,
+      (true, None) => 0,
+      (false, _) => self.segments * self.hop
+    };
+
+    output.truncate(0);
+
+    if complete <= self.emitted {
+      return;
+    }
+
+    let count = std::cmp::min(complete - self.emitted, self.accumulator[0].len());
+
+    for i in range(0, count) {
+      if self.emitted + i >= self.skip {
+        for c in range(0, channels) {
+          output.push(self.accumulator[c][i]);
+        }
+      }
+    }
+
+    for accumulator in self.accumulator.iter_mut() {
+      let rest = accumulator.slice_from(count).to_vec();
+      *accumulator = rest;
+    }
+
+    self.emitted += count;
+  }
+}
+
+/// Changes the tempo of audio without changing its pitch, and its pitch
+/// without changing its tempo.
+///
+/// Tempo is changed by stretching with the chosen algorithm, and pitch by
+/// stretching and then resampling back to the original length. New ratios
+/// received from `changes` take effect from the next packet.
+pub struct Stretcher {
+  source: channel::Source<::Audio>,
+  sink: channel::Sink<::Audio>,
+  algorithm: Algorithm,
+  ratios: Ratios,
+  changes: Option<Receiver<Ratios>>
+}
+
+impl Stretcher {
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, algorithm: Algorithm, ratios: Ratios, changes: Option<Receiver<Ratios>>) -> Stretcher {
+    return Stretcher { source: source, sink: sink, algorithm: algorithm, ratios: ratios, changes: changes };
+  }
+
+  pub fn run(&mut self) {
+    let algorithm = self.algorithm;
+    let changes = &self.changes;
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    let mut ratios = self.ratios.clone();
+    let mut state: Option<State> = None;
+    let mut varispeed: Option<resample::Varispeed> = None;
+
+    let mut samples = Vec::new();
+    let mut stretched = Vec::new();
+    let mut output = Vec::new();
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        match *changes {
+          Some(ref changes) => {
+            loop {
+              match changes.try_recv() {
+                Ok(r) => ratios = r,
+                Err(_) => break
+              }
+            }
+          },
+          None => ()
+        }
+
+        samples.truncate(0);
+
+        if !audio.data.is_empty() {
+          if state.is_none() {
+            state = Some(State::new(algorithm, audio.channels, audio.sample_rate, ratios.stretch()));
+          }
+
+          if audio.channels != state.as_ref().unwrap().channels {
+            panic!("stretch::Stretcher: Channel count changed (INPUT)");
+          }
+
+          sample::decode(audio, &mut samples);
+        }
+
+        output.truncate(0);
+
+        match state {
+          Some(ref mut state) => {
+            state.process(samples.as_slice(), last, ratios.stretch(), &mut stretched);
+
+            if ratios.pitch != 1.0 && varispeed.is_none() {
+              varispeed = Some(resample::Varispeed::new(state.channels, 1.0 / ratios.pitch, resample::Medium));
+            }
+
+            match varispeed {
+              Some(ref mut varispeed) => {
+                varispeed.set_ratio(1.0 / ratios.pitch);
+                varispeed.process(stretched.as_slice(), last, &mut output);
+              },
+              None => output.push_all(stretched.as_slice())
+            }
+          },
+          None => ()
+        }
+
+        sink.write(|out| {
+          out.channels = audio.channels;
+          out.layout = audio.layout.clone();
+          out.sample_rate = audio.sample_rate;
+          out.sample_type = audio.sample_type;
+          out.endian = audio.endian;
+          out.last = last;
+
+          if !output.is_empty() {
+            sample::encode(output.as_slice(), out);
+          }
+        });
+      });
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::f64::consts::PI;
+
+  use channel;
+  use endian;
+  use sample::testing;
+  use sample_type;
+
+  fn stretch(algorithm: super::Algorithm, ratios: super::Ratios, input: Vec<f64>) -> Vec<f64> {
+    let source = testing::source(input, 1000, 1, 8000.0, sample_type::Float(32), endian::Big);
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() {
+      super::Stretcher::new(source, out_sink, algorithm, ratios, None).run();
+    });
+
+    return testing::collect(&mut out_source);
+  }
+
+  fn sine(frequency: f64, frames: uint) -> Vec<f64> {
+    return Vec::from_fn(frames, |i| 0.5 * (2.0 * PI * frequency * i as f64 / 8000.0).sin());
+  }
+
+  /// Estimates the frequency of the middle half of `samples` from its zero
+  /// crossings.
+  fn frequency(samples: &[f64]) -> f64 {
+    let middle = samples.slice(samples.len() / 4, samples.len() * 3 / 4);
+    let crossings = middle.windows(2).filter(|w| (w[0] < 0.0) != (w[1] < 0.0)).count();
+
+    return crossings as f64 / 2.0 / (middle.len() as f64 / 8000.0);
+  }
+
+  #[test]
+  fn test_wsola() {
+    let output = stretch(super::Wsola, super::Ratios::new(0.5, 1.0), sine(440.0, 16000));
+
+    assert!((output.len() as int - 32000).abs() <= 1);
+    assert!((frequency(output.as_slice()) - 440.0).abs() < 5.0);
+  }
+
+  #[test]
+  fn test_phase_vocoder() {
+    let output = stretch(super::PhaseVocoder, super::Ratios::new(2.0, 1.0), sine(440.0, 16000));
+
+    assert!((output.len() as int - 8000).abs() <= 2);
+    assert!((frequency(output.as_slice()) - 440.0).abs() < 5.0);
+  }
+
+  #[test]
+  fn test_pitch() {
+    let output = stretch(super::PhaseVocoder, super::Ratios::new(1.0, 1.5), sine(440.0, 16000));
+
+    assert!((output.len() as int - 16000).abs() <= 2);
+    assert!((frequency(output.as_slice()) - 660.0).abs() < 10.0);
+  }
+}
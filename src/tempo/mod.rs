@@ -0,0 +1,386 @@
+use std;
+use std::comm::Sender;
+
+use channel;
+use fft;
+use sample;
+
+/// The range of tempos considered, in beats per minute.
+static MIN_BPM: f64 = 30.0;
+static MAX_BPM: f64 = 300.0;
+
+/// The tempo preferred when the envelope is ambiguous, and how strongly,
+/// as a width in octaves.
+static PRIOR_BPM: f64 = 120.0;
+static PRIOR_OCTAVES: f64 = 1.0;
+
+/// How strongly beats are held to the tempo when tracking.
+static TIGHTNESS: f64 = 100.0;
+
+/// The onset strength of each frame: the summed increase in log magnitude
+/// of each spectral bin since the frame before, so that notes and drum
+/// hits starting make peaks.
+pub struct Onsets {
+  fft: fft::RealFft,
+  window: Vec<f64>,
+  size: uint,
+  hop: uint,
+  buffer: Vec<f64>,
+  frame: Vec<f64>,
+  bins: Vec<fft::Complex>,
+  previous: Vec<f64>,
+  current: Vec<f64>,
+  envelope: Vec<f64>
+}
+
+impl Onsets {
+  /// Measures onsets in frames 10 ms apart, at `sample_rate`.
+  pub fn new(sample_rate: f64) -> Onsets {
+    let hop = std::cmp::max((sample_rate * 0.01).round() as uint, 1);
+    let size = (hop * 4).next_power_of_two();
+
+    return Onsets {
+      fft: fft::RealFft::new(size),
+      window: fft::Hann.coefficients(size),
+      size: size,
+      hop: hop,
+      buffer: Vec::new(),
+      frame: Vec::from_elem(size, 0.0),
+      bins: Vec::new(),
+      previous: Vec::new(),
+      current: Vec::new(),
+      envelope: Vec::new()
+    };
+  }
+
+  /// Samples between frames.
+  pub fn hop(&self) -> uint {
+    return self.hop;
+  }
+
+  /// Samples per frame. Frame `t` starts at sample `t * hop()`.
+  pub fn size(&self) -> uint {
+    return self.size;
+  }
+
+  /// Adds mono samples.
+  pub fn add(&mut self, samples: &[f64]) {
+    self.buffer.push_all(samples);
+
+    let mut start = 0;
+
+    while start + self.size <= self.buffer.len() {
+      for i in range(0, self.size) {
+        *self.frame.get_mut(i) = self.buffer[start + i] * self.window[i];
+      }
+
+      self.fft.forward(self.frame.as_slice(), &mut self.bins);
+
+      self.current.truncate(0);
+      self.current.extend(self.bins.iter().map(|b| (1.0 + 1000.0 * b.norm()).ln()));
+
+      let flux = if self.previous.is_empty() {
+        0.0
+      } else {
+        self.current.iter().zip(self.previous.iter()).fold(0.0, |a, (&x, &y)| a + (x - y).max(0.0))
+      };
+
+      self.envelope.push(flux);
+      std::mem::swap(&mut self.current, &mut self.previous);
+
+      start += self.hop;
+    }
+
+    let rest = self.buffer.slice_from(start).to_vec();
+    self.buffer = rest;
+  }
+
+  pub fn envelope(&self) -> &[f64] {
+    return self.envelope.as_slice();
+  }
+}
+
+/// Estimates the tempo of an onset envelope with frames `period` seconds
+/// apart, from the autocorrelation of the envelope weighted towards
+/// tempos near 120 BPM. Returns the tempo in BPM and a confidence from 0
+/// to 1: the correlation at the tempo relative to that at no lag.
+pub fn estimate(envelope: &[f64], period: f64) -> (f64, f64) {
+  let n = envelope.len();
+  let mean = envelope.iter().fold(0.0, |a, &x| a + x) / std::cmp::max(n, 1) as f64;
+  let centered: Vec<f64> = envelope.iter().map(|&x| x - mean).collect();
+
+  let correlation = |lag: uint| range(0, n - lag).fold(0.0, |a, i| a + centered[i] * centered[i + lag]);
+
+  let shortest = std::cmp::max((60.0 / MAX_BPM / period).floor() as uint, 1);
+  let longest = (60.0 / MIN_BPM / period).ceil() as uint;
+
+  if n < longest + 2 {
+    return (0.0, 0.0);
+  }
+
+  let energy = correlation(0);
+  let values: Vec<f64> = range(0, longest + 2).map(|lag| if lag + 1 < shortest { 0.0 } else { correlation(lag) }).collect();
+
+  let weight = |lag: f64| {
+    let octaves = (60.0 / (lag * period) / PRIOR_BPM).log2() / PRIOR_OCTAVES;
+
+    (-0.5 * octaves * octaves).exp()
+  };
+
+  let mut best = 0;
+  let mut score = std::f64::NEG_INFINITY;
+
+  for lag in range(shortest, longest + 1) {
+    let s = values[lag] * weight(lag as f64);
+
+    if s > score {
+      score = s;
+      best = lag;
+    }
+  }
+
+  if !(energy > 0.0) || values[best] <= 0.0 {
+    return (0.0, 0.0);
+  }
+
+  // A parabola through the peak and its neighbours places it between lags.
+  let (a, b, c) = (values[best - 1], values[best], values[best + 1]);
+  let denominator = a - 2.0 * b + c;
+  let offset = if denominator < 0.0 { (0.5 * (a - c) / denominator).max(-0.5).min(0.5) } else { 0.0 };
+
+  return (60.0 / ((best as f64 + offset) * period), (values[best] / energy).min(1.0));
+}
+
+/// Finds beats in an onset envelope at a tempo of one beat every `interval`
+/// frames, by dynamic programming: each beat is placed to make the sum of
+/// onset strengths at beats large while keeping the gaps between beats
+/// close to the interval. Returns the frames of the beats.
+pub fn track(envelope: &[f64], interval: f64) -> Vec<uint> {
+  let n = envelope.len();
+
+  if n == 0 || !(interval >= 1.0) {
+    return Vec::new();
+  }
+
+  let mean = envelope.iter().fold(0.0, |a, &x| a + x) / n as f64;
+  let deviation = (envelope.iter().fold(0.0, |a, &x| a + (x - mean) * (x - mean)) / n as f64).sqrt();
+  let local: Vec<f64> = envelope.iter().map(|&x| if deviation > 0.0 { x / deviation } else { 0.0 }).collect();
+
+  let mut scores = local.clone();
+  let mut previous: Vec<Option<uint>> = Vec::from_elem(n, None);
+
+  let earliest = (2.0 * interval).round() as uint;
+  let latest = std::cmp::max((interval / 2.0).round() as uint, 1);
+
+  for t in range(0, n) {
+    if t < latest {
+      continue;
+    }
+
+    let from = if t > earliest { t - earliest } else { 0 };
+    let mut best = None;
+    let mut score = std::f64::NEG_INFINITY;
+
+    for tau in range(from, t - latest + 1) {
+      let penalty = ((t - tau) as f64 / interval).ln();
+      let s = scores[tau] - TIGHTNESS * penalty * penalty;
+
+      if s > score {
+        score = s;
+        best = Some(tau);
+      }
+    }
+
+    if best.is_some() && score > 0.0 {
+      *scores.get_mut(t) += score;
+      *previous.get_mut(t) = best;
+    }
+  }
+
+  // The last beat is the best scoring frame in the last interval.
+  let tail = if n > interval as uint { n - interval as uint } else { 0 };
+  let mut beat = range(tail, n).fold(tail, |a, t| if scores[t] > scores[a] { t } else { a });
+  let mut beats = vec![beat];
+
+  loop {
+    match previous[beat] {
+      Some(p) => {
+        beats.push(p);
+        beat = p;
+      },
+      None => break
+    }
+  }
+
+  beats.reverse();
+
+  return beats;
+}
+
+#[deriving(Clone,Show,PartialEq)]
+pub struct Beat {
+  /// The sample at the center of the frame where the beat falls.
+  pub position: uint,
+
+  /// The onset strength at the beat, relative to the strongest onset.
+  pub strength: f64
+}
+
+#[deriving(Clone,Show,PartialEq)]
+pub struct Tempo {
+  /// The tempo in beats per minute, or zero when none was found.
+  pub bpm: f64,
+
+  /// How periodic the onsets are at that tempo, from 0 to 1.
+  pub confidence: f64,
+
+  pub beats: Vec<Beat>,
+
+  /// The onset strength envelope, with one value per `hop` samples.
+  pub onsets: Vec<f64>,
+  pub hop: uint
+}
+
+/// Detects the tempo and beats of audio passing through, and sends them
+/// to `report` at the end of the stream. Channels are mixed to mono.
+pub struct Tracker {
+  source: channel::Source<::Audio>,
+  sink: channel::Sink<::Audio>,
+  report: Sender<Tempo>
+}
+
+impl Tracker {
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, report: Sender<Tempo>) -> Tracker {
+    return Tracker { source: source, sink: sink, report: report };
+  }
+
+  pub fn run(&mut self) {
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    let mut onsets: Option<Onsets> = None;
+    let mut sample_rate = 0.0;
+    let mut samples = Vec::new();
+    let mut mono = Vec::new();
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        if !audio.data.is_empty() {
+          if onsets.is_none() {
+            onsets = Some(Onsets::new(audio.sample_rate));
+            sample_rate = audio.sample_rate;
+          }
+
+          sample::decode(audio, &mut samples);
+
+          mono.truncate(0);
+
+          for frame in samples.as_slice().chunks(audio.channels) {
+            mono.push(frame.iter().fold(0.0, |a, &x| a + x) / audio.channels as f64);
+          }
+
+          onsets.as_mut().unwrap().add(mono.as_slice());
+        }
+
+        sink.write(|out| {
+          out.channels = audio.channels;
+          out.layout = audio.layout.clone();
+          out.sample_rate = audio.sample_rate;
+          out.sample_type = audio.sample_type;
+          out.endian = audio.endian;
+          out.last = last;
+          out.data.push_all(audio.data.as_slice());
+        });
+      });
+    }
+
+    let tempo = match onsets {
+      Some(ref onsets) => {
+        let envelope = onsets.envelope();
+        let period = onsets.hop() as f64 / sample_rate;
+        let (bpm, confidence) = estimate(envelope, period);
+        let strongest = envelope.iter().fold(0.0f64, |a, &x| a.max(x));
+
+        let beats = if bpm > 0.0 {
+          track(envelope, 60.0 / bpm / period).iter().map(|&t| Beat {
+            position: t * onsets.hop() + onsets.size() / 2,
+            strength: if strongest > 0.0 { envelope[t] / strongest } else { 0.0 }
+          }).collect()
+        } else {
+          Vec::new()
+        };
+
+        Tempo { bpm: bpm, confidence: confidence, beats: beats, onsets: envelope.to_vec(), hop: onsets.hop() }
+      },
+      None => Tempo { bpm: 0.0, confidence: 0.0, beats: Vec::new(), onsets: Vec::new(), hop: 0 }
+    };
+
+    self.report.send(tempo);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::comm;
+  use std::f64::consts::PI;
+
+  use channel;
+  use endian;
+  use sample;
+  use sample_type;
+
+  #[test]
+  fn test_track() {
+    let envelope = Vec::from_fn(100, |t| if t % 10 == 3 { 1.0 } else if t == 50 { 0.8 } else { 0.0 });
+
+    assert_eq!(super::track(envelope.as_slice(), 10.0), vec![3, 13, 23, 33, 43, 53, 63, 73, 83, 93]);
+  }
+
+  #[test]
+  fn test_tracker() {
+    let (mut sink, source) = channel::create::<::Audio>(1);
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+    let (report, tempo) = comm::channel();
+
+    spawn(proc() {
+      super::Tracker::new(source, out_sink, report).run();
+    });
+
+    // Clicks every half second, at 120 BPM.
+    spawn(proc() {
+      let clicks = Vec::from_fn(80000, |i| {
+        let t = (i % 4000) as f64;
+
+        if t < 80.0 { (2.0 * PI * 1000.0 * t / 8000.0).sin() * (1.0 - t / 80.0) } else { 0.0 }
+      });
+
+      sink.write(|audio| {
+        audio.channels = 1;
+        audio.sample_rate = 8000.0;
+        audio.sample_type = sample_type::Float(32);
+        audio.endian = endian::Big;
+        audio.last = true;
+
+        sample::encode(clicks.as_slice(), audio);
+      });
+    });
+
+    out_source.read(|audio| assert!(audio.last));
+
+    let tempo = tempo.recv();
+
+    assert!((tempo.bpm - 120.0).abs() < 1.0);
+    assert!(tempo.confidence > 0.5);
+    assert!(tempo.beats.len() >= 17);
+
+    for beat in tempo.beats.iter() {
+      let offset = (beat.position + 2000) % 4000;
+
+      assert!(offset > 1600 && offset < 2400);
+    }
+  }
+}
@@ -0,0 +1,468 @@
+use std;
+
+use super::{Module, Pattern, Cell, Instrument, Envelope, Sample, Loop, Reader};
+use super::s3m;
+
+pub fn detect(data: &[u8]) -> bool {
+  return data.len() >= 192 && data.slice(0, 4) == b"IMPM";
+}
+
+pub fn load(data: &[u8]) -> Module {
+  let mut r = Reader::new(data, 32);
+
+  let order_count = r.le_u16() as uint;
+  let instrument_count = r.le_u16() as uint;
+  let sample_count = r.le_u16() as uint;
+  let pattern_count = r.le_u16() as uint;
+
+  r.skip(2);
+
+  let compatible = r.le_u16();
+  let flags = r.le_u16();
+
+  r.skip(2);
+
+  let global_volume = std::cmp::min(r.u8() as uint, 128) / 2;
+
+  r.skip(1);
+
+  let speed = r.u8() as uint;
+  let tempo = r.u8() as uint;
+
+  r.seek(64);
+
+  let channel_panning: Vec<u8> = range(0u, 64).map(|_| r.u8()).collect();
+  let channel_volume: Vec<u8> = range(0u, 64).map(|_| r.u8()).collect();
+
+  let orders: Vec<uint> = range(0, order_count).map(|_| r.u8() as uint).collect();
+  let instrument_pointers: Vec<uint> = range(0, instrument_count).map(|_| r.le_u32() as uint).collect();
+  let sample_pointers: Vec<uint> = range(0, sample_count).map(|_| r.le_u32() as uint).collect();
+  let pattern_pointers: Vec<uint> = range(0, pattern_count).map(|_| r.le_u32() as uint).collect();
+
+  let samples: Vec<Sample> = sample_pointers.iter().map(|&p| load_sample(data, p)).collect();
+
+  let instruments: Vec<Instrument> = if flags & 4 != 0 {
+    instrument_pointers.iter().map(|&p| load_instrument(data, p, compatible >= 0x200)).collect()
+  } else {
+    range(1, samples.len() + 1).map(|i| Instrument::simple(i)).collect()
+  };
+
+  let mut channels = 1;
+
+  let wide: Vec<Pattern> = pattern_pointers.iter().map(|&p| {
+    let pattern = if p == 0 { Pattern::new(64, 64) } else { load_pattern(data, p) };
+
+    for (i, cell) in pattern.cells.iter().enumerate() {
+      if cell.note != super::NoNote || cell.instrument != 0 || cell.volume.is_some() || cell.effect != super::NoEffect || cell.volume_effect != super::NoEffect {
+        channels = std::cmp::max(channels, i % 64 + 1);
+      }
+    }
+
+    pattern
+  }).collect();
+
+  let patterns = wide.iter().map(|pattern| {
+    let mut narrow = Pattern::new(pattern.rows, channels);
+
+    for row in range(0, pattern.rows) {
+      for channel in range(0, channels) {
+        *narrow.cells.get_mut(row * channels + channel) = pattern.cells[row * 64 + channel].clone();
+      }
+    }
+
+    narrow
+  }).collect();
+
+  return Module {
+    format: super::ImpulseTracker,
+    channels: channels,
+    orders: orders,
+    patterns: patterns,
+    instruments: instruments,
+    samples: samples,
+    speed: if speed > 0 { speed } else { 6 },
+    tempo: if tempo >= 32 { tempo } else { 125 },
+    global_volume: global_volume,
+    linear: flags & 8 != 0,
+    panning: range(0, channels).map(|i| {
+      match channel_panning[i] & 0x7F {
+        p if p <= 64 => std::cmp::min(p as uint * 4, 255),
+        _ => 128
+      }
+    }).collect(),
+    volume: range(0, channels).map(|i| {
+      if channel_panning[i] & 0x80 != 0 { 0 } else { std::cmp::min(channel_volume[i] as uint, 64) }
+    }).collect()
+  };
+}
+
+/// Loads a pattern with all 64 channels.
+fn load_pattern(data: &[u8], position: uint) -> Pattern {
+  let mut r = Reader::new(data, position);
+
+  let length = r.le_u16() as uint;
+  let rows = std::cmp::max(r.le_u16() as uint, 1);
+
+  r.skip(4);
+
+  let end = r.position + length;
+  let mut pattern = Pattern::new(rows, 64);
+
+  let mut masks = [0u8, ..64];
+  let mut last = Vec::from_elem(64, (0u8, 0u8, 0u8, 0u8, 0u8));
+  let mut row = 0;
+
+  while row < rows && r.position < end {
+    let variable = r.u8();
+
+    if variable == 0 {
+      row += 1;
+      continue;
+    }
+
+    let channel = ((variable - 1) & 63) as uint;
+
+    if variable & 128 != 0 {
+      masks[channel] = r.u8();
+    }
+
+    let mask = masks[channel];
+    let (mut note, mut instrument, mut volume, mut command, mut param) = last[channel];
+
+    if mask & 1 != 0 {
+      note = r.u8();
+    }
+
+    if mask & 2 != 0 {
+      instrument = r.u8();
+    }
+
+    if mask & 4 != 0 {
+      volume = r.u8();
+    }
+
+    if mask & 8 != 0 {
+      command = r.u8();
+      param = r.u8();
+    }
+
+    *last.get_mut(channel) = (note, instrument, volume, command, param);
+
+    let cell = pattern.cells.get_mut(row * 64 + channel);
+
+    if mask & 0x11 != 0 {
+      cell.note = match note {
+        n if n < 120 => super::Key(n as uint),
+        255 => super::Off,
+        254 => super::Cut,
+        _ => super::Fade
+      };
+    }
+
+    if mask & 0x22 != 0 {
+      cell.instrument = instrument as uint;
+    }
+
+    if mask & 0x44 != 0 {
+      volume_column(volume, cell);
+    }
+
+    if mask & 0x88 != 0 {
+      cell.effect = s3m::effect(command, param, true);
+    }
+  }
+
+  return pattern;
+}
+
+fn volume_column(volume: u8, cell: &mut Cell) {
+  static TONE_PORTA: [u8, ..10] = [0, 1, 4, 8, 16, 32, 64, 96, 128, 255];
+
+  let v = volume as uint;
+
+  if v <= 64 {
+    cell.volume = Some(v);
+  } else if v >= 128 && v <= 192 {
+    cell.volume_effect = super::SetPanning(std::cmp::min((v - 128) * 4, 255) as u8);
+  } else {
+    cell.volume_effect = match v {
+      n if n >= 65 && n <= 74 => super::FineVolumeUp((n - 65) as u8),
+      n if n >= 75 && n <= 84 => super::FineVolumeDown((n - 75) as u8),
+      n if n >= 85 && n <= 94 => super::VolumeSlide(((n - 85) << 4) as u8),
+      n if n >= 95 && n <= 104 => super::VolumeSlide((n - 95) as u8),
+      n if n >= 105 && n <= 114 => super::PortaDown(((n - 105) * 4) as u8),
+      n if n >= 115 && n <= 124 => super::PortaUp(((n - 115) * 4) as u8),
+      n if n >= 193 && n <= 202 => super::TonePorta(TONE_PORTA[n - 193]),
+      n if n >= 203 && n <= 212 => super::Vibrato((n - 203) as u8),
+      _ => super::NoEffect
+    };
+  }
+}
+
+fn load_instrument(data: &[u8], position: uint, new_format: bool) -> Instrument {
+  let mut r = Reader::new(data, position);
+  let mut instrument = Instrument::simple(0);
+
+  if !new_format {
+    r.seek(position + 24);
+
+    instrument.fadeout = r.le_u16() as f64 / 512.0;
+  } else {
+    r.seek(position + 20);
+
+    instrument.fadeout = r.le_u16() as f64 / 1024.0;
+
+    r.skip(2);
+
+    instrument.global_volume = std::cmp::min(r.u8() as uint, 128) / 2;
+
+    let panning = r.u8();
+
+    if panning & 0x80 == 0 {
+      instrument.panning_default = Some(std::cmp::min(panning as uint * 4, 255));
+    }
+  }
+
+  r.seek(position + 64);
+
+  for note in range(0u, 120) {
+    let mapped = std::cmp::min(r.u8() as uint, 119);
+    let sample = r.u8() as uint;
+
+    *instrument.keymap.get_mut(note) = (mapped, sample);
+  }
+
+  if new_format {
+    instrument.volume = envelope(&mut r, 0);
+    instrument.panning = envelope(&mut r, 32);
+  }
+
+  return instrument;
+}
+
+/// Reads an envelope, offsetting its values by `offset` so that they are
+/// never negative.
+fn envelope(r: &mut Reader, offset: int) -> Envelope {
+  let flags = r.u8();
+  let count = std::cmp::min(r.u8() as uint, 25);
+  let loop_start = r.u8() as uint;
+  let loop_end = r.u8() as uint;
+  let sustain_start = r.u8() as uint;
+  let sustain_end = r.u8() as uint;
+
+  let points: Vec<(uint, uint)> = range(0u, 25).map(|_| {
+    let value = r.u8() as i8 as int + offset;
+    let tick = r.le_u16() as uint;
+
+    (tick, std::cmp::max(std::cmp::min(value, 64), 0) as uint)
+  }).collect();
+
+  r.skip(1);
+
+  return Envelope::new(
+    flags & 1 != 0,
+    points.slice_to(count).to_vec(),
+    if flags & 4 != 0 { Some((sustain_start, sustain_end)) } else { None },
+    if flags & 2 != 0 { Some((loop_start, loop_end)) } else { None });
+}
+
+fn load_sample(data: &[u8], position: uint) -> Sample {
+  let mut r = Reader::new(data, position + 17);
+  let mut sample = Sample::empty();
+
+  sample.global_volume = std::cmp::min(r.u8() as uint, 64);
+
+  let flags = r.u8();
+
+  sample.volume = std::cmp::min(r.u8() as uint, 64);
+
+  r.skip(26);
+
+  let convert = r.u8();
+  let panning = r.u8();
+
+  if panning & 0x80 != 0 {
+    sample.panning = Some(std::cmp::min((panning & 0x7F) as uint * 4, 255));
+  }
+
+  let length = r.le_u32() as uint;
+  let loop_start = r.le_u32() as uint;
+  let loop_end = r.le_u32() as uint;
+
+  sample.rate = match r.le_u32() {
+    0 => 8363.0,
+    rate => rate as f64
+  };
+
+  let sustain_start = r.le_u32() as uint;
+  let sustain_end = r.le_u32() as uint;
+  let pointer = r.le_u32() as uint;
+
+  if flags & 1 == 0 {
+    return sample;
+  }
+
+  let sixteen = flags & 2 != 0;
+  let signed = convert & 1 != 0;
+
+  r.seek(pointer);
+
+  sample.data = if flags & 8 != 0 {
+    decompress(&mut r, length, sixteen, convert & 4 != 0)
+  } else if sixteen {
+    super::pcm16(r.bytes(length * 2), signed)
+  } else {
+    super::pcm8(r.bytes(length), signed)
+  };
+
+  if flags & 16 != 0 {
+    sample.repeat = Loop::new(loop_start, loop_end, flags & 64 != 0, sample.data.len());
+  }
+
+  if flags & 32 != 0 {
+    sample.sustain = Loop::new(sustain_start, sustain_end, flags & 128 != 0, sample.data.len());
+  }
+
+  return sample;
+}
+
+/// Decompresses an IT 2.14 compressed sample, or an IT 2.15 one when
+/// `double` is set (the data is then integrated twice).
+fn decompress(r: &mut Reader, length: uint, sixteen: bool, double: bool) -> Vec<f32> {
+  let (block, full, width_bits, scale) = if sixteen { (0x4000u, 17u, 4u, 32768.0) } else { (0x8000u, 9u, 3u, 128.0) };
+  let sample_bits = full - 1;
+
+  let mut output = Vec::with_capacity(length);
+
+  while output.len() < length {
+    let size = r.le_u16() as uint;
+    let bytes = r.bytes(size);
+
+    let mut position = 0u;
+    let count = std::cmp::min(block, length - output.len());
+    let mut width = full;
+    let mut d1 = 0i32;
+    let mut d2 = 0i32;
+    let mut decoded = 0;
+
+    while decoded < count && position < size * 8 {
+      let mut value = bits(bytes, &mut position, width);
+
+      if width < 7 {
+        if value == 1 << (width - 1) {
+          value = bits(bytes, &mut position, width_bits) + 1;
+          width = if (value as uint) < width { value as uint } else { value as uint + 1 };
+          continue;
+        }
+      } else if width < full {
+        let border = ((1u32 << sample_bits) - 1) >> (full - width) as uint;
+        let border = border - (1 << (width_bits - 1));
+
+        if value > border && value <= border + (1 << width_bits) {
+          value -= border;
+          width = if (value as uint) < width { value as uint } else { value as uint + 1 };
+          continue;
+        }
+      } else if width == full {
+        if value & (1 << sample_bits) != 0 {
+          width = ((value + 1) & 0xFF) as uint;
+          continue;
+        }
+      } else {
+        break;
+      }
+
+      let shift = 32 - std::cmp::min(width, sample_bits);
+      let delta = ((value << shift) as i32) >> shift;
+
+      d1 = wrap(d1 + delta, sample_bits);
+      d2 = wrap(d2 + d1, sample_bits);
+
+      output.push((if double { d2 } else { d1 }) as f32 / scale);
+
+      decoded += 1;
+    }
+
+    if decoded < count {
+      output.grow(count - decoded, 0.0);
+    }
+  }
+
+  return output;
+}
+
+/// Reads `count` bits, least significant first, from `position`.
+fn bits(data: &[u8], position: &mut uint, count: uint) -> u32 {
+  let mut value = 0u32;
+
+  for i in range(0, count) {
+    let p = *position + i;
+
+    if p / 8 < data.len() {
+      value |= (((data[p / 8] >> (p % 8)) & 1) as u32) << i;
+    }
+  }
+
+  *position += count;
+
+  return value;
+}
+
+/// Wraps `value` to a signed integer of `bits` bits.
+fn wrap(value: i32, bits: uint) -> i32 {
+  let shift = 32 - bits;
+
+  return (value << shift) >> shift;
+}
+
+#[cfg(test)]
+mod tests {
+  #[test]
+  fn test_volume_column() {
+    let mut cell = ::tracker::Cell::new();
+
+    super::volume_column(32, &mut cell);
+    assert_eq!(cell.volume, Some(32));
+
+    super::volume_column(88, &mut cell);
+    assert_eq!(cell.volume_effect, ::tracker::VolumeSlide(0x30));
+
+    super::volume_column(160, &mut cell);
+    assert_eq!(cell.volume_effect, ::tracker::SetPanning(128));
+
+    super::volume_column(195, &mut cell);
+    assert_eq!(cell.volume_effect, ::tracker::TonePorta(4));
+  }
+
+  #[test]
+  fn test_decompress() {
+    // One 8-bit block: 9-bit deltas of 1, 2 and -3 (the ninth bit clear).
+    let mut bits = Vec::new();
+
+    for &value in [1u32, 2, 0xFD].iter() {
+      for i in range(0u, 9) {
+        bits.push((value >> i) & 1);
+      }
+    }
+
+    let mut block = Vec::from_elem((bits.len() + 7) / 8, 0u8);
+
+    for (i, &bit) in bits.iter().enumerate() {
+      *block.get_mut(i / 8) |= (bit as u8) << (i % 8);
+    }
+
+    let mut data = vec![block.len() as u8, 0];
+
+    data.push_all(block.as_slice());
+
+    let mut r = ::tracker::Reader::new(data.as_slice(), 0);
+    let output = super::decompress(&mut r, 3, false, false);
+
+    assert_eq!(output, vec![1.0 / 128.0, 3.0 / 128.0, 0.0]);
+
+    let mut r = ::tracker::Reader::new(data.as_slice(), 0);
+    let output = super::decompress(&mut r, 3, false, true);
+
+    assert_eq!(output, vec![1.0 / 128.0, 4.0 / 128.0, 4.0 / 128.0]);
+  }
+}
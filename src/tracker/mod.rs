@@ -0,0 +1,553 @@
+use std;
+
+use channel;
+use endian;
+use layout;
+use sample;
+use sample_type;
+use stream;
+
+mod protracker;
+mod s3m;
+mod xm;
+mod it;
+mod player;
+
+/// The number of frames in each packet written by the renderer.
+static PACKET: uint = 4096;
+
+/// Renders MOD, S3M, XM and IT modules into stereo float audio.
+///
+/// The whole module is read before rendering starts. Rendering stops at the
+/// end of the order list, or when the song jumps back to a position it has
+/// already played, so looping songs are rendered once.
+pub struct Renderer {
+  source: channel::Source<::Binary>,
+  sink: channel::Sink<::Audio>,
+  sample_rate: f64
+}
+
+impl Renderer {
+  pub fn new(source: channel::Source<::Binary>, sink: channel::Sink<::Audio>, sample_rate: f64) -> Renderer {
+    if sample_rate <= 0.0 {
+      panic!("tracker::Renderer: Sample rate must be positive (ARGUMENT)");
+    }
+
+    return Renderer { source: source, sink: sink, sample_rate: sample_rate };
+  }
+
+  pub fn run(&mut self) {
+    let sample_rate = self.sample_rate;
+    let sink = &mut self.sink;
+
+    let mut data = Vec::new();
+
+    {
+      let mut s = stream::Stream::new(&mut self.source);
+      let mut buffer = [0u8, ..4096];
+
+      loop {
+        match s.try_read(buffer) {
+          Some(n) => data.push_all(buffer.slice_to(n)),
+          None => break
+        }
+      }
+    }
+
+    let module = load(data.as_slice());
+    let mut player = player::Player::new(&module, sample_rate);
+    let mut output = Vec::from_elem(PACKET * 2, 0.0f64);
+
+    loop {
+      let frames = player.render(output.as_mut_slice());
+      let last = frames < PACKET;
+
+      sink.write(|audio| {
+        audio.channels = 2;
+        audio.layout = layout::Layout::stereo();
+        audio.sample_rate = sample_rate;
+        audio.sample_type = sample_type::Float(32);
+        audio.endian = endian::Big;
+
+        sample::encode(output.slice_to(frames * 2), audio);
+
+        audio.last = last;
+      });
+
+      if last {
+        break;
+      }
+    }
+  }
+}
+
+/// Detects the format of a module, and loads it.
+fn load(data: &[u8]) -> Module {
+  if xm::detect(data) {
+    return xm::load(data);
+  } else if it::detect(data) {
+    return it::load(data);
+  } else if s3m::detect(data) {
+    return s3m::load(data);
+  } else if protracker::detect(data) {
+    return protracker::load(data);
+  }
+
+  panic!("tracker::Renderer: Unknown module format (INPUT)");
+}
+
+/// The tracker a module comes from, which decides how some effect
+/// parameters are interpreted.
+#[deriving(Show,PartialEq)]
+enum Format {
+  Protracker, ScreamTracker, FastTracker, ImpulseTracker
+}
+
+/// Order list entries that are not pattern numbers.
+static SKIP: uint = 254;
+static END: uint = 255;
+
+/// The note at which a sample plays at its base rate (C-5 in Impulse
+/// Tracker terms). Notes are numbered in semitones from C-0.
+static BASE_NOTE: uint = 60;
+
+/// A song, converted from any of the supported formats.
+struct Module {
+  format: Format,
+  channels: uint,
+  orders: Vec<uint>,
+  patterns: Vec<Pattern>,
+  instruments: Vec<Instrument>,
+  samples: Vec<Sample>,
+
+  /// Initial ticks per row, and initial tempo in BPM.
+  speed: uint,
+  tempo: uint,
+
+  /// Initial global volume, 0 to 64.
+  global_volume: uint,
+
+  /// Whether slides are in 1/64 semitones (linear) or Amiga periods.
+  linear: bool,
+
+  /// Initial panning (0 to 255) and volume (0 to 64) of each channel.
+  panning: Vec<uint>,
+  volume: Vec<uint>
+}
+
+struct Pattern {
+  rows: uint,
+  cells: Vec<Cell>
+}
+
+impl Pattern {
+  fn new(rows: uint, channels: uint) -> Pattern {
+    return Pattern { rows: rows, cells: Vec::from_elem(rows * channels, Cell::new()) };
+  }
+}
+
+#[deriving(Clone,Show,PartialEq)]
+enum Note {
+  NoNote,
+  Key(uint),
+  Off,
+  Cut,
+  Fade
+}
+
+/// Effects, normalized across formats. Parameters are as in the pattern,
+/// except where noted.
+#[deriving(Clone,Show,PartialEq)]
+enum Effect {
+  NoEffect,
+  Arpeggio(u8),
+  PortaUp(u8),
+  PortaDown(u8),
+  FinePortaUp(u8),
+  FinePortaDown(u8),
+  ExtraFinePortaUp(u8),
+  ExtraFinePortaDown(u8),
+  TonePorta(u8),
+  Vibrato(u8),
+  TonePortaVolumeSlide(u8),
+  VibratoVolumeSlide(u8),
+  Tremolo(u8),
+
+  /// Panning from 0 (left) to 255 (right).
+  SetPanning(u8),
+
+  /// Slides right by the high nibble, or left by the low nibble.
+  PanningSlide(u8),
+  SampleOffset(u8),
+  VolumeSlide(u8),
+  FineVolumeUp(u8),
+  FineVolumeDown(u8),
+  SetVolume(u8),
+
+  /// Global volume from 0 to 64.
+  SetGlobalVolume(u8),
+  GlobalVolumeSlide(u8),
+  PositionJump(u8),
+
+  /// The row to break to, already decoded from decimal where needed.
+  PatternBreak(u8),
+  PatternLoop(u8),
+  PatternDelay(u8),
+  SetSpeed(u8),
+  SetTempo(u8),
+  NoteCut(u8),
+  NoteDelay(u8),
+  Retrigger(u8),
+  KeyOff(u8)
+}
+
+#[deriving(Clone,Show)]
+struct Cell {
+  note: Note,
+
+  /// Instrument number, 0 for none.
+  instrument: uint,
+
+  /// Volume from 0 to 64.
+  volume: Option<uint>,
+
+  /// The effect in the volume column (XM and IT), and the effect column.
+  volume_effect: Effect,
+  effect: Effect
+}
+
+impl Cell {
+  fn new() -> Cell {
+    return Cell { note: NoNote, instrument: 0, volume: None, volume_effect: NoEffect, effect: NoEffect };
+  }
+}
+
+/// A volume (0 to 64) or panning (0 to 64, centered on 32) envelope. Points
+/// are (tick, value), loops are pairs of point indices.
+struct Envelope {
+  enabled: bool,
+  points: Vec<(uint, uint)>,
+  sustain: Option<(uint, uint)>,
+  repeat: Option<(uint, uint)>
+}
+
+impl Envelope {
+  fn new(enabled: bool, points: Vec<(uint, uint)>, sustain: Option<(uint, uint)>, repeat: Option<(uint, uint)>) -> Envelope {
+    let count = points.len();
+    let valid = |range: Option<(uint, uint)>| match range {
+      Some((start, end)) if start <= end && end < count => range,
+      _ => None
+    };
+
+    return Envelope { enabled: enabled && count > 0, sustain: valid(sustain), repeat: valid(repeat), points: points };
+  }
+
+  fn disabled() -> Envelope {
+    return Envelope { enabled: false, points: Vec::new(), sustain: None, repeat: None };
+  }
+
+  /// The value at `tick`, interpolated between points.
+  fn value(&self, tick: uint) -> f64 {
+    let points = self.points.as_slice();
+    let mut i = 0;
+
+    while i + 1 < points.len() && self.tick(i + 1) <= tick {
+      i += 1;
+    }
+
+    let (x0, y0) = points[i];
+
+    if i + 1 >= points.len() || tick <= x0 {
+      return y0 as f64;
+    }
+
+    let (x1, y1) = points[i + 1];
+
+    return y0 as f64 + (y1 as f64 - y0 as f64) * (tick - x0) as f64 / (x1 - x0) as f64;
+  }
+
+  /// The tick following `tick`, taking the sustain loop (while the key is
+  /// held) and the envelope loop into account.
+  fn advance(&self, tick: uint, released: bool) -> uint {
+    match self.sustain {
+      Some((start, end)) if !released && tick >= self.tick(end) => return self.tick(start),
+      _ => {}
+    }
+
+    match self.repeat {
+      Some((start, end)) if tick >= self.tick(end) => return self.tick(start),
+      _ => {}
+    }
+
+    return if tick >= self.tick(self.points.len() - 1) { tick } else { tick + 1 };
+  }
+
+  fn tick(&self, point: uint) -> uint {
+    let (tick, _) = self.points[point];
+
+    return tick;
+  }
+}
+
+struct Instrument {
+  /// For each note, the note to play and the sample number (0 for none).
+  keymap: Vec<(uint, uint)>,
+
+  volume: Envelope,
+  panning: Envelope,
+
+  /// The fraction of full volume removed per tick after key off.
+  fadeout: f64,
+
+  /// Volume from 0 to 64.
+  global_volume: uint,
+
+  /// Panning from 0 to 255, which overrides the channel panning.
+  panning_default: Option<uint>
+}
+
+impl Instrument {
+  /// An instrument that plays sample number `sample` on every note, as used
+  /// by formats without instruments.
+  fn simple(sample: uint) -> Instrument {
+    return Instrument {
+      keymap: Vec::from_fn(120, |note| (note, sample)),
+      volume: Envelope::disabled(),
+      panning: Envelope::disabled(),
+      fadeout: 0.0,
+      global_volume: 64,
+      panning_default: None
+    };
+  }
+}
+
+struct Loop {
+  start: uint,
+  end: uint,
+  pingpong: bool
+}
+
+impl Loop {
+  /// Returns a loop, or `None` if it is empty once clamped to `length`.
+  fn new(start: uint, end: uint, pingpong: bool, length: uint) -> Option<Loop> {
+    let end = std::cmp::min(end, length);
+
+    if start + 1 >= end {
+      return None;
+    }
+
+    return Some(Loop { start: start, end: end, pingpong: pingpong });
+  }
+}
+
+struct Sample {
+  /// Mono samples from -1 to 1.
+  data: Vec<f32>,
+
+  /// The playback rate at `BASE_NOTE`.
+  rate: f64,
+
+  /// Default volume and volume scale, 0 to 64.
+  volume: uint,
+  global_volume: uint,
+
+  /// Panning from 0 to 255, which overrides the channel panning.
+  panning: Option<uint>,
+
+  repeat: Option<Loop>,
+
+  /// A loop that only applies until key off.
+  sustain: Option<Loop>
+}
+
+impl Sample {
+  fn empty() -> Sample {
+    return Sample {
+      data: Vec::new(), rate: 8363.0, volume: 0, global_volume: 64, panning: None, repeat: None, sustain: None
+    };
+  }
+}
+
+/// Converts 8-bit PCM to floats.
+fn pcm8(data: &[u8], signed: bool) -> Vec<f32> {
+  return data.iter().map(|&b| {
+    let value = if signed { b as i8 as f32 } else { b as f32 - 128.0 };
+
+    value / 128.0
+  }).collect();
+}
+
+/// Converts little endian 16-bit PCM to floats.
+fn pcm16(data: &[u8], signed: bool) -> Vec<f32> {
+  return range(0, data.len() / 2).map(|i| {
+    let raw = data[i * 2] as u16 | (data[i * 2 + 1] as u16 << 8);
+    let value = if signed { raw as i16 as f32 } else { raw as f32 - 32768.0 };
+
+    value / 32768.0
+  }).collect();
+}
+
+/// Reads little and big endian values from a module in memory. Reads past
+/// the end return zeros, as truncated modules are common.
+struct Reader<'a> {
+  data: &'a [u8],
+  position: uint
+}
+
+impl<'a> Reader<'a> {
+  fn new(data: &'a [u8], position: uint) -> Reader<'a> {
+    return Reader { data: data, position: position };
+  }
+
+  fn seek(&mut self, position: uint) {
+    self.position = position;
+  }
+
+  fn skip(&mut self, amount: uint) {
+    self.position += amount;
+  }
+
+  fn u8(&mut self) -> u8 {
+    let value = if self.position < self.data.len() { self.data[self.position] } else { 0 };
+
+    self.position += 1;
+
+    return value;
+  }
+
+  fn le_u16(&mut self) -> u16 {
+    let low = self.u8() as u16;
+
+    return low | (self.u8() as u16 << 8);
+  }
+
+  fn be_u16(&mut self) -> u16 {
+    let high = self.u8() as u16;
+
+    return (high << 8) | self.u8() as u16;
+  }
+
+  fn le_u32(&mut self) -> u32 {
+    let low = self.le_u16() as u32;
+
+    return low | (self.le_u16() as u32 << 16);
+  }
+
+  /// Returns up to `amount` bytes, fewer at the end of the data.
+  fn bytes(&mut self, amount: uint) -> &'a [u8] {
+    let start = std::cmp::min(self.position, self.data.len());
+    let end = std::cmp::min(self.position + amount, self.data.len());
+
+    self.position += amount;
+
+    return self.data.slice(start, end);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use channel;
+  use buffer;
+  use sample;
+
+  #[test]
+  fn test_envelope() {
+    let envelope = super::Envelope::new(true, vec![(0, 0), (10, 64), (20, 32)], Some((1, 1)), None);
+
+    assert_eq!(envelope.value(0), 0.0);
+    assert_eq!(envelope.value(5), 32.0);
+    assert_eq!(envelope.value(15), 48.0);
+    assert_eq!(envelope.value(30), 32.0);
+
+    assert_eq!(envelope.advance(9, false), 10);
+    assert_eq!(envelope.advance(10, false), 10);
+    assert_eq!(envelope.advance(10, true), 11);
+    assert_eq!(envelope.advance(20, true), 20);
+
+    let envelope = super::Envelope::new(true, vec![(0, 0), (10, 64)], None, Some((0, 1)));
+
+    assert_eq!(envelope.advance(10, true), 0);
+
+    assert!(!super::Envelope::new(true, Vec::new(), None, None).enabled);
+    assert!(super::Envelope::new(true, vec![(0, 0)], Some((0, 3)), None).sustain.is_none());
+  }
+
+  #[test]
+  fn test_loop() {
+    assert!(super::Loop::new(10, 10, false, 100).is_none());
+    assert!(super::Loop::new(200, 300, false, 100).is_none());
+    assert_eq!(super::Loop::new(10, 300, false, 100).unwrap().end, 100);
+  }
+
+  #[test]
+  #[should_fail]
+  fn test_unknown() {
+    let (sink, source) = channel::create::<::Binary>(1);
+    let (audio_sink, _) = channel::create::<::Audio>(1);
+
+    spawn(proc() { buffer::Buffer::new(Vec::from_elem(2000, 0u8), 4096, sink).run(); });
+
+    super::Renderer::new(source, audio_sink, 44100.0).run();
+  }
+
+  #[test]
+  fn test_render() {
+    let data = super::protracker::tests::module();
+
+    let (sink, source) = channel::create::<::Binary>(1);
+    let (audio_sink, mut audio_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() { buffer::Buffer::new(data, 4096, sink).run(); });
+    spawn(proc() { super::Renderer::new(source, audio_sink, 44100.0).run(); });
+
+    let mut samples = Vec::new();
+    let mut output = Vec::new();
+    let mut last = false;
+
+    while !last {
+      audio_source.read(|audio| {
+        assert_eq!(audio.channels, 2);
+        assert_eq!(audio.sample_rate, 44100.0);
+
+        last = audio.last;
+
+        sample::decode(audio, &mut samples);
+        output.push_all(samples.as_slice());
+      });
+    }
+
+    // Two rows at speed 6 and tempo 125, 882 frames per tick.
+    assert_eq!(output.len(), 2 * 12 * 882);
+
+    // The first channel is panned left.
+    let left = output.iter().enumerate().filter(|&(i, _)| i % 2 == 0).fold(0.0, |a, (_, &x)| a + x.abs());
+    let right = output.iter().enumerate().filter(|&(i, _)| i % 2 == 1).fold(0.0, |a, (_, &x)| a + x.abs());
+
+    assert!(left > 0.0);
+    assert!(left > right);
+  }
+
+  #[test]
+  fn test_retrigger_empty() {
+    let mut data = super::protracker::tests::module();
+
+    // Row 0, channel 2: C-2 with the empty sample 2, retriggered every 3
+    // ticks.
+    *data.get_mut(1084 + 8) = 0x01;
+    *data.get_mut(1084 + 9) = 0xAC;
+    *data.get_mut(1084 + 10) = 0x2E;
+    *data.get_mut(1084 + 11) = 0x93;
+
+    let (sink, source) = channel::create::<::Binary>(1);
+    let (audio_sink, mut audio_source) = channel::create::<::Audio>(1);
+
+    spawn(proc() { buffer::Buffer::new(data, 4096, sink).run(); });
+    spawn(proc() { super::Renderer::new(source, audio_sink, 44100.0).run(); });
+
+    let mut last = false;
+
+    while !last {
+      audio_source.read(|audio| last = audio.last);
+    }
+  }
+}
@@ -0,0 +1,884 @@
+use std;
+
+use super::{Module, Cell, Effect, Instrument, Sample};
+
+/// Scales the mix so that a few loud channels do not clip.
+static MASTER: f64 = 0.5;
+
+/// Effect memory slots.
+static ARPEGGIO: uint = 0;
+static PORTA_UP: uint = 1;
+static PORTA_DOWN: uint = 2;
+static FINE_PORTA_UP: uint = 3;
+static FINE_PORTA_DOWN: uint = 4;
+static TONE_PORTA: uint = 5;
+static VIBRATO: uint = 6;
+static TREMOLO: uint = 7;
+static VOLUME_SLIDE: uint = 8;
+static FINE_VOLUME: uint = 9;
+static PANNING_SLIDE: uint = 10;
+static SAMPLE_OFFSET: uint = 11;
+static GLOBAL_VOLUME_SLIDE: uint = 12;
+static RETRIGGER: uint = 13;
+
+struct Channel {
+  instrument: Option<uint>,
+  sample: Option<uint>,
+  active: bool,
+
+  /// Playback position in samples, and direction in ping-pong loops.
+  position: f64,
+  forward: bool,
+
+  /// The current period, and the target of a tone portamento.
+  period: f64,
+  target: f64,
+
+  /// Volume from 0 to 64, panning from 0 to 255.
+  volume: int,
+  panning: int,
+  channel_volume: uint,
+
+  released: bool,
+  fading: bool,
+  fade: f64,
+  volume_tick: uint,
+  panning_tick: uint,
+
+  /// Waveform positions, and the offsets they give on this tick.
+  vibrato: uint,
+  tremolo: uint,
+  vibrato_offset: f64,
+  tremolo_offset: int,
+  arpeggio: uint,
+
+  /// The effects of the current row, with parameters recalled from memory.
+  effects: [Effect, ..2],
+  memory: [u8, ..14],
+
+  /// A cell waiting for a note delay, and the tick it starts on.
+  delayed: Option<(uint, Cell)>,
+
+  loop_row: uint,
+  loop_count: uint,
+
+  /// Gains and playback rate for the current tick.
+  left: f64,
+  right: f64,
+  frequency: f64
+}
+
+impl Channel {
+  fn new(panning: uint, volume: uint) -> Channel {
+    return Channel {
+      instrument: None,
+      sample: None,
+      active: false,
+      position: 0.0,
+      forward: true,
+      period: 0.0,
+      target: 0.0,
+      volume: 0,
+      panning: panning as int,
+      channel_volume: volume,
+      released: false,
+      fading: false,
+      fade: 1.0,
+      volume_tick: 0,
+      panning_tick: 0,
+      vibrato: 0,
+      tremolo: 0,
+      vibrato_offset: 0.0,
+      tremolo_offset: 0,
+      arpeggio: 0,
+      effects: [super::NoEffect, super::NoEffect],
+      memory: [0, ..14],
+      delayed: None,
+      loop_row: 0,
+      loop_count: 0,
+      left: 0.0,
+      right: 0.0,
+      frequency: 0.0
+    };
+  }
+
+  /// Releases the key. Without a volume envelope, Fasttracker 2 silences
+  /// the note immediately, the others start the fadeout.
+  fn release(&mut self, module: &Module) {
+    self.released = true;
+
+    let envelope = match self.instrument {
+      Some(i) => module.instruments[i].volume.enabled,
+      None => false
+    };
+
+    if module.format == super::FastTracker && !envelope {
+      self.volume = 0;
+    } else {
+      self.fading = true;
+    }
+  }
+
+  fn volume_slide(&mut self, param: u8, first: bool, fine: bool) {
+    let x = (param >> 4) as int;
+    let y = (param & 15) as int;
+
+    let delta = if fine && y == 15 && x > 0 {
+      if first { x } else { 0 }
+    } else if fine && x == 15 && y > 0 {
+      if first { -y } else { 0 }
+    } else if first {
+      0
+    } else if x > 0 {
+      x
+    } else {
+      -y
+    };
+
+    self.volume = std::cmp::max(std::cmp::min(self.volume + delta, 64), 0);
+  }
+
+  fn tone_porta(&mut self, param: u8) {
+    let speed = param as f64 * 4.0;
+
+    if self.period < self.target {
+      self.period = (self.period + speed).min(self.target);
+    } else {
+      self.period = (self.period - speed).max(self.target);
+    }
+  }
+
+  fn vibrato(&mut self, param: u8, first: bool) {
+    self.vibrato_offset = sine(self.vibrato) * (param & 15) as f64 * 255.0 / 32.0;
+
+    if !first {
+      self.vibrato += (param >> 4) as uint;
+    }
+  }
+
+  fn tremolo(&mut self, param: u8, first: bool) {
+    self.tremolo_offset = (sine(self.tremolo) * (param & 15) as f64 * 255.0 / 64.0) as int;
+
+    if !first {
+      self.tremolo += (param >> 4) as uint;
+    }
+  }
+
+  /// Moves the playback position by `step` samples, following the loops.
+  fn advance(&mut self, step: f64, sample: &Sample) {
+    let repeat = if !self.released && sample.sustain.is_some() { &sample.sustain } else { &sample.repeat };
+
+    match *repeat {
+      Some(ref repeat) => {
+        let start = repeat.start as f64;
+        let end = repeat.end as f64;
+        let length = end - start;
+
+        if !repeat.pingpong {
+          self.forward = true;
+        }
+
+        if self.forward {
+          self.position += step;
+
+          if self.position >= end {
+            let over = (self.position - end) % (if repeat.pingpong { 2.0 * length } else { length });
+
+            if !repeat.pingpong {
+              self.position = start + over;
+            } else if over < length {
+              self.position = (end - over).min(end - 1.0);
+              self.forward = false;
+            } else {
+              self.position = start + over - length;
+            }
+          }
+        } else {
+          self.position -= step;
+
+          if self.position < start {
+            let over = (start - self.position) % (2.0 * length);
+
+            if over < length {
+              self.position = start + over;
+              self.forward = true;
+            } else {
+              self.position = (end - over + length).min(end - 1.0);
+            }
+          }
+        }
+      },
+      None => {
+        self.forward = true;
+        self.position += step;
+
+        if self.position >= sample.data.len() as f64 {
+          self.active = false;
+        }
+      }
+    }
+  }
+}
+
+/// Plays a module, tick by tick.
+pub struct Player<'a> {
+  module: &'a Module,
+  sample_rate: f64,
+  channels: Vec<Channel>,
+
+  order: uint,
+  row: uint,
+  tick: uint,
+  speed: uint,
+  tempo: uint,
+  global_volume: int,
+
+  /// The number of times the row is repeated by a pattern delay, and the
+  /// repetitions played so far.
+  delay: uint,
+  repeats: uint,
+
+  /// Where to continue after the row, set by jumps, breaks and loops.
+  jump_order: Option<uint>,
+  jump_row: Option<uint>,
+  loop_jump: Option<uint>,
+
+  /// The (order, row) positions the song has entered, to detect loops.
+  entered: Vec<(uint, uint)>,
+  finished: bool,
+
+  /// Frames left in the current tick, and the fraction carried over.
+  remaining: uint,
+  fraction: f64
+}
+
+impl<'a> Player<'a> {
+  pub fn new(module: &'a Module, sample_rate: f64) -> Player<'a> {
+    let mut player = Player {
+      module: module,
+      sample_rate: sample_rate,
+      channels: range(0, module.channels).map(|i| Channel::new(module.panning[i], module.volume[i])).collect(),
+      order: 0,
+      row: 0,
+      tick: 0,
+      speed: module.speed,
+      tempo: module.tempo,
+      global_volume: module.global_volume as int,
+      delay: 0,
+      repeats: 0,
+      jump_order: None,
+      jump_row: None,
+      loop_jump: None,
+      entered: Vec::new(),
+      finished: false,
+      remaining: 0,
+      fraction: 0.0
+    };
+
+    player.enter(0, 0);
+
+    return player;
+  }
+
+  /// Renders interleaved stereo frames into `output`, and returns the
+  /// number of frames rendered. Fewer frames than fit are rendered only at
+  /// the end of the song.
+  pub fn render(&mut self, output: &mut [f64]) -> uint {
+    let frames = output.len() / 2;
+    let mut done = 0;
+
+    while done < frames {
+      if self.remaining == 0 {
+        if !self.step() {
+          break;
+        }
+
+        let exact = self.sample_rate * 2.5 / self.tempo as f64 + self.fraction;
+
+        self.remaining = exact.floor() as uint;
+        self.fraction = exact - exact.floor();
+
+        continue;
+      }
+
+      let count = std::cmp::min(self.remaining, frames - done);
+
+      self.mix(output.slice_mut(done * 2, (done + count) * 2));
+
+      done += count;
+      self.remaining -= count;
+    }
+
+    return done;
+  }
+
+  fn mix(&mut self, output: &mut [f64]) {
+    let module = self.module;
+    let sample_rate = self.sample_rate;
+
+    for x in output.iter_mut() {
+      *x = 0.0;
+    }
+
+    for channel in self.channels.iter_mut() {
+      let sample = match channel.sample {
+        Some(i) if channel.active => &module.samples[i],
+        _ => continue
+      };
+
+      let data = sample.data.as_slice();
+      let step = channel.frequency / sample_rate;
+
+      for frame in range(0, output.len() / 2) {
+        if !channel.active {
+          break;
+        }
+
+        let i = channel.position as uint;
+        let fraction = channel.position - i as f64;
+
+        let a = data[i] as f64;
+        let b = if i + 1 < data.len() { data[i + 1] as f64 } else { a };
+        let value = a + (b - a) * fraction;
+
+        output[frame * 2] += value * channel.left;
+        output[frame * 2 + 1] += value * channel.right;
+
+        channel.advance(step, sample);
+      }
+    }
+  }
+
+  /// Plays one tick, returning false once the song has ended.
+  fn step(&mut self) -> bool {
+    if self.finished {
+      return false;
+    }
+
+    let first = self.tick == 0 && self.repeats == 0;
+
+    if first {
+      self.start_row();
+    }
+
+    for ch in range(0, self.channels.len()) {
+      self.update(ch, first);
+    }
+
+    self.tick += 1;
+
+    if self.tick >= self.speed {
+      self.tick = 0;
+
+      if self.repeats < self.delay {
+        self.repeats += 1;
+      } else {
+        self.repeats = 0;
+        self.delay = 0;
+        self.next_row();
+      }
+    }
+
+    return true;
+  }
+
+  fn start_row(&mut self) {
+    for ch in range(0, self.channels.len()) {
+      let cell = self.cell(ch);
+      let effects = [self.recall(ch, cell.volume_effect.clone()), self.recall(ch, cell.effect.clone())];
+
+      let delay = match cell.effect {
+        super::NoteDelay(d) => d as uint,
+        _ => 0
+      };
+
+      {
+        let channel = self.channels.get_mut(ch);
+
+        channel.effects = effects;
+        channel.delayed = None;
+      }
+
+      if delay == 0 {
+        self.trigger(ch, &cell);
+      } else if delay < self.speed {
+        self.channels.get_mut(ch).delayed = Some((delay, cell));
+      }
+    }
+  }
+
+  /// Returns `effect` with a zero parameter replaced by the one last used,
+  /// where the format remembers it.
+  fn recall(&mut self, ch: uint, effect: Effect) -> Effect {
+    let format = self.module.format;
+    let memory = format != super::Protracker;
+
+    // Scream Tracker and Impulse Tracker share the slide up and down memory.
+    let porta_down = if format == super::ScreamTracker || format == super::ImpulseTracker { PORTA_UP } else { PORTA_DOWN };
+
+    let slots = &mut self.channels.get_mut(ch).memory;
+
+    return match effect {
+      super::Arpeggio(p) => super::Arpeggio(remember(slots, ARPEGGIO, p, memory)),
+      super::PortaUp(p) => super::PortaUp(remember(slots, PORTA_UP, p, memory)),
+      super::PortaDown(p) => super::PortaDown(remember(slots, porta_down, p, memory)),
+      super::FinePortaUp(p) => super::FinePortaUp(remember(slots, FINE_PORTA_UP, p, memory)),
+      super::FinePortaDown(p) => super::FinePortaDown(remember(slots, FINE_PORTA_DOWN, p, memory)),
+      super::ExtraFinePortaUp(p) => super::ExtraFinePortaUp(remember(slots, FINE_PORTA_UP, p, memory)),
+      super::ExtraFinePortaDown(p) => super::ExtraFinePortaDown(remember(slots, FINE_PORTA_DOWN, p, memory)),
+      super::TonePorta(p) => super::TonePorta(remember(slots, TONE_PORTA, p, true)),
+      super::Vibrato(p) => super::Vibrato(nibbles(slots, VIBRATO, p)),
+      super::Tremolo(p) => super::Tremolo(nibbles(slots, TREMOLO, p)),
+      super::TonePortaVolumeSlide(p) => super::TonePortaVolumeSlide(remember(slots, VOLUME_SLIDE, p, memory)),
+      super::VibratoVolumeSlide(p) => super::VibratoVolumeSlide(remember(slots, VOLUME_SLIDE, p, memory)),
+      super::VolumeSlide(p) => super::VolumeSlide(remember(slots, VOLUME_SLIDE, p, memory)),
+      super::FineVolumeUp(p) => super::FineVolumeUp(remember(slots, FINE_VOLUME, p, memory)),
+      super::FineVolumeDown(p) => super::FineVolumeDown(remember(slots, FINE_VOLUME, p, memory)),
+      super::PanningSlide(p) => super::PanningSlide(remember(slots, PANNING_SLIDE, p, memory)),
+      super::SampleOffset(p) => super::SampleOffset(remember(slots, SAMPLE_OFFSET, p, true)),
+      super::GlobalVolumeSlide(p) => super::GlobalVolumeSlide(remember(slots, GLOBAL_VOLUME_SLIDE, p, memory)),
+      super::Retrigger(p) => super::Retrigger(remember(slots, RETRIGGER, p, memory)),
+      other => other
+    };
+  }
+
+  /// Starts the note, instrument and volume of a cell.
+  fn trigger(&mut self, ch: uint, cell: &Cell) {
+    let module = self.module;
+    let channel = self.channels.get_mut(ch);
+
+    let tone = channel.active && channel.effects.iter().any(|effect| match *effect {
+      super::TonePorta(_) | super::TonePortaVolumeSlide(_) => true,
+      _ => false
+    });
+
+    if cell.instrument > 0 && cell.instrument <= module.instruments.len() {
+      channel.instrument = Some(cell.instrument - 1);
+    }
+
+    match cell.note {
+      super::Key(note) => match channel.instrument {
+        Some(i) => {
+          let (mapped, number) = module.instruments[i].keymap[note];
+
+          if number > 0 && number <= module.samples.len() {
+            let sample = &module.samples[number - 1];
+            let period = period(module.linear, mapped, sample.rate);
+
+            channel.target = period;
+
+            if !tone {
+              channel.sample = Some(number - 1);
+              channel.period = period;
+              channel.position = 0.0;
+              channel.forward = true;
+              channel.active = !sample.data.is_empty();
+              channel.vibrato = 0;
+              channel.tremolo = 0;
+
+              for effect in channel.effects.iter() {
+                match *effect {
+                  super::SampleOffset(p) => {
+                    channel.position = p as f64 * 256.0;
+
+                    if channel.position >= sample.data.len() as f64 {
+                      match sample.repeat {
+                        Some(ref repeat) => channel.position = repeat.start as f64,
+                        None => channel.active = false
+                      }
+                    }
+                  },
+                  _ => {}
+                }
+              }
+            }
+          }
+        },
+        None => {}
+      },
+      super::Off => channel.release(module),
+      super::Cut => channel.active = false,
+      super::Fade => channel.fading = true,
+      super::NoNote => {}
+    }
+
+    if cell.instrument > 0 {
+      match channel.instrument {
+        Some(i) => match module.instruments[i].panning_default {
+          Some(panning) => channel.panning = panning as int,
+          None => {}
+        },
+        None => {}
+      }
+
+      match channel.sample {
+        Some(i) => {
+          let sample = &module.samples[i];
+
+          channel.volume = sample.volume as int;
+
+          match sample.panning {
+            Some(panning) => channel.panning = panning as int,
+            None => {}
+          }
+        },
+        None => {}
+      }
+
+      channel.released = false;
+      channel.fading = false;
+      channel.fade = 1.0;
+      channel.volume_tick = 0;
+      channel.panning_tick = 0;
+    }
+
+    match cell.volume {
+      Some(volume) => channel.volume = volume as int,
+      None => {}
+    }
+  }
+
+  /// Applies the effects of a channel for the current tick, and works out
+  /// its gains and playback rate.
+  fn update(&mut self, ch: uint, first: bool) {
+    let tick = self.tick;
+
+    let delayed = {
+      let channel = self.channels.get_mut(ch);
+
+      channel.vibrato_offset = 0.0;
+      channel.tremolo_offset = 0;
+      channel.arpeggio = 0;
+
+      let ready = match channel.delayed {
+        Some((delay, _)) => delay == tick,
+        None => false
+      };
+
+      if ready { channel.delayed.take() } else { None }
+    };
+
+    match delayed {
+      Some((_, cell)) => self.trigger(ch, &cell),
+      None => {}
+    }
+
+    for i in range(0u, 2) {
+      let effect = self.channels[ch].effects[i].clone();
+
+      self.apply(ch, effect, first);
+    }
+
+    self.finish(ch);
+  }
+
+  fn apply(&mut self, ch: uint, effect: Effect, first: bool) {
+    let module = self.module;
+    let tick = self.tick;
+    let fine = module.format == super::ScreamTracker || module.format == super::ImpulseTracker;
+    let channel = self.channels.get_mut(ch);
+
+    match effect {
+      super::NoEffect => {},
+      super::Arpeggio(p) => channel.arpeggio = [0, p >> 4, p & 15][tick % 3] as uint,
+      super::PortaUp(p) => channel.period -= slide(p, first, fine),
+      super::PortaDown(p) => channel.period += slide(p, first, fine),
+      super::FinePortaUp(p) => if first { channel.period -= p as f64 * 4.0 },
+      super::FinePortaDown(p) => if first { channel.period += p as f64 * 4.0 },
+      super::ExtraFinePortaUp(p) => if first { channel.period -= p as f64 },
+      super::ExtraFinePortaDown(p) => if first { channel.period += p as f64 },
+      super::TonePorta(p) => if !first { channel.tone_porta(p) },
+      super::Vibrato(p) => channel.vibrato(p, first),
+      super::TonePortaVolumeSlide(p) => {
+        if !first {
+          let speed = channel.memory[TONE_PORTA];
+
+          channel.tone_porta(speed);
+        }
+
+        channel.volume_slide(p, first, fine);
+      },
+      super::VibratoVolumeSlide(p) => {
+        let vibrato = channel.memory[VIBRATO];
+
+        channel.vibrato(vibrato, first);
+        channel.volume_slide(p, first, fine);
+      },
+      super::Tremolo(p) => channel.tremolo(p, first),
+      super::SetPanning(p) => if first { channel.panning = p as int },
+      super::PanningSlide(p) => if !first {
+        let scale = if module.format == super::ImpulseTracker { 4 } else { 1 };
+        let delta = ((p >> 4) as int - (p & 15) as int) * scale;
+
+        channel.panning = std::cmp::max(std::cmp::min(channel.panning + delta, 255), 0);
+      },
+      super::SampleOffset(_) => {},
+      super::VolumeSlide(p) => channel.volume_slide(p, first, fine),
+      super::FineVolumeUp(p) => if first { channel.volume = std::cmp::min(channel.volume + p as int, 64) },
+      super::FineVolumeDown(p) => if first { channel.volume = std::cmp::max(channel.volume - p as int, 0) },
+      super::SetVolume(p) => if first { channel.volume = std::cmp::min(p as int, 64) },
+      super::SetGlobalVolume(p) => if first { self.global_volume = std::cmp::min(p as int, 64) },
+      super::GlobalVolumeSlide(p) => if !first {
+        let delta = if p >> 4 > 0 { (p >> 4) as int } else { -((p & 15) as int) };
+
+        self.global_volume = std::cmp::max(std::cmp::min(self.global_volume + delta, 64), 0);
+      },
+      super::PositionJump(p) => if first { self.jump_order = Some(p as uint) },
+      super::PatternBreak(p) => if first { self.jump_row = Some(p as uint) },
+      super::PatternLoop(p) => if first {
+        if p == 0 {
+          channel.loop_row = self.row;
+        } else if channel.loop_count == 0 {
+          channel.loop_count = p as uint;
+          self.loop_jump = Some(channel.loop_row);
+        } else {
+          channel.loop_count -= 1;
+
+          if channel.loop_count > 0 {
+            self.loop_jump = Some(channel.loop_row);
+          }
+        }
+      },
+      super::PatternDelay(p) => if first && self.delay == 0 { self.delay = p as uint },
+      super::SetSpeed(p) => if first && p > 0 { self.speed = p as uint },
+      super::SetTempo(p) => if first && p >= 32 { self.tempo = p as uint },
+      super::NoteCut(p) => if tick == p as uint { channel.volume = 0 },
+      super::NoteDelay(_) => {},
+      super::Retrigger(p) => {
+        let interval = (p & 15) as uint;
+
+        if interval > 0 && tick > 0 && tick % interval == 0 {
+          channel.position = 0.0;
+          channel.forward = true;
+          channel.active = channel.sample.map_or(false, |i| !module.samples[i].data.is_empty());
+          channel.volume = std::cmp::max(std::cmp::min(retrigger_volume(channel.volume, p >> 4), 64), 0);
+        }
+      },
+      super::KeyOff(p) => if tick == p as uint { channel.release(module) }
+    }
+  }
+
+  /// Advances the envelopes and fadeout of a channel, and works out its
+  /// gains and playback rate for the tick.
+  fn finish(&mut self, ch: uint) {
+    let module = self.module;
+    let global_volume = self.global_volume;
+    let channel = self.channels.get_mut(ch);
+
+    let sample = match channel.sample {
+      Some(i) if channel.active => &module.samples[i],
+      _ => return
+    };
+
+    let mut envelope = 1.0;
+    let mut panning = channel.panning as f64;
+    let mut scale = sample.global_volume as f64 / 64.0;
+
+    match channel.instrument {
+      Some(i) => {
+        let instrument: &Instrument = &module.instruments[i];
+
+        if instrument.volume.enabled {
+          envelope = instrument.volume.value(channel.volume_tick) / 64.0;
+          channel.volume_tick = instrument.volume.advance(channel.volume_tick, channel.released);
+        }
+
+        if instrument.panning.enabled {
+          let value = instrument.panning.value(channel.panning_tick);
+
+          panning += (value - 32.0) * (128.0 - (panning - 128.0).abs()) / 32.0;
+          channel.panning_tick = instrument.panning.advance(channel.panning_tick, channel.released);
+        }
+
+        if channel.fading {
+          channel.fade = (channel.fade - instrument.fadeout).max(0.0);
+        }
+
+        scale *= instrument.global_volume as f64 / 64.0;
+      },
+      None => {}
+    }
+
+    if channel.fading && channel.fade <= 0.0 {
+      channel.active = false;
+    }
+
+    let volume = std::cmp::max(std::cmp::min(channel.volume + channel.tremolo_offset, 64), 0) as f64 / 64.0;
+    let gain = volume * envelope * channel.fade * scale * MASTER
+      * global_volume as f64 / 64.0 * channel.channel_volume as f64 / 64.0;
+    let p = (panning / 255.0).max(0.0).min(1.0);
+
+    channel.left = gain * (2.0 * (1.0 - p)).min(1.0);
+    channel.right = gain * (2.0 * p).min(1.0);
+
+    let period = (channel.period + channel.vibrato_offset).max(1.0);
+    let frequency = if module.linear {
+      sample.rate * (2.0f64).powf((3840.0 - period) / 768.0)
+    } else {
+      1712.0 * 8363.0 / period
+    };
+
+    channel.frequency = frequency * (2.0f64).powf(channel.arpeggio as f64 / 12.0);
+  }
+
+  fn next_row(&mut self) {
+    if self.jump_order.is_some() || self.jump_row.is_some() {
+      let order = self.jump_order.unwrap_or(self.order + 1);
+      let row = self.jump_row.unwrap_or(0);
+
+      self.jump_order = None;
+      self.jump_row = None;
+      self.loop_jump = None;
+
+      self.enter(order, row);
+    } else if self.loop_jump.is_some() {
+      self.row = self.loop_jump.take().unwrap();
+    } else {
+      self.row += 1;
+
+      if self.row >= self.rows() {
+        let order = self.order + 1;
+
+        self.enter(order, 0);
+      }
+    }
+  }
+
+  /// Moves to a position in the order list, skipping marker entries. The
+  /// song ends at the end of the list, or when a position repeats.
+  fn enter(&mut self, order: uint, row: uint) {
+    let orders = self.module.orders.as_slice();
+    let mut order = order;
+
+    while order < orders.len() && orders[order] == super::SKIP {
+      order += 1;
+    }
+
+    if order >= orders.len() || orders[order] == super::END || self.entered.contains(&(order, row)) {
+      self.finished = true;
+      return;
+    }
+
+    self.entered.push((order, row));
+    self.order = order;
+    self.row = if row < self.rows() { row } else { 0 };
+
+    for channel in self.channels.iter_mut() {
+      channel.loop_row = 0;
+      channel.loop_count = 0;
+    }
+  }
+
+  /// The number of rows in the current pattern. Missing patterns play as
+  /// 64 empty rows.
+  fn rows(&self) -> uint {
+    let pattern = self.module.orders[self.order];
+
+    return if pattern < self.module.patterns.len() { self.module.patterns[pattern].rows } else { 64 };
+  }
+
+  fn cell(&self, ch: uint) -> Cell {
+    let pattern = self.module.orders[self.order];
+
+    if pattern >= self.module.patterns.len() {
+      return Cell::new();
+    }
+
+    return self.module.patterns[pattern].cells[self.row * self.module.channels + ch].clone();
+  }
+}
+
+/// Returns the period of a note, for a sample playing at `rate` on
+/// `BASE_NOTE`. Linear periods are in 1/64 semitones, Amiga periods are
+/// four times the Amiga hardware ones.
+fn period(linear: bool, note: uint, rate: f64) -> f64 {
+  if linear {
+    return 7680.0 - note as f64 * 64.0;
+  }
+
+  return 1712.0 * 8363.0 / rate * (2.0f64).powf((super::BASE_NOTE as f64 - note as f64) / 12.0);
+}
+
+/// The amount a portamento slides on a tick. Scream Tracker and Impulse
+/// Tracker encode fine (Fx) and extra fine (Ex) slides in the high values.
+fn slide(param: u8, first: bool, fine: bool) -> f64 {
+  if fine && param >= 0xF0 {
+    return if first { (param & 15) as f64 * 4.0 } else { 0.0 };
+  } else if fine && param >= 0xE0 {
+    return if first { (param & 15) as f64 } else { 0.0 };
+  }
+
+  return if first { 0.0 } else { param as f64 * 4.0 };
+}
+
+fn sine(position: uint) -> f64 {
+  return (2.0 * std::f64::consts::PI * (position % 64) as f64 / 64.0).sin();
+}
+
+fn remember(memory: &mut [u8, ..14], slot: uint, param: u8, enabled: bool) -> u8 {
+  if param != 0 {
+    memory[slot] = param;
+    return param;
+  }
+
+  return if enabled { memory[slot] } else { 0 };
+}
+
+/// Recalls the speed and depth nibbles separately.
+fn nibbles(memory: &mut [u8, ..14], slot: uint, param: u8) -> u8 {
+  let speed = if param >> 4 != 0 { param & 0xF0 } else { memory[slot] & 0xF0 };
+  let depth = if param & 15 != 0 { param & 15 } else { memory[slot] & 15 };
+
+  memory[slot] = speed | depth;
+
+  return memory[slot];
+}
+
+/// Applies the volume change of a retrigger.
+fn retrigger_volume(volume: int, change: u8) -> int {
+  return match change {
+    0x1 => volume - 1,
+    0x2 => volume - 2,
+    0x3 => volume - 4,
+    0x4 => volume - 8,
+    0x5 => volume - 16,
+    0x6 => volume * 2 / 3,
+    0x7 => volume / 2,
+    0x9 => volume + 1,
+    0xA => volume + 2,
+    0xB => volume + 4,
+    0xC => volume + 8,
+    0xD => volume + 16,
+    0xE => volume * 3 / 2,
+    0xF => volume * 2,
+    _ => volume
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  #[test]
+  fn test_period() {
+    assert_eq!(super::period(false, 60, 8363.0), 1712.0);
+    assert!((super::period(false, 72, 8363.0) - 856.0).abs() < 1e-9);
+    assert_eq!(super::period(true, 60, 8363.0), 3840.0);
+  }
+
+  #[test]
+  fn test_slide() {
+    assert_eq!(super::slide(0x10, false, false), 64.0);
+    assert_eq!(super::slide(0x10, true, false), 0.0);
+    assert_eq!(super::slide(0xF2, true, true), 8.0);
+    assert_eq!(super::slide(0xF2, false, true), 0.0);
+    assert_eq!(super::slide(0xE2, true, true), 2.0);
+  }
+
+  #[test]
+  fn test_nibbles() {
+    let mut memory = [0u8, ..14];
+
+    assert_eq!(super::nibbles(&mut memory, 0, 0x48), 0x48);
+    assert_eq!(super::nibbles(&mut memory, 0, 0x03), 0x43);
+    assert_eq!(super::nibbles(&mut memory, 0, 0x00), 0x43);
+  }
+}
@@ -0,0 +1,229 @@
+use std;
+
+use super::{Module, Pattern, Cell, Instrument, Sample, Loop, Reader, Effect};
+
+/// Returns the number of channels for a signature at offset 1080.
+fn channels(signature: &[u8]) -> Option<uint> {
+  if signature == b"M.K." || signature == b"M!K!" || signature == b"FLT4" || signature == b"4CHN" {
+    return Some(4);
+  } else if signature == b"FLT8" || signature == b"OCTA" || signature == b"CD81" {
+    return Some(8);
+  }
+
+  let digit = |b: u8| if b >= b'0' && b <= b'9' { Some((b - b'0') as uint) } else { None };
+
+  if signature.slice_from(1) == b"CHN" {
+    return digit(signature[0]).and_then(|n| if n > 0 { Some(n) } else { None });
+  }
+
+  if signature.slice_from(2) == b"CH" {
+    return match (digit(signature[0]), digit(signature[1])) {
+      (Some(a), Some(b)) if a * 10 + b > 0 && a * 10 + b <= 32 => Some(a * 10 + b),
+      _ => None
+    };
+  }
+
+  return None;
+}
+
+pub fn detect(data: &[u8]) -> bool {
+  return data.len() >= 1084 && channels(data.slice(1080, 1084)).is_some();
+}
+
+pub fn load(data: &[u8]) -> Module {
+  let count = channels(data.slice(1080, 1084)).unwrap();
+
+  let mut r = Reader::new(data, 20);
+  let mut samples = Vec::new();
+  let mut lengths = Vec::new();
+
+  for _ in range(0u, 31) {
+    r.skip(22);
+
+    let length = r.be_u16() as uint * 2;
+    let finetune = ((r.u8() & 15) << 4) as i8 >> 4;
+    let volume = std::cmp::min(r.u8() as uint, 64);
+    let start = r.be_u16() as uint * 2;
+    let repeat = r.be_u16() as uint * 2;
+
+    let mut sample = Sample::empty();
+
+    sample.rate = 8363.0 * (2.0f64).powf(finetune as f64 / 96.0);
+    sample.volume = volume;
+
+    if repeat > 2 {
+      sample.repeat = Loop::new(start, start + repeat, false, length);
+    }
+
+    samples.push(sample);
+    lengths.push(length);
+  }
+
+  let song_length = std::cmp::min(r.u8() as uint, 128);
+
+  r.skip(1);
+
+  let orders: Vec<uint> = range(0u, 128).map(|_| r.u8() as uint).collect();
+  let patterns = orders.iter().fold(0, |a, &b| std::cmp::max(a, b)) + 1;
+
+  r.seek(1084);
+
+  let mut pattern_list = Vec::new();
+
+  for _ in range(0, patterns) {
+    let mut pattern = Pattern::new(64, count);
+
+    for i in range(0, 64 * count) {
+      let b0 = r.u8();
+      let b1 = r.u8();
+      let b2 = r.u8();
+      let b3 = r.u8();
+
+      let period = ((b0 & 15) as uint << 8) | b1 as uint;
+      let cell = pattern.cells.get_mut(i);
+
+      cell.instrument = ((b0 & 0xF0) | (b2 >> 4)) as uint;
+      cell.note = note(period);
+      cell.effect = effect(b2 & 15, b3);
+    }
+
+    pattern_list.push(pattern);
+  }
+
+  for (sample, &length) in samples.iter_mut().zip(lengths.iter()) {
+    sample.data = super::pcm8(r.bytes(length), true);
+  }
+
+  return Module {
+    format: super::Protracker,
+    channels: count,
+    orders: orders.slice_to(song_length).to_vec(),
+    patterns: pattern_list,
+    instruments: range(1u, 32).map(|i| Instrument::simple(i)).collect(),
+    samples: samples,
+    speed: 6,
+    tempo: 125,
+    global_volume: 64,
+    linear: false,
+    panning: range(0, count).map(|i| [64u, 192, 192, 64][i % 4]).collect(),
+    volume: Vec::from_elem(count, 64)
+  };
+}
+
+/// Converts an Amiga period to a note, period 428 being the base note.
+fn note(period: uint) -> super::Note {
+  if period == 0 {
+    return super::NoNote;
+  }
+
+  let semitones = 12.0 * (428.0 / period as f64).log2();
+
+  return super::Key((super::BASE_NOTE as f64 + semitones).round().max(0.0).min(119.0) as uint);
+}
+
+/// Converts a ProTracker effect, which Fasttracker 2 also uses for its
+/// first 16 commands.
+pub fn effect(command: u8, param: u8) -> Effect {
+  let x = param >> 4;
+  let y = param & 15;
+
+  return match command {
+    0x0 => if param != 0 { super::Arpeggio(param) } else { super::NoEffect },
+    0x1 => super::PortaUp(param),
+    0x2 => super::PortaDown(param),
+    0x3 => super::TonePorta(param),
+    0x4 => super::Vibrato(param),
+    0x5 => super::TonePortaVolumeSlide(param),
+    0x6 => super::VibratoVolumeSlide(param),
+    0x7 => super::Tremolo(param),
+    0x8 => super::SetPanning(param),
+    0x9 => super::SampleOffset(param),
+    0xA => super::VolumeSlide(param),
+    0xB => super::PositionJump(param),
+    0xC => super::SetVolume(param),
+    0xD => super::PatternBreak(x * 10 + y),
+    0xE => match x {
+      0x1 => super::FinePortaUp(y),
+      0x2 => super::FinePortaDown(y),
+      0x6 => super::PatternLoop(y),
+      0x8 => super::SetPanning(y * 17),
+      0x9 => super::Retrigger(y),
+      0xA => super::FineVolumeUp(y),
+      0xB => super::FineVolumeDown(y),
+      0xC => super::NoteCut(y),
+      0xD => super::NoteDelay(y),
+      0xE => super::PatternDelay(y),
+      _ => super::NoEffect
+    },
+    0xF => if param == 0 { super::NoEffect } else if param < 32 { super::SetSpeed(param) } else { super::SetTempo(param) },
+    _ => super::NoEffect
+  };
+}
+
+#[cfg(test)]
+pub mod tests {
+  /// A four channel module with a single pattern, that plays a looped
+  /// square wave on the first channel and jumps back to the start on the
+  /// second row.
+  pub fn module() -> Vec<u8> {
+    let mut data = Vec::from_elem(1084 + 1024, 0u8);
+
+    // Sample 1: 64 bytes, volume 64, looped.
+    *data.get_mut(20 + 23) = 32;
+    *data.get_mut(20 + 25) = 64;
+    *data.get_mut(20 + 29) = 32;
+
+    *data.get_mut(950) = 1;
+    *data.get_mut(951) = 0x7F;
+
+    for (i, &b) in b"M.K.".iter().enumerate() {
+      *data.get_mut(1080 + i) = b;
+    }
+
+    // Row 0, channel 0: C-2 (period 428) with sample 1.
+    *data.get_mut(1084) = 0x01;
+    *data.get_mut(1085) = 0xAC;
+    *data.get_mut(1086) = 0x10;
+
+    // Row 1, channel 1: B00.
+    *data.get_mut(1084 + 16 + 4 + 2) = 0x0B;
+
+    for i in range(0u, 64) {
+      data.push(if i < 32 { 100 } else { -100i8 as u8 });
+    }
+
+    return data;
+  }
+
+  #[test]
+  fn test_load() {
+    let module = super::load(module().as_slice());
+
+    assert_eq!(module.channels, 4);
+    assert_eq!(module.orders, vec![0u]);
+    assert_eq!(module.patterns.len(), 1);
+    assert_eq!(module.samples[0].data.len(), 64);
+    assert_eq!(module.samples[0].volume, 64);
+    assert_eq!(module.samples[0].repeat.as_ref().unwrap().end, 64);
+    assert_eq!(module.patterns[0].cells[0].note, ::tracker::Key(60));
+    assert_eq!(module.patterns[0].cells[0].instrument, 1);
+    assert_eq!(module.patterns[0].cells[5].effect, ::tracker::PositionJump(0));
+  }
+
+  #[test]
+  fn test_channels() {
+    assert_eq!(super::channels(b"M.K."), Some(4));
+    assert_eq!(super::channels(b"6CHN"), Some(6));
+    assert_eq!(super::channels(b"16CH"), Some(16));
+    assert_eq!(super::channels(b"0CHN"), None);
+    assert_eq!(super::channels(b"SCRM"), None);
+  }
+
+  #[test]
+  fn test_note() {
+    assert_eq!(super::note(0), ::tracker::NoNote);
+    assert_eq!(super::note(428), ::tracker::Key(60));
+    assert_eq!(super::note(856), ::tracker::Key(48));
+    assert_eq!(super::note(453), ::tracker::Key(59));
+  }
+}
@@ -0,0 +1,227 @@
+use std;
+
+use super::{Module, Pattern, Instrument, Sample, Loop, Reader, Effect};
+
+pub fn detect(data: &[u8]) -> bool {
+  return data.len() >= 96 && data.slice(44, 48) == b"SCRM";
+}
+
+pub fn load(data: &[u8]) -> Module {
+  let mut r = Reader::new(data, 32);
+
+  let order_count = r.le_u16() as uint;
+  let instrument_count = r.le_u16() as uint;
+  let pattern_count = r.le_u16() as uint;
+
+  r.seek(42);
+
+  let signed = r.le_u16() != 2;
+
+  r.seek(48);
+
+  let global_volume = std::cmp::min(r.u8() as uint, 64);
+  let speed = r.u8() as uint;
+  let tempo = r.u8() as uint;
+  let stereo = r.u8() & 0x80 != 0;
+
+  r.seek(53);
+
+  let panning_table = r.u8() == 0xFC;
+
+  r.seek(64);
+
+  let settings: Vec<u8> = range(0u, 32).map(|_| r.u8()).collect();
+  let orders: Vec<uint> = range(0, order_count).map(|_| r.u8() as uint).collect();
+  let instrument_pointers: Vec<uint> = range(0, instrument_count).map(|_| r.le_u16() as uint * 16).collect();
+  let pattern_pointers: Vec<uint> = range(0, pattern_count).map(|_| r.le_u16() as uint * 16).collect();
+
+  let mut panning: Vec<uint> = settings.iter().map(|&s| {
+    if !stereo || s >= 16 { 128 } else if s < 8 { 51 } else { 204 }
+  }).collect();
+
+  if panning_table {
+    for i in range(0u, 32) {
+      let b = r.u8();
+
+      if b & 0x20 != 0 {
+        *panning.get_mut(i) = (b & 15) as uint * 17;
+      }
+    }
+  }
+
+  let channels = settings.iter().enumerate().fold(1, |a, (i, &s)| if s < 16 { std::cmp::max(a, i + 1) } else { a });
+  let volume: Vec<uint> = range(0, channels).map(|i| if settings[i] < 16 { 64 } else { 0 }).collect();
+
+  panning.truncate(channels);
+
+  let samples: Vec<Sample> = instrument_pointers.iter().map(|&p| load_sample(data, p, signed)).collect();
+
+  let patterns = pattern_pointers.iter().map(|&p| {
+    let mut pattern = Pattern::new(64, channels);
+
+    if p > 0 {
+      load_pattern(data, p + 2, channels, &mut pattern);
+    }
+
+    pattern
+  }).collect();
+
+  return Module {
+    format: super::ScreamTracker,
+    channels: channels,
+    orders: orders,
+    patterns: patterns,
+    instruments: range(1, samples.len() + 1).map(|i| Instrument::simple(i)).collect(),
+    samples: samples,
+    speed: if speed > 0 { speed } else { 6 },
+    tempo: if tempo >= 32 { tempo } else { 125 },
+    global_volume: global_volume,
+    linear: false,
+    panning: panning,
+    volume: volume
+  };
+}
+
+fn load_sample(data: &[u8], position: uint, signed: bool) -> Sample {
+  let mut r = Reader::new(data, position);
+  let mut sample = Sample::empty();
+
+  if r.u8() != 1 {
+    return sample;
+  }
+
+  r.skip(12);
+
+  let high = r.u8() as uint;
+  let low = r.le_u16() as uint;
+  let length = r.le_u32() as uint;
+  let loop_start = r.le_u32() as uint;
+  let loop_end = r.le_u32() as uint;
+
+  sample.volume = std::cmp::min(r.u8() as uint, 64);
+
+  r.skip(2);
+
+  let flags = r.u8();
+
+  sample.rate = match r.le_u32() {
+    0 => 8363.0,
+    rate => rate as f64
+  };
+
+  let sixteen = flags & 4 != 0;
+  let stereo = flags & 2 != 0;
+  let width = if sixteen { 2 } else { 1 };
+
+  r.seek(((high << 16) | low) * 16);
+
+  let left = r.bytes(length * width);
+
+  sample.data = if sixteen { super::pcm16(left, signed) } else { super::pcm8(left, signed) };
+
+  if stereo {
+    let right = r.bytes(length * width);
+    let right = if sixteen { super::pcm16(right, signed) } else { super::pcm8(right, signed) };
+
+    for (left, &right) in sample.data.iter_mut().zip(right.iter()) {
+      *left = (*left + right) / 2.0;
+    }
+  }
+
+  if flags & 1 != 0 {
+    sample.repeat = Loop::new(loop_start, loop_end, false, sample.data.len());
+  }
+
+  return sample;
+}
+
+fn load_pattern(data: &[u8], position: uint, channels: uint, pattern: &mut Pattern) {
+  let mut r = Reader::new(data, position);
+  let mut row = 0;
+
+  while row < 64 && r.position < data.len() {
+    let what = r.u8();
+
+    if what == 0 {
+      row += 1;
+      continue;
+    }
+
+    let channel = (what & 31) as uint;
+    let mut cell = super::Cell::new();
+
+    if what & 32 != 0 {
+      cell.note = match r.u8() {
+        255 => super::NoNote,
+        254 => super::Cut,
+        n => super::Key(std::cmp::min((n >> 4) as uint * 12 + (n & 15) as uint + 12, 119))
+      };
+
+      cell.instrument = r.u8() as uint;
+    }
+
+    if what & 64 != 0 {
+      cell.volume = Some(std::cmp::min(r.u8() as uint, 64));
+    }
+
+    if what & 128 != 0 {
+      let command = r.u8();
+
+      cell.effect = effect(command, r.u8(), false);
+    }
+
+    if channel < channels {
+      *pattern.cells.get_mut(row * channels + channel) = cell;
+    }
+  }
+}
+
+/// Converts a Scream Tracker effect, or an Impulse Tracker one when `it` is
+/// set. Commands are numbered from 1 for A.
+pub fn effect(command: u8, param: u8, it: bool) -> Effect {
+  let x = param >> 4;
+  let y = param & 15;
+
+  return match command {
+    1 => if param > 0 { super::SetSpeed(param) } else { super::NoEffect },
+    2 => super::PositionJump(param),
+    3 => super::PatternBreak(if it { param } else { x * 10 + y }),
+    4 => super::VolumeSlide(param),
+    5 => super::PortaDown(param),
+    6 => super::PortaUp(param),
+    7 => super::TonePorta(param),
+    8 => super::Vibrato(param),
+    10 => super::Arpeggio(param),
+    11 => super::VibratoVolumeSlide(param),
+    12 => super::TonePortaVolumeSlide(param),
+    15 => super::SampleOffset(param),
+    16 if it => super::PanningSlide((y << 4) | x),
+    17 => super::Retrigger(param),
+    18 => super::Tremolo(param),
+    19 => match x {
+      0x8 => super::SetPanning(y * 17),
+      0xB => super::PatternLoop(y),
+      0xC => super::NoteCut(y),
+      0xD => super::NoteDelay(y),
+      0xE => super::PatternDelay(y),
+      _ => super::NoEffect
+    },
+    20 => if param >= 32 { super::SetTempo(param) } else { super::NoEffect },
+    22 => super::SetGlobalVolume(if it { param / 2 } else { std::cmp::min(param, 64) }),
+    23 if it => super::GlobalVolumeSlide(param),
+    24 => super::SetPanning(if it { param } else { std::cmp::min(param as uint * 2, 255) as u8 }),
+    _ => super::NoEffect
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  #[test]
+  fn test_effect() {
+    assert_eq!(super::effect(3, 0x12, false), ::tracker::PatternBreak(12));
+    assert_eq!(super::effect(3, 0x12, true), ::tracker::PatternBreak(0x12));
+    assert_eq!(super::effect(19, 0xD3, false), ::tracker::NoteDelay(3));
+    assert_eq!(super::effect(24, 0x40, false), ::tracker::SetPanning(0x80));
+    assert_eq!(super::effect(16, 0x40, false), ::tracker::NoEffect);
+  }
+}
@@ -0,0 +1,286 @@
+use std;
+
+use super::{Module, Pattern, Instrument, Envelope, Sample, Loop, Reader, Effect};
+use super::protracker;
+
+pub fn detect(data: &[u8]) -> bool {
+  return data.len() >= 80 && data.slice(0, 17) == b"Extended Module: ";
+}
+
+pub fn load(data: &[u8]) -> Module {
+  let mut r = Reader::new(data, 60);
+
+  let header_size = r.le_u32() as uint;
+  let song_length = std::cmp::min(r.le_u16() as uint, 256);
+
+  r.skip(2);
+
+  let channels = std::cmp::max(std::cmp::min(r.le_u16() as uint, 64), 1);
+  let pattern_count = r.le_u16() as uint;
+  let instrument_count = r.le_u16() as uint;
+  let linear = r.le_u16() & 1 != 0;
+  let speed = r.le_u16() as uint;
+  let tempo = r.le_u16() as uint;
+
+  let orders: Vec<uint> = range(0, song_length).map(|_| r.u8() as uint).collect();
+
+  r.seek(60 + header_size);
+
+  let mut patterns = Vec::new();
+
+  for _ in range(0, pattern_count) {
+    let start = r.position;
+    let length = r.le_u32() as uint;
+
+    r.skip(1);
+
+    let rows = std::cmp::max(r.le_u16() as uint, 1);
+    let size = r.le_u16() as uint;
+
+    r.seek(start + length);
+
+    let mut pattern = Pattern::new(rows, channels);
+
+    load_pattern(r.bytes(size), channels, &mut pattern);
+
+    patterns.push(pattern);
+  }
+
+  let mut instruments = Vec::new();
+  let mut samples = Vec::new();
+
+  for _ in range(0, instrument_count) {
+    instruments.push(load_instrument(&mut r, &mut samples));
+  }
+
+  return Module {
+    format: super::FastTracker,
+    channels: channels,
+    orders: orders,
+    patterns: patterns,
+    instruments: instruments,
+    samples: samples,
+    speed: if speed > 0 { speed } else { 6 },
+    tempo: if tempo >= 32 { tempo } else { 125 },
+    global_volume: 64,
+    linear: linear,
+    panning: Vec::from_elem(channels, 128),
+    volume: Vec::from_elem(channels, 64)
+  };
+}
+
+fn load_pattern(data: &[u8], channels: uint, pattern: &mut Pattern) {
+  let mut r = Reader::new(data, 0);
+
+  for i in range(0, pattern.rows * channels) {
+    if r.position >= data.len() {
+      break;
+    }
+
+    let first = r.u8();
+    let flags = if first & 0x80 != 0 { first } else { 0x1F };
+
+    let note = if flags & 1 == 0 { 0 } else if first & 0x80 != 0 { r.u8() } else { first };
+    let instrument = if flags & 2 != 0 { r.u8() } else { 0 };
+    let volume = if flags & 4 != 0 { r.u8() } else { 0 };
+    let command = if flags & 8 != 0 { r.u8() } else { 0 };
+    let param = if flags & 16 != 0 { r.u8() } else { 0 };
+
+    let cell = pattern.cells.get_mut(i);
+
+    cell.note = match note {
+      0 => super::NoNote,
+      97 => super::Off,
+      n if n < 97 => super::Key(n as uint - 1 + 12),
+      _ => super::NoNote
+    };
+
+    cell.instrument = instrument as uint;
+
+    let x = volume & 15;
+
+    if volume >= 0x10 && volume <= 0x50 {
+      cell.volume = Some((volume - 0x10) as uint);
+    } else {
+      cell.volume_effect = match volume >> 4 {
+        0x6 => super::VolumeSlide(x),
+        0x7 => super::VolumeSlide(x << 4),
+        0x8 => super::FineVolumeDown(x),
+        0x9 => super::FineVolumeUp(x),
+        0xA => super::Vibrato(x << 4),
+        0xB => super::Vibrato(x),
+        0xC => super::SetPanning(x * 17),
+        0xD => super::PanningSlide(x),
+        0xE => super::PanningSlide(x << 4),
+        0xF => super::TonePorta(x << 4),
+        _ => super::NoEffect
+      };
+    }
+
+    cell.effect = effect(command, param);
+  }
+}
+
+fn effect(command: u8, param: u8) -> Effect {
+  let x = param >> 4;
+  let y = param & 15;
+
+  return match command {
+    n if n <= 0x0F => protracker::effect(n, param),
+    0x10 => super::SetGlobalVolume(std::cmp::min(param, 64)),
+    0x11 => super::GlobalVolumeSlide(param),
+    0x14 => super::KeyOff(param),
+    0x19 => super::PanningSlide(param),
+    0x1B => super::Retrigger(param),
+    0x21 => match x {
+      1 => super::ExtraFinePortaUp(y),
+      2 => super::ExtraFinePortaDown(y),
+      _ => super::NoEffect
+    },
+    _ => super::NoEffect
+  };
+}
+
+fn load_instrument(r: &mut Reader, samples: &mut Vec<Sample>) -> Instrument {
+  let start = r.position;
+  let size = r.le_u32() as uint;
+
+  r.skip(23);
+
+  let count = r.le_u16() as uint;
+  let mut instrument = Instrument::simple(0);
+
+  if count == 0 {
+    r.seek(start + size);
+
+    return instrument;
+  }
+
+  r.skip(4);
+
+  let keymap: Vec<uint> = range(0u, 96).map(|_| r.u8() as uint).collect();
+
+  let volume_points: Vec<(uint, uint)> = range(0u, 12).map(|_| {
+    let x = r.le_u16() as uint;
+
+    (x, std::cmp::min(r.le_u16() as uint, 64))
+  }).collect();
+
+  let panning_points: Vec<(uint, uint)> = range(0u, 12).map(|_| {
+    let x = r.le_u16() as uint;
+
+    (x, std::cmp::min(r.le_u16() as uint, 64))
+  }).collect();
+
+  let volume_count = std::cmp::min(r.u8() as uint, 12);
+  let panning_count = std::cmp::min(r.u8() as uint, 12);
+
+  let volume_sustain = r.u8() as uint;
+  let volume_loop = (r.u8() as uint, r.u8() as uint);
+  let panning_sustain = r.u8() as uint;
+  let panning_loop = (r.u8() as uint, r.u8() as uint);
+
+  let volume_type = r.u8();
+  let panning_type = r.u8();
+
+  r.skip(4);
+
+  let fadeout = r.le_u16() as f64;
+
+  r.seek(start + size);
+
+  let envelope = |points: Vec<(uint, uint)>, count: uint, kind: u8, sustain: uint, repeat: (uint, uint)| {
+    Envelope::new(
+      kind & 1 != 0,
+      points.slice_to(count).to_vec(),
+      if kind & 2 != 0 { Some((sustain, sustain)) } else { None },
+      if kind & 4 != 0 { Some(repeat) } else { None })
+  };
+
+  instrument.volume = envelope(volume_points, volume_count, volume_type, volume_sustain, volume_loop);
+  instrument.panning = envelope(panning_points, panning_count, panning_type, panning_sustain, panning_loop);
+  instrument.fadeout = fadeout / 32768.0;
+
+  let first = samples.len();
+  let mut headers = Vec::new();
+
+  for _ in range(0, count) {
+    let header_start = r.position;
+
+    let length = r.le_u32() as uint;
+    let loop_start = r.le_u32() as uint;
+    let loop_length = r.le_u32() as uint;
+    let volume = std::cmp::min(r.u8() as uint, 64);
+    let finetune = r.u8() as i8;
+    let kind = r.u8();
+    let panning = r.u8() as uint;
+    let relative = r.u8() as i8;
+
+    r.seek(header_start + 40);
+
+    headers.push((length, loop_start, loop_length, volume, finetune, kind, panning, relative));
+  }
+
+  for &(length, loop_start, loop_length, volume, finetune, kind, panning, relative) in headers.iter() {
+    let sixteen = kind & 16 != 0;
+    let width = if sixteen { 2 } else { 1 };
+
+    let mut sample = Sample::empty();
+
+    sample.data = delta(r.bytes(length), sixteen);
+    sample.volume = volume;
+    sample.panning = Some(panning);
+    sample.rate = 8363.0 * (2.0f64).powf((relative as f64 * 128.0 + finetune as f64) / 1536.0);
+
+    sample.repeat = match kind & 3 {
+      1 => Loop::new(loop_start / width, (loop_start + loop_length) / width, false, sample.data.len()),
+      2 => Loop::new(loop_start / width, (loop_start + loop_length) / width, true, sample.data.len()),
+      _ => None
+    };
+
+    samples.push(sample);
+  }
+
+  for note in range(0u, 96) {
+    let sample = if keymap[note] < count { first + keymap[note] + 1 } else { 0 };
+
+    *instrument.keymap.get_mut(note + 12) = (note + 12, sample);
+  }
+
+  return instrument;
+}
+
+/// Decodes delta encoded signed samples.
+fn delta(data: &[u8], sixteen: bool) -> Vec<f32> {
+  if sixteen {
+    let mut value = 0i16;
+
+    return range(0, data.len() / 2).map(|i| {
+      value += (data[i * 2] as u16 | (data[i * 2 + 1] as u16 << 8)) as i16;
+      value as f32 / 32768.0
+    }).collect();
+  }
+
+  let mut value = 0i8;
+
+  return data.iter().map(|&b| {
+    value += b as i8;
+    value as f32 / 128.0
+  }).collect();
+}
+
+#[cfg(test)]
+mod tests {
+  #[test]
+  fn test_delta() {
+    assert_eq!(super::delta([1u8, 1, 0xFE], false), vec![1.0 / 128.0, 2.0 / 128.0, 0.0]);
+    assert_eq!(super::delta([0x00u8, 0x40, 0x00, 0x40], true), vec![0.5, -1.0]);
+  }
+
+  #[test]
+  fn test_effect() {
+    assert_eq!(super::effect(0x0F, 0x7D), ::tracker::SetTempo(0x7D));
+    assert_eq!(super::effect(0x21, 0x13), ::tracker::ExtraFinePortaUp(3));
+    assert_eq!(super::effect(0x14, 0x05), ::tracker::KeyOff(5));
+  }
+}
@@ -0,0 +1,267 @@
+use std;
+use std::comm::Sender;
+
+use channel;
+use sample;
+
+/// The smallest and largest sample, and the RMS level, of one channel
+/// over a pixel.
+#[deriving(Clone,Show,PartialEq)]
+pub struct Point {
+  pub min: f64,
+  pub max: f64,
+  pub rms: f64
+}
+
+/// A waveform overview at one resolution.
+#[deriving(Clone,Show,PartialEq)]
+pub struct Overview {
+  pub sample_rate: f64,
+  pub channels: uint,
+  pub samples_per_pixel: uint,
+
+  /// Points by pixel, then channel.
+  pub points: Vec<Point>
+}
+
+/// Scales `x` to a signed integer of `bits` bits.
+fn quantize(x: f64, bits: uint) -> i32 {
+  let scale = ((1u << (bits - 1)) - 1) as f64;
+
+  return (x * scale).round().max(-scale - 1.0).min(scale) as i32;
+}
+
+fn push_u32(data: &mut Vec<u8>, x: u32) {
+  for shift in [0u, 8, 16, 24].iter() {
+    data.push((x >> *shift) as u8);
+  }
+}
+
+fn check_bits(bits: uint) {
+  if bits != 8 && bits != 16 {
+    panic!("waveform::Overview: Bits must be 8 or 16 (ARGUMENT)");
+  }
+}
+
+impl Overview {
+  /// The number of pixels.
+  pub fn length(&self) -> uint {
+    return self.points.len() / std::cmp::max(self.channels, 1);
+  }
+
+  /// The overview in the audiowaveform `.dat` format, with `bits` (8 or 16)
+  /// bits per value. Mono overviews use version 1 of the format, and
+  /// others version 2, which adds a channel count. The format has no RMS
+  /// values.
+  pub fn dat(&self, bits: uint) -> Vec<u8> {
+    check_bits(bits);
+
+    let mut data = Vec::with_capacity(24 + self.points.len() * bits / 4);
+
+    push_u32(&mut data, if self.channels == 1 { 1 } else { 2 });
+    push_u32(&mut data, if bits == 8 { 1 } else { 0 });
+    push_u32(&mut data, self.sample_rate as u32);
+    push_u32(&mut data, self.samples_per_pixel as u32);
+    push_u32(&mut data, self.length() as u32);
+
+    if self.channels != 1 {
+      push_u32(&mut data, self.channels as u32);
+    }
+
+    for point in self.points.iter() {
+      for &x in [point.min, point.max].iter() {
+        let value = quantize(x, bits);
+
+        data.push(value as u8);
+
+        if bits == 16 {
+          data.push((value >> 8) as u8);
+        }
+      }
+    }
+
+    return data;
+  }
+
+  /// The overview in the audiowaveform JSON format, with `bits` (8 or 16)
+  /// bits per value, plus an `rms` array in the same order as `data`.
+  pub fn json(&self, bits: uint) -> String {
+    check_bits(bits);
+
+    let data: Vec<String> = self.points.iter().flat_map(|p| {
+      vec![quantize(p.min, bits), quantize(p.max, bits)].into_iter()
+    }).map(|x| x.to_string()).collect();
+
+    let rms: Vec<String> = self.points.iter().map(|p| quantize(p.rms, bits).to_string()).collect();
+
+    return format!("{{\"version\":2,\"channels\":{},\"sample_rate\":{},\"samples_per_pixel\":{},\"bits\":{},\"length\":{},\"data\":[{}],\"rms\":[{}]}}",
+      self.channels, self.sample_rate as u32, self.samples_per_pixel, bits, self.length(), data.connect(","), rms.connect(","));
+  }
+}
+
+/// Accumulates the points of one overview.
+struct Level {
+  overview: Overview,
+  min: Vec<f64>,
+  max: Vec<f64>,
+  squares: Vec<f64>,
+  count: uint
+}
+
+impl Level {
+  fn new(samples_per_pixel: uint, channels: uint, sample_rate: f64) -> Level {
+    return Level {
+      overview: Overview { sample_rate: sample_rate, channels: channels, samples_per_pixel: samples_per_pixel, points: Vec::new() },
+      min: Vec::from_elem(channels, std::f64::INFINITY),
+      max: Vec::from_elem(channels, std::f64::NEG_INFINITY),
+      squares: Vec::from_elem(channels, 0.0),
+      count: 0
+    };
+  }
+
+  fn add(&mut self, frame: &[f64]) {
+    for (c, &x) in frame.iter().enumerate() {
+      *self.min.get_mut(c) = self.min[c].min(x);
+      *self.max.get_mut(c) = self.max[c].max(x);
+      *self.squares.get_mut(c) += x * x;
+    }
+
+    self.count += 1;
+
+    if self.count == self.overview.samples_per_pixel {
+      self.flush();
+    }
+  }
+
+  fn flush(&mut self) {
+    if self.count == 0 {
+      return;
+    }
+
+    for c in range(0, self.overview.channels) {
+      self.overview.points.push(Point { min: self.min[c], max: self.max[c], rms: (self.squares[c] / self.count as f64).sqrt() });
+
+      *self.min.get_mut(c) = std::f64::INFINITY;
+      *self.max.get_mut(c) = std::f64::NEG_INFINITY;
+      *self.squares.get_mut(c) = 0.0;
+    }
+
+    self.count = 0;
+  }
+}
+
+/// Computes waveform overviews of audio passing through, one per number of
+/// samples per pixel in `levels`, and sends them to `report` at the end of
+/// the stream. The last pixel of each level may cover fewer samples.
+pub struct Waveform {
+  source: channel::Source<::Audio>,
+  sink: channel::Sink<::Audio>,
+  levels: Vec<uint>,
+  report: Sender<Vec<Overview>>
+}
+
+impl Waveform {
+  pub fn new(source: channel::Source<::Audio>, sink: channel::Sink<::Audio>, levels: Vec<uint>, report: Sender<Vec<Overview>>) -> Waveform {
+    if levels.is_empty() || levels.iter().any(|&n| n == 0) {
+      panic!("waveform::Waveform: Samples per pixel must be given and positive (ARGUMENT)");
+    }
+
+    return Waveform { source: source, sink: sink, levels: levels, report: report };
+  }
+
+  pub fn run(&mut self) {
+    let levels = &self.levels;
+    let source = &mut self.source;
+    let sink = &mut self.sink;
+
+    let mut accumulators: Vec<Level> = Vec::new();
+    let mut samples = Vec::new();
+
+    let mut last = false;
+
+    while !last {
+      source.read(|audio| {
+        last = audio.last;
+
+        if !audio.data.is_empty() {
+          if accumulators.is_empty() {
+            accumulators = levels.iter().map(|&n| Level::new(n, audio.channels, audio.sample_rate)).collect();
+          }
+
+          sample::decode(audio, &mut samples);
+
+          for frame in samples.as_slice().chunks(audio.channels) {
+            for level in accumulators.iter_mut() {
+              level.add(frame);
+            }
+          }
+        }
+
+        sink.write(|out| {
+          out.channels = audio.channels;
+          out.layout = audio.layout.clone();
+          out.sample_rate = audio.sample_rate;
+          out.sample_type = audio.sample_type;
+          out.endian = audio.endian;
+          out.last = last;
+          out.data.push_all(audio.data.as_slice());
+        });
+      });
+    }
+
+    let overviews = accumulators.into_iter().map(|mut level| {
+      level.flush();
+      level.overview
+    }).collect();
+
+    self.report.send(overviews);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::comm;
+
+  use channel;
+  use endian;
+  use sample;
+  use sample_type;
+
+  #[test]
+  fn test_waveform() {
+    let (mut sink, source) = channel::create::<::Audio>(1);
+    let (out_sink, mut out_source) = channel::create::<::Audio>(1);
+    let (report, overviews) = comm::channel();
+
+    spawn(proc() {
+      super::Waveform::new(source, out_sink, vec![2, 4], report).run();
+    });
+
+    spawn(proc() {
+      sink.write(|audio| {
+        audio.channels = 2;
+        audio.sample_rate = 44100.0;
+        audio.sample_type = sample_type::Float(32);
+        audio.endian = endian::Little;
+        audio.last = true;
+
+        sample::encode(&[0.5, -0.5, -0.5, 0.25, 0.5, 0.0, -0.5, 0.0, 1.0, 0.0], audio);
+      });
+    });
+
+    out_source.read(|audio| assert!(audio.last));
+
+    let overviews = overviews.recv();
+
+    assert_eq!(overviews.len(), 2);
+    assert_eq!(overviews[0].length(), 3);
+    assert_eq!(overviews[1].points[0], super::Point { min: -0.5, max: 0.5, rms: 0.5 });
+    assert_eq!(overviews[1].points[3], super::Point { min: 0.0, max: 0.0, rms: 0.0 });
+
+    let dat = overviews[1].dat(8);
+
+    assert_eq!(dat.slice_to(24).to_vec(), vec![2, 0, 0, 0, 1, 0, 0, 0, 0x44, 0xAC, 0, 0, 4, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0]);
+    assert_eq!(dat.slice_from(24).to_vec(), vec![0xC0, 0x40, 0xC0, 0x20, 0x7F, 0x7F, 0, 0]);
+    assert_eq!(overviews[1].json(8).as_slice(), "{\"version\":2,\"channels\":2,\"sample_rate\":44100,\"samples_per_pixel\":4,\"bits\":8,\"length\":2,\"data\":[-64,64,-64,32,127,127,0,0],\"rms\":[64,35,127,0]}");
+  }
+}